@@ -0,0 +1,251 @@
+//! `auth-svc explain`: builds a check request from a curl-like command line
+//! and prints the trace returned by a running auth-svc.
+//!
+//! ```shell
+//! auth-svc explain -X POST -H "Authorization: Bearer $TOKEN" https://f2.local/rest/v1/images
+//! ```
+
+use crate::explain::ExplainResponse;
+use crate::explain::pb::auth_admin_client::AuthAdminClient;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use envoy_types::pb::envoy::service::auth::v3::{
+    AttributeContext, CheckRequest, attribute_context,
+};
+use f2_utils::client::h2c::{H2cChannel, H2cMode};
+use f2_utils::client::parse_origin;
+use f2_utils::tls::ClientTls;
+use http::Uri;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Write;
+use std::time::Duration;
+use tonic::Code;
+use tonic::metadata::MetadataValue;
+
+const USAGE: &str = "usage: auth-svc explain [--server URL] [--admin USER:PASSWORD] \
+[-X METHOD] [-H 'NAME: VALUE']... [-u USER:PASSWORD] [-d DATA] URL";
+
+#[derive(Debug)]
+pub(crate) struct Invocation {
+    server: String,
+    admin: Option<String>,
+    method: Option<String>,
+    headers: HashMap<String, String>,
+    body: String,
+    url: Uri,
+}
+
+impl Invocation {
+    /// Parses the arguments following `explain`. The admin credentials
+    /// default to `DASHBOARD_USERNAME` and `DASHBOARD_PASSWORD`.
+    pub(crate) fn parse(args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let mut server =
+            env::var("AUTH_SVC_ADDR").unwrap_or_else(|_| "http://127.0.0.1:8080".to_string());
+        let mut admin = match (
+            env::var("DASHBOARD_USERNAME"),
+            env::var("DASHBOARD_PASSWORD"),
+        ) {
+            (Ok(username), Ok(password)) => Some(format!("{username}:{password}")),
+            _ => None,
+        };
+        let mut method = None;
+        let mut headers = HashMap::new();
+        let mut body = String::new();
+        let mut url = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |flag: &str| {
+                args.next()
+                    .ok_or_else(|| anyhow::anyhow!("{flag} needs a value\n{USAGE}"))
+            };
+            match arg.as_str() {
+                "--server" => server = value(&arg)?,
+                "--admin" => admin = Some(value(&arg)?),
+                "-X" | "--request" => method = Some(value(&arg)?),
+                "-H" | "--header" => {
+                    let header = value(&arg)?;
+                    let (name, value) = header
+                        .split_once(':')
+                        .ok_or_else(|| anyhow::anyhow!("malformed header {header:?}"))?;
+                    headers.insert(name.trim().to_string(), value.trim().to_string());
+                }
+                "-u" | "--user" => {
+                    let credentials = BASE64_STANDARD.encode(value(&arg)?);
+                    headers.insert("Authorization".to_string(), format!("Basic {credentials}"));
+                }
+                "-d" | "--data" => body = value(&arg)?,
+                flag if flag.starts_with('-') => {
+                    anyhow::bail!("unknown option {flag}\n{USAGE}")
+                }
+                _ if url.is_none() => url = Some(arg.parse::<Uri>()?),
+                _ => anyhow::bail!("unexpected argument {arg:?}\n{USAGE}"),
+            }
+        }
+
+        Ok(Self {
+            server,
+            admin,
+            method,
+            headers,
+            body,
+            url: url.ok_or_else(|| anyhow::anyhow!("missing URL\n{USAGE}"))?,
+        })
+    }
+
+    /// The request Envoy would send for this command line.
+    pub(crate) fn check_request(&self) -> CheckRequest {
+        // like curl, sending data switches the default method to POST
+        let method = match (&self.method, self.body.is_empty()) {
+            (Some(method), _) => method.to_uppercase(),
+            (None, true) => "GET".to_string(),
+            (None, false) => "POST".to_string(),
+        };
+
+        CheckRequest {
+            attributes: Some(AttributeContext {
+                request: Some(attribute_context::Request {
+                    time: None,
+                    http: Some(attribute_context::HttpRequest {
+                        method,
+                        headers: self.headers.clone(),
+                        path: self
+                            .url
+                            .path_and_query()
+                            .map_or("/", |p| p.as_str())
+                            .to_string(),
+                        host: self.url.authority().map_or("", |a| a.as_str()).to_string(),
+                        scheme: self.url.scheme_str().unwrap_or("http").to_string(),
+                        query: self.url.query().unwrap_or_default().to_string(),
+                        size: self.body.len() as i64,
+                        protocol: "HTTP/1.1".to_string(),
+                        body: self.body.clone(),
+                        ..Default::default()
+                    }),
+                }),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+pub(crate) async fn run(args: impl IntoIterator<Item = String>) -> anyhow::Result<()> {
+    let invocation = Invocation::parse(args)?;
+
+    let mode = match env::var("AUTH_SVC_H2C_MODE") {
+        Ok(mode) => mode.parse()?,
+        Err(_) => H2cMode::default(),
+    };
+    let mut channel = H2cChannel::new(Client::builder(TokioExecutor::new()).build_http())
+        .with_mode(mode)
+        .with_connect_timeout(Duration::from_secs(5))
+        .with_timeout(Duration::from_secs(30));
+    if let Ok(ca) = env::var("AUTH_SVC_CA_FILE") {
+        channel = channel.with_tls(ClientTls::new(ca));
+    }
+    let mut client = AuthAdminClient::with_origin(channel, parse_origin(&invocation.server)?);
+
+    let mut request = tonic::Request::new(invocation.check_request());
+    if let Some(admin) = &invocation.admin {
+        let credentials = BASE64_STANDARD.encode(admin);
+        request.metadata_mut().insert(
+            "authorization",
+            MetadataValue::try_from(format!("Basic {credentials}"))?,
+        );
+    }
+
+    let trace = client.explain(request).await?.into_inner();
+    print!("{}", render(&trace));
+    Ok(())
+}
+
+fn render(trace: &ExplainResponse) -> String {
+    let mut out = String::new();
+    let source = match trace.credential_source.as_str() {
+        "" => "none",
+        source => source,
+    };
+    let _ = writeln!(out, "credential source: {source}");
+    for key in &trace.keys {
+        let result = if key.matched { "matched" } else { &key.error };
+        let _ = writeln!(out, "key {}: {result}", key.key_id);
+    }
+    for claim in &trace.claims {
+        let result = if claim.valid { "ok" } else { "FAILED" };
+        let _ = writeln!(out, "claim {}: {result} ({})", claim.claim, claim.detail);
+    }
+    if !trace.role.is_empty() {
+        let _ = writeln!(out, "role: {}", trace.role);
+    }
+    for rule in &trace.rules {
+        let result = if rule.allowed { "allow" } else { "deny" };
+        let _ = writeln!(out, "rule {} [{}]: {result}", rule.policy, rule.mode);
+    }
+    let _ = writeln!(
+        out,
+        "decision: {:?}: {}",
+        Code::from_i32(trace.code),
+        trace.reason
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Invocation {
+        Invocation::parse(args.iter().map(|a| a.to_string())).unwrap()
+    }
+
+    #[test]
+    fn builds_check_request_from_curl_args() {
+        let invocation = parse(&[
+            "-X",
+            "patch",
+            "-H",
+            "apikey: abc",
+            "-H",
+            "Accept:application/json",
+            "https://f2.local/rest/v1/images?id=eq.1",
+        ]);
+        let request = invocation.check_request();
+        let http = request.attributes.unwrap().request.unwrap().http.unwrap();
+        assert_eq!(http.method, "PATCH");
+        assert_eq!(http.path, "/rest/v1/images?id=eq.1");
+        assert_eq!(http.query, "id=eq.1");
+        assert_eq!(http.host, "f2.local");
+        assert_eq!(http.scheme, "https");
+        assert_eq!(http.headers["apikey"], "abc");
+        assert_eq!(http.headers["Accept"], "application/json");
+    }
+
+    #[test]
+    fn user_flag_sets_basic_auth_and_data_defaults_to_post() {
+        let invocation = parse(&["-u", "admin:s3cr3t", "-d", "{}", "http://f2.local/"]);
+        let http = invocation
+            .check_request()
+            .attributes
+            .unwrap()
+            .request
+            .unwrap()
+            .http
+            .unwrap();
+        assert_eq!(http.method, "POST");
+        assert_eq!(http.body, "{}");
+        assert_eq!(
+            http.headers["Authorization"],
+            format!("Basic {}", BASE64_STANDARD.encode("admin:s3cr3t"))
+        );
+    }
+
+    #[test]
+    fn rejects_missing_url_and_unknown_flags() {
+        assert!(Invocation::parse(Vec::<String>::new()).is_err());
+        assert!(Invocation::parse(["--frobnicate".to_string()]).is_err());
+        assert!(Invocation::parse(["-H".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,345 @@
+use crate::jwt::AuthState;
+use crate::jwt::extauth::AuthSvc;
+use crate::policy::{Policy, PolicyMode};
+use envoy_types::ext_authz::v3::pb::CheckResponse;
+use envoy_types::pb::envoy::service::auth::v3::CheckRequest;
+use std::sync::Arc;
+use tonic::{Code, Request, Response, Status};
+
+pub(crate) mod cli;
+
+/// The `f2.auth.v1.AuthAdmin` service and its messages, from
+/// `proto/f2/auth/v1/admin.proto`.
+pub(crate) use f2_proto::f2::auth::v1 as pb;
+pub(crate) use pb::{
+    ClaimCheck, ExplainResponse, KeyAttempt, RevokeTokenRequest, RevokeTokenResponse,
+    RuleEvaluation,
+};
+
+use pb::auth_admin_server::AuthAdmin;
+
+/// Collects the steps of a check when explaining it. The default trace
+/// records nothing, so the regular check path pays no more than a branch.
+#[derive(Debug, Default)]
+pub(crate) struct Trace(Option<ExplainResponse>);
+
+impl Trace {
+    pub(crate) fn recording() -> Self {
+        Self(Some(ExplainResponse::default()))
+    }
+
+    pub(crate) fn is_recording(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub(crate) fn credential_source(&mut self, source: &str) {
+        if let Some(t) = &mut self.0 {
+            t.credential_source = source.to_string();
+        }
+    }
+
+    pub(crate) fn key(&mut self, key_id: &str, error: Option<String>) {
+        if let Some(t) = &mut self.0 {
+            t.keys.push(KeyAttempt {
+                key_id: key_id.to_string(),
+                matched: error.is_none(),
+                error: error.unwrap_or_default(),
+            });
+        }
+    }
+
+    pub(crate) fn claim(&mut self, claim: &str, valid: bool, detail: impl FnOnce() -> String) {
+        if let Some(t) = &mut self.0 {
+            t.claims.push(ClaimCheck {
+                claim: claim.to_string(),
+                valid,
+                detail: detail(),
+            });
+        }
+    }
+
+    pub(crate) fn role(&mut self, role: &str) {
+        if let Some(t) = &mut self.0 {
+            t.role = role.to_string();
+        }
+    }
+
+    pub(crate) fn rule(&mut self, policy: &Policy, allowed: bool) {
+        if let Some(t) = &mut self.0 {
+            t.rules.push(RuleEvaluation {
+                policy: policy.name.clone(),
+                mode: match policy.mode {
+                    PolicyMode::Enforce => "enforce",
+                    PolicyMode::Shadow => "shadow",
+                }
+                .to_string(),
+                allowed,
+            });
+        }
+    }
+
+    /// Completes the trace with the decision sent back to Envoy.
+    pub(crate) fn finish(self, response: &CheckResponse) -> ExplainResponse {
+        let mut trace = self.0.unwrap_or_default();
+        match &response.status {
+            Some(status) => {
+                trace.code = status.code;
+                trace.reason = status.message.clone();
+            }
+            None => {
+                // envoy reads a missing status as OK and lets the request through
+                trace.code = Code::Ok as i32;
+                trace.reason = "no credentials found, allowed without a role".to_string();
+            }
+        }
+        trace
+    }
+}
+
+/// Admin-only debugging endpoints. Callers must present the dashboard basic
+/// auth credentials or a `service_role` JWT in the `authorization` metadata.
+pub(crate) struct AdminSvc {
+    auth: Arc<AuthSvc>,
+    state: Arc<AuthState>,
+}
+
+impl AdminSvc {
+    pub(crate) fn new(auth: Arc<AuthSvc>, state: Arc<AuthState>) -> Self {
+        Self { auth, state }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn authorize_admin<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let authorization = request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| Status::unauthenticated("admin credentials required"))?;
+        if !self.state.is_admin(authorization) {
+            tracing::warn!("Rejected admin request with non-admin credentials");
+            return Err(Status::permission_denied("admin credentials required"));
+        }
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl AuthAdmin for AdminSvc {
+    async fn explain(
+        &self,
+        request: Request<CheckRequest>,
+    ) -> Result<Response<ExplainResponse>, Status> {
+        self.authorize_admin(&request)?;
+
+        let mut trace = Trace::recording();
+        let response = self.auth.evaluate(request.get_ref(), &mut trace)?;
+        Ok(Response::new(trace.finish(&response)))
+    }
+
+    /// Revocations are held in memory, so they only apply to the replica that
+    /// received them and are lost on restart.
+    async fn revoke_token(
+        &self,
+        request: Request<RevokeTokenRequest>,
+    ) -> Result<Response<RevokeTokenResponse>, Status> {
+        self.authorize_admin(&request)?;
+
+        let revoked = self.state.revoke_token(&request.get_ref().token);
+        Ok(Response::new(RevokeTokenResponse { revoked }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jwt::Claims;
+    use crate::policy::PolicySet;
+    use base64::Engine;
+    use base64::prelude::BASE64_STANDARD;
+    use chrono::Utc;
+    use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
+    use tonic::metadata::MetadataValue;
+
+    fn make_admin_svc() -> AdminSvc {
+        let policies = PolicySet::from_json(
+            r#"[
+                {"name": "rest-writes", "path_prefix": "/rest/v1/", "methods": ["POST"], "roles": ["service_role"]},
+                {"name": "rest-all", "path_prefix": "/rest/v1/", "roles": ["service_role"], "mode": "shadow"}
+            ]"#,
+        )
+        .unwrap();
+        let jwt_secret = BASE64_STANDARD.encode(b"secret");
+        let state = Arc::new(
+            AuthState::new(jwt_secret, "admin".into(), "s3cr3t".into()).with_policies(policies),
+        );
+        AdminSvc::new(Arc::new(AuthSvc::new(state.clone())), state)
+    }
+
+    fn create_jwt(role: &str, iss: &str, exp_offset: i64) -> String {
+        let now = Utc::now().timestamp();
+        let claims = Claims {
+            role: role.to_string(),
+            iss: iss.to_string(),
+            iat: now,
+            exp: now + exp_offset,
+        };
+        encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap()
+    }
+
+    fn admin_request(args: &[&str]) -> Request<CheckRequest> {
+        let check = cli::Invocation::parse(args.iter().map(|a| a.to_string()))
+            .unwrap()
+            .check_request();
+        let mut request = Request::new(check);
+        let creds = BASE64_STANDARD.encode(b"admin:s3cr3t");
+        request.metadata_mut().insert(
+            "authorization",
+            MetadataValue::try_from(format!("Basic {creds}")).unwrap(),
+        );
+        request
+    }
+
+    #[tokio::test]
+    async fn explain_requires_admin() {
+        let svc = make_admin_svc();
+        let check = cli::Invocation::parse(["http://f2.local/".to_string()])
+            .unwrap()
+            .check_request();
+
+        let err = svc.explain(Request::new(check.clone())).await.unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+
+        let mut request = Request::new(check);
+        let token = create_jwt("anon", "f2", 3600);
+        request.metadata_mut().insert(
+            "authorization",
+            MetadataValue::try_from(format!("Bearer {token}")).unwrap(),
+        );
+        let err = svc.explain(request).await.unwrap_err();
+        assert_eq!(err.code(), Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn explain_policy_denial() {
+        let svc = make_admin_svc();
+        let token = create_jwt("anon", "f2", 3600);
+        let auth = format!("Authorization: Bearer {token}");
+        let request = admin_request(&["-X", "POST", "-H", &auth, "http://f2.local/rest/v1/images"]);
+
+        let trace = svc.explain(request).await.unwrap().into_inner();
+        assert_eq!(trace.credential_source, "Authorization header (Bearer)");
+        assert_eq!(trace.keys.len(), 1);
+        assert!(trace.keys[0].matched);
+        assert!(trace.claims.iter().all(|c| c.valid));
+        assert_eq!(trace.role, "anon");
+        assert_eq!(
+            trace
+                .rules
+                .iter()
+                .map(|r| (r.policy.as_str(), r.mode.as_str(), r.allowed))
+                .collect::<Vec<_>>(),
+            vec![
+                ("rest-writes", "enforce", false),
+                ("rest-all", "shadow", false)
+            ]
+        );
+        assert_eq!(trace.code, Code::PermissionDenied as i32);
+        assert_eq!(trace.reason, "denied by policy rest-writes");
+    }
+
+    #[tokio::test]
+    async fn explain_no_credentials() {
+        let svc = make_admin_svc();
+        let request = admin_request(&["http://f2.local/rest/v1/images"]);
+
+        let trace = svc.explain(request).await.unwrap().into_inner();
+        assert!(trace.credential_source.is_empty());
+        assert!(trace.role.is_empty());
+        assert_eq!(trace.code, Code::Ok as i32);
+        assert_eq!(trace.reason, "no credentials found, allowed without a role");
+    }
+
+    #[tokio::test]
+    async fn explain_invalid_issuer() {
+        let svc = make_admin_svc();
+        let token = create_jwt("anon", "someone-else", 3600);
+        let request = admin_request(&["-H", &format!("apikey: {token}"), "http://f2.local/"]);
+
+        let trace = svc.explain(request).await.unwrap().into_inner();
+        assert_eq!(trace.credential_source, "apikey header");
+        assert!(trace.keys[0].matched);
+        assert_eq!(trace.claims.len(), 1);
+        assert_eq!(trace.claims[0].claim, "iss");
+        assert!(!trace.claims[0].valid);
+        assert!(trace.rules.is_empty());
+        assert_eq!(trace.reason, "invalid JWT token");
+    }
+
+    #[tokio::test]
+    async fn explain_bad_signature() {
+        let svc = make_admin_svc();
+        let token = encode(
+            &Header::new(Algorithm::HS256),
+            &Claims {
+                role: "anon".into(),
+                iss: "f2".into(),
+                iat: 0,
+                exp: Utc::now().timestamp() + 3600,
+            },
+            &EncodingKey::from_secret(b"wrongsecret"),
+        )
+        .unwrap();
+        let auth = format!("Authorization: Bearer {token}");
+        let request = admin_request(&["-H", &auth, "http://f2.local/"]);
+
+        let trace = svc.explain(request).await.unwrap().into_inner();
+        assert!(!trace.keys[0].matched);
+        assert!(!trace.keys[0].error.is_empty());
+        assert!(trace.claims.is_empty());
+        assert_eq!(trace.code, Code::PermissionDenied as i32);
+    }
+
+    #[tokio::test]
+    async fn revoke_token_requires_admin_and_rejects_token() {
+        let svc = make_admin_svc();
+        let token = create_jwt("anon", "f2", 3600);
+
+        let err = svc
+            .revoke_token(Request::new(RevokeTokenRequest {
+                token: token.clone(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+
+        let mut request = Request::new(RevokeTokenRequest {
+            token: token.clone(),
+        });
+        let creds = BASE64_STANDARD.encode(b"admin:s3cr3t");
+        request.metadata_mut().insert(
+            "authorization",
+            MetadataValue::try_from(format!("Basic {creds}")).unwrap(),
+        );
+        assert!(
+            svc.revoke_token(request)
+                .await
+                .unwrap()
+                .into_inner()
+                .revoked
+        );
+
+        let auth = format!("Authorization: Bearer {token}");
+        let trace = svc
+            .explain(admin_request(&["-H", &auth, "http://f2.local/"]))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(trace.claims[0].claim, "revocation");
+        assert_eq!(trace.reason, "invalid JWT token");
+    }
+}
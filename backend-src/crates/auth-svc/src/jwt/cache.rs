@@ -0,0 +1,202 @@
+use crate::metrics::{CacheLabels, CacheMetrics};
+use chrono::Utc;
+use moka::Expiry;
+use moka::sync::Cache;
+use sha2::{Digest, Sha256};
+use std::time::{Duration, Instant};
+
+/// Most revocations kept. Past this the least recently checked go first, and
+/// a token evicted early is accepted again until it expires.
+const MAX_REVOKED: u64 = 100_000;
+
+/// Tokens are stored by their SHA-256 so the cache never holds a usable credential.
+pub(crate) type TokenHash = [u8; 32];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct VerifiedToken {
+    pub role: String,
+    pub username: String,
+    pub exp: i64,
+    /// Generation of the signing key the token was verified with.
+    pub generation: u64,
+}
+
+/// Bounded cache of verified tokens plus the tokens revoked on this replica.
+/// Verified entries live until the sooner of the token's `exp` and the
+/// configured TTL; revoked entries live until the token would have expired.
+pub(crate) struct TokenCache {
+    verified: Cache<TokenHash, VerifiedToken>,
+    revoked: Cache<TokenHash, i64>,
+    metrics: CacheMetrics,
+}
+
+struct VerifiedExpiry {
+    ttl: Duration,
+}
+
+struct RevokedExpiry;
+
+fn until(exp: i64) -> Duration {
+    Duration::from_secs(exp.saturating_sub(Utc::now().timestamp()).max(0) as u64)
+}
+
+impl Expiry<TokenHash, VerifiedToken> for VerifiedExpiry {
+    fn expire_after_create(
+        &self,
+        _: &TokenHash,
+        token: &VerifiedToken,
+        _: Instant,
+    ) -> Option<Duration> {
+        Some(until(token.exp).min(self.ttl))
+    }
+}
+
+impl Expiry<TokenHash, i64> for RevokedExpiry {
+    fn expire_after_create(&self, _: &TokenHash, exp: &i64, _: Instant) -> Option<Duration> {
+        Some(until(*exp))
+    }
+}
+
+impl TokenCache {
+    /// A `capacity` of zero disables caching verified tokens. Up to
+    /// [`MAX_REVOKED`] revocations are kept regardless.
+    pub(crate) fn new(capacity: u64, ttl: Duration, metrics: CacheMetrics) -> Self {
+        Self {
+            verified: Cache::builder()
+                .max_capacity(capacity)
+                .expire_after(VerifiedExpiry { ttl })
+                .build(),
+            revoked: Cache::builder()
+                .max_capacity(MAX_REVOKED)
+                .expire_after(RevokedExpiry)
+                .build(),
+            metrics,
+        }
+    }
+
+    pub(crate) fn hash(token: &str) -> TokenHash {
+        Sha256::digest(token.as_bytes()).into()
+    }
+
+    /// Looks up a token verified with the key of the given `generation`.
+    pub(crate) fn get(&self, hash: &TokenHash, generation: u64) -> Option<VerifiedToken> {
+        let hit = self
+            .verified
+            .get(hash)
+            .filter(|t| t.generation == generation && t.exp >= Utc::now().timestamp());
+
+        let result = if hit.is_some() { "hit" } else { "miss" };
+        self.metrics
+            .lookups
+            .get_or_create(&CacheLabels { result })
+            .inc();
+        self.metrics.entries.set(self.verified.entry_count() as i64);
+        hit
+    }
+
+    pub(crate) fn insert(&self, hash: TokenHash, token: VerifiedToken) {
+        self.verified.insert(hash, token);
+        self.update_entries();
+    }
+
+    pub(crate) fn is_revoked(&self, hash: &TokenHash) -> bool {
+        self.revoked.contains_key(hash)
+    }
+
+    pub(crate) fn revoke(&self, hash: TokenHash, exp: i64) {
+        self.verified.invalidate(&hash);
+        self.revoked.insert(hash, exp);
+        self.update_entries();
+    }
+
+    /// Drops every verified token, e.g. after the signing key changed.
+    pub(crate) fn invalidate_all(&self) {
+        self.verified.invalidate_all();
+        self.metrics.entries.set(0);
+    }
+
+    /// Applies the pending inserts, invalidations and evictions, which the
+    /// cache otherwise counts lazily, and sets the entries gauge. Lookups only
+    /// read the count, keeping the maintenance off the hit path.
+    fn update_entries(&self) {
+        self.verified.run_pending_tasks();
+        self.metrics.entries.set(self.verified.entry_count() as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verified(exp_offset: i64, generation: u64) -> VerifiedToken {
+        VerifiedToken {
+            role: "anon".into(),
+            username: "anon".into(),
+            exp: Utc::now().timestamp() + exp_offset,
+            generation,
+        }
+    }
+
+    #[test]
+    fn expires_at_the_sooner_of_exp_and_ttl() {
+        let expiry = VerifiedExpiry {
+            ttl: Duration::from_secs(300),
+        };
+        let hash = TokenCache::hash("token");
+        let long = expiry.expire_after_create(&hash, &verified(3600, 0), Instant::now());
+        assert_eq!(long, Some(Duration::from_secs(300)));
+
+        let short = expiry
+            .expire_after_create(&hash, &verified(60, 0), Instant::now())
+            .unwrap();
+        assert!(short <= Duration::from_secs(60) && short >= Duration::from_secs(59));
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let metrics = CacheMetrics::default();
+        let cache = TokenCache::new(16, Duration::from_secs(300), metrics.clone());
+        let hash = TokenCache::hash("token");
+
+        assert!(cache.get(&hash, 0).is_none());
+        cache.insert(hash, verified(3600, 0));
+        assert!(cache.get(&hash, 0).is_some());
+
+        let lookups = |result| metrics.lookups.get_or_create(&CacheLabels { result }).get();
+        assert_eq!(lookups("hit"), 1);
+        assert_eq!(lookups("miss"), 1);
+    }
+
+    #[test]
+    fn stale_generation_is_a_miss() {
+        let cache = TokenCache::new(16, Duration::from_secs(300), CacheMetrics::default());
+        let hash = TokenCache::hash("token");
+        cache.insert(hash, verified(3600, 1));
+        assert!(cache.get(&hash, 2).is_none());
+    }
+
+    #[test]
+    fn revoke_drops_verified_entry() {
+        let cache = TokenCache::new(16, Duration::from_secs(300), CacheMetrics::default());
+        let hash = TokenCache::hash("token");
+        let token = verified(3600, 0);
+        cache.insert(hash, token.clone());
+        cache.revoke(hash, token.exp);
+        assert!(cache.is_revoked(&hash));
+        assert!(cache.get(&hash, 0).is_none());
+    }
+
+    #[test]
+    fn entries_follow_inserts_and_revocations() {
+        let metrics = CacheMetrics::default();
+        let cache = TokenCache::new(16, Duration::from_secs(300), metrics.clone());
+        let hash = TokenCache::hash("token");
+        let token = verified(3600, 0);
+
+        cache.insert(hash, token.clone());
+        assert_eq!(metrics.entries.get(), 1);
+
+        cache.revoke(hash, token.exp);
+        assert_eq!(metrics.entries.get(), 0);
+    }
+}
@@ -1,10 +1,17 @@
+use crate::explain::Trace;
 use crate::jwt::{AuthState, VerificationResult};
+use crate::metrics::{AuthMetrics, DecisionLabels, PolicyLabels, RequestIdLabels, ShadowLabels};
+use crate::policy::{Target, Verdict};
+use crate::telemetry;
 use envoy_types::ext_authz::v3::pb::{Authorization, CheckRequest, CheckResponse};
-use envoy_types::ext_authz::v3::{CheckRequestExt, CheckResponseExt};
+use envoy_types::ext_authz::v3::{CheckRequestExt, CheckResponseExt, DeniedHttpResponseBuilder};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tonic::{Code, Request, Response, Status};
 
+/// Role given to requests authenticated with the dashboard basic auth credentials.
+const DASHBOARD_ROLE: &str = "dashboard";
+
 #[derive(Debug)]
 enum AuthScheme {
     Bearer(String),
@@ -13,80 +20,253 @@ enum AuthScheme {
 
 pub(crate) struct AuthSvc {
     state: Arc<AuthState>,
+    metrics: AuthMetrics,
 }
 
 impl AuthSvc {
     pub(crate) fn new(state: Arc<AuthState>) -> Self {
-        Self { state }
+        Self {
+            state,
+            metrics: AuthMetrics::default(),
+        }
     }
-}
 
-#[tonic::async_trait]
-impl Authorization for AuthSvc {
-    async fn check(
+    pub(crate) fn with_metrics(mut self, metrics: AuthMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Runs a check, recording each step into `trace`, and compares the
+    /// shadow policies against the decision. Explaining a request must not
+    /// skew the shadow policy metrics or logs, so shadow policies are only
+    /// evaluated for untraced checks.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn evaluate(
         &self,
-        request: Request<CheckRequest>,
-    ) -> Result<Response<CheckResponse>, Status> {
-        let request = request.into_inner();
+        request: &CheckRequest,
+        trace: &mut Trace,
+    ) -> Result<CheckResponse, Status> {
+        let target = request_target(request);
+        let (response, role) = self.decide(request, &target, trace)?;
+        if !trace.is_recording() {
+            self.shadow(&target, role.as_deref(), decision(&response) == "allow");
+        }
+        Ok(response)
+    }
+
+    /// The enforced decision, with the role the credentials authenticated
+    /// as, if any.
+    #[allow(clippy::result_large_err)]
+    fn decide(
+        &self,
+        request: &CheckRequest,
+        target: &Target<'_>,
+        trace: &mut Trace,
+    ) -> Result<(CheckResponse, Option<String>), Status> {
         let headers = request
             .get_client_headers()
             .ok_or_else(|| Status::invalid_argument("client headers not populated by envoy"))?;
 
-        let auth_scheme = match parse_authorization_header(&headers) {
+        let auth_scheme = match parse_authorization_header(headers) {
             Some(scheme) => scheme,
             None => {
                 tracing::debug!("No valid authorization header found");
-                return Ok(Response::new(CheckResponse::default()));
+                return Ok((CheckResponse::default(), None));
             }
         };
+        trace.credential_source(credential_source(headers, &auth_scheme));
 
-        match auth_scheme {
+        let role = match auth_scheme {
             AuthScheme::Bearer(jwt_token) => {
-                match &self.state.verify_jwt(&jwt_token) {
+                match self.state.verify_jwt_traced(&jwt_token, trace) {
                     VerificationResult::Valid {
-                        role: _role,
+                        role,
                         username: _username,
-                    } => Ok(Response::new(CheckResponse::with_status(Status::new(
-                        Code::Ok,
-                        "authorized",
-                    )))),
+                    } => role,
                     VerificationResult::Expired => {
                         tracing::warn!("JWT token has expired");
-                        Ok(Response::new(CheckResponse::with_status(Status::new(
+                        let response = CheckResponse::with_status(Status::new(
                             Code::PermissionDenied,
                             "JWT token has expired",
-                        ))))
+                        ));
+                        return Ok((response, None));
                     }
                     VerificationResult::Invalid => {
                         // this is an error so we can see if there are sudden spikes in invalid tokens
                         tracing::error!("Invalid JWT token provided");
-                        Ok(Response::new(CheckResponse::with_status(Status::new(
+                        let response = CheckResponse::with_status(Status::new(
                             Code::PermissionDenied,
                             "invalid JWT token",
-                        ))))
+                        ));
+                        return Ok((response, None));
                     }
                 }
             }
             AuthScheme::Basic(base64_credentials) => {
                 // Handle Basic auth verification
-                match &self.state.verify_basic_auth(&base64_credentials) {
-                    Ok(_) => Ok(Response::new(CheckResponse::with_status(Status::new(
-                        Code::Ok,
-                        "authorized",
-                    )))),
+                match self.state.verify_basic_auth(&base64_credentials) {
+                    Ok(_) => DASHBOARD_ROLE.to_string(),
                     Err(_) => {
                         tracing::error!("Invalid basic auth credentials");
-                        Ok(Response::new(CheckResponse::with_status(Status::new(
+                        let response = CheckResponse::with_status(Status::new(
                             Code::PermissionDenied,
                             "invalid basic auth credentials",
-                        ))))
+                        ));
+                        return Ok((response, None));
                     }
                 }
             }
+        };
+        trace.role(&role);
+
+        let response = self.authorize(target, &role, trace);
+        Ok((response, Some(role)))
+    }
+
+    /// Runs the authenticated role through the enforced policies, which
+    /// alone decide the response.
+    fn authorize(&self, target: &Target<'_>, role: &str, trace: &mut Trace) -> CheckResponse {
+        let policies = self.state.policies();
+
+        if trace.is_recording() {
+            for policy in policies.matching(target) {
+                trace.rule(policy, policy.allows(role));
+            }
+        }
+
+        if let Verdict::Deny(policy) = policies.evaluate(target, role) {
+            tracing::Span::current().record("auth.policy", policy.name.as_str());
+            tracing::warn!(
+                "Policy {} denied {} {} for role {}",
+                policy.name,
+                target.method,
+                target.path,
+                role
+            );
+            return CheckResponse::with_status(Status::new(
+                Code::PermissionDenied,
+                format!("denied by policy {}", policy.name),
+            ));
+        }
+
+        CheckResponse::with_status(Status::new(Code::Ok, "authorized"))
+    }
+
+    /// Counts every shadow policy matching the request, logging and counting
+    /// those that would have decided otherwise than the enforced policies.
+    /// Requests without a verified role are allowed by no shadow policy.
+    fn shadow(&self, target: &Target<'_>, role: Option<&str>, allowed: bool) {
+        for policy in self.state.policies().shadowed(target) {
+            let would_allow = role.is_some_and(|role| policy.allows(role));
+            let labels = PolicyLabels {
+                policy: policy.name.clone(),
+            };
+            self.metrics.shadow_evaluations.get_or_create(&labels).inc();
+            if would_allow == allowed {
+                continue;
+            }
+
+            let (would, enforced) = match would_allow {
+                true => ("allow", "denied"),
+                false => ("deny", "allowed"),
+            };
+            tracing::warn!(
+                "Shadow policy {} would {} {} {} for role {}, which the enforced policies {}",
+                policy.name,
+                would,
+                target.method,
+                target.path,
+                role.unwrap_or("none"),
+                enforced
+            );
+            self.metrics
+                .shadow_disagreements
+                .get_or_create(&ShadowLabels {
+                    policy: labels.policy,
+                    shadow: would,
+                })
+                .inc();
         }
     }
 }
 
+#[tonic::async_trait]
+impl Authorization for AuthSvc {
+    async fn check(
+        &self,
+        request: Request<CheckRequest>,
+    ) -> Result<Response<CheckResponse>, Status> {
+        let request = request.into_inner();
+        let request_id = telemetry::request_id(&request);
+        let span = telemetry::check_span(&request);
+        let _entered = span.enter();
+
+        let mut response = self.evaluate(&request, &mut Trace::default())?;
+        let decision = decision(&response);
+        span.record("auth.decision", decision);
+        tracing::info!(
+            target: "auth_svc::audit",
+            request_id,
+            decision,
+            reason = response.status.as_ref().map_or("no credentials", |s| s.message.as_str()),
+            "check decided"
+        );
+        self.metrics
+            .checks
+            .get_or_create(&DecisionLabels { decision })
+            .inc_by(
+                1,
+                (!request_id.is_empty()).then(|| RequestIdLabels {
+                    request_id: request_id.to_string(),
+                }),
+                None,
+            );
+
+        // echo the ID so support can match what the user saw to our records
+        if decision == "deny" && !request_id.is_empty() {
+            let mut denied = DeniedHttpResponseBuilder::new();
+            denied.add_header(telemetry::REQUEST_ID_HEADER, request_id, None, false);
+            response.set_http_response(denied);
+        }
+        Ok(Response::new(response))
+    }
+}
+
+/// `allow` or `deny`, as Envoy reads the response: a missing status has
+/// code 0, so checks without credentials are allowed.
+fn decision(response: &CheckResponse) -> &'static str {
+    match &response.status {
+        Some(status) if status.code != Code::Ok as i32 => "deny",
+        _ => "allow",
+    }
+}
+
+fn credential_source(headers: &HashMap<String, String>, scheme: &AuthScheme) -> &'static str {
+    match scheme {
+        AuthScheme::Basic(_) => "Authorization header (Basic)",
+        AuthScheme::Bearer(_)
+            if headers
+                .get("Authorization")
+                .is_some_and(|h| h.starts_with("Bearer ")) =>
+        {
+            "Authorization header (Bearer)"
+        }
+        AuthScheme::Bearer(_) => "apikey header",
+    }
+}
+
+fn request_target(request: &CheckRequest) -> Target<'_> {
+    let http = request
+        .attributes
+        .as_ref()
+        .and_then(|a| a.request.as_ref())
+        .and_then(|r| r.http.as_ref());
+    Target {
+        method: http.map_or("", |h| h.method.as_str()),
+        path: http.map_or("", |h| h.path.as_str()),
+    }
+}
+
 fn parse_authorization_header(headers: &HashMap<String, String>) -> Option<AuthScheme> {
     if let Some(auth_header) = headers.get("Authorization").map(|h| h.as_str()) {
         if let Some(token) = auth_header.strip_prefix("Bearer ") {
@@ -110,14 +290,18 @@ fn parse_authorization_header(headers: &HashMap<String, String>) -> Option<AuthS
 mod tests {
     use super::*;
     use crate::jwt::{AuthState, Claims};
+    use crate::policy::PolicySet;
     use base64::Engine;
     use base64::prelude::BASE64_STANDARD;
     use chrono::Utc;
-    use envoy_types::ext_authz::v3::CheckResponseExt;
-    use envoy_types::pb::envoy::service::auth::v3;
-    use envoy_types::pb::envoy::service::auth::v3::attribute_context;
+    use envoy_types::ext_authz::v3::pb::{AuthorizationServer, HttpResponse};
+    use envoy_types::pb::envoy::service::auth::v3::authorization_client::AuthorizationClient;
+    use f2_utils::testing::{CheckRequestBuilder, TestServer};
     use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
+    use prometheus_client::encoding::text;
+    use prometheus_client::registry::Registry;
     use std::collections::HashMap;
+    use tonic::service::Routes;
     use tonic::{Code, Request};
 
     fn make_auth_state() -> Arc<AuthState> {
@@ -176,75 +360,41 @@ mod tests {
     async fn test_no_auth_header() {
         let svc = AuthSvc::new(make_auth_state());
 
-        let req = CheckRequest {
-            attributes: Some(v3::AttributeContext {
-                source: None,
-                destination: None,
-                request: Some(attribute_context::Request {
-                    time: None,
-                    http: Some(attribute_context::HttpRequest {
-                        id: "".to_string(),
-                        method: "".to_string(),
-                        headers: HashMap::new(),
-                        header_map: None,
-                        path: "".to_string(),
-                        host: "".to_string(),
-                        scheme: "".to_string(),
-                        query: "".to_string(),
-                        fragment: "".to_string(),
-                        size: 0,
-                        protocol: "".to_string(),
-                        body: "".to_string(),
-                        raw_body: vec![],
-                    }),
-                }),
-                context_extensions: Default::default(),
-                metadata_context: None,
-                route_metadata_context: None,
-                tls_session: None,
-            }),
-        };
+        let req = CheckRequestBuilder::new().build();
 
         let resp = svc.check(Request::new(req)).await.unwrap();
         // Should return default response (neither deny nor allow explicitly)
         assert!(resp.get_ref().status.is_none());
     }
 
+    #[tokio::test]
+    async fn test_no_credentials_are_counted_as_allowed() {
+        let mut registry = Registry::default();
+        let svc =
+            AuthSvc::new(make_auth_state()).with_metrics(AuthMetrics::register(&mut registry));
+
+        let req = CheckRequestBuilder::new().id("req-123").build();
+        let resp = svc.check(Request::new(req)).await.unwrap().into_inner();
+        assert_eq!(decision(&resp), "allow");
+        assert!(resp.http_response.is_none());
+
+        let mut metrics = String::new();
+        text::encode(&mut metrics, &registry).unwrap();
+        assert!(
+            metrics.contains("auth_svc_checks_total{decision=\"allow\"} 1"),
+            "{metrics}"
+        );
+        assert!(!metrics.contains("decision=\"deny\""), "{metrics}");
+    }
+
     #[tokio::test]
     async fn test_basic_auth_success() {
         let svc = AuthSvc::new(make_auth_state());
         let creds = BASE64_STANDARD.encode(b"admin:s3cr3t");
-        let mut headers = HashMap::new();
-        headers.insert("Authorization".into(), format!("Basic {}", creds));
-
-        let req = CheckRequest {
-            attributes: Some(v3::AttributeContext {
-                source: None,
-                destination: None,
-                request: Some(attribute_context::Request {
-                    time: None,
-                    http: Some(attribute_context::HttpRequest {
-                        id: "".to_string(),
-                        method: "".to_string(),
-                        headers: headers,
-                        header_map: None,
-                        path: "".to_string(),
-                        host: "".to_string(),
-                        scheme: "".to_string(),
-                        query: "".to_string(),
-                        fragment: "".to_string(),
-                        size: 0,
-                        protocol: "".to_string(),
-                        body: "".to_string(),
-                        raw_body: vec![],
-                    }),
-                }),
-                context_extensions: Default::default(),
-                metadata_context: None,
-                route_metadata_context: None,
-                tls_session: None,
-            }),
-        };
+
+        let req = CheckRequestBuilder::new()
+            .header("Authorization", format!("Basic {}", creds))
+            .build();
 
         let resp = svc.check(Request::new(req)).await.unwrap();
         let status = resp.get_ref().status.as_ref().unwrap();
@@ -256,37 +406,10 @@ mod tests {
     async fn test_basic_auth_failure() {
         let svc = AuthSvc::new(make_auth_state());
         let bad = BASE64_STANDARD.encode(b"admin:wrong");
-        let mut headers = HashMap::new();
-        headers.insert("Authorization".into(), format!("Basic {}", bad));
-
-        let req = CheckRequest {
-            attributes: Some(v3::AttributeContext {
-                source: None,
-                destination: None,
-                request: Some(attribute_context::Request {
-                    time: None,
-                    http: Some(attribute_context::HttpRequest {
-                        id: "".to_string(),
-                        method: "".to_string(),
-                        headers: headers,
-                        header_map: None,
-                        path: "".to_string(),
-                        host: "".to_string(),
-                        scheme: "".to_string(),
-                        query: "".to_string(),
-                        fragment: "".to_string(),
-                        size: 0,
-                        protocol: "".to_string(),
-                        body: "".to_string(),
-                        raw_body: vec![],
-                    }),
-                }),
-                context_extensions: Default::default(),
-                metadata_context: None,
-                route_metadata_context: None,
-                tls_session: None,
-            }),
-        };
+
+        let req = CheckRequestBuilder::new()
+            .header("Authorization", format!("Basic {}", bad))
+            .build();
 
         let resp = svc.check(Request::new(req)).await.unwrap();
         let status = resp.get_ref().status.as_ref().unwrap();
@@ -297,40 +420,13 @@ mod tests {
     #[tokio::test]
     async fn test_jwt_valid() {
         let state = make_auth_state();
-        let raw = BASE64_STANDARD.decode(&state.jwt_secret).unwrap();
+        let raw = b"secret".to_vec();
         let token = create_jwt("service_role", 3600, &raw);
         let svc = AuthSvc::new(state);
-        let mut headers = HashMap::new();
-        headers.insert("Authorization".into(), format!("Bearer {}", token));
-
-        let req = CheckRequest {
-            attributes: Some(v3::AttributeContext {
-                source: None,
-                destination: None,
-                request: Some(attribute_context::Request {
-                    time: None,
-                    http: Some(attribute_context::HttpRequest {
-                        id: "".to_string(),
-                        method: "".to_string(),
-                        headers: headers,
-                        header_map: None,
-                        path: "".to_string(),
-                        host: "".to_string(),
-                        scheme: "".to_string(),
-                        query: "".to_string(),
-                        fragment: "".to_string(),
-                        size: 0,
-                        protocol: "".to_string(),
-                        body: "".to_string(),
-                        raw_body: vec![],
-                    }),
-                }),
-                context_extensions: Default::default(),
-                metadata_context: None,
-                route_metadata_context: None,
-                tls_session: None,
-            }),
-        };
+
+        let req = CheckRequestBuilder::new()
+            .header("Authorization", format!("Bearer {}", token))
+            .build();
 
         let resp = svc.check(Request::new(req)).await.unwrap();
         let status = resp.get_ref().status.as_ref().unwrap();
@@ -340,40 +436,13 @@ mod tests {
     #[tokio::test]
     async fn test_jwt_expired() {
         let state = make_auth_state();
-        let raw = BASE64_STANDARD.decode(&state.jwt_secret).unwrap();
+        let raw = b"secret".to_vec();
         let token = create_jwt("anon", -1, &raw);
         let svc = AuthSvc::new(state);
-        let mut headers = HashMap::new();
-        headers.insert("Authorization".into(), format!("Bearer {}", token));
-
-        let req = CheckRequest {
-            attributes: Some(v3::AttributeContext {
-                source: None,
-                destination: None,
-                request: Some(attribute_context::Request {
-                    time: None,
-                    http: Some(attribute_context::HttpRequest {
-                        id: "".to_string(),
-                        method: "".to_string(),
-                        headers: headers,
-                        header_map: None,
-                        path: "".to_string(),
-                        host: "".to_string(),
-                        scheme: "".to_string(),
-                        query: "".to_string(),
-                        fragment: "".to_string(),
-                        size: 0,
-                        protocol: "".to_string(),
-                        body: "".to_string(),
-                        raw_body: vec![],
-                    }),
-                }),
-                context_extensions: Default::default(),
-                metadata_context: None,
-                route_metadata_context: None,
-                tls_session: None,
-            }),
-        };
+
+        let req = CheckRequestBuilder::new()
+            .header("Authorization", format!("Bearer {}", token))
+            .build();
 
         let resp = svc.check(Request::new(req)).await.unwrap();
         let status = resp.get_ref().status.as_ref().unwrap();
@@ -384,43 +453,254 @@ mod tests {
     #[tokio::test]
     async fn test_apikey_fallback() {
         let state = make_auth_state();
-        let raw = BASE64_STANDARD.decode(&state.jwt_secret).unwrap();
+        let raw = b"secret".to_vec();
         let token = create_jwt("anon", 3600, &raw);
         let svc = AuthSvc::new(state);
-        let mut headers = HashMap::new();
-        headers.insert("apikey".into(), token);
-
-        let req = CheckRequest {
-            attributes: Some(v3::AttributeContext {
-                source: None,
-                destination: None,
-                request: Some(attribute_context::Request {
-                    time: None,
-                    http: Some(attribute_context::HttpRequest {
-                        id: "".to_string(),
-                        method: "".to_string(),
-                        headers: headers,
-                        header_map: None,
-                        path: "".to_string(),
-                        host: "".to_string(),
-                        scheme: "".to_string(),
-                        query: "".to_string(),
-                        fragment: "".to_string(),
-                        size: 0,
-                        protocol: "".to_string(),
-                        body: "".to_string(),
-                        raw_body: vec![],
-                    }),
-                }),
-                context_extensions: Default::default(),
-                metadata_context: None,
-                route_metadata_context: None,
-                tls_session: None,
-            }),
+
+        let req = CheckRequestBuilder::new().header("apikey", token).build();
+
+        let resp = svc.check(Request::new(req)).await.unwrap();
+        let status = resp.get_ref().status.as_ref().unwrap();
+        assert_eq!(status.code, Code::Ok as i32);
+    }
+
+    fn make_policy_state() -> Arc<AuthState> {
+        let policies = PolicySet::from_json(
+            r#"[
+                {"name": "rest-writes", "path_prefix": "/rest/v1/", "methods": ["POST"], "roles": ["service_role"]},
+                {"name": "rest-reads", "path_prefix": "/rest/v1/", "methods": ["GET"], "roles": ["service_role"], "mode": "shadow"}
+            ]"#,
+        )
+        .unwrap();
+        let jwt_secret = BASE64_STANDARD.encode(b"secret");
+        Arc::new(
+            AuthState::new(jwt_secret, "admin".into(), "s3cr3t".into()).with_policies(policies),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_enforced_policy_denies() {
+        let state = make_policy_state();
+        let token = create_jwt("anon", 3600, b"secret");
+        let svc = AuthSvc::new(state);
+
+        let req = CheckRequestBuilder::new()
+            .method("POST")
+            .path("/rest/v1/images")
+            .header("Authorization", format!("Bearer {token}"))
+            .build();
+        let resp = svc.check(Request::new(req)).await.unwrap();
+        let status = resp.get_ref().status.as_ref().unwrap();
+        assert_eq!(status.code, Code::PermissionDenied as i32);
+        assert_eq!(status.message, "denied by policy rest-writes");
+    }
+
+    #[tokio::test]
+    async fn test_shadow_policy_keeps_enforced_decision() {
+        let state = make_policy_state();
+        let token = create_jwt("anon", 3600, b"secret");
+        let svc = AuthSvc::new(state);
+
+        let req = CheckRequestBuilder::new()
+            .method("GET")
+            .path("/rest/v1/images")
+            .header("Authorization", format!("Bearer {token}"))
+            .build();
+        let resp = svc.check(Request::new(req)).await.unwrap();
+        let status = resp.get_ref().status.as_ref().unwrap();
+        assert_eq!(status.code, Code::Ok as i32);
+
+        let labels = PolicyLabels {
+            policy: "rest-reads".into(),
         };
+        assert_eq!(
+            svc.metrics.shadow_evaluations.get_or_create(&labels).get(),
+            1
+        );
+        assert_eq!(
+            svc.metrics
+                .shadow_disagreements
+                .get_or_create(&ShadowLabels {
+                    policy: labels.policy,
+                    shadow: "deny",
+                })
+                .get(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shadow_policy_agreement_is_not_counted() {
+        let state = make_policy_state();
+        let token = create_jwt("service_role", 3600, b"secret");
+        let svc = AuthSvc::new(state);
 
+        let req = CheckRequestBuilder::new()
+            .method("GET")
+            .path("/rest/v1/images")
+            .header("Authorization", format!("Bearer {token}"))
+            .build();
         let resp = svc.check(Request::new(req)).await.unwrap();
         let status = resp.get_ref().status.as_ref().unwrap();
         assert_eq!(status.code, Code::Ok as i32);
+
+        let labels = PolicyLabels {
+            policy: "rest-reads".into(),
+        };
+        assert_eq!(
+            svc.metrics.shadow_evaluations.get_or_create(&labels).get(),
+            1
+        );
+        assert_eq!(
+            svc.metrics
+                .shadow_disagreements
+                .get_or_create(&ShadowLabels {
+                    policy: labels.policy,
+                    shadow: "deny",
+                })
+                .get(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shadow_policy_disagrees_both_ways() {
+        let policies = PolicySet::from_json(
+            r#"[
+                {"name": "rest-writes", "path_prefix": "/rest/v1/", "methods": ["POST"], "roles": ["service_role"]},
+                {"name": "rest-writes-v2", "path_prefix": "/rest/v1/", "methods": ["POST"], "roles": ["service_role", "anon"], "mode": "shadow"},
+                {"name": "rest-reads", "path_prefix": "/rest/v1/", "methods": ["GET"], "roles": ["service_role"], "mode": "shadow"}
+            ]"#,
+        )
+        .unwrap();
+        let jwt_secret = BASE64_STANDARD.encode(b"secret");
+        let state = Arc::new(
+            AuthState::new(jwt_secret, "admin".into(), "s3cr3t".into()).with_policies(policies),
+        );
+        let svc = AuthSvc::new(state);
+        let token = create_jwt("anon", 3600, b"secret");
+
+        // denied by the enforced policy, allowed by the shadow one
+        let req = CheckRequestBuilder::new()
+            .method("POST")
+            .path("/rest/v1/images")
+            .header("Authorization", format!("Bearer {token}"))
+            .build();
+        svc.check(Request::new(req)).await.unwrap();
+        // denied before any policy ran, and by the shadow policy too
+        let req = CheckRequestBuilder::new()
+            .method("POST")
+            .path("/rest/v1/images")
+            .header("Authorization", "Bearer not-a-jwt")
+            .build();
+        svc.check(Request::new(req)).await.unwrap();
+        // allowed without credentials, denied by the shadow policy
+        let req = CheckRequestBuilder::new()
+            .method("GET")
+            .path("/rest/v1/images")
+            .build();
+        svc.check(Request::new(req)).await.unwrap();
+
+        let disagreements = |policy: &str, shadow| {
+            svc.metrics
+                .shadow_disagreements
+                .get_or_create(&ShadowLabels {
+                    policy: policy.into(),
+                    shadow,
+                })
+                .get()
+        };
+        let evaluations = |policy: &str| {
+            svc.metrics
+                .shadow_evaluations
+                .get_or_create(&PolicyLabels {
+                    policy: policy.into(),
+                })
+                .get()
+        };
+        assert_eq!(evaluations("rest-writes-v2"), 2);
+        assert_eq!(disagreements("rest-writes-v2", "allow"), 1);
+        assert_eq!(disagreements("rest-writes-v2", "deny"), 0);
+        assert_eq!(evaluations("rest-reads"), 1);
+        assert_eq!(disagreements("rest-reads", "deny"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_denial_echoes_request_id() {
+        let mut registry = Registry::default();
+        let svc =
+            AuthSvc::new(make_policy_state()).with_metrics(AuthMetrics::register(&mut registry));
+        let token = create_jwt("anon", 3600, b"secret");
+
+        let req = CheckRequestBuilder::new()
+            .id("req-123")
+            .method("POST")
+            .path("/rest/v1/images")
+            .header("Authorization", format!("Bearer {token}"))
+            .build();
+        let resp = svc.check(Request::new(req)).await.unwrap().into_inner();
+        assert_eq!(resp.status.unwrap().code, Code::PermissionDenied as i32);
+        let Some(HttpResponse::DeniedResponse(denied)) = resp.http_response else {
+            panic!("Expected a denied http response");
+        };
+        let header = denied.headers[0].header.as_ref().unwrap();
+        assert_eq!(header.key, "x-request-id");
+        assert_eq!(header.value, "req-123");
+
+        let mut metrics = String::new();
+        text::encode(&mut metrics, &registry).unwrap();
+        let sample = metrics
+            .lines()
+            .find(|l| l.starts_with("auth_svc_checks_total{decision=\"deny\"}"))
+            .unwrap();
+        assert!(sample.contains("# {request_id=\"req-123\"}"), "{sample}");
+    }
+
+    #[tokio::test]
+    async fn test_allowed_response_has_no_request_id_header() {
+        let svc = AuthSvc::new(make_policy_state());
+        let token = create_jwt("service_role", 3600, b"secret");
+
+        let req = CheckRequestBuilder::new()
+            .method("POST")
+            .path("/rest/v1/images")
+            .header("Authorization", format!("Bearer {token}"))
+            .header("x-request-id", "req-456")
+            .build();
+        let resp = svc.check(Request::new(req)).await.unwrap().into_inner();
+        assert_eq!(resp.status.unwrap().code, Code::Ok as i32);
+        assert!(resp.http_response.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_checks_over_h2c() {
+        let svc = AuthSvc::new(make_policy_state());
+        let server = TestServer::duplex(Routes::new(AuthorizationServer::new(svc))).await;
+        let mut client = AuthorizationClient::new(server.channel());
+        let token = create_jwt("anon", 3600, b"secret");
+
+        let req = CheckRequestBuilder::new()
+            .id("req-789")
+            .method("POST")
+            .path("/rest/v1/images")
+            .header("Authorization", format!("Bearer {token}"))
+            .build();
+        let resp = client.check(req).await.unwrap().into_inner();
+        let status = resp.status.unwrap();
+        assert_eq!(status.code, Code::PermissionDenied as i32);
+        assert_eq!(status.message, "denied by policy rest-writes");
+        let Some(HttpResponse::DeniedResponse(denied)) = resp.http_response else {
+            panic!("Expected a denied http response");
+        };
+        assert_eq!(denied.headers[0].header.as_ref().unwrap().value, "req-789");
+
+        let creds = BASE64_STANDARD.encode(b"admin:s3cr3t");
+        let req = CheckRequestBuilder::new()
+            .method("GET")
+            .path("/dashboard")
+            .header("Authorization", format!("Basic {creds}"))
+            .build();
+        let resp = client.check(req).await.unwrap().into_inner();
+        assert_eq!(resp.status.unwrap().code, Code::Ok as i32);
     }
 }
@@ -1,13 +1,34 @@
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
 use chrono::Utc;
+use jsonwebtoken::errors::ErrorKind;
 use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode};
 use serde::{Deserialize, Serialize};
 
+use crate::explain::Trace;
+use crate::jwt::cache::{TokenCache, VerifiedToken};
+use crate::metrics::CacheMetrics;
+use crate::policy::PolicySet;
+use std::sync::{PoisonError, RwLock};
+use std::time::Duration;
+
+pub(crate) mod cache;
 pub(crate) mod extauth;
 
+/// Name the JWT secret is reported under in decision traces.
+const JWT_KEY_ID: &str = "jwt_secret";
+
+const DEFAULT_TOKEN_CACHE_CAPACITY: u64 = 10_000;
+const DEFAULT_TOKEN_CACHE_TTL: Duration = Duration::from_secs(300);
+
+fn validation() -> Validation {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_issuer(&["f2", "supabase"]);
+    validation
+}
+
 #[derive(Debug, Serialize, Deserialize)]
-struct Claims {
+pub(crate) struct Claims {
     pub role: String,
     pub iss: String,
     pub iat: i64,
@@ -21,11 +42,35 @@ enum VerificationResult {
     Expired,
 }
 
-#[derive(Clone)]
 pub(crate) struct AuthState {
-    jwt_secret: String,
+    jwt_key: RwLock<JwtKey>,
     dashboard_username: String,
     dashboard_password: String,
+    policies: PolicySet,
+    token_cache: TokenCache,
+}
+
+/// The decoded JWT secret. The generation changes on every rotation so cached
+/// verifications made with an older key are never served.
+struct JwtKey {
+    decoding_key: Option<DecodingKey>,
+    generation: u64,
+}
+
+impl JwtKey {
+    fn new(jwt_secret: &str, generation: u64) -> Self {
+        let decoding_key = match BASE64_STANDARD.decode(jwt_secret) {
+            Ok(secret) => Some(DecodingKey::from_secret(&secret)),
+            Err(e) => {
+                tracing::error!("Failed to decode JWT secret: {}", e);
+                None
+            }
+        };
+        Self {
+            decoding_key,
+            generation,
+        }
+    }
 }
 
 impl AuthState {
@@ -35,37 +80,103 @@ impl AuthState {
         dashboard_password: String,
     ) -> Self {
         Self {
-            jwt_secret,
+            jwt_key: RwLock::new(JwtKey::new(&jwt_secret, 0)),
             dashboard_username,
             dashboard_password,
+            policies: PolicySet::default(),
+            token_cache: TokenCache::new(
+                DEFAULT_TOKEN_CACHE_CAPACITY,
+                DEFAULT_TOKEN_CACHE_TTL,
+                CacheMetrics::default(),
+            ),
         }
     }
 
-    fn verify_jwt(&self, token: &str) -> VerificationResult {
-        // Create validation rules
-        let mut validation = Validation::new(Algorithm::HS256);
-        validation.set_issuer(&["f2", "supabase"]);
+    pub(crate) fn with_token_cache(mut self, token_cache: TokenCache) -> Self {
+        self.token_cache = token_cache;
+        self
+    }
 
-        let secret = match BASE64_STANDARD.decode(&self.jwt_secret) {
-            Ok(decoded) => decoded,
-            Err(e) => {
-                tracing::error!("Failed to decode JWT secret: {}", e);
-                return VerificationResult::Invalid;
-            }
+    /// Switches to a new JWT secret and drops every cached verification.
+    pub(crate) fn rotate_jwt_secret(&self, jwt_secret: &str) {
+        let mut key = self.jwt_key.write().unwrap_or_else(PoisonError::into_inner);
+        *key = JwtKey::new(jwt_secret, key.generation + 1);
+        self.token_cache.invalidate_all();
+        tracing::info!("Rotated JWT secret, generation {}", key.generation);
+    }
+
+    /// Rejects `token` on this replica until it expires. Returns false if the
+    /// token doesn't verify in the first place.
+    pub(crate) fn revoke_token(&self, token: &str) -> bool {
+        let key = self.jwt_key.read().unwrap_or_else(PoisonError::into_inner);
+        let Some(decoding_key) = &key.decoding_key else {
+            return false;
         };
+        match decode::<Claims>(token, decoding_key, &validation()) {
+            Ok(token_data) => {
+                self.token_cache
+                    .revoke(TokenCache::hash(token), token_data.claims.exp);
+                tracing::info!("Revoked JWT for role {}", token_data.claims.role);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub(crate) fn with_policies(mut self, policies: PolicySet) -> Self {
+        self.policies = policies;
+        self
+    }
+
+    pub(crate) fn policies(&self) -> &PolicySet {
+        &self.policies
+    }
+
+    fn verify_jwt(&self, token: &str) -> VerificationResult {
+        self.verify_jwt_traced(token, &mut Trace::default())
+    }
+
+    /// Verifies `token`, recording each step into `trace`. Traced checks skip
+    /// the verified-token cache so the full verification is always reported.
+    fn verify_jwt_traced(&self, token: &str, trace: &mut Trace) -> VerificationResult {
+        let hash = TokenCache::hash(token);
+        if self.token_cache.is_revoked(&hash) {
+            tracing::warn!("Revoked JWT presented");
+            trace.claim("revocation", false, || "token has been revoked".to_string());
+            return VerificationResult::Invalid;
+        }
 
-        let decoding_key = DecodingKey::from_secret(&secret);
+        let key = self.jwt_key.read().unwrap_or_else(PoisonError::into_inner);
+        if !trace.is_recording()
+            && let Some(cached) = self.token_cache.get(&hash, key.generation)
+        {
+            return VerificationResult::Valid {
+                role: cached.role,
+                username: cached.username,
+            };
+        }
 
-        match decode::<Claims>(token, &decoding_key, &validation) {
+        let Some(decoding_key) = &key.decoding_key else {
+            trace.key(JWT_KEY_ID, Some("secret is not valid base64".to_string()));
+            return VerificationResult::Invalid;
+        };
+
+        match decode::<Claims>(token, decoding_key, &validation()) {
             Ok(token_data) => {
                 let claims = token_data.claims;
+                trace.key(JWT_KEY_ID, None);
+                trace.claim("iss", true, || claims.iss.clone());
 
                 // Check if token is expired
                 let now = Utc::now().timestamp();
                 if claims.exp < now {
                     tracing::warn!("JWT token expired. exp: {}, now: {}", claims.exp, now);
+                    trace.claim("exp", false, || {
+                        format!("expired at {}, now {now}", claims.exp)
+                    });
                     return VerificationResult::Expired;
                 }
+                trace.claim("exp", true, || format!("expires at {}", claims.exp));
 
                 // Determine username based on role
                 let username = match claims.role.as_str() {
@@ -73,11 +184,22 @@ impl AuthState {
                     "service_role" => "service_role".to_string(),
                     _ => {
                         tracing::warn!("Unknown role in JWT: {}", claims.role);
+                        trace.claim("role", false, || format!("unknown role {}", claims.role));
                         return VerificationResult::Invalid;
                     }
                 };
+                trace.claim("role", true, || claims.role.clone());
 
                 tracing::debug!("JWT verified successfully for role: {}", claims.role);
+                self.token_cache.insert(
+                    hash,
+                    VerifiedToken {
+                        role: claims.role.clone(),
+                        username: username.clone(),
+                        exp: claims.exp,
+                        generation: key.generation,
+                    },
+                );
                 VerificationResult::Valid {
                     role: claims.role,
                     username,
@@ -86,22 +208,48 @@ impl AuthState {
             Err(e) => {
                 println!("JWT verification failed: {e}");
                 tracing::warn!("JWT verification failed: {}", e);
+                // the signature is checked before the claims, so a claim error
+                // means this key matched
+                match e.kind() {
+                    ErrorKind::ExpiredSignature => {
+                        trace.key(JWT_KEY_ID, None);
+                        trace.claim("exp", false, || e.to_string());
+                    }
+                    ErrorKind::InvalidIssuer => {
+                        trace.key(JWT_KEY_ID, None);
+                        trace.claim("iss", false, || e.to_string());
+                    }
+                    _ => trace.key(JWT_KEY_ID, Some(e.to_string())),
+                }
                 VerificationResult::Invalid
             }
         }
     }
 
+    /// Whether an `authorization` value carries admin credentials: the
+    /// dashboard basic auth user or a `service_role` JWT.
+    pub(crate) fn is_admin(&self, authorization: &str) -> bool {
+        if let Some(credentials) = authorization.strip_prefix("Basic ") {
+            return self.verify_basic_auth(credentials).is_ok();
+        }
+        if let Some(token) = authorization.strip_prefix("Bearer ") {
+            return matches!(
+                self.verify_jwt(token),
+                VerificationResult::Valid { role, .. } if role == "service_role"
+            );
+        }
+        false
+    }
+
     fn verify_basic_auth(&self, base64_credentials: &str) -> Result<(), &'static str> {
         match BASE64_STANDARD.decode(base64_credentials) {
             Ok(decoded) => {
-                if let Ok(credentials) = String::from_utf8(decoded) {
-                    if let Some((username, password)) = credentials.split_once(':') {
-                        if username == &self.dashboard_username
-                            && password == &self.dashboard_password
-                        {
-                            return Ok(());
-                        }
-                    }
+                if let Ok(credentials) = String::from_utf8(decoded)
+                    && let Some((username, password)) = credentials.split_once(':')
+                    && username == self.dashboard_username
+                    && password == self.dashboard_password
+                {
+                    return Ok(());
                 }
             }
             Err(_) => return Err("Invalid base64 encoding"),
@@ -113,6 +261,7 @@ impl AuthState {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::metrics::CacheLabels;
     use base64::Engine;
     use base64::prelude::BASE64_STANDARD;
     use chrono::Utc;
@@ -128,7 +277,7 @@ mod tests {
     #[test]
     fn verify_basic_auth_success() {
         let state = make_auth_state();
-        let creds = format!("admin:s3cr3t");
+        let creds = "admin:s3cr3t".to_string();
         let encoded = BASE64_STANDARD.encode(creds.as_bytes());
         assert!(state.verify_basic_auth(&encoded).is_ok());
     }
@@ -159,7 +308,7 @@ mod tests {
     #[test]
     fn verify_jwt_valid_token() {
         let state = make_auth_state();
-        let raw_secret = BASE64_STANDARD.decode(&state.jwt_secret).unwrap();
+        let raw_secret = b"secret".to_vec();
         let token = create_jwt("anon", 3600, &raw_secret);
         match state.verify_jwt(&token) {
             VerificationResult::Valid { role, username } => {
@@ -173,7 +322,7 @@ mod tests {
     #[test]
     fn verify_jwt_expired_token() {
         let state = make_auth_state();
-        let raw_secret = BASE64_STANDARD.decode(&state.jwt_secret).unwrap();
+        let raw_secret = b"secret".to_vec();
         let token = create_jwt("service_role", -1, &raw_secret);
         assert!(matches!(
             state.verify_jwt(&token),
@@ -191,4 +340,64 @@ mod tests {
             VerificationResult::Invalid
         ));
     }
+
+    #[test]
+    fn verify_jwt_uses_cache() {
+        let metrics = CacheMetrics::default();
+        let state = make_auth_state().with_token_cache(TokenCache::new(
+            16,
+            Duration::from_secs(60),
+            metrics.clone(),
+        ));
+        let token = create_jwt("anon", 3600, b"secret");
+        for _ in 0..3 {
+            assert!(matches!(
+                state.verify_jwt(&token),
+                VerificationResult::Valid { .. }
+            ));
+        }
+
+        let lookups = |result| metrics.lookups.get_or_create(&CacheLabels { result }).get();
+        assert_eq!(lookups("miss"), 1);
+        assert_eq!(lookups("hit"), 2);
+    }
+
+    #[test]
+    fn rotate_jwt_secret_invalidates_cached_tokens() {
+        let state = make_auth_state();
+        let token = create_jwt("anon", 3600, b"secret");
+        assert!(matches!(
+            state.verify_jwt(&token),
+            VerificationResult::Valid { .. }
+        ));
+
+        state.rotate_jwt_secret(&BASE64_STANDARD.encode(b"rotated"));
+        assert!(matches!(
+            state.verify_jwt(&token),
+            VerificationResult::Invalid
+        ));
+
+        let rotated = create_jwt("anon", 3600, b"rotated");
+        assert!(matches!(
+            state.verify_jwt(&rotated),
+            VerificationResult::Valid { .. }
+        ));
+    }
+
+    #[test]
+    fn revoked_token_is_rejected() {
+        let state = make_auth_state();
+        let token = create_jwt("service_role", 3600, b"secret");
+        assert!(matches!(
+            state.verify_jwt(&token),
+            VerificationResult::Valid { .. }
+        ));
+
+        assert!(state.revoke_token(&token));
+        assert!(matches!(
+            state.verify_jwt(&token),
+            VerificationResult::Invalid
+        ));
+        assert!(!state.revoke_token("not-a-jwt"));
+    }
 }
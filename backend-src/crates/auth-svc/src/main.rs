@@ -1,22 +1,41 @@
+use crate::explain::AdminSvc;
+use crate::explain::pb::auth_admin_server::AuthAdminServer;
+use crate::jwt::cache::TokenCache;
 use crate::jwt::extauth::AuthSvc;
+use crate::metrics::{AuthMetrics, CacheMetrics};
+use crate::policy::PolicySet;
 use envoy_types::ext_authz::v3::pb::AuthorizationServer;
-use f2_utils::server::h2c::H2c;
-use hyper_util::rt::{TokioExecutor, TokioIo};
-use hyper_util::server::conn::auto::Builder;
-use hyper_util::service::TowerToHyperService;
+use f2_utils::server::Server;
+use f2_utils::tls::ServerTls;
+use prometheus_client::registry::Registry;
 use std::env;
 use std::sync::Arc;
-use tokio::net::TcpListener;
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
 use tonic::service::Routes;
 
+mod explain;
 mod jwt;
+mod metrics;
+mod policy;
+mod telemetry;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt().init();
+    let _tracer_provider = telemetry::init()?;
 
-    let jwt_secret = env::var("JWT_SECRET")
-        .map_err(|_| anyhow::anyhow!("JWT_SECRET environment variable not set"))?;
+    if env::args().nth(1).as_deref() == Some("explain") {
+        explain::cli::run(env::args().skip(2)).await?;
+        return Ok(());
+    }
+
+    // a mounted secret file can be rotated in place and reloaded with SIGHUP
+    let jwt_secret_file = env::var("JWT_SECRET_FILE").ok();
+    let jwt_secret = match &jwt_secret_file {
+        Some(path) => read_jwt_secret(path)?,
+        None => env::var("JWT_SECRET")
+            .map_err(|_| anyhow::anyhow!("JWT_SECRET environment variable not set"))?,
+    };
 
     let dashboard_username = env::var("DASHBOARD_USERNAME")
         .map_err(|_| anyhow::anyhow!("DASHBOARD_USERNAME environment variable not set"))?;
@@ -24,41 +43,140 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let dashboard_password = env::var("DASHBOARD_PASSWORD")
         .map_err(|_| anyhow::anyhow!("DASHBOARD_PASSWORD environment variable not set"))?;
 
-    let state = Arc::new(jwt::AuthState::new(
-        jwt_secret,
-        dashboard_username,
-        dashboard_password,
-    ));
+    let policies = match env::var("AUTH_POLICY_FILE") {
+        Ok(path) => PolicySet::load(path)?,
+        Err(_) => PolicySet::default(),
+    };
+
+    let token_cache_capacity = env::var("TOKEN_CACHE_CAPACITY")
+        .unwrap_or_else(|_| "10000".to_string())
+        .parse::<u64>()
+        .unwrap_or(10000);
+
+    let token_cache_ttl = env::var("TOKEN_CACHE_TTL_SECS")
+        .unwrap_or_else(|_| "300".to_string())
+        .parse::<u64>()
+        .unwrap_or(300);
+
+    let mut registry = Registry::default();
+    let auth_metrics = AuthMetrics::register(&mut registry);
+    let token_cache = TokenCache::new(
+        token_cache_capacity,
+        Duration::from_secs(token_cache_ttl),
+        CacheMetrics::register(&mut registry),
+    );
+
+    let state = Arc::new(
+        jwt::AuthState::new(jwt_secret, dashboard_username, dashboard_password)
+            .with_policies(policies)
+            .with_token_cache(token_cache),
+    );
+
+    if let Some(path) = jwt_secret_file {
+        let state = state.clone();
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                match read_jwt_secret(&path) {
+                    Ok(jwt_secret) => state.rotate_jwt_secret(&jwt_secret),
+                    Err(e) => tracing::error!("Failed to reload JWT secret: {}", e),
+                }
+            }
+        });
+    }
 
-    let auth_server = AuthorizationServer::new(AuthSvc::new(state.clone()));
+    let auth_svc = Arc::new(AuthSvc::new(state.clone()).with_metrics(auth_metrics));
+    let auth_server = AuthorizationServer::from_arc(auth_svc.clone());
+    let admin_server = AuthAdminServer::new(AdminSvc::new(auth_svc, state.clone()));
 
     let port = env::var("PORT")
         .unwrap_or_else(|_| "8080".to_string())
         .parse::<u16>()
         .unwrap_or(8080);
 
+    let metrics_port = env::var("METRICS_PORT")
+        .unwrap_or_else(|_| "9090".to_string())
+        .parse::<u16>()
+        .unwrap_or(9090);
+
     tracing::info!("Starting auth service on port {}", port);
 
-    let routes = Routes::new(auth_server).prepare();
-    let server = H2c::new(routes);
-    let listener = TcpListener::bind(format!("0.0.0.0:{port}")).await?;
-
-    loop {
-        match listener.accept().await {
-            Ok((io, _)) => {
-                let router = server.clone();
-                tokio::spawn(async move {
-                    let builder = Builder::new(TokioExecutor::new());
-                    let conn = builder.serve_connection_with_upgrades(
-                        TokioIo::new(io),
-                        TowerToHyperService::new(router),
-                    );
-                    let _ = conn.await;
-                });
-            }
-            Err(e) => {
-                eprintln!("Error accepting connection: {e}");
-            }
+    let routes = Routes::new(auth_server).add_service(admin_server);
+    let mut server = Server::new(routes)
+        .with_addr(([0, 0, 0, 0], port).into())
+        .with_metrics(registry, ([0, 0, 0, 0], metrics_port).into())
+        .with_socket_activation()
+        .with_rpc_middleware()
+        .with_file_descriptor_set(f2_utils::descriptors::FILE_DESCRIPTOR_SET);
+
+    if let (Ok(cert), Ok(key)) = (env::var("TLS_CERT_FILE"), env::var("TLS_KEY_FILE")) {
+        let mut tls = ServerTls::new(cert, key);
+        if let Ok(ca) = env::var("TLS_CLIENT_CA_FILE") {
+            tls = tls.with_client_ca(ca);
         }
+        server = server.with_tls(tls);
+    }
+
+    if let Some(max) = env::var("MAX_CONCURRENT_STREAMS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        server = server.with_max_concurrent_streams(max);
     }
+    if let Some(max) = env::var("MAX_IN_FLIGHT_REQUESTS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        server = server.with_max_in_flight_requests(max);
+    }
+    if let Some(max) = env::var("MAX_QUEUED_REQUESTS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        server = server.with_max_queued_requests(max);
+    }
+    if let Some(acceptors) = env::var("REUSEPORT_ACCEPTORS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        server = server.with_reuseport(acceptors);
+    }
+    if let Some(secs) = env::var("KEEPALIVE_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        server = server.with_keepalive(Duration::from_secs(secs), Duration::from_secs(20));
+    }
+    if let Some(secs) = env::var("IDLE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        server = server.with_idle_timeout(Duration::from_secs(secs));
+    }
+
+    if let Ok(trusted) = env::var("PROXY_PROTOCOL_TRUSTED") {
+        server = server.with_proxy_protocol(trusted.parse()?);
+    }
+
+    if env::var("GRPC_REFLECTION").is_ok_and(|v| v == "false") {
+        server = server.with_reflection(false);
+    }
+
+    if let Ok(path) = env::var("UNIX_SOCKET") {
+        server = server.with_unix_socket(path);
+        if let Ok(mode) = env::var("UNIX_SOCKET_MODE") {
+            let mode = u32::from_str_radix(&mode, 8)
+                .map_err(|_| anyhow::anyhow!("UNIX_SOCKET_MODE must be octal, got {}", mode))?;
+            server = server.with_unix_permissions(mode);
+        }
+    }
+
+    server.serve().await?;
+    Ok(())
+}
+
+fn read_jwt_secret(path: &str) -> anyhow::Result<String> {
+    let secret = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("failed to read JWT secret file {path}: {e}"))?;
+    Ok(secret.trim().to_string())
 }
@@ -0,0 +1,92 @@
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::exemplar::CounterWithExemplar;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct PolicyLabels {
+    pub policy: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct ShadowLabels {
+    pub policy: String,
+    /// What the shadow policy would have decided, `allow` or `deny`.
+    pub shadow: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct CacheLabels {
+    /// `hit` or `miss`.
+    pub result: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct DecisionLabels {
+    /// `allow` or `deny`.
+    pub decision: &'static str,
+}
+
+/// Exemplar labels tying a metric sample to a single checked request.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct RequestIdLabels {
+    pub request_id: String,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct AuthMetrics {
+    pub checks: Family<DecisionLabels, CounterWithExemplar<RequestIdLabels>>,
+    /// Checks a shadow policy was evaluated against.
+    pub shadow_evaluations: Family<PolicyLabels, Counter>,
+    /// Checks a shadow policy would have decided otherwise.
+    pub shadow_disagreements: Family<ShadowLabels, Counter>,
+}
+
+impl AuthMetrics {
+    pub(crate) fn register(registry: &mut Registry) -> Self {
+        let metrics = Self::default();
+        let registry = registry.sub_registry_with_prefix("auth_svc");
+        registry.register(
+            "checks",
+            "Checks by decision, with the request ID as exemplar",
+            metrics.checks.clone(),
+        );
+        registry.register(
+            "shadow_policy_evaluations",
+            "Shadow policy evaluations, on every check the policy matches",
+            metrics.shadow_evaluations.clone(),
+        );
+        registry.register(
+            "shadow_policy_disagreements",
+            "Checks a shadow policy would have decided otherwise, by what it would decide",
+            metrics.shadow_disagreements.clone(),
+        );
+        metrics
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct CacheMetrics {
+    pub lookups: Family<CacheLabels, Counter>,
+    pub entries: Gauge,
+}
+
+impl CacheMetrics {
+    pub(crate) fn register(registry: &mut Registry) -> Self {
+        let metrics = Self::default();
+        let registry = registry.sub_registry_with_prefix("auth_svc_token_cache");
+        registry.register(
+            "lookups",
+            "Verified-token cache lookups by result",
+            metrics.lookups.clone(),
+        );
+        registry.register(
+            "entries",
+            "Verified tokens currently cached",
+            metrics.entries.clone(),
+        );
+        metrics
+    }
+}
@@ -0,0 +1,155 @@
+use serde::Deserialize;
+use std::path::Path;
+
+/// How a policy participates in a check. Enforced policies decide the response
+/// sent back to Envoy, shadow policies are only evaluated and compared against
+/// that decision so we can see who a new rule would break before turning it on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PolicyMode {
+    #[default]
+    Enforce,
+    Shadow,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub(crate) struct Policy {
+    pub name: String,
+    pub path_prefix: String,
+    /// HTTP methods the policy applies to. Empty means every method.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Roles allowed through when the policy matches.
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub mode: PolicyMode,
+}
+
+/// The part of the checked request the policies are matched against.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Target<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Verdict<'a> {
+    Allow,
+    Deny(&'a Policy),
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PolicySet {
+    policies: Vec<Policy>,
+}
+
+impl Policy {
+    fn matches(&self, target: &Target<'_>) -> bool {
+        let path = target.path.split_once('?').map_or(target.path, |(p, _)| p);
+        path.starts_with(&self.path_prefix)
+            && (self.methods.is_empty()
+                || self
+                    .methods
+                    .iter()
+                    .any(|m| m.eq_ignore_ascii_case(target.method)))
+    }
+
+    pub(crate) fn allows(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+impl PolicySet {
+    pub(crate) fn new(policies: Vec<Policy>) -> Self {
+        Self { policies }
+    }
+
+    pub(crate) fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json).map(Self::new)
+    }
+
+    pub(crate) fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read policy file {}: {e}", path.display()))?;
+        Ok(Self::from_json(&json)?)
+    }
+
+    /// Evaluates the enforced policies. The first matching policy that does
+    /// not allow `role` denies the request.
+    pub(crate) fn evaluate(&self, target: &Target<'_>, role: &str) -> Verdict<'_> {
+        self.policies
+            .iter()
+            .find(|p| p.mode == PolicyMode::Enforce && p.matches(target) && !p.allows(role))
+            .map_or(Verdict::Allow, Verdict::Deny)
+    }
+
+    /// Every policy matching the request, enforced or shadow, in file order.
+    pub(crate) fn matching<'a>(
+        &'a self,
+        target: &'a Target<'_>,
+    ) -> impl Iterator<Item = &'a Policy> + 'a {
+        self.policies.iter().filter(move |p| p.matches(target))
+    }
+
+    /// Shadow policies matching the request. These never change the decision.
+    pub(crate) fn shadowed<'a>(
+        &'a self,
+        target: &'a Target<'_>,
+    ) -> impl Iterator<Item = &'a Policy> + 'a {
+        self.policies
+            .iter()
+            .filter(move |p| p.mode == PolicyMode::Shadow && p.matches(target))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICIES: &str = r#"[
+        {"name": "rest-writes", "path_prefix": "/rest/v1/", "methods": ["POST", "PATCH"], "roles": ["service_role"]},
+        {"name": "rest-reads", "path_prefix": "/rest/v1/", "roles": ["service_role"], "mode": "shadow"}
+    ]"#;
+
+    #[test]
+    fn enforced_policy_denies_unlisted_role() {
+        let set = PolicySet::from_json(POLICIES).unwrap();
+        let target = Target {
+            method: "post",
+            path: "/rest/v1/images?select=*",
+        };
+        match set.evaluate(&target, "anon") {
+            Verdict::Deny(policy) => assert_eq!(policy.name, "rest-writes"),
+            other => panic!("Expected Deny, got {:?}", other),
+        }
+        assert_eq!(set.evaluate(&target, "service_role"), Verdict::Allow);
+    }
+
+    #[test]
+    fn shadow_policy_is_not_enforced() {
+        let set = PolicySet::from_json(POLICIES).unwrap();
+        let target = Target {
+            method: "GET",
+            path: "/rest/v1/images",
+        };
+        assert_eq!(set.evaluate(&target, "anon"), Verdict::Allow);
+
+        let shadowed: Vec<_> = set.shadowed(&target).collect();
+        assert_eq!(shadowed.len(), 1);
+        assert_eq!(shadowed[0].name, "rest-reads");
+        assert!(!shadowed[0].allows("anon"));
+        assert!(shadowed[0].allows("service_role"));
+    }
+
+    #[test]
+    fn unmatched_path_is_allowed() {
+        let set = PolicySet::from_json(POLICIES).unwrap();
+        let target = Target {
+            method: "POST",
+            path: "/auth/v1/token",
+        };
+        assert_eq!(set.evaluate(&target, "anon"), Verdict::Allow);
+        assert_eq!(set.shadowed(&target).count(), 0);
+    }
+}
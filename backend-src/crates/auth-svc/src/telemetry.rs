@@ -0,0 +1,229 @@
+use envoy_types::ext_authz::v3::CheckRequestExt;
+use envoy_types::ext_authz::v3::pb::CheckRequest;
+use opentelemetry::global;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use std::env;
+use tracing::Span;
+use tracing::field::Empty;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Sets up logging and, when `OTEL_EXPORTER_OTLP_ENDPOINT` is set, span export
+/// over OTLP/gRPC. The returned provider must be kept alive for spans to be
+/// exported.
+pub(crate) fn init() -> anyhow::Result<Option<SdkTracerProvider>> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let provider = match env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(endpoint) => Some(tracer_provider(&endpoint)?),
+        Err(_) => None,
+    };
+    let otel = provider
+        .as_ref()
+        .map(|p| tracing_opentelemetry::layer().with_tracer(p.tracer("auth-svc")));
+
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer())
+        .with(otel)
+        .init();
+    Ok(provider)
+}
+
+pub(crate) fn tracer_provider(endpoint: &str) -> anyhow::Result<SdkTracerProvider> {
+    let exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("auth-svc").build())
+        .build())
+}
+
+/// Span for one ext_authz check, parented to the trace context Envoy forwards
+/// in the checked request's headers. Only the request ID, the decision and the
+/// policy are ever recorded on it, never the credentials. Every log line
+/// emitted during the check carries the request ID through this span.
+pub(crate) fn check_span(request: &CheckRequest) -> Span {
+    let span = tracing::info_span!(
+        "check",
+        request_id = request_id(request),
+        otel.name = "envoy.service.auth.v3.Authorization/Check",
+        otel.kind = "server",
+        auth.decision = Empty,
+        auth.policy = Empty,
+    );
+    if let Some(headers) = request.get_client_headers() {
+        let parent = global::get_text_map_propagator(|p| p.extract(headers));
+        span.set_parent(parent);
+    }
+    span
+}
+
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Envoy's `x-request-id` for the checked request, or an empty string.
+pub(crate) fn request_id(request: &CheckRequest) -> &str {
+    let http = request
+        .attributes
+        .as_ref()
+        .and_then(|a| a.request.as_ref())
+        .and_then(|r| r.http.as_ref());
+    match http {
+        Some(http) if !http.id.is_empty() => &http.id,
+        Some(http) => http
+            .headers
+            .get(REQUEST_ID_HEADER)
+            .map_or("", String::as_str),
+        None => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jwt::AuthState;
+    use crate::jwt::Claims;
+    use crate::jwt::extauth::AuthSvc;
+    use crate::policy::PolicySet;
+    use base64::Engine;
+    use base64::prelude::BASE64_STANDARD;
+    use chrono::Utc;
+    use envoy_types::ext_authz::v3::pb::Authorization;
+    use f2_utils::testing::CheckRequestBuilder;
+    use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{
+        TraceService, TraceServiceServer,
+    };
+    use opentelemetry_proto::tonic::collector::trace::v1::{
+        ExportTraceServiceRequest, ExportTraceServiceResponse,
+    };
+    use opentelemetry_proto::tonic::common::v1::any_value::Value;
+    use opentelemetry_proto::tonic::trace::v1::Span;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::{Request, Response, Status};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    /// Stands in for an OTLP collector, keeping every span it receives.
+    #[derive(Clone, Default)]
+    struct Collector {
+        spans: Arc<Mutex<Vec<Span>>>,
+    }
+
+    #[tonic::async_trait]
+    impl TraceService for Collector {
+        async fn export(
+            &self,
+            request: Request<ExportTraceServiceRequest>,
+        ) -> Result<Response<ExportTraceServiceResponse>, Status> {
+            let spans = request
+                .into_inner()
+                .resource_spans
+                .into_iter()
+                .flat_map(|r| r.scope_spans)
+                .flat_map(|s| s.spans);
+            self.spans.lock().unwrap().extend(spans);
+            Ok(Response::new(ExportTraceServiceResponse::default()))
+        }
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    fn attribute(span: &Span, key: &str) -> Option<String> {
+        span.attributes
+            .iter()
+            .find(|kv| kv.key == key)
+            .and_then(|kv| kv.value.as_ref()?.value.as_ref())
+            .map(|v| match v {
+                Value::StringValue(s) => s.clone(),
+                other => format!("{other:?}"),
+            })
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn check_span_joins_incoming_trace() {
+        let collector = Collector::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(TraceServiceServer::new(collector.clone()))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        opentelemetry::global::set_text_map_propagator(
+            opentelemetry_sdk::propagation::TraceContextPropagator::new(),
+        );
+        let provider = super::tracer_provider(&endpoint).unwrap();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let policies = PolicySet::from_json(
+            r#"[{"name": "rest-writes", "path_prefix": "/rest/v1/", "roles": ["service_role"]}]"#,
+        )
+        .unwrap();
+        let state = AuthState::new(
+            BASE64_STANDARD.encode(b"secret"),
+            "admin".into(),
+            "s3cr3t".into(),
+        )
+        .with_policies(policies);
+        let svc = AuthSvc::new(Arc::new(state));
+
+        let now = Utc::now().timestamp();
+        let token = encode(
+            &Header::new(Algorithm::HS256),
+            &Claims {
+                role: "anon".into(),
+                iss: "f2".into(),
+                iat: now,
+                exp: now + 3600,
+            },
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        let request = CheckRequestBuilder::new()
+            .method("POST")
+            .path("/rest/v1/images")
+            .header("Authorization", format!("Bearer {token}"))
+            .header("traceparent", format!("00-{TRACE_ID}-{PARENT_ID}-01"))
+            .build();
+        svc.check(Request::new(request)).await.unwrap();
+
+        tokio::task::spawn_blocking(move || provider.force_flush())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let spans = collector.spans.lock().unwrap();
+        let span = spans
+            .iter()
+            .find(|s| s.name == "envoy.service.auth.v3.Authorization/Check")
+            .expect("check span exported");
+        assert_eq!(hex(&span.trace_id), TRACE_ID);
+        assert_eq!(hex(&span.parent_span_id), PARENT_ID);
+        assert_eq!(attribute(span, "auth.decision").as_deref(), Some("deny"));
+        assert_eq!(
+            attribute(span, "auth.policy").as_deref(),
+            Some("rest-writes")
+        );
+        assert!(
+            span.attributes
+                .iter()
+                .all(|kv| !format!("{:?}", kv.value).contains(&token))
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::{env, fs, io};
+
+/// Directories under envoy-types' `proto` holding the envoy protos and
+/// everything they import.
+const ENVOY_PROTO_DIRS: [&str; 5] = [
+    "data-plane-api",
+    "googleapis",
+    "protoc-gen-validate",
+    "xds",
+    "cel-spec/proto",
+];
+
+/// Packages the envoy protos bring in, which envoy-types already generated:
+/// f2 code refers to its types and gets no module for them.
+const ENVOY_PACKAGES: [&str; 5] = ["envoy", "google.rpc", "udpa", "validate", "xds"];
+
+/// Proto packages as a tree of Rust modules, e.g. `f2.users.v1` as
+/// `f2 -> users -> v1`.
+#[derive(Default)]
+struct Module {
+    package: Option<String>,
+    children: BTreeMap<String, Module>,
+}
+
+impl Module {
+    fn insert(&mut self, package: &str) {
+        let module = package.split('.').fold(self, |module, name| {
+            module.children.entry(name.into()).or_default()
+        });
+        module.package = Some(package.into());
+    }
+
+    /// Writes the modules, each including the messages and services prost
+    /// and tonic generated for its package and the serde impls from pbjson.
+    fn write(&self, out: &Path, code: &mut String) {
+        if let Some(package) = &self.package {
+            for file in [format!("{package}.rs"), format!("{package}.serde.rs")] {
+                if out.join(&file).exists() {
+                    writeln!(code, "include!(concat!(env!(\"OUT_DIR\"), \"/{file}\"));").unwrap();
+                }
+            }
+        }
+        for (name, module) in &self.children {
+            // generated code isn't held to our lints
+            writeln!(code, "#[allow(clippy::all)]\npub mod {name} {{").unwrap();
+            module.write(out, code);
+            writeln!(code, "}}").unwrap();
+        }
+    }
+}
+
+/// Collects every `.proto` under `dir`, relative to `root`.
+fn protos(root: &Path, dir: &Path, found: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            protos(root, &path, found)?;
+        } else if path.extension().is_some_and(|ext| ext == "proto") {
+            found.push(path.strip_prefix(root).unwrap().to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+/// The `proto` directory envoy-types ships, so f2 protos can import envoy's
+/// and the generated code uses envoy-types' messages for them.
+fn envoy_protos() -> io::Result<PathBuf> {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+    let output = Command::new(cargo)
+        .args(["metadata", "--format-version", "1", "--manifest-path"])
+        .arg(PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("Cargo.toml"))
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ));
+    }
+    let metadata: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let manifest = metadata["packages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|package| package["name"] == "envoy-types")
+        .and_then(|package| package["manifest_path"].as_str())
+        .ok_or_else(|| io::Error::other("envoy-types is not a dependency"))?;
+    Ok(Path::new(manifest).with_file_name("proto"))
+}
+
+fn main() -> io::Result<()> {
+    let root = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("../../proto");
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let descriptors = out.join("f2_descriptor.bin");
+
+    let mut found = Vec::new();
+    protos(&root, &root, &mut found)?;
+    found.sort();
+    let envoy = envoy_protos()?;
+    let mut includes = vec![root.clone()];
+    includes.extend(ENVOY_PROTO_DIRS.iter().map(|dir| envoy.join(dir)));
+
+    // the vendored protoc keeps the build off whatever protoc the host has,
+    // and the well-known types come from pbjson-types so they have serde
+    // impls too
+    let mut config = prost_build::Config::new();
+    config
+        .protoc_executable(protoc_bin_vendored::protoc_bin_path().map_err(io::Error::other)?)
+        .file_descriptor_set_path(&descriptors)
+        .compile_well_known_types()
+        .extern_path(".google.protobuf", "::pbjson_types");
+    for package in ENVOY_PACKAGES {
+        config.extern_path(
+            format!(".{package}"),
+            format!("::envoy_types::pb::{}", package.replace('.', "::")),
+        );
+    }
+    tonic_build::configure()
+        .build_server(true)
+        .build_client(true)
+        .build_transport(false)
+        .compile_protos_with_config(config, &found, &includes)?;
+
+    let set = fs::read(&descriptors)?;
+    pbjson_build::Builder::new()
+        .register_descriptors(&set)?
+        .build(&[".f2"])?;
+
+    // packages without messages or services get no file, so the modules
+    // follow what was generated rather than the protos; envoy's services are
+    // generated too but served from envoy-types
+    let mut modules = Module::default();
+    for entry in fs::read_dir(&out)? {
+        let name = entry?.file_name().into_string().unwrap();
+        if let Some(package) = name.strip_suffix(".rs")
+            && !package.ends_with(".serde")
+            && package != "packages"
+            && !ENVOY_PACKAGES
+                .iter()
+                .any(|envoy| package == *envoy || package.starts_with(&format!("{envoy}.")))
+        {
+            modules.insert(package);
+        }
+    }
+    let mut code = String::new();
+    modules.write(&out, &mut code);
+    fs::write(out.join("packages.rs"), code)?;
+
+    println!("cargo:rerun-if-changed={}", root.display());
+    for proto in &found {
+        println!("cargo:rerun-if-changed={}", root.join(proto).display());
+    }
+    Ok(())
+}
@@ -0,0 +1,62 @@
+//! Rust bindings for the f2 protobuf APIs under `backend-src/proto`:
+//! messages, tonic servers and clients, and protobuf JSON through serde.
+//!
+//! Modules follow the proto packages, so `f2.users.v1` is
+//! [`f2::users::v1`]. Packages declaring neither messages nor services, like
+//! `f2.images.v1` for now, have no module.
+
+include!(concat!(env!("OUT_DIR"), "/packages.rs"));
+
+/// What the generated serde impls build on: helpers for bytes and 64-bit
+/// fields, and the well-known types such as `google.protobuf.Timestamp`.
+pub use {pbjson, pbjson_types};
+
+/// Every proto under `backend-src/proto` with everything it imports,
+/// including source info so reflection clients can show comments.
+pub const FILE_DESCRIPTOR_SET: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/f2_descriptor.bin"));
+
+#[cfg(test)]
+mod tests {
+    use prost_reflect::DescriptorPool;
+
+    use super::f2::errors::v1::{Error, ErrorCode};
+    use super::f2::users::v1::{CreateUserRequest, User};
+    use super::*;
+
+    #[test]
+    fn describes_f2_services() {
+        let pool = DescriptorPool::decode(FILE_DESCRIPTOR_SET).unwrap();
+        assert!(pool.get_service_by_name("f2.users.v1.Users").is_some());
+        assert!(pool.get_service_by_name("f2.auth.v1.AuthAdmin").is_some());
+        assert!(pool.get_message_by_name("f2.errors.v1.Error").is_some());
+        assert!(pool.get_file_by_name("f2/images/v1/images.proto").is_some());
+    }
+
+    #[test]
+    fn speaks_protobuf_json() {
+        let request = CreateUserRequest {
+            user: Some(User {
+                id: "u-1".into(),
+                handle: "ada".into(),
+                email: "ada@example.com".into(),
+            }),
+        };
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["user"]["handle"], "ada");
+        assert_eq!(
+            serde_json::from_value::<CreateUserRequest>(json).unwrap(),
+            request
+        );
+
+        let error = Error {
+            code: ErrorCode::NotFound.into(),
+            message: "no such user".into(),
+        };
+        let json = serde_json::to_string(&error).unwrap();
+        assert_eq!(
+            json,
+            r#"{"code":"ERROR_CODE_NOT_FOUND","message":"no such user"}"#
+        );
+    }
+}
@@ -0,0 +1,634 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{BuildHasher, RandomState};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tonic::body::Body;
+use tower::Service;
+
+use super::h2c::H2cChannel;
+use crate::Error;
+
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_FAILURES: u32 = 5;
+const DEFAULT_EJECTION_TIME: Duration = Duration::from_secs(30);
+
+/// Turns a target into the addresses to balance over.
+pub trait Resolve: Send + Sync + 'static {
+    fn resolve(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<SocketAddr>>> + Send + '_>>;
+}
+
+/// Resolves a host name through the system resolver. A Kubernetes headless
+/// service resolves to one A record per ready pod, so every pod becomes an
+/// endpoint.
+pub struct Dns {
+    host: String,
+    port: u16,
+}
+
+impl Dns {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+        }
+    }
+}
+
+impl Resolve for Dns {
+    fn resolve(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<SocketAddr>>> + Send + '_>> {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((self.host.as_str(), self.port)).await?;
+            Ok(addrs.collect())
+        })
+    }
+}
+
+/// A fixed list of addresses.
+pub struct Static(Vec<SocketAddr>);
+
+impl Static {
+    pub fn new(addrs: impl IntoIterator<Item = SocketAddr>) -> Self {
+        Self(addrs.into_iter().collect())
+    }
+}
+
+impl Resolve for Static {
+    fn resolve(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<SocketAddr>>> + Send + '_>> {
+        let addrs = self.0.clone();
+        Box::pin(async move { Ok(addrs) })
+    }
+}
+
+/// Request extension naming the endpoint a [`BalancedChannel`] picked for a
+/// call. Channels connect to this address instead of the URI's authority,
+/// which stays the `:authority` of the call and the name TLS verifies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DialAddr(pub SocketAddr);
+
+/// A channel keeping state per endpoint, like pooled connections, which the
+/// balancer tells when an endpoint is no longer resolved so it can drop it.
+pub trait Forget {
+    /// Drops what is kept for `authority`, the endpoint's `ip:port`.
+    fn forget(&self, authority: &str);
+}
+
+/// How the next endpoint is picked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Of two random endpoints, the one with fewer calls in flight.
+    #[default]
+    PowerOfTwoChoices,
+    /// Every endpoint in turn.
+    RoundRobin,
+}
+
+impl FromStr for Strategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "p2c" | "power-of-two-choices" => Ok(Strategy::PowerOfTwoChoices),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            _ => Err(Error::UnknownStrategy(s.to_string())),
+        }
+    }
+}
+
+/// Spreads calls over the endpoints a [`Resolve`] returns, each reached
+/// through the wrapped channel, an [`H2cChannel`] and its connection pool
+/// unless e.g. a [`Breaker`](super::breaker::Breaker) goes in between.
+///
+/// Every call goes to the picked endpoint, set as its [`DialAddr`], while its
+/// URI keeps the origin's authority, so TLS still verifies the target's
+/// name. Endpoints failing `max_failures` calls in a row are
+/// ejected for `ejection_time`, unless that would eject all of them. The
+/// target is resolved again every `refresh_interval`, keeping the failure
+/// counts of endpoints that are still there; the wrapped channel is told to
+/// [`Forget`] those that went away, closing their pooled connections.
+///
+/// Configuring a clone leaves the original as it was: the clone balances on
+/// its own, starting with a fresh resolve.
+#[derive(Clone)]
+pub struct BalancedChannel<S = H2cChannel> {
+    channel: S,
+    balancer: Arc<Balancer>,
+}
+
+struct Balancer {
+    resolver: Arc<dyn Resolve>,
+    strategy: Strategy,
+    refresh_interval: Duration,
+    max_failures: u32,
+    ejection_time: Duration,
+    endpoints: RwLock<Vec<Arc<Endpoint>>>,
+    next: AtomicUsize,
+    random: RandomState,
+    /// Whether the refresh task runs yet, started by the first call.
+    refreshing: AtomicBool,
+}
+
+/// A balancer with the same settings and resolver that resolves the target
+/// anew, for a clone that is configured differently.
+impl Clone for Balancer {
+    fn clone(&self) -> Self {
+        Self {
+            resolver: self.resolver.clone(),
+            strategy: self.strategy,
+            refresh_interval: self.refresh_interval,
+            max_failures: self.max_failures,
+            ejection_time: self.ejection_time,
+            endpoints: RwLock::default(),
+            next: AtomicUsize::new(0),
+            random: RandomState::new(),
+            refreshing: AtomicBool::new(false),
+        }
+    }
+}
+
+struct Endpoint {
+    addr: SocketAddr,
+    in_flight: AtomicUsize,
+    failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+/// Counts a call against its endpoint until the response headers arrive.
+struct InFlight(Arc<Endpoint>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<S> BalancedChannel<S> {
+    pub fn new(channel: S, resolver: impl Resolve) -> Self {
+        Self {
+            channel,
+            balancer: Arc::new(Balancer {
+                resolver: Arc::new(resolver),
+                strategy: Strategy::default(),
+                refresh_interval: DEFAULT_REFRESH_INTERVAL,
+                max_failures: DEFAULT_MAX_FAILURES,
+                ejection_time: DEFAULT_EJECTION_TIME,
+                endpoints: RwLock::default(),
+                next: AtomicUsize::new(0),
+                random: RandomState::new(),
+                refreshing: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    pub fn with_strategy(mut self, strategy: Strategy) -> Self {
+        self.balancer_mut().strategy = strategy;
+        self
+    }
+
+    /// How often the target is resolved again.
+    pub fn with_refresh_interval(mut self, interval: Duration) -> Self {
+        self.balancer_mut().refresh_interval = interval;
+        self
+    }
+
+    /// Ejects an endpoint for `time` after `max_failures` failed calls in a
+    /// row.
+    pub fn with_ejection(mut self, max_failures: u32, time: Duration) -> Self {
+        let balancer = self.balancer_mut();
+        balancer.max_failures = max_failures.max(1);
+        balancer.ejection_time = time;
+        self
+    }
+
+    fn balancer_mut(&mut self) -> &mut Balancer {
+        Arc::make_mut(&mut self.balancer)
+    }
+}
+
+impl Balancer {
+    /// Resolves the target and swaps in the new endpoints, returning the
+    /// addresses that went away. An empty answer, e.g. while every pod of a
+    /// headless service restarts, keeps the endpoints known so far.
+    async fn refresh(&self) -> Result<Vec<SocketAddr>, Error> {
+        let addrs = self.resolver.resolve().await?;
+        let mut endpoints = self.endpoints.write().unwrap();
+        if addrs.is_empty() && !endpoints.is_empty() {
+            tracing::warn!(
+                "Resolved no endpoints, keeping the {} known ones",
+                endpoints.len()
+            );
+            return Ok(Vec::new());
+        }
+        let mut known: HashMap<_, _> = endpoints.drain(..).map(|e| (e.addr, e)).collect();
+        for addr in addrs {
+            let endpoint = known.remove(&addr).unwrap_or_else(|| {
+                Arc::new(Endpoint {
+                    addr,
+                    in_flight: AtomicUsize::new(0),
+                    failures: AtomicU32::new(0),
+                    ejected_until: Mutex::new(None),
+                })
+            });
+            if !endpoints.iter().any(|e| e.addr == addr) {
+                endpoints.push(endpoint);
+            }
+        }
+        Ok(known.into_keys().collect())
+    }
+
+    fn pick(&self) -> Option<Arc<Endpoint>> {
+        let endpoints = self.endpoints.read().unwrap();
+        let now = Instant::now();
+        let mut healthy: Vec<_> = endpoints.iter().filter(|e| !e.is_ejected(now)).collect();
+        if healthy.is_empty() {
+            // better to try an ejected endpoint than to fail every call
+            healthy = endpoints.iter().collect();
+        }
+
+        let endpoint = match (self.strategy, healthy.len()) {
+            (_, 0) => return None,
+            (_, 1) => healthy[0],
+            (Strategy::RoundRobin, n) => healthy[self.next.fetch_add(1, Ordering::Relaxed) % n],
+            (Strategy::PowerOfTwoChoices, n) => {
+                let seed = self.next.fetch_add(1, Ordering::Relaxed);
+                let a = self.random.hash_one(seed) as usize % n;
+                let b = (a + 1 + self.random.hash_one(!seed) as usize % (n - 1)) % n;
+                let (a, b) = (healthy[a], healthy[b]);
+                if b.in_flight.load(Ordering::Relaxed) < a.in_flight.load(Ordering::Relaxed) {
+                    b
+                } else {
+                    a
+                }
+            }
+        };
+        Some(endpoint.clone())
+    }
+
+    fn record(&self, endpoint: &Endpoint, ok: bool) {
+        if ok {
+            endpoint.failures.store(0, Ordering::Relaxed);
+            return;
+        }
+        let failures = endpoint.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= self.max_failures {
+            tracing::warn!("Ejecting {} after {} failed calls", endpoint.addr, failures);
+            endpoint.failures.store(0, Ordering::Relaxed);
+            *endpoint.ejected_until.lock().unwrap() = Some(Instant::now() + self.ejection_time);
+        }
+    }
+}
+
+impl Endpoint {
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| until > now)
+    }
+}
+
+/// Resolves the target again every `refresh_interval` until the channel is
+/// dropped, and has `channel` forget the endpoints that went away.
+fn spawn_refresh<S: Forget + Send + 'static>(
+    balancer: Weak<Balancer>,
+    channel: S,
+    interval: Duration,
+) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval_at(Instant::now() + interval, interval);
+        loop {
+            ticks.tick().await;
+            let Some(balancer) = balancer.upgrade() else {
+                return;
+            };
+            match balancer.refresh().await {
+                Ok(gone) => {
+                    for addr in gone {
+                        channel.forget(&addr.to_string());
+                    }
+                }
+                Err(e) => tracing::warn!("Resolving endpoints failed: {}", e),
+            }
+        }
+    });
+}
+
+impl<S> Service<http::Request<Body>> for BalancedChannel<S>
+where
+    S: Service<http::Request<Body>, Error = Error> + Forget + Clone + Send + 'static,
+    S::Response: Send,
+    S::Future: Send,
+{
+    type Response = S::Response;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.channel.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<Body>) -> Self::Future {
+        // the ready channel is handed to the call and a fresh clone kept
+        let fresh = self.channel.clone();
+        let mut channel = std::mem::replace(&mut self.channel, fresh);
+        let balancer = self.balancer.clone();
+
+        Box::pin(async move {
+            if balancer.endpoints.read().unwrap().is_empty() {
+                balancer.refresh().await?;
+            }
+            if !balancer.refreshing.swap(true, Ordering::Relaxed) {
+                spawn_refresh(
+                    Arc::downgrade(&balancer),
+                    channel.clone(),
+                    balancer.refresh_interval,
+                );
+            }
+            let endpoint = balancer.pick().ok_or(Error::NoEndpoints)?;
+
+            if request.uri().authority().is_none() {
+                // an origin without a host gets the endpoint's address
+                let mut parts = request.uri().clone().into_parts();
+                let authority = http::uri::Authority::try_from(endpoint.addr.to_string());
+                parts.authority = Some(authority.map_err(http::Error::from)?);
+                parts.scheme.get_or_insert(http::uri::Scheme::HTTP);
+                *request.uri_mut() = http::Uri::from_parts(parts).map_err(http::Error::from)?;
+            }
+            request.extensions_mut().insert(DialAddr(endpoint.addr));
+
+            endpoint.in_flight.fetch_add(1, Ordering::Relaxed);
+            let _in_flight = InFlight(endpoint.clone());
+            let res = channel.call(request).await;
+            balancer.record(&endpoint, res.is_ok());
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::h2c::H2cMode;
+    use crate::server::h2c::H2c;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use std::convert::Infallible;
+    use tokio::net::TcpListener;
+    use tower::ServiceExt;
+
+    /// Stands in for DNS, answering with whatever the test put in.
+    #[derive(Clone, Default)]
+    struct Resolver(Arc<Mutex<Vec<SocketAddr>>>);
+
+    impl Resolver {
+        fn set(&self, addrs: &[SocketAddr]) {
+            *self.0.lock().unwrap() = addrs.to_vec();
+        }
+    }
+
+    impl Resolve for Resolver {
+        fn resolve(
+            &self,
+        ) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<SocketAddr>>> + Send + '_>> {
+            let addrs = self.0.lock().unwrap().clone();
+            Box::pin(async move { Ok(addrs) })
+        }
+    }
+
+    /// h2c backend counting the calls it answered.
+    async fn backend() -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let svc = H2c::new(tower::service_fn(move |_: http::Request<Body>| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            async { Ok::<_, Infallible>(http::Response::new(Body::empty())) }
+        }));
+        tokio::spawn(async move {
+            loop {
+                let (io, _) = listener.accept().await.unwrap();
+                tokio::spawn(svc.clone().serve_connection(TokioIo::new(io)));
+            }
+        });
+        (addr, hits)
+    }
+
+    /// h2c backend counting its open connections.
+    async fn tracked_backend() -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let open = Arc::new(AtomicUsize::new(0));
+        let counted = open.clone();
+        let svc = H2c::new(tower::service_fn(|_: http::Request<Body>| async {
+            Ok::<_, Infallible>(http::Response::new(Body::empty()))
+        }));
+        tokio::spawn(async move {
+            loop {
+                let (io, _) = listener.accept().await.unwrap();
+                let open = counted.clone();
+                let conn = svc.clone().serve_connection(TokioIo::new(io));
+                open.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let _ = conn.await;
+                    open.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        (addr, open)
+    }
+
+    /// An address nothing listens on.
+    async fn dead() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    fn balanced(resolver: &Resolver) -> BalancedChannel {
+        let channel = H2cChannel::new(Client::builder(TokioExecutor::new()).build_http())
+            .with_mode(H2cMode::PriorKnowledge);
+        BalancedChannel::new(channel, resolver.clone())
+    }
+
+    async fn call(channel: &BalancedChannel) -> Result<(), Error> {
+        let req = http::Request::builder()
+            .uri("http://f2-backend.internal/f2.test.v1.Test/Call")
+            .body(Body::empty())
+            .unwrap();
+        channel.clone().oneshot(req).await.map(drop)
+    }
+
+    fn hits(counters: &[Arc<AtomicUsize>]) -> Vec<usize> {
+        counters.iter().map(|c| c.load(Ordering::SeqCst)).collect()
+    }
+
+    #[tokio::test]
+    async fn round_robin_visits_every_endpoint() {
+        let (a, a_hits) = backend().await;
+        let (b, b_hits) = backend().await;
+        let (c, c_hits) = backend().await;
+        let resolver = Resolver::default();
+        resolver.set(&[a, b, c]);
+        let channel = balanced(&resolver).with_strategy(Strategy::RoundRobin);
+
+        for _ in 0..6 {
+            call(&channel).await.unwrap();
+        }
+        assert_eq!(hits(&[a_hits, b_hits, c_hits]), [2, 2, 2]);
+    }
+
+    #[tokio::test]
+    async fn configures_clones_apart() {
+        let (a, a_hits) = backend().await;
+        let (b, b_hits) = backend().await;
+        let resolver = Resolver::default();
+        resolver.set(&[a, b]);
+        let channel = balanced(&resolver);
+        call(&channel).await.unwrap();
+
+        let round_robin = channel.clone().with_strategy(Strategy::RoundRobin);
+        for _ in 0..4 {
+            call(&round_robin).await.unwrap();
+        }
+        assert_eq!(channel.balancer.strategy, Strategy::PowerOfTwoChoices);
+        assert_eq!(hits(&[a_hits, b_hits]).iter().sum::<usize>(), 5);
+    }
+
+    #[tokio::test]
+    async fn power_of_two_choices_uses_every_endpoint() {
+        let (a, a_hits) = backend().await;
+        let (b, b_hits) = backend().await;
+        let resolver = Resolver::default();
+        resolver.set(&[a, b]);
+        let channel = balanced(&resolver);
+
+        for _ in 0..40 {
+            call(&channel).await.unwrap();
+        }
+        let hits = hits(&[a_hits, b_hits]);
+        assert_eq!(hits.iter().sum::<usize>(), 40);
+        assert!(hits.iter().all(|&n| n > 0), "{hits:?}");
+    }
+
+    #[tokio::test]
+    async fn ejects_failing_endpoints() {
+        let (live, live_hits) = backend().await;
+        let resolver = Resolver::default();
+        resolver.set(&[dead().await, live]);
+        let channel = balanced(&resolver)
+            .with_strategy(Strategy::RoundRobin)
+            .with_ejection(1, Duration::from_secs(60));
+
+        let mut failed = 0;
+        for _ in 0..5 {
+            if call(&channel).await.is_err() {
+                failed += 1;
+            }
+        }
+        assert_eq!(failed, 1);
+        assert_eq!(live_hits.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn follows_resolved_endpoints() {
+        let (a, a_hits) = backend().await;
+        let (b, b_hits) = backend().await;
+        let resolver = Resolver::default();
+        resolver.set(&[a]);
+        let channel = balanced(&resolver).with_refresh_interval(Duration::from_millis(20));
+
+        call(&channel).await.unwrap();
+        resolver.set(&[b]);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        call(&channel).await.unwrap();
+        assert_eq!(hits(&[a_hits, b_hits]), [1, 1]);
+    }
+
+    #[tokio::test]
+    async fn closes_connections_to_endpoints_that_went_away() {
+        let (a, a_open) = tracked_backend().await;
+        let (b, _) = backend().await;
+        let resolver = Resolver::default();
+        resolver.set(&[a]);
+        let channel = balanced(&resolver).with_refresh_interval(Duration::from_millis(20));
+
+        call(&channel).await.unwrap();
+        assert_eq!(a_open.load(Ordering::SeqCst), 1);
+        resolver.set(&[b]);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(a_open.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn keeps_endpoints_when_resolving_none() {
+        let (a, a_hits) = backend().await;
+        let resolver = Resolver::default();
+        resolver.set(&[a]);
+        let channel = balanced(&resolver).with_refresh_interval(Duration::from_millis(20));
+
+        call(&channel).await.unwrap();
+        resolver.set(&[]);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        call(&channel).await.unwrap();
+        assert_eq!(a_hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn upgrades_at_the_endpoint_keeping_the_origin() {
+        let (addr, hits) = backend().await;
+        let resolver = Resolver::default();
+        resolver.set(&[addr]);
+        let channel = H2cChannel::new(Client::builder(TokioExecutor::new()).build_http());
+        let channel = BalancedChannel::new(channel, resolver);
+
+        // f2-backend.internal doesn't resolve, so the upgrade went to the endpoint
+        call(&channel).await.unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn fails_without_endpoints() {
+        let channel = balanced(&Resolver::default());
+        assert!(matches!(call(&channel).await, Err(Error::NoEndpoints)));
+    }
+
+    #[tokio::test]
+    async fn resolves_static_lists() {
+        let addrs: Vec<SocketAddr> = vec![([10, 0, 0, 1], 80).into(), ([10, 0, 0, 2], 80).into()];
+        assert_eq!(Static::new(addrs.clone()).resolve().await.unwrap(), addrs);
+        let dns = Dns::new("localhost", 8080).resolve().await.unwrap();
+        assert!(
+            dns.iter()
+                .all(|addr| addr.ip().is_loopback() && addr.port() == 8080)
+        );
+    }
+
+    #[test]
+    fn parses_strategy() {
+        assert_eq!(
+            "p2c".parse::<Strategy>().unwrap(),
+            Strategy::PowerOfTwoChoices
+        );
+        assert_eq!(
+            "round-robin".parse::<Strategy>().unwrap(),
+            Strategy::RoundRobin
+        );
+        assert!("random".parse::<Strategy>().is_err());
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use http::{Request, Response};
+use tokio::time::Instant;
+use tonic::Code;
+use tonic::body::Body;
+use tower::{Layer, Service};
+
+use super::balance::{DialAddr, Forget};
+use super::methods::Methods;
+use super::replay;
+use crate::Error;
+
+/// When a method's failures open the circuit to an endpoint.
+#[derive(Clone, Debug)]
+pub struct BreakerPolicy {
+    failure_threshold: u32,
+    open_for: Duration,
+    failure_codes: Vec<Code>,
+}
+
+impl BreakerPolicy {
+    /// Opens after `failure_threshold` failed calls in a row and lets a
+    /// single probe through after `open_for`. `UNAVAILABLE` and
+    /// `DEADLINE_EXCEEDED` count as failures.
+    pub fn new(failure_threshold: u32, open_for: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            open_for,
+            failure_codes: vec![Code::Unavailable, Code::DeadlineExceeded],
+        }
+    }
+
+    pub fn with_failure_codes(mut self, codes: impl IntoIterator<Item = Code>) -> Self {
+        self.failure_codes = codes.into_iter().collect();
+        self
+    }
+}
+
+/// A circuit breaker per endpoint, told apart by the call's
+/// [`DialAddr`](super::balance::DialAddr), or else the authority of its URI.
+/// Put it under a
+/// [`BalancedChannel`](super::balance::BalancedChannel) to break the circuit
+/// to single endpoints rather than the whole target.
+///
+/// While a circuit is open, calls fail right away with
+/// [`Error::CircuitOpen`]. Only methods with a [`BreakerPolicy`] are
+/// guarded and counted.
+#[derive(Clone)]
+pub struct BreakerLayer {
+    methods: Arc<Methods<BreakerPolicy>>,
+}
+
+impl BreakerLayer {
+    pub fn new(methods: Methods<BreakerPolicy>) -> Self {
+        Self {
+            methods: Arc::new(methods),
+        }
+    }
+}
+
+impl<S> Layer<S> for BreakerLayer {
+    type Service = Breaker<S>;
+
+    fn layer(&self, inner: S) -> Breaker<S> {
+        Breaker {
+            inner,
+            methods: self.methods.clone(),
+            circuits: Arc::default(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Breaker<S> {
+    inner: S,
+    methods: Arc<Methods<BreakerPolicy>>,
+    circuits: Arc<Mutex<HashMap<String, Circuit>>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Circuit {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A probe is on its way; another one goes out if it never returns.
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+impl Circuit {
+    /// Whether a call may go through. Once the circuit was open for long
+    /// enough, the call goes through as the probe.
+    fn admit(&mut self, policy: &BreakerPolicy, now: Instant) -> bool {
+        let probe = match *self {
+            Circuit::Closed { .. } => return true,
+            Circuit::Open { until } => now >= until,
+            Circuit::HalfOpen { since } => now >= since + policy.open_for,
+        };
+        if probe {
+            *self = Circuit::HalfOpen { since: now };
+        }
+        probe
+    }
+
+    /// Counts a finished call, returning whether it opened the circuit.
+    fn record(&mut self, policy: &BreakerPolicy, failed: bool, now: Instant) -> bool {
+        let next = match (*self, failed) {
+            (Circuit::Closed { failures }, true) if failures + 1 < policy.failure_threshold => {
+                Circuit::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (Circuit::Closed { .. } | Circuit::HalfOpen { .. }, true) => Circuit::Open {
+                until: now + policy.open_for,
+            },
+            (Circuit::Closed { .. } | Circuit::HalfOpen { .. }, false) => {
+                Circuit::Closed { failures: 0 }
+            }
+            // a call let through before the circuit opened
+            (Circuit::Open { .. }, _) => return false,
+        };
+        *self = next;
+        matches!(next, Circuit::Open { .. })
+    }
+}
+
+impl<S: Forget> Forget for Breaker<S> {
+    fn forget(&self, authority: &str) {
+        self.circuits.lock().unwrap().remove(authority);
+        self.inner.forget(authority);
+    }
+}
+
+impl<S, B> Service<Request<Body>> for Breaker<S>
+where
+    S: Service<Request<Body>, Response = Response<B>, Error = Error>,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = Response<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<B>, Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let Some(policy) = self.methods.get(request.uri().path()).cloned() else {
+            return Box::pin(self.inner.call(request));
+        };
+        let endpoint = match request.extensions().get::<DialAddr>() {
+            Some(DialAddr(addr)) => addr.to_string(),
+            None => request
+                .uri()
+                .authority()
+                .map_or_else(String::new, |a| a.to_string()),
+        };
+
+        let admitted = {
+            let mut circuits = self.circuits.lock().unwrap();
+            let circuit = circuits
+                .entry(endpoint.clone())
+                .or_insert(Circuit::Closed { failures: 0 });
+            circuit.admit(&policy, Instant::now())
+        };
+        if !admitted {
+            return Box::pin(async move { Err(Error::CircuitOpen(endpoint)) });
+        }
+
+        let call = self.inner.call(request);
+        let circuits = self.circuits.clone();
+        Box::pin(async move {
+            let result = call.await;
+            let failed = policy.failure_codes.contains(&replay::code(&result));
+            let mut circuits = circuits.lock().unwrap();
+            if let Some(circuit) = circuits.get_mut(&endpoint)
+                && circuit.record(&policy, failed, Instant::now())
+            {
+                tracing::warn!("Opening circuit to {} for {:?}", endpoint, policy.open_for);
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::replay::scripted::{Scripted, request};
+    use tower::ServiceExt;
+
+    async fn call(svc: &Breaker<Scripted>, endpoint: &str) -> Code {
+        let uri = format!("http://{endpoint}/f2.test.v1.Test/Get");
+        replay::code(&svc.clone().oneshot(request(&uri)).await)
+    }
+
+    #[tokio::test]
+    async fn opens_per_endpoint_and_probes() {
+        let inner = Scripted::codes([Code::Unavailable, Code::Unavailable]);
+        let policy = BreakerPolicy::new(2, Duration::from_millis(50));
+        let svc = BreakerLayer::new(Methods::new().with_default(policy)).layer(inner.clone());
+
+        assert_eq!(call(&svc, "10.0.0.1:80").await, Code::Unavailable);
+        assert_eq!(call(&svc, "10.0.0.1:80").await, Code::Unavailable);
+        assert_eq!(call(&svc, "10.0.0.1:80").await, Code::Unavailable);
+        assert_eq!(inner.calls(), 2, "open circuit fails fast");
+        assert_eq!(call(&svc, "10.0.0.2:80").await, Code::Ok);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(call(&svc, "10.0.0.1:80").await, Code::Ok);
+        assert_eq!(call(&svc, "10.0.0.1:80").await, Code::Ok);
+        assert_eq!(inner.calls(), 5);
+    }
+
+    #[test]
+    fn lets_one_probe_through() {
+        let policy = BreakerPolicy::new(1, Duration::from_secs(10));
+        let now = Instant::now();
+        let mut circuit = Circuit::Closed { failures: 0 };
+        assert!(circuit.record(&policy, true, now));
+
+        let later = now + Duration::from_secs(11);
+        assert!(circuit.admit(&policy, later));
+        assert!(!circuit.admit(&policy, later));
+        assert!(circuit.record(&policy, true, later));
+        assert!(!circuit.admit(&policy, later + Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,166 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use http::{HeaderValue, Request, Response};
+use hyper::body::Bytes;
+use tonic::Status;
+use tonic::body::Body;
+use tower::{Layer, Service};
+
+use crate::Error;
+use crate::compression::{ACCEPT_ENCODING, Compression, ENCODING};
+use crate::rewrite::rewrite;
+
+/// Compresses request messages and decompresses responses per
+/// [`Compression`], asking servers for compressed responses through
+/// `grpc-accept-encoding`.
+///
+/// Requests are sent in the first configured encoding, so configure only
+/// encodings the server accepts.
+///
+/// ```
+/// # use f2_utils::client::compression::CompressionLayer;
+/// # use f2_utils::compression::{Compression, CompressionMetrics};
+/// # use prometheus_client::registry::Registry;
+/// let mut registry = Registry::default();
+/// let metrics = CompressionMetrics::register(registry.sub_registry_with_prefix("f2_client"));
+/// let layer = CompressionLayer::new(Compression::new().with_metrics(metrics));
+/// ```
+#[derive(Clone)]
+pub struct CompressionLayer {
+    compression: Arc<Compression>,
+}
+
+impl CompressionLayer {
+    pub fn new(compression: Compression) -> Self {
+        Self {
+            compression: Arc::new(compression),
+        }
+    }
+}
+
+impl<S> Layer<S> for CompressionLayer {
+    type Service = Compressed<S>;
+
+    fn layer(&self, inner: S) -> Compressed<S> {
+        Compressed {
+            inner,
+            compression: self.compression.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Compressed<S> {
+    inner: S,
+    compression: Arc<Compression>,
+}
+
+impl<S, B> Service<Request<Body>> for Compressed<S>
+where
+    S: Service<Request<Body>, Response = Response<B>, Error = Error>,
+    S::Future: Send + 'static,
+    B: hyper::body::Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    type Response = Response<Body>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let compression = self.compression.clone();
+        let (mut parts, body) = request.into_parts();
+        parts
+            .headers
+            .insert(ACCEPT_ENCODING, compression.accept_encoding());
+        let body = match compression.send(parts.uri.path(), None) {
+            Some(encoding) if !parts.headers.contains_key(ENCODING) => {
+                parts
+                    .headers
+                    .insert(ENCODING, HeaderValue::from_static(encoding.name()));
+                rewrite(body, compression.compress(encoding))
+            }
+            _ => body,
+        };
+        let call = self.inner.call(Request::from_parts(parts, body));
+
+        Box::pin(async move {
+            let (mut parts, body) = call.await?.into_parts();
+            let body = Body::new(body);
+            let body = match parts.headers.remove(ENCODING) {
+                Some(name) => match compression.receive(&name) {
+                    Ok(encoding) => rewrite(body, compression.decompress(encoding)),
+                    Err(_) => {
+                        let message = format!("response in unsupported {ENCODING} {name:?}");
+                        return Ok(Status::internal(message).into_http());
+                    }
+                },
+                None => body,
+            };
+            Ok(Response::from_parts(parts, body))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::pending;
+
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::registry::Registry;
+    use tokio::net::TcpListener;
+    use tonic::service::Routes;
+    use tonic_health::pb::health_client::HealthClient;
+    use tonic_health::pb::{HealthCheckRequest, health_check_response};
+    use tower::ServiceBuilder;
+
+    use super::*;
+    use crate::client::h2c::{H2cChannel, H2cMode};
+    use crate::compression::{CompressionMetrics, Encoding};
+    use crate::server::Server;
+
+    #[tokio::test]
+    async fn compresses_both_ways() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new(Routes::default())
+            .with_listener(listener)
+            .with_compression(Compression::new().with_min_size(0));
+        tokio::spawn(server.serve_with_shutdown(pending()));
+
+        let mut registry = Registry::default();
+        let compression = Compression::new()
+            .with_encodings([Encoding::Gzip])
+            .with_min_size(0)
+            .with_metrics(CompressionMetrics::register(&mut registry));
+        let channel = H2cChannel::new(Client::builder(TokioExecutor::new()).build_http())
+            .with_mode(H2cMode::PriorKnowledge);
+        let svc = ServiceBuilder::new()
+            .layer(CompressionLayer::new(compression))
+            .service(channel);
+        let origin = format!("http://{addr}").parse().unwrap();
+        let mut health = HealthClient::with_origin(svc, origin);
+
+        let res = health.check(HealthCheckRequest::default()).await.unwrap();
+        assert_eq!(
+            res.into_inner().status(),
+            health_check_response::ServingStatus::Serving
+        );
+
+        let mut metrics = String::new();
+        encode(&mut metrics, &registry).unwrap();
+        for direction in ["sent", "received"] {
+            let count =
+                format!("compression_ratio_count{{encoding=\"gzip\",direction=\"{direction}\"}} 1");
+            assert!(metrics.contains(&count), "{metrics}");
+        }
+    }
+}
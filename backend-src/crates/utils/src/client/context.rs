@@ -0,0 +1,204 @@
+use http::HeaderValue;
+use opentelemetry::global;
+use opentelemetry::propagation::Injector;
+use opentelemetry::trace::TraceContextExt;
+use tonic::metadata::{AsciiMetadataKey, AsciiMetadataValue, MetadataMap};
+use tonic::{Request, Status};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::context::RequestContext;
+use crate::deadline;
+
+/// Interceptor copying the [`RequestContext`] of the call being served onto
+/// an outgoing call: the time left until its deadline becomes the call's
+/// `grpc-timeout`, unless the call set a shorter one, and the forwarded
+/// headers are added where the call doesn't set them itself. Calls made
+/// outside a served call go out as they are.
+///
+/// Calls made within a span OpenTelemetry traces carry that span's context,
+/// injected with the global propagator, so they show up as its children.
+/// The served call's own `traceparent` and `tracestate` are only passed on
+/// as they came when there is no such span.
+///
+/// Calls made once the deadline has passed fail with `DEADLINE_EXCEEDED`
+/// without being sent.
+///
+/// ```
+/// # use f2_utils::client::h2c::H2cChannel;
+/// # use hyper_util::client::legacy::Client;
+/// # use hyper_util::rt::TokioExecutor;
+/// use f2_utils::client::context::propagate;
+/// use tonic::service::interceptor::InterceptedService;
+/// use tonic_health::pb::health_client::HealthClient;
+///
+/// let channel = H2cChannel::new(Client::builder(TokioExecutor::new()).build_http());
+/// let channel = InterceptedService::new(channel, propagate);
+/// let health = HealthClient::with_origin(channel, "http://auth-svc:8080".parse().unwrap());
+/// ```
+#[allow(clippy::result_large_err)]
+pub fn propagate(mut request: Request<()>) -> Result<Request<()>, Status> {
+    let span = tracing::Span::current().context();
+    let traced = span.span().span_context().is_valid();
+    if traced && !request.metadata().contains_key(TRACEPARENT) {
+        global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&span, &mut Metadata(request.metadata_mut()))
+        });
+    }
+
+    let Some(context) = RequestContext::current() else {
+        return Ok(request);
+    };
+
+    if let Some(deadline) = context.deadline() {
+        let remaining = deadline.remaining();
+        if remaining.is_zero() {
+            return Err(Status::deadline_exceeded(
+                "deadline of the call being served has passed",
+            ));
+        }
+        let requested = request
+            .metadata()
+            .get(deadline::GRPC_TIMEOUT)
+            .and_then(|value| HeaderValue::from_bytes(value.as_bytes()).ok())
+            .and_then(|value| deadline::parse(&value));
+        if requested.is_none_or(|requested| remaining < requested) {
+            request.set_timeout(remaining);
+        }
+    }
+
+    for name in context.headers().keys() {
+        if traced && (name == TRACEPARENT || name == TRACESTATE) {
+            continue;
+        }
+        let Ok(key) = AsciiMetadataKey::from_bytes(name.as_str().as_bytes()) else {
+            continue;
+        };
+        if request.metadata().contains_key(&key) {
+            continue;
+        }
+        for value in context.headers().get_all(name) {
+            if let Ok(value) = AsciiMetadataValue::try_from(value.as_bytes()) {
+                request.metadata_mut().append(key.clone(), value);
+            }
+        }
+    }
+    Ok(request)
+}
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+/// Lets the OpenTelemetry propagator write into a call's metadata.
+struct Metadata<'a>(&'a mut MetadataMap);
+
+impl Injector for Metadata<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            AsciiMetadataKey::from_bytes(key.as_bytes()),
+            AsciiMetadataValue::try_from(value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::HeaderName;
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::Deadline;
+
+    const SERVED_TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    fn context(deadline: Deadline) -> RequestContext {
+        RequestContext::default()
+            .with_deadline(deadline)
+            .with_header(
+                HeaderName::from_static("x-request-id"),
+                HeaderValue::from_static("req-123"),
+            )
+            .with_header(
+                HeaderName::from_static("x-tenant-id"),
+                HeaderValue::from_static("admin"),
+            )
+    }
+
+    fn grpc_timeout(request: &Request<()>) -> Duration {
+        let value = request.metadata().get(deadline::GRPC_TIMEOUT).unwrap();
+        deadline::parse(&HeaderValue::from_bytes(value.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn passes_the_deadline_and_headers_on() {
+        let context = context(Deadline::after(Duration::from_secs(2)));
+        context
+            .scope(async {
+                let mut request = Request::new(());
+                request
+                    .metadata_mut()
+                    .insert("x-tenant-id", "service".parse().unwrap());
+                let request = propagate(request).unwrap();
+                let timeout = grpc_timeout(&request);
+                assert!(timeout <= Duration::from_secs(2) && timeout > Duration::from_secs(1));
+                assert_eq!(request.metadata().get("x-request-id").unwrap(), "req-123");
+                // headers the call sets itself win
+                assert_eq!(request.metadata().get("x-tenant-id").unwrap(), "service");
+
+                let mut shorter = Request::new(());
+                shorter.set_timeout(Duration::from_millis(100));
+                let shorter = propagate(shorter).unwrap();
+                assert_eq!(grpc_timeout(&shorter), Duration::from_millis(100));
+            })
+            .await;
+
+        let request = propagate(Request::new(())).unwrap();
+        assert!(request.metadata().is_empty());
+    }
+
+    #[tokio::test]
+    async fn fails_calls_past_the_deadline() {
+        let context = context(Deadline::after(Duration::ZERO));
+        let code = context
+            .scope(async {
+                propagate(Request::new(()))
+                    .map(|_| ())
+                    .map_err(|s| s.code())
+            })
+            .await;
+        assert_eq!(code, Err(tonic::Code::DeadlineExceeded));
+    }
+
+    #[test]
+    fn injects_the_current_span_over_the_served_trace_context() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let context = RequestContext::default().with_header(
+            HeaderName::from_static(TRACEPARENT),
+            HeaderValue::from_static(SERVED_TRACEPARENT),
+        );
+        let traceparent = |request: &Request<()>| {
+            let value = request.metadata().get(TRACEPARENT).unwrap();
+            value.to_str().unwrap().to_owned()
+        };
+
+        let span = tracing::info_span!("call");
+        let trace_id = span.context().span().span_context().trace_id();
+        let request = span.in_scope(|| context.sync_scope(|| propagate(Request::new(())).unwrap()));
+        assert!(traceparent(&request).contains(&format!("{trace_id:032x}")));
+        assert_ne!(traceparent(&request), SERVED_TRACEPARENT);
+
+        // without a span the served call's trace context goes on as it came
+        let request = context.sync_scope(|| propagate(Request::new(())).unwrap());
+        assert_eq!(traceparent(&request), SERVED_TRACEPARENT);
+    }
+}
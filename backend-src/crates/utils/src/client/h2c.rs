@@ -1,60 +1,1079 @@
 use std::{
+    collections::HashMap,
+    net::SocketAddr,
     pin::Pin,
-    task::{Context, Poll},
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll, ready},
+    time::Duration,
 };
 
-use hyper::body::Incoming;
-use hyper::client::conn::http2;
+use hyper::body::{Bytes, Frame, Incoming, SizeHint};
+use hyper::client::conn::http2::{self, SendRequest};
 use hyper_util::{
     client::legacy::{Client, connect::HttpConnector},
-    rt::TokioExecutor,
+    rt::{TokioExecutor, TokioIo, TokioTimer},
 };
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Instant, Sleep};
 use tonic::body::Body;
 use tower::Service;
 
+use super::balance::{DialAddr, Forget};
+use super::unix;
+use crate::Error;
+use crate::deadline;
+use crate::tls::{self, ClientTls};
+
+const DEFAULT_MAX_CONNECTIONS_PER_HOST: usize = 4;
+const DEFAULT_MAX_STREAMS_PER_CONNECTION: usize = 100;
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a new connection gets to HTTP/2.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum H2cMode {
+    /// Send an HTTP/1.1 `Upgrade: h2c` request first and switch protocols.
+    #[default]
+    Upgrade,
+    /// Start with the HTTP/2 connection preface, like tonic and Envoy do.
+    PriorKnowledge,
+}
+
+impl FromStr for H2cMode {
+    type Err = Error;
+
+    /// Parses `upgrade` or `prior-knowledge`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "upgrade" => Ok(H2cMode::Upgrade),
+            "prior-knowledge" => Ok(H2cMode::PriorKnowledge),
+            other => Err(Error::UnknownMode(other.to_string())),
+        }
+    }
+}
+
+/// gRPC client transport speaking h2c, reached through an HTTP/1.1 `Upgrade`
+/// unless the channel is set to [`H2cMode::PriorKnowledge`].
+///
+/// Upgraded connections are kept per authority and shared by every clone of
+/// the channel. Calls are multiplexed over the least busy connection, a new one
+/// is only opened once all of them carry `max_streams_per_connection` calls,
+/// and never more than `max_connections_per_host` are opened. Connections
+/// closed by the server, e.g. after a GOAWAY, are dropped and replaced on the
+/// next call. Failures are returned as [`Error`], which converts into a tonic
+/// `Status`.
+///
+/// Origins from [`parse_origin`](super::parse_origin) with a `unix:` address
+/// connect to that Unix socket and always speak h2c with prior knowledge.
+///
+/// With [`with_max_in_flight`](Self::with_max_in_flight), `poll_ready` waits
+/// until the channel has room for another call.
+///
+/// Configuring a clone leaves the original as it was: the clone gets its own
+/// pool with the changed settings and opens its own connections.
 pub struct H2cChannel {
-    pub client: Client<HttpConnector, Body>,
+    client: Client<HttpConnector, Body>,
+    pool: Arc<Pool>,
+    permit: Option<OwnedSemaphorePermit>,
+    acquiring: Option<Pin<Box<dyn Future<Output = OwnedSemaphorePermit> + Send + Sync>>>,
+}
+
+impl Clone for H2cChannel {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            pool: self.pool.clone(),
+            // a permit belongs to the call it was acquired for
+            permit: None,
+            acquiring: None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pool {
+    mode: H2cMode,
+    tls: Option<ClientTls>,
+    max_connections_per_host: usize,
+    max_streams_per_connection: usize,
+    in_flight: Option<Arc<Semaphore>>,
+    connect_timeout: Duration,
+    timeout: Option<Duration>,
+    keepalive: Option<(Duration, Duration)>,
+    idle_timeout: Option<Duration>,
+    hosts: Mutex<HashMap<String, Arc<Host>>>,
+}
+
+/// The connections to one authority.
+#[derive(Default)]
+struct Host {
+    conns: Mutex<Conns>,
+    /// Woken when a dial ends, for the calls waiting on it.
+    dialed: Notify,
+}
+
+#[derive(Default)]
+struct Conns {
+    open: Vec<Connection>,
+    /// Connections being dialed, which count against the host's limit.
+    dialing: usize,
+}
+
+/// Holds a dialing slot of a host, giving it back when the dial ends or is
+/// cancelled.
+struct Dialing<'a>(&'a Host);
+
+impl Drop for Dialing<'_> {
+    fn drop(&mut self) {
+        self.0.conns.lock().unwrap().dialing -= 1;
+        self.0.dialed.notify_waiters();
+    }
+}
+
+/// A pool with the same settings and no connections yet, for a clone that
+/// is configured differently.
+impl Clone for Pool {
+    fn clone(&self) -> Self {
+        Self {
+            mode: self.mode,
+            tls: self.tls.clone(),
+            max_connections_per_host: self.max_connections_per_host,
+            max_streams_per_connection: self.max_streams_per_connection,
+            in_flight: self.in_flight.clone(),
+            connect_timeout: self.connect_timeout,
+            timeout: self.timeout,
+            keepalive: self.keepalive,
+            idle_timeout: self.idle_timeout,
+            hosts: Mutex::default(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Connection {
+    send: SendRequest<Body>,
+    /// Calls currently waiting on a response over this connection.
+    in_flight: Arc<AtomicUsize>,
+    /// When the last call on this connection got its response.
+    last_used: Arc<Mutex<Instant>>,
+}
+
+/// Counts a call against its connection until its [`ResponseBody`] ends or
+/// is dropped, so streaming calls keep their stream for as long as they run.
+struct InFlight(Connection);
+
+impl InFlight {
+    fn start(conn: &Connection) -> Self {
+        conn.in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(conn.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        *self.0.last_used.lock().unwrap() = Instant::now();
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl H2cChannel {
+    pub fn new(client: Client<HttpConnector, Body>) -> Self {
+        Self {
+            client,
+            pool: Arc::new(Pool {
+                max_connections_per_host: DEFAULT_MAX_CONNECTIONS_PER_HOST,
+                max_streams_per_connection: DEFAULT_MAX_STREAMS_PER_CONNECTION,
+                connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+                ..Default::default()
+            }),
+            permit: None,
+            acquiring: None,
+        }
+    }
+
+    /// Upper bound on upgraded connections kept open to a single authority.
+    pub fn with_max_connections_per_host(mut self, max: usize) -> Self {
+        self.pool_mut().max_connections_per_host = max.max(1);
+        self
+    }
+
+    /// Concurrent calls on one connection before another one is opened.
+    pub fn with_max_streams_per_connection(mut self, max: usize) -> Self {
+        self.pool_mut().max_streams_per_connection = max.max(1);
+        self
+    }
+
+    /// Calls in flight across all hosts before `poll_ready` holds further
+    /// ones back. A call counts until its [`ResponseBody`] ends or is
+    /// dropped.
+    pub fn with_max_in_flight(mut self, max: usize) -> Self {
+        self.pool_mut().in_flight = Some(Arc::new(Semaphore::new(max.max(1))));
+        self
+    }
+
+    /// Time a new connection gets to be established, including the TLS
+    /// handshake or h2c upgrade; 10 seconds by default.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_mut().connect_timeout = timeout;
+        self
+    }
+
+    /// Deadline for calls without a `grpc-timeout` header, sent to the
+    /// server as one. Calls that get no response headers in time fail with
+    /// [`Error::DeadlineExceeded`], as do calls exceeding their own header;
+    /// a [`ResponseBody`] still streaming at the deadline fails with
+    /// `DEADLINE_EXCEEDED`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_mut().timeout = Some(timeout);
+        self
+    }
+
+    /// Pings the server every `interval`, also while no call is running, and
+    /// drops connections whose ping goes unanswered for `timeout`.
+    pub fn with_keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.pool_mut().keepalive = Some((interval, timeout));
+        self
+    }
+
+    /// Closes pooled connections that had no call for `timeout`.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_mut().idle_timeout = Some(timeout);
+        self
+    }
+
+    /// How new connections get to HTTP/2.
+    pub fn with_mode(mut self, mode: H2cMode) -> Self {
+        self.pool_mut().mode = mode;
+        self
+    }
+
+    /// Speaks HTTP/2 over TLS, negotiated with ALPN, instead of h2c. The
+    /// mode is ignored and `https` URIs default to port 443.
+    pub fn with_tls(mut self, tls: ClientTls) -> Self {
+        self.pool_mut().tls = Some(tls);
+        self
+    }
+
+    fn pool_mut(&mut self) -> &mut Pool {
+        Arc::make_mut(&mut self.pool)
+    }
+}
+
+impl Forget for H2cChannel {
+    /// Drops the pooled connections to `authority`, or dialed at it as a
+    /// [`DialAddr`], closing them once their calls are done.
+    fn forget(&self, authority: &str) {
+        let suffixes = [format!("://{authority}"), format!(" via {authority}")];
+        let mut hosts = self.pool.hosts.lock().unwrap();
+        hosts.retain(|key, _| !suffixes.iter().any(|suffix| key.ends_with(suffix)));
+    }
+}
+
+impl Pool {
+    fn host(&self, key: &str) -> Arc<Host> {
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts.entry(key.to_string()).or_default().clone()
+    }
+
+    /// Picks the connection for the next call, opening one if every
+    /// connection is busy and the host still has room. Calls finding no
+    /// connection wait for the one being dialed rather than dialing another.
+    async fn checkout(
+        &self,
+        client: &Client<HttpConnector, Body>,
+        key: &str,
+        origin: &http::Uri,
+        addr: Option<SocketAddr>,
+    ) -> Result<(Connection, InFlight), Error> {
+        self.reap_idle();
+        let host = self.host(key);
+        loop {
+            let dialed = host.dialed.notified();
+            let mut dialed = std::pin::pin!(dialed);
+            {
+                let mut conns = host.conns.lock().unwrap();
+                conns
+                    .open
+                    .retain(|c| !c.send.is_closed() && !self.is_idle(c));
+                let full = conns.open.len() + conns.dialing >= self.max_connections_per_host;
+                let least_busy = conns
+                    .open
+                    .iter()
+                    .min_by_key(|c| c.in_flight.load(Ordering::Relaxed));
+                match least_busy {
+                    Some(conn)
+                        if conn.in_flight.load(Ordering::Relaxed)
+                            < self.max_streams_per_connection
+                            || full =>
+                    {
+                        // counted while still holding the lock so the next caller sees it
+                        return Ok((conn.clone(), InFlight::start(conn)));
+                    }
+                    _ if conns.dialing > 0 => {
+                        // registered before unlocking so the dial ending can't slip past
+                        dialed.as_mut().enable();
+                    }
+                    _ => {
+                        conns.dialing += 1;
+                        break;
+                    }
+                }
+            }
+            dialed.await;
+        }
+
+        // dialed without holding the host, so calls on its open connections
+        // go ahead meanwhile
+        let _dialing = Dialing(&host);
+        let send = tokio::time::timeout(self.connect_timeout, self.dial(client, origin, addr))
+            .await
+            .map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timed out")
+            })??;
+        let conn = Connection {
+            send,
+            in_flight: Arc::default(),
+            last_used: Arc::new(Mutex::new(Instant::now())),
+        };
+        let in_flight = InFlight::start(&conn);
+        host.conns.lock().unwrap().open.push(conn.clone());
+        Ok((conn, in_flight))
+    }
+
+    /// Opens a new connection to `origin`, reached at `addr` if given.
+    async fn dial(
+        &self,
+        client: &Client<HttpConnector, Body>,
+        origin: &http::Uri,
+        addr: Option<SocketAddr>,
+    ) -> Result<SendRequest<Body>, Error> {
+        let builder = self.http2();
+        match (unix::socket_path(origin), &self.tls, self.mode) {
+            (Some(path), _, _) => handshake(builder, UnixStream::connect(path).await?).await,
+            (None, Some(tls), _) => connect_tls(builder, tls, origin, addr).await,
+            (None, None, H2cMode::Upgrade) => connect(builder, client, origin, addr).await,
+            (None, None, H2cMode::PriorKnowledge) => {
+                connect_prior_knowledge(builder, origin, addr).await
+            }
+        }
+    }
+
+    fn is_idle(&self, conn: &Connection) -> bool {
+        self.idle_timeout.is_some_and(|timeout| {
+            conn.in_flight.load(Ordering::Relaxed) == 0
+                && conn.last_used.lock().unwrap().elapsed() >= timeout
+        })
+    }
+
+    /// Drops idle connections to every host.
+    fn reap_idle(&self) {
+        if self.idle_timeout.is_none() {
+            return;
+        }
+        let hosts = self.hosts.lock().unwrap();
+        for host in hosts.values() {
+            let mut conns = host.conns.lock().unwrap();
+            conns.open.retain(|c| !self.is_idle(c));
+        }
+    }
+
+    fn http2(&self) -> http2::Builder<TokioExecutor> {
+        let mut builder = http2::Builder::new(TokioExecutor::new());
+        if let Some((interval, timeout)) = self.keepalive {
+            builder
+                .timer(TokioTimer::new())
+                .keep_alive_interval(interval)
+                .keep_alive_timeout(timeout)
+                .keep_alive_while_idle(true);
+        }
+        builder
+    }
+
+    fn evict(&self, key: &str, conn: &Connection) {
+        let host = self.host(key);
+        let mut conns = host.conns.lock().unwrap();
+        conns
+            .open
+            .retain(|c| !Arc::ptr_eq(&c.in_flight, &conn.in_flight));
+    }
+}
+
+/// Upgrades a fresh HTTP/1.1 connection to h2c and spawns its driver.
+async fn connect(
+    builder: http2::Builder<TokioExecutor>,
+    client: &Client<HttpConnector, Body>,
+    origin: &http::Uri,
+    addr: Option<SocketAddr>,
+) -> Result<SendRequest<Body>, Error> {
+    let mut h2c_req = hyper::Request::builder().header(http::header::UPGRADE, "h2c");
+    h2c_req = match (addr, origin.authority()) {
+        // sent to the endpoint, naming the origin's host
+        (Some(addr), Some(authority)) => {
+            let mut parts = origin.clone().into_parts();
+            parts.authority =
+                Some(http::uri::Authority::try_from(addr.to_string()).map_err(http::Error::from)?);
+            h2c_req
+                .uri(http::Uri::from_parts(parts).map_err(http::Error::from)?)
+                .header(http::header::HOST, authority.as_str())
+        }
+        _ => h2c_req.uri(origin),
+    };
+    let h2c_req = h2c_req.body(Body::default())?;
+
+    let res = client.request(h2c_req).await?;
+
+    if res.status() != http::StatusCode::SWITCHING_PROTOCOLS {
+        return Err(Error::UpgradeRefused(res.status()));
+    }
+
+    let upgraded_io = hyper::upgrade::on(res).await?;
+
+    let (h2_client, conn) = builder.handshake(upgraded_io).await?;
+    tokio::spawn(conn);
+
+    Ok(h2_client)
+}
+
+/// The host to connect to and verify, which the call's URI must name.
+fn host(origin: &http::Uri) -> Result<&str, Error> {
+    origin
+        .host()
+        .ok_or_else(|| Error::MissingHost(origin.clone()))
+}
+
+/// Connects to `addr`, or else the host and port `origin` names.
+async fn connect_tcp(origin: &http::Uri, addr: Option<SocketAddr>) -> Result<TcpStream, Error> {
+    if let Some(addr) = addr {
+        let io = TcpStream::connect(addr).await?;
+        io.set_nodelay(true)?;
+        return Ok(io);
+    }
+    let host = host(origin)?;
+    let default_port = if origin.scheme() == Some(&http::uri::Scheme::HTTPS) {
+        443
+    } else {
+        80
+    };
+    let port = origin.port_u16().unwrap_or(default_port);
+    let io = TcpStream::connect((host.trim_matches(['[', ']']), port)).await?;
+    io.set_nodelay(true)?;
+    Ok(io)
+}
+
+/// Starts HTTP/2 on an established connection and spawns its driver.
+async fn handshake<I>(
+    builder: http2::Builder<TokioExecutor>,
+    io: I,
+) -> Result<SendRequest<Body>, Error>
+where
+    I: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let (h2_client, conn) = builder.handshake(TokioIo::new(io)).await?;
+    tokio::spawn(conn);
+
+    Ok(h2_client)
+}
+
+/// Opens a TCP connection and starts HTTP/2 on it right away.
+async fn connect_prior_knowledge(
+    builder: http2::Builder<TokioExecutor>,
+    origin: &http::Uri,
+    addr: Option<SocketAddr>,
+) -> Result<SendRequest<Body>, Error> {
+    handshake(builder, connect_tcp(origin, addr).await?).await
+}
+
+/// Opens a TLS connection and starts HTTP/2 once the server agreed to it.
+async fn connect_tls(
+    builder: http2::Builder<TokioExecutor>,
+    tls: &ClientTls,
+    origin: &http::Uri,
+    addr: Option<SocketAddr>,
+) -> Result<SendRequest<Body>, Error> {
+    let name = tls::server_name(host(origin)?)?;
+    let io = tls
+        .connector()?
+        .connect(name, connect_tcp(origin, addr).await?)
+        .await?;
+    if io.get_ref().1.alpn_protocol() != Some(tls::ALPN_H2) {
+        return Err(Error::Tls(rustls::Error::NoApplicationProtocol));
+    }
+    handshake(builder, io).await
 }
 
 impl Service<http::Request<Body>> for H2cChannel {
-    type Response = http::Response<Incoming>;
-    type Error = hyper::Error;
-    type Future =
-        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+    type Response = http::Response<ResponseBody>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let Some(limit) = &self.pool.in_flight else {
+            return Poll::Ready(Ok(()));
+        };
+        if self.permit.is_none() {
+            let acquiring = self.acquiring.get_or_insert_with(|| {
+                let limit = limit.clone();
+                Box::pin(async move {
+                    limit
+                        .acquire_owned()
+                        .await
+                        .expect("call limit is never closed")
+                })
+            });
+            self.permit = Some(ready!(acquiring.as_mut().poll(cx)));
+            self.acquiring = None;
+        }
         Poll::Ready(Ok(()))
     }
 
     fn call(&mut self, request: http::Request<Body>) -> Self::Future {
         let client = self.client.clone();
+        let pool = self.pool.clone();
+        let permit = self.permit.take();
 
         Box::pin(async move {
-            let origin = request.uri();
+            let permit = match (permit, &pool.in_flight) {
+                (None, Some(limit)) => Some(
+                    limit
+                        .clone()
+                        .acquire_owned()
+                        .await
+                        .expect("call limit is never closed"),
+                ),
+                (permit, _) => permit,
+            };
+            let origin = request.uri().clone();
+            let addr = request.extensions().get::<DialAddr>().map(|dial| dial.0);
+            let mut key = format!(
+                "{}://{}",
+                origin.scheme_str().unwrap_or("http"),
+                origin.authority().map_or("", |a| a.as_str())
+            );
+            if let Some(addr) = addr {
+                key.push_str(&format!(" via {addr}"));
+            }
 
-            let h2c_req = hyper::Request::builder()
-                .uri(origin)
-                .header(http::header::UPGRADE, "h2c")
-                .body(Body::default())
-                .unwrap();
+            let mut request = request;
+            if unix::socket_path(&origin).is_some() {
+                // the socket path is not an authority the server knows about
+                let path = request.uri().path_and_query().map_or("/", |p| p.as_str());
+                *request.uri_mut() = http::Uri::builder()
+                    .scheme("http")
+                    .authority("localhost")
+                    .path_and_query(path)
+                    .build()?;
+            }
 
-            let res = client.request(h2c_req).await.unwrap();
+            let timeout = match request.headers().get(deadline::GRPC_TIMEOUT) {
+                Some(value) => deadline::parse(value),
+                None => {
+                    if let Some(timeout) = pool.timeout {
+                        request
+                            .headers_mut()
+                            .insert(deadline::GRPC_TIMEOUT, deadline::encode(timeout));
+                    }
+                    pool.timeout
+                }
+            };
 
-            if res.status() != http::StatusCode::SWITCHING_PROTOCOLS {
-                panic!("Our server didn't upgrade: {}", res.status());
-            }
+            let call = async {
+                let mut retried = false;
+                loop {
+                    let (mut conn, in_flight) = pool.checkout(&client, &key, &origin, addr).await?;
 
-            let upgraded_io = hyper::upgrade::on(res).await.unwrap();
+                    let sent = match conn.send.ready().await {
+                        Ok(()) => conn.send.try_send_request(request).await,
+                        // the connection died while idle, the request is still ours
+                        Err(_) if !retried => {
+                            pool.evict(&key, &conn);
+                            retried = true;
+                            continue;
+                        }
+                        Err(error) => return Err(Error::from(error)),
+                    };
 
-            // In an ideal world you would somehow cache this connection
-            let (mut h2_client, conn) =
-                http2::Builder::new(TokioExecutor::new())
-                    .handshake(upgraded_io)
+                    match sent {
+                        Ok(res) => return Ok((res, in_flight)),
+                        Err(mut e) => {
+                            // the connection went away before the request was sent,
+                            // e.g. after a GOAWAY, so it is safe to retry elsewhere
+                            pool.evict(&key, &conn);
+                            match e.take_message() {
+                                Some(req) if !retried => {
+                                    request = req;
+                                    retried = true;
+                                }
+                                _ => return Err(e.into_error().into()),
+                            }
+                        }
+                    }
+                }
+            };
+            let deadline = timeout.map(|timeout| Instant::now() + timeout);
+            let (res, in_flight) = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, call)
                     .await
-                    .unwrap();
-            tokio::spawn(conn);
-
-            h2_client.send_request(request).await
+                    .map_err(|_| Error::DeadlineExceeded)??,
+                None => call.await?,
+            };
+            Ok(res.map(|inner| ResponseBody {
+                inner: Some(inner),
+                deadline: deadline.map(|deadline| Box::pin(tokio::time::sleep_until(deadline))),
+                _in_flight: in_flight,
+                _permit: permit,
+            }))
         })
     }
 }
+
+/// Body of a response from an [`H2cChannel`]. It holds the call's stream on
+/// its connection, and its slot under
+/// [`with_max_in_flight`](H2cChannel::with_max_in_flight), until it ends or is
+/// dropped, so streaming calls count for as long as they run, and fails with
+/// `DEADLINE_EXCEEDED` once the call's deadline passes before it ends.
+pub struct ResponseBody {
+    /// `None` once the deadline passed.
+    inner: Option<Incoming>,
+    deadline: Option<Pin<Box<Sleep>>>,
+    _in_flight: InFlight,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl hyper::body::Body for ResponseBody {
+    type Data = Bytes;
+    type Error = tonic::Status;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, tonic::Status>>> {
+        let this = self.get_mut();
+        if let Some(deadline) = &mut this.deadline
+            && deadline.as_mut().poll(cx).is_ready()
+        {
+            // dropping the stream resets it, so the server stops as well
+            this.deadline = None;
+            this.inner = None;
+            return Poll::Ready(Some(Err(tonic::Status::deadline_exceeded(
+                "call exceeded its deadline",
+            ))));
+        }
+        let Some(inner) = &mut this.inner else {
+            return Poll::Ready(None);
+        };
+        Pin::new(inner)
+            .poll_frame(cx)
+            .map_err(|e| tonic::Status::from_error(Box::new(e)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.as_ref().is_none_or(Incoming::is_end_stream)
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner
+            .as_ref()
+            .map_or_else(|| SizeHint::with_exact(0), Incoming::size_hint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, StreamBody};
+    use hyper::server::conn::{http1, http2};
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::AbortHandle;
+    use tonic_health::pb::HealthCheckRequest;
+    use tonic_health::pb::health_check_response::ServingStatus;
+    use tonic_health::pb::health_client::HealthClient;
+    use tower::ServiceExt;
+
+    /// h2c server counting the connections it accepted. Each upgraded
+    /// connection can be torn down through its abort handle, and once
+    /// stalled, new connections are accepted but never answered.
+    #[derive(Clone, Default)]
+    struct TestServer {
+        accepted: Arc<AtomicUsize>,
+        upgraded: Arc<Mutex<Vec<AbortHandle>>>,
+        stalled: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl TestServer {
+        async fn start(&self, delay: Duration) -> SocketAddr {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = self.clone();
+            tokio::spawn(async move {
+                let mut held = Vec::new();
+                loop {
+                    let (io, _) = listener.accept().await.unwrap();
+                    server.accepted.fetch_add(1, Ordering::SeqCst);
+                    if server.stalled.load(Ordering::SeqCst) {
+                        held.push(io);
+                        continue;
+                    }
+                    let upgraded = server.upgraded.clone();
+                    let upgrade = service_fn(move |mut req: http::Request<Incoming>| {
+                        let upgraded = upgraded.clone();
+                        async move {
+                            let task = tokio::spawn(async move {
+                                let io = hyper::upgrade::on(&mut req).await.unwrap();
+                                let svc = service_fn(move |_| async move {
+                                    tokio::time::sleep(delay).await;
+                                    Ok::<_, Infallible>(http::Response::new(Body::empty()))
+                                });
+                                let _ = http2::Builder::new(TokioExecutor::new())
+                                    .serve_connection(io, svc)
+                                    .await;
+                            });
+                            upgraded.lock().unwrap().push(task.abort_handle());
+                            http::Response::builder()
+                                .status(http::StatusCode::SWITCHING_PROTOCOLS)
+                                .header(http::header::UPGRADE, "h2c")
+                                .body(Body::empty())
+                        }
+                    });
+                    tokio::spawn(
+                        http1::Builder::new()
+                            .serve_connection(TokioIo::new(io), upgrade)
+                            .with_upgrades(),
+                    );
+                }
+            });
+            addr
+        }
+
+        fn accepted(&self) -> usize {
+            self.accepted.load(Ordering::SeqCst)
+        }
+
+        fn stall(&self) {
+            self.stalled.store(true, Ordering::SeqCst);
+        }
+
+        fn drop_connections(&self) {
+            for task in self.upgraded.lock().unwrap().drain(..) {
+                task.abort();
+            }
+        }
+    }
+
+    fn channel() -> H2cChannel {
+        H2cChannel::new(Client::builder(TokioExecutor::new()).build_http())
+    }
+
+    async fn try_call(channel: &H2cChannel, addr: SocketAddr) -> Result<http::StatusCode, Error> {
+        let req = http::Request::builder()
+            .uri(format!("http://{addr}/f2.test.v1.Test/Call"))
+            .body(Body::empty())
+            .unwrap();
+        Ok(channel.clone().oneshot(req).await?.status())
+    }
+
+    async fn call(channel: &H2cChannel, addr: SocketAddr) -> http::StatusCode {
+        try_call(channel, addr).await.unwrap()
+    }
+
+    /// Answers every connection with `response` and hangs up.
+    async fn raw_server(response: &'static [u8]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut io, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = io.read(&mut buf).await;
+                let _ = io.write_all(response).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn concurrent_calls_share_one_connection() {
+        let server = TestServer::default();
+        let addr = server.start(Duration::from_millis(20)).await;
+        let channel = channel();
+
+        let calls: Vec<_> = (0..20)
+            .map(|_| {
+                let channel = channel.clone();
+                tokio::spawn(async move { call(&channel, addr).await })
+            })
+            .collect();
+        for c in calls {
+            assert_eq!(c.await.unwrap(), http::StatusCode::OK);
+        }
+        assert_eq!(call(&channel, addr).await, http::StatusCode::OK);
+        assert_eq!(server.accepted(), 1);
+    }
+
+    #[tokio::test]
+    async fn caps_connections_per_host() {
+        let server = TestServer::default();
+        let addr = server.start(Duration::from_millis(50)).await;
+        let channel = channel()
+            .with_max_connections_per_host(2)
+            .with_max_streams_per_connection(1);
+
+        let calls: Vec<_> = (0..6)
+            .map(|_| {
+                let channel = channel.clone();
+                tokio::spawn(async move { call(&channel, addr).await })
+            })
+            .collect();
+        for c in calls {
+            assert_eq!(c.await.unwrap(), http::StatusCode::OK);
+        }
+        assert_eq!(server.accepted(), 2);
+    }
+
+    #[tokio::test]
+    async fn holds_calls_back_over_the_limit() {
+        let channel = channel().with_max_in_flight(1);
+        let mut first = channel.clone();
+        first.ready().await.unwrap();
+
+        let mut second = channel.clone();
+        let waiting = tokio::time::timeout(Duration::from_millis(50), second.ready()).await;
+        assert!(waiting.is_err());
+
+        drop(first);
+        second.ready().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn configures_clones_apart() {
+        let server = TestServer::default();
+        let addr = server.start(Duration::ZERO).await;
+        let channel = channel();
+        let shared = channel.clone();
+        let configured = channel.clone().with_max_connections_per_host(1);
+
+        assert_eq!(call(&channel, addr).await, http::StatusCode::OK);
+        assert_eq!(call(&shared, addr).await, http::StatusCode::OK);
+        assert_eq!(server.accepted(), 1);
+        assert_eq!(call(&configured, addr).await, http::StatusCode::OK);
+        assert_eq!(server.accepted(), 2);
+    }
+
+    #[tokio::test]
+    async fn counts_calls_until_their_body_is_done() {
+        let server = TestServer::default();
+        let addr = server.start(Duration::ZERO).await;
+        let channel = channel().with_max_in_flight(1);
+
+        let req = http::Request::builder()
+            .uri(format!("http://{addr}/f2.test.v1.Test/Call"))
+            .body(Body::empty())
+            .unwrap();
+        let res = channel.clone().oneshot(req).await.unwrap();
+        let mut second = channel.clone();
+        let waiting = tokio::time::timeout(Duration::from_millis(50), second.ready()).await;
+        assert!(waiting.is_err());
+
+        drop(res);
+        second.ready().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_connections_busy_until_their_body_is_done() {
+        let server = TestServer::default();
+        let addr = server.start(Duration::ZERO).await;
+        let channel = channel().with_max_streams_per_connection(1);
+
+        let req = http::Request::builder()
+            .uri(format!("http://{addr}/f2.test.v1.Test/Call"))
+            .body(Body::empty())
+            .unwrap();
+        let res = channel.clone().oneshot(req).await.unwrap();
+        assert_eq!(call(&channel, addr).await, http::StatusCode::OK);
+        assert_eq!(server.accepted(), 2);
+
+        drop(res);
+        assert_eq!(call(&channel, addr).await, http::StatusCode::OK);
+        assert_eq!(server.accepted(), 2);
+    }
+
+    #[tokio::test]
+    async fn dials_without_holding_up_open_connections() {
+        let server = TestServer::default();
+        let addr = server.start(Duration::ZERO).await;
+        let channel = channel()
+            .with_max_connections_per_host(2)
+            .with_max_streams_per_connection(1);
+
+        let req = http::Request::builder()
+            .uri(format!("http://{addr}/f2.test.v1.Test/Call"))
+            .body(Body::empty())
+            .unwrap();
+        let res = channel.clone().oneshot(req).await.unwrap();
+        server.stall();
+        let dialing = tokio::spawn({
+            let channel = channel.clone();
+            async move { try_call(&channel, addr).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(server.accepted(), 2);
+
+        drop(res);
+        let open = tokio::time::timeout(Duration::from_millis(500), call(&channel, addr)).await;
+        assert_eq!(open.unwrap(), http::StatusCode::OK);
+        dialing.abort();
+    }
+
+    #[tokio::test]
+    async fn times_out_connecting() {
+        let server = TestServer::default();
+        let addr = server.start(Duration::ZERO).await;
+        server.stall();
+        let channel = channel().with_connect_timeout(Duration::from_millis(50));
+
+        let err = try_call(&channel, addr).await.unwrap_err();
+        assert!(
+            matches!(&err, Error::Io(e) if e.kind() == std::io::ErrorKind::TimedOut),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn times_out_slow_calls() {
+        let server = TestServer::default();
+        let addr = server.start(Duration::from_secs(5)).await;
+        let channel = channel().with_timeout(Duration::from_millis(50));
+
+        let err = try_call(&channel, addr).await.unwrap_err();
+        assert!(matches!(err, Error::DeadlineExceeded), "{err}");
+    }
+
+    #[tokio::test]
+    async fn times_out_slow_response_bodies() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (io, _) = listener.accept().await.unwrap();
+            let svc = service_fn(|_| async {
+                let frames = tokio_stream::pending::<Result<Frame<Bytes>, Infallible>>();
+                Ok::<_, Infallible>(http::Response::new(StreamBody::new(frames)))
+            });
+            let _ = http2::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(io), svc)
+                .await;
+        });
+        let channel = channel()
+            .with_mode(H2cMode::PriorKnowledge)
+            .with_timeout(Duration::from_millis(50));
+
+        let req = http::Request::builder()
+            .uri(format!("http://{addr}/f2.test.v1.Test/Stream"))
+            .body(Body::empty())
+            .unwrap();
+        let res = channel.oneshot(req).await.unwrap();
+        let status = res.into_body().collect().await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::DeadlineExceeded);
+    }
+
+    #[tokio::test]
+    async fn reaps_idle_connections() {
+        let server = TestServer::default();
+        let addr = server.start(Duration::ZERO).await;
+        let channel = channel().with_idle_timeout(Duration::from_millis(50));
+
+        assert_eq!(call(&channel, addr).await, http::StatusCode::OK);
+        assert_eq!(call(&channel, addr).await, http::StatusCode::OK);
+        assert_eq!(server.accepted(), 1);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(call(&channel, addr).await, http::StatusCode::OK);
+        assert_eq!(server.accepted(), 2);
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_connection_is_closed() {
+        let server = TestServer::default();
+        let addr = server.start(Duration::ZERO).await;
+        let channel = channel();
+
+        assert_eq!(call(&channel, addr).await, http::StatusCode::OK);
+        server.drop_connections();
+        // give the client driver a moment to notice the closed socket
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(call(&channel, addr).await, http::StatusCode::OK);
+        assert_eq!(server.accepted(), 2);
+    }
+
+    #[tokio::test]
+    async fn non_upgrading_server_is_an_error() {
+        let addr =
+            raw_server(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n").await;
+
+        let err = try_call(&channel(), addr).await.unwrap_err();
+        assert!(matches!(err, Error::UpgradeRefused(http::StatusCode::OK)));
+        assert_eq!(tonic::Status::from(err).code(), tonic::Code::Unavailable);
+    }
+
+    #[tokio::test]
+    async fn truncated_handshake_is_an_error() {
+        let addr = raw_server(
+            b"HTTP/1.1 101 Switching Protocols\r\nconnection: upgrade\r\nupgrade: h2c\r\n\r\n",
+        )
+        .await;
+
+        let err = try_call(&channel(), addr).await.unwrap_err();
+        assert!(matches!(err, Error::Http(_)), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn prior_knowledge_reaches_tonic_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_, health) = tonic_health::server::health_reporter();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(health)
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+
+        let channel = channel().with_mode(H2cMode::PriorKnowledge);
+        let mut client =
+            HealthClient::with_origin(channel, format!("http://{addr}").parse().unwrap());
+        let res = client.check(HealthCheckRequest::default()).await.unwrap();
+        assert_eq!(res.into_inner().status(), ServingStatus::Serving);
+    }
+
+    #[tokio::test]
+    async fn refuses_uris_without_a_host() {
+        let channel = channel().with_mode(H2cMode::PriorKnowledge);
+        let req = http::Request::builder()
+            .uri("/f2.test.v1.Test/Call")
+            .body(Body::empty())
+            .unwrap();
+        let result = channel.oneshot(req).await;
+        assert!(matches!(result, Err(Error::MissingHost(_))));
+    }
+
+    #[test]
+    fn parses_mode() {
+        assert_eq!(
+            "prior-knowledge".parse::<H2cMode>().unwrap(),
+            H2cMode::PriorKnowledge
+        );
+        assert_eq!("upgrade".parse::<H2cMode>().unwrap(), H2cMode::Upgrade);
+        assert!(matches!(
+            "h3".parse::<H2cMode>(),
+            Err(Error::UnknownMode(_))
+        ));
+    }
+}
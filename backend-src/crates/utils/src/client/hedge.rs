@@ -0,0 +1,209 @@
+use std::future::Future;
+use std::pin::{Pin, pin};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use http::{Request, Response};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tonic::Code;
+use tonic::body::Body;
+use tower::{Layer, Service, ServiceExt};
+
+use super::methods::Methods;
+use super::replay::{self, Buffered, Replay};
+use super::retry::RetryBudget;
+use crate::Error;
+
+/// When a method's calls are hedged. Like retries, only give it to
+/// idempotent methods: every attempt may reach the server.
+#[derive(Clone, Debug)]
+pub struct HedgePolicy {
+    max_attempts: u32,
+    delay: Duration,
+    non_fatal: Vec<Code>,
+}
+
+impl HedgePolicy {
+    /// Sends another attempt every `delay` without a response, up to
+    /// `max_attempts` in total.
+    pub fn new(max_attempts: u32, delay: Duration) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            delay,
+            non_fatal: vec![Code::Unavailable],
+        }
+    }
+
+    /// Codes that start the next attempt right away instead of ending the
+    /// call, `UNAVAILABLE` unless set.
+    pub fn with_non_fatal_codes(mut self, codes: impl IntoIterator<Item = Code>) -> Self {
+        self.non_fatal = codes.into_iter().collect();
+        self
+    }
+}
+
+/// Hedges calls per [`HedgePolicy`], for the methods one is configured for.
+/// The first response that is `OK` or carries a fatal code wins and the
+/// other attempts are cancelled. Under a
+/// [`BalancedChannel`](super::balance::BalancedChannel) the attempts
+/// usually go to different endpoints. Calls whose request is larger than a
+/// message may be, or still streaming when they are made, are sent once.
+#[derive(Clone)]
+pub struct HedgeLayer {
+    methods: Arc<Methods<HedgePolicy>>,
+    budget: RetryBudget,
+}
+
+impl HedgeLayer {
+    pub fn new(methods: Methods<HedgePolicy>) -> Self {
+        Self {
+            methods: Arc::new(methods),
+            budget: RetryBudget::default(),
+        }
+    }
+
+    pub fn with_budget(mut self, budget: RetryBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+}
+
+impl<S> Layer<S> for HedgeLayer {
+    type Service = Hedge<S>;
+
+    fn layer(&self, inner: S) -> Hedge<S> {
+        Hedge {
+            inner,
+            methods: self.methods.clone(),
+            budget: self.budget.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Hedge<S> {
+    inner: S,
+    methods: Arc<Methods<HedgePolicy>>,
+    budget: RetryBudget,
+}
+
+impl<S, B> Service<Request<Body>> for Hedge<S>
+where
+    S: Service<Request<Body>, Response = Response<B>, Error = Error> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = Response<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<B>, Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let Some(policy) = self.methods.get(request.uri().path()).cloned() else {
+            return Box::pin(self.inner.call(request));
+        };
+        // the ready service makes the first attempt
+        let fresh = self.inner.clone();
+        let mut first = std::mem::replace(&mut self.inner, fresh);
+        let inner = self.inner.clone();
+        let budget = self.budget.clone();
+
+        Box::pin(async move {
+            let replay = match Replay::buffer(request)? {
+                Buffered::Replay(replay) => replay,
+                Buffered::Once(request) => return first.call(request).await,
+            };
+            // dropping the set cancels the attempts still running
+            let mut attempts = JoinSet::new();
+            let request = replay.request();
+            attempts.spawn(async move { first.call(request).await });
+            let send = move |attempts: &mut JoinSet<_>| {
+                let inner = inner.clone();
+                let request = replay.request();
+                attempts.spawn(async move { inner.oneshot(request).await });
+            };
+            let mut sent = 1;
+            let mut hedge = pin!(tokio::time::sleep(policy.delay));
+
+            loop {
+                let can_hedge = sent < policy.max_attempts && budget.allows();
+                tokio::select! {
+                    Some(joined) = attempts.join_next() => {
+                        let result = joined.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+                        let code = replay::code(&result);
+                        if code == Code::Ok {
+                            budget.succeeded();
+                            return result;
+                        }
+                        if !policy.non_fatal.contains(&code) {
+                            return result;
+                        }
+                        budget.failed();
+                        if sent < policy.max_attempts && budget.allows() {
+                            send(&mut attempts);
+                            sent += 1;
+                            hedge.as_mut().reset(Instant::now() + policy.delay);
+                        } else if attempts.is_empty() {
+                            return result;
+                        }
+                    }
+                    _ = hedge.as_mut(), if can_hedge => {
+                        tracing::debug!("Hedging call after {:?}", policy.delay);
+                        send(&mut attempts);
+                        sent += 1;
+                        hedge.as_mut().reset(Instant::now() + policy.delay);
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::replay::scripted::{Scripted, request};
+
+    fn hedged(inner: &Scripted, delay: Duration) -> Hedge<Scripted> {
+        let policy = HedgePolicy::new(3, delay);
+        HedgeLayer::new(Methods::new().with_default(policy)).layer(inner.clone())
+    }
+
+    async fn call(svc: &Hedge<Scripted>) -> Code {
+        replay::code(&svc.clone().oneshot(request("/f2.test.v1.Test/Get")).await)
+    }
+
+    #[tokio::test]
+    async fn hedges_slow_calls() {
+        let inner = Scripted::new([(Duration::from_secs(5), Code::Ok)]);
+        let svc = hedged(&inner, Duration::from_millis(20));
+
+        let started = Instant::now();
+        assert_eq!(call(&svc).await, Code::Ok);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(inner.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn moves_on_after_non_fatal_codes() {
+        let inner = Scripted::codes([Code::Unavailable, Code::Unavailable]);
+        let svc = hedged(&inner, Duration::from_secs(5));
+
+        assert_eq!(call(&svc).await, Code::Ok);
+        assert_eq!(inner.calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn returns_fatal_codes() {
+        let inner = Scripted::codes([Code::NotFound]);
+        let svc = hedged(&inner, Duration::from_secs(5));
+
+        assert_eq!(call(&svc).await, Code::NotFound);
+        assert_eq!(inner.calls(), 1);
+    }
+}
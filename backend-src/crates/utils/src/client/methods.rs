@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+
+/// Policies picked by gRPC method. A method's own policy wins over its
+/// service's, which wins over the default.
+///
+/// ```
+/// # use f2_utils::client::methods::Methods;
+/// let attempts = Methods::new()
+///     .with_service("f2.users.v1.Users", 3)
+///     .with_method("f2.users.v1.Users/DeleteUser", 1);
+/// assert_eq!(attempts.get("/f2.users.v1.Users/GetUser"), Some(&3));
+/// assert_eq!(attempts.get("/f2.users.v1.Users/DeleteUser"), Some(&1));
+/// assert_eq!(attempts.get("/f2.images.v1.Images/GetImage"), None);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Methods<T> {
+    methods: HashMap<String, T>,
+    services: HashMap<String, T>,
+    default: Option<T>,
+}
+
+impl<T> Default for Methods<T> {
+    fn default() -> Self {
+        Self {
+            methods: HashMap::new(),
+            services: HashMap::new(),
+            default: None,
+        }
+    }
+}
+
+impl<T> Methods<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Policy for methods nothing more specific is configured for.
+    pub fn with_default(mut self, policy: T) -> Self {
+        self.default = Some(policy);
+        self
+    }
+
+    /// Policy for every method of a service, e.g. `f2.users.v1.Users`.
+    pub fn with_service(mut self, service: impl Into<String>, policy: T) -> Self {
+        self.services.insert(service.into(), policy);
+        self
+    }
+
+    /// Policy for one method, e.g. `f2.users.v1.Users/GetUser`.
+    pub fn with_method(mut self, method: impl Into<String>, policy: T) -> Self {
+        self.methods.insert(method.into(), policy);
+        self
+    }
+
+    /// Policy for a request path like `/f2.users.v1.Users/GetUser`.
+    pub fn get(&self, path: &str) -> Option<&T> {
+        let method = path.trim_start_matches('/');
+        let service = method
+            .split_once('/')
+            .map_or(method, |(service, _)| service);
+        self.methods
+            .get(method)
+            .or_else(|| self.services.get(service))
+            .or(self.default.as_ref())
+    }
+}
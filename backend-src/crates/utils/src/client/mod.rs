@@ -1 +1,12 @@
-mod h2c;
\ No newline at end of file
+pub mod balance;
+pub mod breaker;
+pub mod compression;
+pub mod context;
+pub mod h2c;
+pub mod hedge;
+pub mod methods;
+mod replay;
+pub mod retry;
+mod unix;
+
+pub use unix::parse_origin;
@@ -0,0 +1,279 @@
+use std::hash::{BuildHasher, RandomState};
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use http::{HeaderMap, Method, Request, Response, Uri, Version};
+use http_body_util::Full;
+use hyper::body::{Body as _, Bytes, Frame, SizeHint};
+use tonic::Code;
+use tonic::body::Body;
+
+use crate::Error;
+use crate::deadline::{self, Deadline};
+use crate::envelope::MAX_MESSAGE_SIZE;
+
+/// A buffered request that can be sent more than once.
+pub(crate) struct Replay {
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+    extensions: http::Extensions,
+    body: Bytes,
+    deadline: Option<Deadline>,
+}
+
+/// A request as [`Replay::buffer`] left it.
+pub(crate) enum Buffered {
+    /// A request that can be sent again.
+    Replay(Replay),
+    /// A request to send only once, since its body is larger than
+    /// [`MAX_MESSAGE_SIZE`] or still streaming.
+    Once(Request<Body>),
+}
+
+impl Replay {
+    /// Buffers the request body if it is all there already and no larger
+    /// than [`MAX_MESSAGE_SIZE`], as for unary and small client-streaming
+    /// calls. Other requests are handed back with what was read put back in
+    /// front of their body.
+    pub(crate) fn buffer(request: Request<Body>) -> Result<Buffered, Error> {
+        let (parts, mut body) = request.into_parts();
+        if body.size_hint().lower() > MAX_MESSAGE_SIZE as u64 {
+            return Ok(Buffered::Once(Request::from_parts(parts, body)));
+        }
+
+        // frames not ready right away belong to a call still streaming
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut read = BytesMut::new();
+        loop {
+            match Pin::new(&mut body).poll_frame(&mut cx) {
+                Poll::Ready(None) => break,
+                Poll::Ready(Some(Ok(frame))) => {
+                    if let Ok(data) = frame.into_data() {
+                        read.extend_from_slice(&data);
+                    }
+                    if read.len() > MAX_MESSAGE_SIZE {
+                        let body = Body::new(Resumed::new(read.freeze(), body));
+                        return Ok(Buffered::Once(Request::from_parts(parts, body)));
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => return Err(Error::RequestBody(Box::new(e))),
+                Poll::Pending => {
+                    let body = Body::new(Resumed::new(read.freeze(), body));
+                    return Ok(Buffered::Once(Request::from_parts(parts, body)));
+                }
+            }
+        }
+        let body = read.freeze();
+
+        let deadline = parts
+            .headers
+            .get(deadline::GRPC_TIMEOUT)
+            .and_then(deadline::parse)
+            .map(Deadline::after);
+        Ok(Buffered::Replay(Self {
+            method: parts.method,
+            uri: parts.uri,
+            version: parts.version,
+            headers: parts.headers,
+            extensions: parts.extensions,
+            body,
+            deadline,
+        }))
+    }
+
+    /// Time left for further attempts, if the call has a deadline.
+    pub(crate) fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.remaining())
+    }
+
+    /// A fresh copy of the request, its `grpc-timeout` cut to the time left.
+    pub(crate) fn request(&self) -> Request<Body> {
+        let mut request = Request::new(Body::new(Full::new(self.body.clone())));
+        *request.method_mut() = self.method.clone();
+        *request.uri_mut() = self.uri.clone();
+        *request.version_mut() = self.version;
+        *request.headers_mut() = self.headers.clone();
+        *request.extensions_mut() = self.extensions.clone();
+        if let Some(remaining) = self.remaining() {
+            request
+                .headers_mut()
+                .insert(deadline::GRPC_TIMEOUT, deadline::encode(remaining));
+        }
+        request
+    }
+}
+
+/// A body some of which was read already, sending that first.
+struct Resumed {
+    read: Option<Bytes>,
+    rest: Body,
+}
+
+impl Resumed {
+    fn new(read: Bytes, rest: Body) -> Self {
+        Self {
+            read: (!read.is_empty()).then_some(read),
+            rest,
+        }
+    }
+}
+
+impl hyper::body::Body for Resumed {
+    type Data = Bytes;
+    type Error = tonic::Status;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, tonic::Status>>> {
+        let this = self.get_mut();
+        if let Some(read) = this.read.take() {
+            return Poll::Ready(Some(Ok(Frame::data(read))));
+        }
+        Pin::new(&mut this.rest).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.read.is_none() && self.rest.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let read = self.read.as_ref().map_or(0, |read| read.len() as u64);
+        let rest = self.rest.size_hint();
+        let mut hint = SizeHint::new();
+        hint.set_lower(rest.lower() + read);
+        if let Some(upper) = rest.upper() {
+            hint.set_upper(upper + read);
+        }
+        hint
+    }
+}
+
+/// The gRPC code a call ended with, as far as the response headers tell.
+/// Responses with more to come count as `Ok`.
+pub(crate) fn code<B>(result: &Result<Response<B>, Error>) -> Code {
+    let res = match result {
+        Ok(res) => res,
+        Err(e) => return e.code(),
+    };
+    if let Some(status) = res.headers().get("grpc-status") {
+        return status
+            .to_str()
+            .ok()
+            .and_then(|s| s.parse::<i32>().ok())
+            .map_or(Code::Unknown, Code::from);
+    }
+    // https://github.com/grpc/grpc/blob/master/doc/http-grpc-status-mapping.md
+    match res.status().as_u16() {
+        200 => Code::Ok,
+        400 => Code::Internal,
+        401 => Code::Unauthenticated,
+        403 => Code::PermissionDenied,
+        404 => Code::Unimplemented,
+        429 | 502 | 503 | 504 => Code::Unavailable,
+        _ => Code::Unknown,
+    }
+}
+
+/// A random duration up to `max`.
+pub(crate) fn jitter(max: Duration) -> Duration {
+    let fraction = RandomState::new().hash_one(std::time::Instant::now()) as f64 / u64::MAX as f64;
+    max.mul_f64(fraction)
+}
+
+/// An inner service answering each call with the next scripted code after
+/// a delay, recording the request bodies it saw.
+#[cfg(test)]
+pub(crate) mod scripted {
+    use std::collections::VecDeque;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use http::{HeaderValue, Request, Response};
+    use http_body_util::BodyExt;
+    use hyper::body::Bytes;
+    use tonic::Code;
+    use tonic::body::Body;
+
+    use crate::Error;
+
+    #[derive(Clone, Default)]
+    pub(crate) struct Scripted {
+        steps: Arc<Mutex<VecDeque<(Duration, Code)>>>,
+        header: Option<(&'static str, &'static str)>,
+        pub(crate) bodies: Arc<Mutex<Vec<Bytes>>>,
+    }
+
+    impl Scripted {
+        pub(crate) fn new(steps: impl IntoIterator<Item = (Duration, Code)>) -> Self {
+            Self {
+                steps: Arc::new(Mutex::new(steps.into_iter().collect())),
+                ..Default::default()
+            }
+        }
+
+        pub(crate) fn codes(codes: impl IntoIterator<Item = Code>) -> Self {
+            Self::new(codes.into_iter().map(|code| (Duration::ZERO, code)))
+        }
+
+        /// Adds a header to every response.
+        pub(crate) fn with_header(mut self, name: &'static str, value: &'static str) -> Self {
+            self.header = Some((name, value));
+            self
+        }
+
+        pub(crate) fn calls(&self) -> usize {
+            self.bodies.lock().unwrap().len()
+        }
+    }
+
+    pub(crate) fn request(uri: &str) -> Request<Body> {
+        Request::builder()
+            .uri(uri)
+            .body(Body::new(http_body_util::Full::new(Bytes::from_static(
+                b"call",
+            ))))
+            .unwrap()
+    }
+
+    impl tower::Service<Request<Body>> for Scripted {
+        type Response = Response<Body>;
+        type Error = Error;
+        type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, Error>> + Send>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: Request<Body>) -> Self::Future {
+            let (delay, code) = self
+                .steps
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or((Duration::ZERO, Code::Ok));
+            let bodies = self.bodies.clone();
+            let header = self.header;
+            Box::pin(async move {
+                let body = request.into_body().collect().await.unwrap().to_bytes();
+                bodies.lock().unwrap().push(body);
+                tokio::time::sleep(delay).await;
+                let mut res = Response::new(Body::empty());
+                res.headers_mut()
+                    .insert("grpc-status", HeaderValue::from(code as i32));
+                if let Some((name, value)) = header {
+                    res.headers_mut()
+                        .insert(name, HeaderValue::from_static(value));
+                }
+                Ok(res)
+            })
+        }
+    }
+}
@@ -0,0 +1,378 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use http::{Request, Response};
+use tonic::Code;
+use tonic::body::Body;
+use tower::{Layer, Service, ServiceExt};
+
+use super::methods::Methods;
+use super::replay::{self, Buffered, Replay};
+use crate::Error;
+
+const PUSHBACK: &str = "grpc-retry-pushback-ms";
+
+/// When a method's calls are sent again. Only give it to idempotent
+/// methods, since a call failing with a retryable code may still have had
+/// an effect on the server.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: f64,
+    retryable: Vec<Code>,
+}
+
+impl RetryPolicy {
+    /// Retries `UNAVAILABLE` until `max_attempts` calls were made, backing
+    /// off from 100ms up to 5s.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+            retryable: vec![Code::Unavailable],
+        }
+    }
+
+    /// Waits a random time up to `initial * multiplier^n`, capped at `max`,
+    /// before the n+1st retry.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration, multiplier: f64) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    pub fn with_retryable_codes(mut self, codes: impl IntoIterator<Item = Code>) -> Self {
+        self.retryable = codes.into_iter().collect();
+        self
+    }
+
+    fn backoff(&self, retries: u32) -> Duration {
+        let backoff = self.initial_backoff.as_secs_f64() * self.multiplier.powi(retries as i32);
+        replay::jitter(Duration::from_secs_f64(
+            backoff.min(self.max_backoff.as_secs_f64()),
+        ))
+    }
+}
+
+/// Caps retries and hedged attempts at a share of the calls, like gRPC's
+/// retry throttling. Every failed attempt takes a token and every success
+/// gives back `token_ratio` of one; further attempts are only made while
+/// more than half of `max_tokens` are left. Clones share their tokens.
+#[derive(Clone)]
+pub struct RetryBudget(Arc<Mutex<Tokens>>);
+
+struct Tokens {
+    left: f64,
+    max: f64,
+    ratio: f64,
+}
+
+impl RetryBudget {
+    pub fn new(max_tokens: u32, token_ratio: f64) -> Self {
+        let max = f64::from(max_tokens);
+        Self(Arc::new(Mutex::new(Tokens {
+            left: max,
+            max,
+            ratio: token_ratio,
+        })))
+    }
+
+    pub(crate) fn succeeded(&self) {
+        let mut tokens = self.0.lock().unwrap();
+        tokens.left = (tokens.left + tokens.ratio).min(tokens.max);
+    }
+
+    pub(crate) fn failed(&self) {
+        let mut tokens = self.0.lock().unwrap();
+        tokens.left = (tokens.left - 1.0).max(0.0);
+    }
+
+    pub(crate) fn allows(&self) -> bool {
+        let tokens = self.0.lock().unwrap();
+        tokens.left > tokens.max / 2.0
+    }
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        Self::new(10, 0.1)
+    }
+}
+
+/// Retries calls per [`RetryPolicy`], for the methods one is configured
+/// for. Other calls pass through untouched.
+///
+/// Calls are retried on a retryable code in a trailers-only response or a
+/// failure to get a response at all, never once the server started
+/// streaming. A `grpc-retry-pushback-ms` header from the server replaces
+/// the backoff, and no retry is made that could not finish before the
+/// call's `grpc-timeout`. Calls whose request is larger than a message may
+/// be, or still streaming when they are made, are sent once.
+#[derive(Clone)]
+pub struct RetryLayer {
+    methods: Arc<Methods<RetryPolicy>>,
+    budget: RetryBudget,
+}
+
+impl RetryLayer {
+    pub fn new(methods: Methods<RetryPolicy>) -> Self {
+        Self {
+            methods: Arc::new(methods),
+            budget: RetryBudget::default(),
+        }
+    }
+
+    /// Shares `budget`, e.g. with a [`HedgeLayer`](super::hedge::HedgeLayer).
+    pub fn with_budget(mut self, budget: RetryBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+}
+
+impl<S> Layer<S> for RetryLayer {
+    type Service = Retry<S>;
+
+    fn layer(&self, inner: S) -> Retry<S> {
+        Retry {
+            inner,
+            methods: self.methods.clone(),
+            budget: self.budget.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Retry<S> {
+    inner: S,
+    methods: Arc<Methods<RetryPolicy>>,
+    budget: RetryBudget,
+}
+
+/// What `grpc-retry-pushback-ms` asks for, if the response has it.
+enum Pushback {
+    After(Duration),
+    Stop,
+}
+
+fn pushback<B>(result: &Result<Response<B>, Error>) -> Option<Pushback> {
+    let value = result.as_ref().ok()?.headers().get(PUSHBACK)?;
+    Some(
+        match value.to_str().ok().and_then(|v| v.parse::<u64>().ok()) {
+            Some(ms) => Pushback::After(Duration::from_millis(ms)),
+            None => Pushback::Stop,
+        },
+    )
+}
+
+impl<S, B> Service<Request<Body>> for Retry<S>
+where
+    S: Service<Request<Body>, Response = Response<B>, Error = Error> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = Response<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<B>, Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let Some(policy) = self.methods.get(request.uri().path()).cloned() else {
+            return Box::pin(self.inner.call(request));
+        };
+        // the ready service makes the first attempt
+        let fresh = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, fresh);
+        let budget = self.budget.clone();
+
+        Box::pin(async move {
+            let replay = match Replay::buffer(request)? {
+                Buffered::Replay(replay) => replay,
+                Buffered::Once(request) => return inner.call(request).await,
+            };
+            let mut attempts = 1;
+            loop {
+                let result = inner.call(replay.request()).await;
+                let code = replay::code(&result);
+                if code == Code::Ok {
+                    budget.succeeded();
+                    return result;
+                }
+                if !policy.retryable.contains(&code) {
+                    return result;
+                }
+                budget.failed();
+                if attempts >= policy.max_attempts || !budget.allows() {
+                    return result;
+                }
+
+                let delay = match pushback(&result) {
+                    Some(Pushback::Stop) => return result,
+                    Some(Pushback::After(delay)) => delay,
+                    None => policy.backoff(attempts - 1),
+                };
+                if replay.remaining().is_some_and(|left| left <= delay) {
+                    return result;
+                }
+                tracing::debug!("Retrying call ended with {:?} in {:?}", code, delay);
+                tokio::time::sleep(delay).await;
+                inner.ready().await?;
+                attempts += 1;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use http_body_util::{Full, StreamBody};
+    use hyper::body::{Bytes, Frame};
+    use tokio_stream::wrappers::ReceiverStream;
+    use tonic_health::pb::HealthCheckRequest;
+    use tonic_health::pb::health_client::HealthClient;
+
+    use super::*;
+    use crate::client::replay::scripted::{Scripted, request};
+    use crate::envelope::MAX_MESSAGE_SIZE;
+
+    fn layer(policy: RetryPolicy) -> RetryLayer {
+        RetryLayer::new(Methods::new().with_service("f2.test.v1.Test", policy))
+    }
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy::new(max_attempts).with_backoff(
+            Duration::from_millis(1),
+            Duration::from_millis(5),
+            2.0,
+        )
+    }
+
+    async fn call(svc: &Retry<Scripted>, uri: &str) -> Code {
+        replay::code(&svc.clone().oneshot(request(uri)).await)
+    }
+
+    #[tokio::test]
+    async fn retries_retryable_codes() {
+        let inner = Scripted::codes([Code::Unavailable, Code::Unavailable]);
+        let svc = layer(policy(3)).layer(inner.clone());
+
+        assert_eq!(call(&svc, "/f2.test.v1.Test/Get").await, Code::Ok);
+        assert_eq!(inner.calls(), 3);
+        assert!(inner.bodies.lock().unwrap().iter().all(|b| b == "call"));
+    }
+
+    #[tokio::test]
+    async fn sends_large_and_streaming_requests_once() {
+        let inner = Scripted::codes([Code::Unavailable; 4]);
+        let svc = layer(policy(3)).layer(inner.clone());
+
+        let large = Request::builder()
+            .uri("/f2.test.v1.Test/Get")
+            .body(Body::new(Full::new(Bytes::from(vec![
+                0;
+                MAX_MESSAGE_SIZE + 1
+            ]))))
+            .unwrap();
+        let result = svc.clone().oneshot(large).await;
+        assert_eq!(replay::code(&result), Code::Unavailable);
+        assert_eq!(inner.calls(), 1);
+
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        tx.send(Ok::<_, Infallible>(Frame::data(Bytes::from_static(b"ca"))))
+            .await
+            .unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let _ = tx.send(Ok(Frame::data(Bytes::from_static(b"ll")))).await;
+        });
+        let streaming = Request::builder()
+            .uri("/f2.test.v1.Test/Get")
+            .body(Body::new(StreamBody::new(ReceiverStream::new(rx))))
+            .unwrap();
+        let result = svc.clone().oneshot(streaming).await;
+        assert_eq!(replay::code(&result), Code::Unavailable);
+        assert_eq!(inner.calls(), 2);
+        assert_eq!(inner.bodies.lock().unwrap()[1], "call");
+    }
+
+    #[tokio::test]
+    async fn retries_tonic_unary_calls() {
+        let inner = Scripted::codes([Code::Unavailable]);
+        let methods = Methods::new().with_service("grpc.health.v1.Health", policy(3));
+        let svc = RetryLayer::new(methods).layer(inner.clone());
+
+        let mut client = HealthClient::with_origin(svc, "http://localhost".parse().unwrap());
+        let _ = client.check(HealthCheckRequest::default()).await;
+        assert_eq!(inner.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let inner = Scripted::codes([Code::Unavailable; 5]);
+        let svc = layer(policy(2)).layer(inner.clone());
+
+        assert_eq!(call(&svc, "/f2.test.v1.Test/Get").await, Code::Unavailable);
+        assert_eq!(inner.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn leaves_other_codes_and_methods_alone() {
+        let inner = Scripted::codes([Code::InvalidArgument, Code::Unavailable]);
+        let svc = layer(policy(3)).layer(inner.clone());
+
+        assert_eq!(
+            call(&svc, "/f2.test.v1.Test/Get").await,
+            Code::InvalidArgument
+        );
+        assert_eq!(
+            call(&svc, "/f2.other.v1.Other/Get").await,
+            Code::Unavailable
+        );
+        assert_eq!(inner.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn stops_when_the_budget_runs_low() {
+        let inner = Scripted::codes([Code::Unavailable; 5]);
+        let svc = layer(policy(5))
+            .with_budget(RetryBudget::new(2, 0.1))
+            .layer(inner.clone());
+
+        assert_eq!(call(&svc, "/f2.test.v1.Test/Get").await, Code::Unavailable);
+        assert_eq!(inner.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn stops_on_pushback() {
+        let inner = Scripted::codes([Code::Unavailable; 5]).with_header(PUSHBACK, "-1");
+        let svc = layer(policy(5)).layer(inner.clone());
+
+        assert_eq!(call(&svc, "/f2.test.v1.Test/Get").await, Code::Unavailable);
+        assert_eq!(inner.calls(), 1);
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy::new(5).with_backoff(
+            Duration::from_millis(100),
+            Duration::from_millis(300),
+            2.0,
+        );
+        for _ in 0..100 {
+            assert!(policy.backoff(0) <= Duration::from_millis(100));
+            assert!(policy.backoff(4) <= Duration::from_millis(300));
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::path::PathBuf;
+
+use http::Uri;
+
+use crate::Error;
+use crate::server::listener::unix_socket_path;
+
+const SCHEME: &str = "unix";
+
+/// Parses a server address into the origin for a channel. Besides `http` and
+/// `https` URIs this takes Unix sockets in the forms gRPC uses,
+/// `unix:/run/auth.sock`, `unix:///run/auth.sock` or `unix:relative.sock`,
+/// and `unix:@name` for abstract sockets on Linux.
+///
+/// The socket path ends up hex-encoded as the authority, since the URI path
+/// is replaced by the method on every call and `http` rejects most of the
+/// characters a path can hold there.
+pub fn parse_origin(addr: &str) -> Result<Uri, Error> {
+    let Some(path) = addr.strip_prefix("unix:") else {
+        return addr
+            .parse()
+            .map_err(|e| Error::Request(http::Error::from(e)));
+    };
+    let path = match path.strip_prefix("//") {
+        Some(absolute) => absolute,
+        None => path,
+    };
+
+    let authority: String = path.bytes().map(|b| format!("{b:02x}")).collect();
+    Uri::builder()
+        .scheme(SCHEME)
+        .authority(authority)
+        .path_and_query("/")
+        .build()
+        .map_err(Error::Request)
+}
+
+/// The socket a `unix` origin built by [`parse_origin`] points at.
+pub(crate) fn socket_path(origin: &Uri) -> Option<PathBuf> {
+    if origin.scheme_str() != Some(SCHEME) {
+        return None;
+    }
+    let encoded = origin.authority()?.as_str();
+    let path = (0..encoded.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(encoded.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(unix_socket_path(&String::from_utf8_lossy(&path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_socket_paths() {
+        for (addr, path) in [
+            ("unix:/run/f2/auth.sock", "/run/f2/auth.sock"),
+            ("unix:///run/f2/auth.sock", "/run/f2/auth.sock"),
+            ("unix:auth.sock", "auth.sock"),
+            ("unix:@f2-auth", "\0f2-auth"),
+        ] {
+            let origin = parse_origin(addr).unwrap();
+            assert_eq!(socket_path(&origin), Some(PathBuf::from(path)), "{addr}");
+        }
+    }
+
+    #[test]
+    fn leaves_http_origins_alone() {
+        let origin = parse_origin("http://127.0.0.1:8080").unwrap();
+        assert_eq!(origin.authority().unwrap(), "127.0.0.1:8080");
+        assert_eq!(socket_path(&origin), None);
+    }
+}
@@ -0,0 +1,520 @@
+//! gzip and zstd compression of gRPC messages, negotiated through the
+//! `grpc-encoding` and `grpc-accept-encoding` headers.
+//!
+//! The same [`Compression`] configures the server, see
+//! [`Server::with_compression`](crate::server::Server::with_compression),
+//! and clients, see [`CompressionLayer`](crate::client::compression::CompressionLayer).
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use flate2::Compression as GzipLevel;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use http::{HeaderMap, HeaderValue};
+use hyper::body::{Bytes, Frame};
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::histogram::{Histogram, exponential_buckets};
+use prometheus_client::registry::Registry;
+use tonic::Status;
+
+use crate::Error;
+use crate::client::methods::Methods;
+use crate::envelope::{COMPRESSED, Envelopes, MAX_MESSAGE_SIZE, envelope};
+use crate::rewrite::Rewrite;
+
+pub(crate) const ENCODING: &str = "grpc-encoding";
+pub(crate) const ACCEPT_ENCODING: &str = "grpc-accept-encoding";
+
+const DEFAULT_MIN_SIZE: usize = 1024;
+
+/// A message compression algorithm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    /// The name in `grpc-encoding`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    fn compress(self, message: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), GzipLevel::default());
+                encoder.write_all(message)?;
+                encoder.finish()
+            }
+            Encoding::Zstd => zstd::encode_all(message, zstd::DEFAULT_COMPRESSION_LEVEL),
+        }
+    }
+
+    /// Decompresses a message, `None` when it would exceed
+    /// [`MAX_MESSAGE_SIZE`].
+    fn decompress(self, message: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let limit = MAX_MESSAGE_SIZE as u64 + 1;
+        let mut decompressed = Vec::new();
+        match self {
+            Encoding::Gzip => GzDecoder::new(message)
+                .take(limit)
+                .read_to_end(&mut decompressed)?,
+            Encoding::Zstd => zstd::Decoder::new(message)?
+                .take(limit)
+                .read_to_end(&mut decompressed)?,
+        };
+        Ok((decompressed.len() <= MAX_MESSAGE_SIZE).then_some(decompressed))
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "gzip" => Ok(Encoding::Gzip),
+            "zstd" => Ok(Encoding::Zstd),
+            _ => Err(Error::UnknownEncoding(s.to_string())),
+        }
+    }
+}
+
+/// Which encodings to speak and when to compress.
+///
+/// Received messages are decompressed whenever they come in an encoding
+/// listed here. Sent messages are compressed once they reach `min_size`
+/// bytes, unless their method is opted out: servers pick the first
+/// encoding the client accepts, clients always send the first one.
+///
+/// ```
+/// # use f2_utils::client::methods::Methods;
+/// # use f2_utils::compression::{Compression, Encoding};
+/// let compression = Compression::new()
+///     .with_encodings([Encoding::Gzip])
+///     .with_min_size(512)
+///     .with_methods(Methods::new().with_service("f2.images.v1.Images", false));
+/// ```
+#[derive(Clone)]
+pub struct Compression {
+    encodings: Vec<Encoding>,
+    min_size: usize,
+    methods: Arc<Methods<bool>>,
+    metrics: CompressionMetrics,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compression {
+    /// Speaks zstd and gzip, preferring zstd, and compresses messages of at
+    /// least 1 KiB.
+    pub fn new() -> Self {
+        Self {
+            encodings: vec![Encoding::Zstd, Encoding::Gzip],
+            min_size: DEFAULT_MIN_SIZE,
+            methods: Arc::new(Methods::new()),
+            metrics: CompressionMetrics::default(),
+        }
+    }
+
+    /// Encodings in order of preference.
+    pub fn with_encodings(mut self, encodings: impl IntoIterator<Item = Encoding>) -> Self {
+        self.encodings = encodings.into_iter().collect();
+        self
+    }
+
+    /// Smallest message worth compressing, in bytes.
+    pub fn with_min_size(mut self, bytes: usize) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Whether to compress the messages of a method, e.g. `false` for
+    /// methods sending already compressed image data. Methods not listed
+    /// are compressed.
+    pub fn with_methods(mut self, methods: Methods<bool>) -> Self {
+        self.methods = Arc::new(methods);
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: CompressionMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// The encoding to send the messages of a call to `path` with, the
+    /// first one listed in `accepted` if given.
+    pub(crate) fn send(&self, path: &str, accepted: Option<&HeaderMap>) -> Option<Encoding> {
+        if !self.methods.get(path).copied().unwrap_or(true) {
+            return None;
+        }
+        match accepted {
+            None => self.encodings.first().copied(),
+            Some(headers) => {
+                let accepted: Vec<&str> = headers
+                    .get_all(ACCEPT_ENCODING)
+                    .iter()
+                    .filter_map(|v| v.to_str().ok())
+                    .flat_map(|v| v.split(','))
+                    .map(str::trim)
+                    .collect();
+                self.encodings
+                    .iter()
+                    .copied()
+                    .find(|e| accepted.contains(&e.name()))
+            }
+        }
+    }
+
+    /// The encoding messages with `grpc-encoding` set to `name` come in,
+    /// `None` for uncompressed ones and `Err` for encodings not spoken.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn receive(&self, name: &HeaderValue) -> Result<Option<Encoding>, Status> {
+        if name == "identity" {
+            return Ok(None);
+        }
+        name.to_str()
+            .ok()
+            .and_then(|name| name.parse().ok())
+            .filter(|encoding| self.encodings.contains(encoding))
+            .map(Some)
+            .ok_or_else(|| Status::unimplemented(format!("unsupported {ENCODING} {name:?}")))
+    }
+
+    /// The `grpc-accept-encoding` value listing the encodings spoken.
+    pub(crate) fn accept_encoding(&self) -> HeaderValue {
+        let names: Vec<&str> = self.encodings.iter().map(|e| e.name()).collect();
+        HeaderValue::from_str(&names.join(",")).expect("encoding names are valid header values")
+    }
+
+    pub(crate) fn compress(&self, encoding: Encoding) -> Compress {
+        Compress {
+            encoding,
+            min_size: self.min_size,
+            metrics: self.metrics.clone(),
+            envelopes: Envelopes::default(),
+        }
+    }
+
+    pub(crate) fn decompress(&self, encoding: Option<Encoding>) -> Decompress {
+        Decompress {
+            encoding,
+            metrics: self.metrics.clone(),
+            envelopes: Envelopes::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CompressionLabels {
+    encoding: &'static str,
+    direction: &'static str,
+}
+
+/// How well messages compress, by encoding and by whether they were sent
+/// or received.
+#[derive(Clone)]
+pub struct CompressionMetrics {
+    ratio: Family<CompressionLabels, Histogram, fn() -> Histogram>,
+    uncompressed_bytes: Family<CompressionLabels, Counter>,
+    compressed_bytes: Family<CompressionLabels, Counter>,
+}
+
+impl Default for CompressionMetrics {
+    fn default() -> Self {
+        Self {
+            // 1x up to about 38x
+            ratio: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(1.0, 1.5, 10))
+            }),
+            uncompressed_bytes: Family::default(),
+            compressed_bytes: Family::default(),
+        }
+    }
+}
+
+impl CompressionMetrics {
+    /// Registers the metrics as `compression_*`, e.g. in a registry with
+    /// the `f2_client` prefix. Servers register theirs under `f2_server`.
+    pub fn register(registry: &mut Registry) -> Self {
+        let metrics = Self::default();
+        registry.register(
+            "compression_ratio",
+            "Size of compressed messages before compression over their size after",
+            metrics.ratio.clone(),
+        );
+        registry.register(
+            "compression_uncompressed_bytes",
+            "Size of compressed messages before compression",
+            metrics.uncompressed_bytes.clone(),
+        );
+        registry.register(
+            "compression_compressed_bytes",
+            "Size of compressed messages after compression",
+            metrics.compressed_bytes.clone(),
+        );
+        metrics
+    }
+
+    fn record(
+        &self,
+        encoding: Encoding,
+        direction: &'static str,
+        uncompressed: usize,
+        compressed: usize,
+    ) {
+        let labels = CompressionLabels {
+            encoding: encoding.name(),
+            direction,
+        };
+        self.ratio
+            .get_or_create(&labels)
+            .observe(uncompressed as f64 / compressed.max(1) as f64);
+        self.uncompressed_bytes
+            .get_or_create(&labels)
+            .inc_by(uncompressed as u64);
+        self.compressed_bytes
+            .get_or_create(&labels)
+            .inc_by(compressed as u64);
+    }
+}
+
+/// Compresses the messages of a body that reach the minimum size.
+pub(crate) struct Compress {
+    encoding: Encoding,
+    min_size: usize,
+    metrics: CompressionMetrics,
+    envelopes: Envelopes,
+}
+
+impl Rewrite for Compress {
+    fn data(&mut self, data: Bytes, out: &mut VecDeque<Frame<Bytes>>) -> Result<(), Status> {
+        self.envelopes.push(&data);
+        while let Some((flags, message)) = self.envelopes.next() {
+            if flags & COMPRESSED != 0 || message.len() < self.min_size {
+                out.push_back(Frame::data(envelope(flags, &message)));
+                continue;
+            }
+            let compressed = self
+                .encoding
+                .compress(&message)
+                .map_err(|e| Status::internal(format!("failed to compress message: {e}")))?;
+            self.metrics
+                .record(self.encoding, "sent", message.len(), compressed.len());
+            out.push_back(Frame::data(envelope(flags | COMPRESSED, &compressed)));
+        }
+        Ok(())
+    }
+
+    fn end(
+        &mut self,
+        trailers: Option<HeaderMap>,
+        out: &mut VecDeque<Frame<Bytes>>,
+    ) -> Result<(), Status> {
+        if !self.envelopes.is_empty() {
+            return Err(Status::internal("message was cut off"));
+        }
+        out.extend(trailers.map(Frame::trailers));
+        Ok(())
+    }
+}
+
+/// Decompresses the compressed messages of a body.
+pub(crate) struct Decompress {
+    encoding: Option<Encoding>,
+    metrics: CompressionMetrics,
+    envelopes: Envelopes,
+}
+
+impl Rewrite for Decompress {
+    fn data(&mut self, data: Bytes, out: &mut VecDeque<Frame<Bytes>>) -> Result<(), Status> {
+        self.envelopes.push(&data);
+        loop {
+            // as tonic would for messages that reach it uncompressed
+            self.envelopes.check_len()?;
+            let Some((flags, message)) = self.envelopes.next() else {
+                break;
+            };
+            if flags & COMPRESSED == 0 {
+                out.push_back(Frame::data(envelope(flags, &message)));
+                continue;
+            }
+            let Some(encoding) = self.encoding else {
+                return Err(Status::internal(format!(
+                    "compressed message without {ENCODING}"
+                )));
+            };
+            let decompressed = encoding
+                .decompress(&message)
+                .map_err(|e| Status::internal(format!("failed to decompress message: {e}")))?
+                .ok_or_else(|| {
+                    Status::resource_exhausted(format!(
+                        "decompressed message is larger than {MAX_MESSAGE_SIZE} bytes"
+                    ))
+                })?;
+            self.metrics
+                .record(encoding, "received", decompressed.len(), message.len());
+            out.push_back(Frame::data(envelope(flags & !COMPRESSED, &decompressed)));
+        }
+        Ok(())
+    }
+
+    fn end(
+        &mut self,
+        trailers: Option<HeaderMap>,
+        out: &mut VecDeque<Frame<Bytes>>,
+    ) -> Result<(), Status> {
+        if !self.envelopes.is_empty() {
+            return Err(Status::internal("message was cut off"));
+        }
+        out.extend(trailers.map(Frame::trailers));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus_client::encoding::text::encode;
+
+    use super::*;
+
+    #[allow(clippy::result_large_err)]
+    fn rewrite(rewrite: &mut impl Rewrite, data: Bytes) -> Result<Vec<(u8, Bytes)>, Status> {
+        let mut out = VecDeque::new();
+        rewrite.data(data, &mut out)?;
+        rewrite.end(None, &mut out)?;
+        let mut envelopes = Envelopes::default();
+        for frame in out {
+            envelopes.push(&frame.into_data().unwrap());
+        }
+        Ok(std::iter::from_fn(|| envelopes.next()).collect())
+    }
+
+    #[test]
+    fn round_trips_messages_above_the_threshold() {
+        let large = "metadata ".repeat(200);
+        let mut body = envelope(0, b"small").to_vec();
+        body.extend_from_slice(&envelope(0, large.as_bytes()));
+
+        for encoding in [Encoding::Gzip, Encoding::Zstd] {
+            let compression = Compression::new().with_min_size(64);
+            let compressed =
+                rewrite(&mut compression.compress(encoding), body.clone().into()).unwrap();
+            assert_eq!(compressed[0], (0, Bytes::from_static(b"small")));
+            assert_eq!(compressed[1].0, COMPRESSED);
+            assert!(compressed[1].1.len() < large.len() / 10);
+
+            let mut framed = Vec::new();
+            for (flags, message) in compressed {
+                framed.extend_from_slice(&envelope(flags, &message));
+            }
+            let decompressed =
+                rewrite(&mut compression.decompress(Some(encoding)), framed.into()).unwrap();
+            assert_eq!(decompressed[0], (0, Bytes::from_static(b"small")));
+            assert_eq!(decompressed[1], (0, Bytes::from(large.clone())));
+        }
+    }
+
+    #[test]
+    fn refuses_oversized_and_unannounced_messages() {
+        let compression = Compression::new();
+        let bomb = Encoding::Gzip
+            .compress(&vec![0; MAX_MESSAGE_SIZE + 1])
+            .unwrap();
+        let status = rewrite(
+            &mut compression.decompress(Some(Encoding::Gzip)),
+            envelope(COMPRESSED, &bomb),
+        )
+        .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+
+        let status = rewrite(
+            &mut compression.decompress(None),
+            envelope(COMPRESSED, &bomb),
+        )
+        .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Internal);
+    }
+
+    #[test]
+    fn refuses_oversized_length_prefixes() {
+        let mut header = vec![COMPRESSED];
+        header.extend_from_slice(&u32::MAX.to_be_bytes());
+        header.extend_from_slice(b"only the start");
+
+        let mut decompress = Compression::new().decompress(Some(Encoding::Gzip));
+        let status = decompress
+            .data(header.into(), &mut VecDeque::new())
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    }
+
+    #[test]
+    fn negotiates_encodings() {
+        let compression = Compression::new()
+            .with_methods(Methods::new().with_method("f2.images.v1.Images/Upload", false));
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity, gzip"));
+
+        let path = "/f2.users.v1.Users/CreateUser";
+        assert_eq!(compression.send(path, Some(&headers)), Some(Encoding::Gzip));
+        assert_eq!(compression.send(path, Some(&HeaderMap::new())), None);
+        assert_eq!(compression.send(path, None), Some(Encoding::Zstd));
+        assert_eq!(compression.send("/f2.images.v1.Images/Upload", None), None);
+
+        assert_eq!(
+            compression
+                .receive(&HeaderValue::from_static("zstd"))
+                .unwrap(),
+            Some(Encoding::Zstd)
+        );
+        assert_eq!(
+            compression
+                .receive(&HeaderValue::from_static("identity"))
+                .unwrap(),
+            None
+        );
+        let status = compression
+            .receive(&HeaderValue::from_static("br"))
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unimplemented);
+        assert_eq!(compression.accept_encoding(), "zstd,gzip");
+    }
+
+    #[test]
+    fn records_ratios() {
+        let mut registry = Registry::default();
+        let compression = Compression::new()
+            .with_min_size(0)
+            .with_metrics(CompressionMetrics::register(&mut registry));
+        rewrite(
+            &mut compression.compress(Encoding::Gzip),
+            envelope(0, &[7; 4096]),
+        )
+        .unwrap();
+
+        let mut metrics = String::new();
+        encode(&mut metrics, &registry).unwrap();
+        assert!(
+            metrics.contains(
+                "compression_uncompressed_bytes_total{encoding=\"gzip\",direction=\"sent\"} 4096"
+            ),
+            "{metrics}"
+        );
+        assert!(
+            metrics.contains("compression_ratio_count{encoding=\"gzip\",direction=\"sent\"} 1")
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use std::future::Future;
+
+use http::{HeaderMap, HeaderName, Request};
+
+use crate::Deadline;
+
+/// Headers a call carries over to the calls made while serving it: the
+/// request ID and the W3C trace context.
+pub const FORWARDED_HEADERS: [&str; 3] = ["x-request-id", "traceparent", "tracestate"];
+
+tokio::task_local! {
+    static CONTEXT: RequestContext;
+}
+
+/// What the call being served passes on to outgoing calls: its deadline and
+/// the forwarded headers.
+///
+/// The server runs every handler, and every poll of the response body it
+/// returns, within the context of its call, where
+/// [`RequestContext::current`] finds it and
+/// [`client::context::propagate`](crate::client::context::propagate) copies
+/// it onto outgoing calls. Tasks spawned by a handler start without one;
+/// run them in [`RequestContext::scope`] to carry it over.
+#[derive(Clone, Debug, Default)]
+pub struct RequestContext {
+    deadline: Option<Deadline>,
+    headers: HeaderMap,
+}
+
+impl RequestContext {
+    /// Takes the `forwarded` headers from `req` and the [`Deadline`] from its
+    /// extensions.
+    pub fn from_request<B>(req: &Request<B>, forwarded: &[HeaderName]) -> Self {
+        let mut headers = HeaderMap::new();
+        for name in forwarded {
+            for value in req.headers().get_all(name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+        Self {
+            deadline: req.extensions().get::<Deadline>().copied(),
+            headers,
+        }
+    }
+
+    /// The context of the call this task is serving, if any.
+    pub fn current() -> Option<Self> {
+        CONTEXT.try_with(Clone::clone).ok()
+    }
+
+    pub fn with_deadline(mut self, deadline: Deadline) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn with_header(mut self, name: HeaderName, value: http::HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    pub fn deadline(&self) -> Option<Deadline> {
+        self.deadline
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Runs `f` within this context.
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CONTEXT.scope(self, f).await
+    }
+
+    /// Runs `f` within a copy of this context.
+    pub(crate) fn sync_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        CONTEXT.sync_scope(self.clone(), f)
+    }
+}
+
+/// [`FORWARDED_HEADERS`] as header names.
+pub(crate) fn forwarded_headers() -> Vec<HeaderName> {
+    FORWARDED_HEADERS
+        .iter()
+        .map(|name| HeaderName::from_static(name))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn carries_forwarded_headers_and_the_deadline() {
+        let deadline = Deadline::after(Duration::from_secs(1));
+        let mut req = Request::builder()
+            .header("x-request-id", "req-123")
+            .header(
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            )
+            .header("authorization", "Bearer secret")
+            .body(())
+            .unwrap();
+        req.extensions_mut().insert(deadline);
+
+        let context = RequestContext::from_request(&req, &forwarded_headers());
+        assert_eq!(context.deadline(), Some(deadline));
+        assert_eq!(context.headers()["x-request-id"], "req-123");
+        assert!(context.headers().contains_key("traceparent"));
+        assert!(!context.headers().contains_key("authorization"));
+
+        assert!(RequestContext::current().is_none());
+        let current = context.scope(async { RequestContext::current() }).await;
+        assert_eq!(current.unwrap().headers()["x-request-id"], "req-123");
+    }
+}
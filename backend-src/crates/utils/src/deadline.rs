@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+use http::HeaderValue;
+use tokio::time::Instant;
+
+pub(crate) const GRPC_TIMEOUT: &str = "grpc-timeout";
+
+/// When a call has to be answered by, from its `grpc-timeout` header and
+/// the server's request timeout. The server adds it to the extensions of
+/// every request that has one, so handlers can give up early.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    pub fn after(timeout: Duration) -> Self {
+        Self(Instant::now() + timeout)
+    }
+
+    pub fn instant(&self) -> Instant {
+        self.0
+    }
+
+    /// Time left until the deadline, zero once it has passed.
+    pub fn remaining(&self) -> Duration {
+        self.0.saturating_duration_since(Instant::now())
+    }
+}
+
+/// Parses a `grpc-timeout` value, up to eight digits followed by a unit.
+pub(crate) fn parse(value: &HeaderValue) -> Option<Duration> {
+    let value = value.to_str().ok()?;
+    let unit = value.len().checked_sub(1)?;
+    let (digits, unit) = value.split_at(unit);
+    if digits.is_empty() || digits.len() > 8 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let n: u64 = digits.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(n * 3600),
+        "M" => Duration::from_secs(n * 60),
+        "S" => Duration::from_secs(n),
+        "m" => Duration::from_millis(n),
+        "u" => Duration::from_micros(n),
+        "n" => Duration::from_nanos(n),
+        _ => return None,
+    })
+}
+
+/// Encodes `timeout` as a `grpc-timeout` value in the finest unit that
+/// fits into eight digits.
+pub(crate) fn encode(timeout: Duration) -> HeaderValue {
+    const MAX: u128 = 99_999_999;
+    let value = if timeout.as_micros() <= MAX {
+        format!("{}u", timeout.as_micros())
+    } else if timeout.as_millis() <= MAX {
+        format!("{}m", timeout.as_millis())
+    } else if u128::from(timeout.as_secs()) <= MAX {
+        format!("{}S", timeout.as_secs())
+    } else {
+        format!("{}H", (timeout.as_secs() / 3600).min(MAX as u64))
+    };
+    HeaderValue::try_from(value).expect("digits and a unit are a valid header value")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_grpc_timeouts() {
+        for (value, timeout) in [
+            ("1H", Some(Duration::from_secs(3600))),
+            ("2M", Some(Duration::from_secs(120))),
+            ("3S", Some(Duration::from_secs(3))),
+            ("250m", Some(Duration::from_millis(250))),
+            ("10u", Some(Duration::from_micros(10))),
+            ("99999999n", Some(Duration::from_nanos(99_999_999))),
+            ("100000000m", None),
+            ("m", None),
+            ("10", None),
+            ("-1S", None),
+            ("5s", None),
+        ] {
+            assert_eq!(parse(&HeaderValue::from_static(value)), timeout, "{value}");
+        }
+    }
+
+    #[test]
+    fn encodes_in_the_finest_unit_that_fits() {
+        for timeout in [
+            Duration::from_micros(1500),
+            Duration::from_secs(200),
+            Duration::from_secs(86_400 * 365),
+        ] {
+            let parsed = parse(&encode(timeout)).unwrap();
+            assert!(parsed <= timeout && timeout - parsed < Duration::from_secs(1));
+        }
+        assert_eq!(encode(Duration::from_millis(250)), "250000u");
+    }
+}
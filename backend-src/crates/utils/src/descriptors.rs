@@ -0,0 +1,5 @@
+//! File descriptor sets embedded at build time.
+
+/// Every f2 proto with everything it imports, including source info so
+/// reflection clients can show comments.
+pub use f2_proto::FILE_DESCRIPTOR_SET;
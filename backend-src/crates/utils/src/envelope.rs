@@ -0,0 +1,97 @@
+use bytes::{Buf, BufMut, BytesMut};
+use hyper::body::Bytes;
+use tonic::Status;
+
+/// Largest message read, the same as tonic's default limit for decoding
+/// messages.
+pub(crate) const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Set on messages whose payload is compressed.
+pub(crate) const COMPRESSED: u8 = 0x01;
+
+/// Set on the Connect message ending a stream.
+pub(crate) const END_STREAM: u8 = 0x02;
+
+/// Set on the gRPC-Web message carrying the trailers.
+pub(crate) const TRAILERS: u8 = 0x80;
+
+/// Frames a message behind its flags and big-endian length, the same for
+/// gRPC, gRPC-Web and Connect streams.
+pub(crate) fn envelope(flags: u8, message: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(5 + message.len());
+    buf.put_u8(flags);
+    buf.put_u32(message.len() as u32);
+    buf.put_slice(message);
+    buf.freeze()
+}
+
+/// Splits a stream of bytes back into enveloped messages.
+#[derive(Default)]
+pub(crate) struct Envelopes {
+    buf: BytesMut,
+}
+
+impl Envelopes {
+    pub(crate) fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The length the next message declares, once its header is in.
+    pub(crate) fn next_len(&self) -> Option<usize> {
+        let header = self.buf.get(..5)?;
+        Some(u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize)
+    }
+
+    /// Fails with `RESOURCE_EXHAUSTED` once the next message declares more
+    /// than [`MAX_MESSAGE_SIZE`], before buffering what it announces.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn check_len(&self) -> Result<(), Status> {
+        match self.next_len() {
+            Some(len) if len > MAX_MESSAGE_SIZE => Err(Status::resource_exhausted(format!(
+                "message is larger than {MAX_MESSAGE_SIZE} bytes"
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// The next complete message and its flags.
+    pub(crate) fn next(&mut self) -> Option<(u8, Bytes)> {
+        let len = self.next_len()?;
+        if self.buf.len() < 5 + len {
+            return None;
+        }
+        let flags = self.buf.get_u8();
+        self.buf.advance(4);
+        Some((flags, self.buf.split_to(len).freeze()))
+    }
+
+    /// Whether a message was cut off.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_messages_across_chunks() {
+        let mut bytes = envelope(0, b"first").to_vec();
+        bytes.extend_from_slice(&envelope(END_STREAM, b"{}"));
+
+        let mut envelopes = Envelopes::default();
+        envelopes.push(&bytes[..3]);
+        assert_eq!(envelopes.next(), None);
+        envelopes.push(&bytes[3..12]);
+        assert_eq!(envelopes.next(), Some((0, Bytes::from_static(b"first"))));
+        assert_eq!(envelopes.next(), None);
+        assert!(!envelopes.is_empty());
+        envelopes.push(&bytes[12..]);
+        assert_eq!(
+            envelopes.next(),
+            Some((END_STREAM, Bytes::from_static(b"{}")))
+        );
+        assert!(envelopes.is_empty());
+    }
+}
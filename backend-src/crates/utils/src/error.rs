@@ -0,0 +1,191 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use http::StatusCode;
+
+/// Errors from the h2c client and server.
+#[derive(Debug)]
+pub enum Error {
+    /// The upgrade request could not be built from the call's URI.
+    Request(http::Error),
+    /// The call's URI names no host to connect to.
+    MissingHost(http::Uri),
+    /// Connecting to the server or sending the upgrade request failed.
+    Connect(hyper_util::client::legacy::Error),
+    /// A socket could not be bound or connected.
+    Io(std::io::Error),
+    /// The server answered the upgrade request without switching protocols.
+    UpgradeRefused(StatusCode),
+    /// The upgrade or the HTTP/2 connection on top of it failed.
+    Http(hyper::Error),
+    /// A certificate or key file could not be read.
+    Pem(PathBuf, rustls::pki_types::pem::Error),
+    /// TLS configuration or negotiation failed.
+    Tls(rustls::Error),
+    /// A file descriptor set could not be decoded.
+    Descriptors(prost_reflect::DescriptorError),
+    /// The reflection service could not be built from its file descriptor sets.
+    Reflection(tonic_reflection::server::Error),
+    /// A `google.api.http` rule doesn't fit the method it annotates.
+    HttpRule(String, String),
+    /// The call got no response before its deadline.
+    DeadlineExceeded,
+    /// The endpoint's circuit breaker is open.
+    CircuitOpen(String),
+    /// The request body could not be buffered for another attempt.
+    RequestBody(Box<tonic::Status>),
+    /// The balanced channel's target resolved to no address.
+    NoEndpoints,
+    /// A balancing strategy other than `p2c` or `round-robin` was configured.
+    UnknownStrategy(String),
+    /// An h2c mode other than `upgrade` or `prior-knowledge` was configured.
+    UnknownMode(String),
+    /// A compression encoding other than `gzip` or `zstd` was configured.
+    UnknownEncoding(String),
+    /// A trusted proxy network is neither a CIDR block nor an address.
+    InvalidNetwork(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Request(e) => write!(f, "invalid h2c upgrade request: {e}"),
+            Error::MissingHost(uri) => write!(f, "no host to connect to in {uri}"),
+            Error::Connect(e) => write!(f, "h2c connect failed: {e}"),
+            Error::Io(e) => write!(f, "socket error: {e}"),
+            Error::UpgradeRefused(status) => {
+                write!(f, "server refused the h2c upgrade with {status}")
+            }
+            Error::Http(e) => write!(f, "h2c connection failed: {e}"),
+            Error::Pem(path, e) => write!(f, "failed to read {}: {e}", path.display()),
+            Error::Tls(e) => write!(f, "TLS failed: {e}"),
+            Error::Descriptors(e) => write!(f, "invalid file descriptor set: {e}"),
+            Error::Reflection(e) => write!(f, "failed to build the reflection service: {e}"),
+            Error::HttpRule(method, reason) => {
+                write!(f, "invalid google.api.http rule on {method}: {reason}")
+            }
+            Error::DeadlineExceeded => f.write_str("call exceeded its deadline"),
+            Error::CircuitOpen(endpoint) => write!(f, "circuit to {endpoint} is open"),
+            Error::RequestBody(status) => {
+                write!(f, "failed to read the request body: {}", status.message())
+            }
+            Error::NoEndpoints => f.write_str("no endpoints to balance over"),
+            Error::UnknownStrategy(strategy) => write!(
+                f,
+                "unknown balancing strategy {strategy:?}, expected \"p2c\" or \"round-robin\""
+            ),
+            Error::UnknownMode(mode) => write!(
+                f,
+                "unknown h2c mode {mode:?}, expected \"upgrade\" or \"prior-knowledge\""
+            ),
+            Error::UnknownEncoding(encoding) => write!(
+                f,
+                "unknown compression encoding {encoding:?}, expected \"gzip\" or \"zstd\""
+            ),
+            Error::InvalidNetwork(net) => write!(f, "invalid trusted proxy network {net:?}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Request(e) => Some(e),
+            Error::Connect(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::UpgradeRefused(_)
+            | Error::MissingHost(_)
+            | Error::HttpRule(..)
+            | Error::DeadlineExceeded
+            | Error::CircuitOpen(_)
+            | Error::NoEndpoints
+            | Error::UnknownStrategy(_)
+            | Error::UnknownMode(_)
+            | Error::UnknownEncoding(_)
+            | Error::InvalidNetwork(_) => None,
+            Error::Http(e) => Some(e),
+            Error::Pem(_, e) => Some(e),
+            Error::Tls(e) => Some(e),
+            Error::Descriptors(e) => Some(e),
+            Error::Reflection(e) => Some(e),
+            Error::RequestBody(status) => Some(status.as_ref()),
+        }
+    }
+}
+
+impl From<http::Error> for Error {
+    fn from(e: http::Error) -> Self {
+        Error::Request(e)
+    }
+}
+
+impl From<hyper_util::client::legacy::Error> for Error {
+    fn from(e: hyper_util::client::legacy::Error) -> Self {
+        Error::Connect(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<rustls::Error> for Error {
+    fn from(e: rustls::Error) -> Self {
+        Error::Tls(e)
+    }
+}
+
+impl From<prost_reflect::DescriptorError> for Error {
+    fn from(e: prost_reflect::DescriptorError) -> Self {
+        Error::Descriptors(e)
+    }
+}
+
+impl From<tonic_reflection::server::Error> for Error {
+    fn from(e: tonic_reflection::server::Error) -> Self {
+        Error::Reflection(e)
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(e: hyper::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl Error {
+    /// The gRPC code a call failing with this error ends with.
+    pub(crate) fn code(&self) -> tonic::Code {
+        match self {
+            Error::Request(_)
+            | Error::MissingHost(_)
+            | Error::UnknownMode(_)
+            | Error::UnknownEncoding(_)
+            | Error::InvalidNetwork(_)
+            | Error::UnknownStrategy(_)
+            | Error::Pem(..)
+            | Error::Descriptors(_)
+            | Error::HttpRule(..) => tonic::Code::InvalidArgument,
+            Error::Connect(_)
+            | Error::Io(_)
+            | Error::UpgradeRefused(_)
+            | Error::Tls(_)
+            | Error::CircuitOpen(_)
+            | Error::NoEndpoints => tonic::Code::Unavailable,
+            Error::DeadlineExceeded => tonic::Code::DeadlineExceeded,
+            Error::RequestBody(status) => status.code(),
+            Error::Http(inner) if inner.is_canceled() || inner.is_closed() => {
+                tonic::Code::Unavailable
+            }
+            Error::Http(_) | Error::Reflection(_) => tonic::Code::Internal,
+        }
+    }
+}
+
+impl From<Error> for tonic::Status {
+    fn from(e: Error) -> Self {
+        tonic::Status::new(e.code(), e.to_string())
+    }
+}
@@ -1,2 +1,15 @@
-pub mod server;
 pub mod client;
+pub mod compression;
+pub mod context;
+mod deadline;
+pub mod descriptors;
+mod envelope;
+mod error;
+mod rewrite;
+pub mod server;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod tls;
+
+pub use deadline::Deadline;
+pub use error::Error;
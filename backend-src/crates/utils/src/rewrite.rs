@@ -0,0 +1,89 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use http::HeaderMap;
+use hyper::body::{Bytes, Frame};
+use tonic::Status;
+use tonic::body::Body;
+
+/// Rewrites a body frame by frame as it streams through.
+pub(crate) trait Rewrite: Send + Unpin + 'static {
+    #[allow(clippy::result_large_err)]
+    fn data(&mut self, data: Bytes, out: &mut VecDeque<Frame<Bytes>>) -> Result<(), Status>;
+
+    /// Called once the body ended, with its trailers if it had any.
+    #[allow(clippy::result_large_err)]
+    fn end(
+        &mut self,
+        trailers: Option<HeaderMap>,
+        out: &mut VecDeque<Frame<Bytes>>,
+    ) -> Result<(), Status>;
+
+    /// Called when the body failed, passing the error on unless the
+    /// protocol has a way to end the body with it.
+    #[allow(clippy::result_large_err)]
+    fn error(&mut self, e: Status, _out: &mut VecDeque<Frame<Bytes>>) -> Result<(), Status> {
+        Err(e)
+    }
+}
+
+struct Rewritten<R> {
+    body: Body,
+    rewrite: R,
+    out: VecDeque<Frame<Bytes>>,
+    ended: bool,
+}
+
+pub(crate) fn rewrite(body: Body, rewrite: impl Rewrite) -> Body {
+    Body::new(Rewritten {
+        body,
+        rewrite,
+        out: VecDeque::new(),
+        ended: false,
+    })
+}
+
+impl<R: Rewrite> hyper::body::Body for Rewritten<R> {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Status>>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(frame) = this.out.pop_front() {
+                return Poll::Ready(Some(Ok(frame)));
+            }
+            if this.ended {
+                return Poll::Ready(None);
+            }
+            let res = match ready!(Pin::new(&mut this.body).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => this.rewrite.data(data, &mut this.out),
+                    Err(frame) => match frame.into_trailers() {
+                        Ok(trailers) => {
+                            this.ended = true;
+                            this.rewrite.end(Some(trailers), &mut this.out)
+                        }
+                        Err(_) => Ok(()),
+                    },
+                },
+                Some(Err(e)) => {
+                    this.ended = true;
+                    this.rewrite.error(e, &mut this.out)
+                }
+                None => {
+                    this.ended = true;
+                    this.rewrite.end(None, &mut this.out)
+                }
+            };
+            if let Err(e) = res {
+                this.ended = true;
+                return Poll::Ready(Some(Err(e)));
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use http::{Request, Response};
+use tonic::Code;
+use tonic::body::Body;
+use tonic::transport::server::TcpConnectInfo;
+use tower::{Layer, Service};
+
+use super::body::{self, grpc_code};
+
+/// Logs one line per call once its response has been sent, with the method,
+/// gRPC code, latency, message bytes each way and the client address.
+///
+/// Calls ending `INTERNAL`, `UNKNOWN` or `DATA_LOSS` are logged at `warn`,
+/// everything else at `info`.
+#[derive(Clone, Default)]
+pub struct AccessLogLayer {}
+
+impl AccessLogLayer {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl<S> Layer<S> for AccessLogLayer {
+    type Service = AccessLog<S>;
+
+    fn layer(&self, inner: S) -> AccessLog<S> {
+        AccessLog { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct AccessLog<S> {
+    inner: S,
+}
+
+impl<S> Service<Request<Body>> for AccessLog<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let started = Instant::now();
+        let method = req.uri().path().to_string();
+        let peer = req
+            .extensions()
+            .get::<TcpConnectInfo>()
+            .and_then(TcpConnectInfo::remote_addr)
+            .map_or_else(|| "unknown".to_string(), |addr| addr.to_string());
+        let received = Arc::new(AtomicU64::new(0));
+        let req = req.map(|body| body::count(body, received.clone()));
+        let call = self.inner.call(req);
+
+        Box::pin(async move {
+            let res = call.await?;
+            let code = grpc_code(res.headers());
+            Ok(res.map(|body| {
+                body::observe(body, code, move |code, sent| {
+                    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+                    let received = received.load(Ordering::Relaxed);
+                    match code {
+                        Code::Internal | Code::Unknown | Code::DataLoss => tracing::warn!(
+                            %method, ?code, latency_ms, received, sent, %peer, "Call failed"
+                        ),
+                        _ => tracing::info!(
+                            %method, ?code, latency_ms, received, sent, %peer, "Call finished"
+                        ),
+                    }
+                })
+            }))
+        })
+    }
+}
@@ -0,0 +1,199 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
+
+use http::HeaderMap;
+use hyper::body::{Bytes, Frame, SizeHint};
+use tonic::Code;
+use tonic::body::Body;
+
+use crate::context::RequestContext;
+
+/// Keeps `guard` alive until `body` has been sent or dropped, so streaming
+/// responses count for as long as they run.
+pub(crate) fn hold<G: Send + Unpin + 'static>(body: Body, guard: G) -> Body {
+    Body::new(Holding {
+        inner: body,
+        _guard: guard,
+    })
+}
+
+struct Holding<G> {
+    inner: Body,
+    _guard: G,
+}
+
+impl<G: Unpin> hyper::body::Body for Holding<G> {
+    type Data = Bytes;
+    type Error = tonic::Status;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, tonic::Status>>> {
+        Pin::new(&mut self.get_mut().inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Polls `body` within `context`, so streaming responses see the context of
+/// their call for as long as they run.
+pub(crate) fn scoped(body: Body, context: RequestContext) -> Body {
+    Body::new(Scoped {
+        inner: body,
+        context,
+    })
+}
+
+struct Scoped {
+    inner: Body,
+    context: RequestContext,
+}
+
+impl hyper::body::Body for Scoped {
+    type Data = Bytes;
+    type Error = tonic::Status;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, tonic::Status>>> {
+        let this = self.get_mut();
+        this.context
+            .sync_scope(|| Pin::new(&mut this.inner).poll_frame(cx))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Calls `done` with the call's gRPC code and the data bytes sent once
+/// `body` ends with its trailers. A body dropped before then ends with
+/// `code`, the code from the response headers of a trailers-only response,
+/// or `CANCELLED`.
+pub(crate) fn observe(
+    body: Body,
+    code: Option<Code>,
+    done: impl FnOnce(Code, u64) + Send + 'static,
+) -> Body {
+    Body::new(Observed {
+        inner: body,
+        code,
+        bytes: 0,
+        done: Some(Box::new(done)),
+    })
+}
+
+/// The `grpc-status` in `headers`, if any.
+pub(crate) fn grpc_code(headers: &HeaderMap) -> Option<Code> {
+    headers
+        .get("grpc-status")
+        .map(|status| Code::from_bytes(status.as_bytes()))
+}
+
+struct Observed {
+    inner: Body,
+    code: Option<Code>,
+    bytes: u64,
+    done: Option<Box<dyn FnOnce(Code, u64) + Send>>,
+}
+
+impl Observed {
+    fn finish(&mut self, code: Code) {
+        if let Some(done) = self.done.take() {
+            done(code, self.bytes);
+        }
+    }
+}
+
+impl hyper::body::Body for Observed {
+    type Data = Bytes;
+    type Error = tonic::Status;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, tonic::Status>>> {
+        let this = self.get_mut();
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    this.bytes += data.len() as u64;
+                }
+                if let Some(trailers) = frame.trailers_ref() {
+                    let code = grpc_code(trailers).unwrap_or(Code::Unknown);
+                    this.finish(code);
+                }
+            }
+            Some(Err(status)) => this.finish(status.code()),
+            // a gRPC response has to end with a status
+            None => this.finish(this.code.unwrap_or(Code::Unknown)),
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for Observed {
+    fn drop(&mut self) {
+        self.finish(self.code.unwrap_or(Code::Cancelled));
+    }
+}
+
+/// Adds the data bytes read from `body` to `bytes`.
+pub(crate) fn count(body: Body, bytes: Arc<AtomicU64>) -> Body {
+    Body::new(Counted { inner: body, bytes })
+}
+
+struct Counted {
+    inner: Body,
+    bytes: Arc<AtomicU64>,
+}
+
+impl hyper::body::Body for Counted {
+    type Data = Bytes;
+    type Error = tonic::Status;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, tonic::Status>>> {
+        let this = self.get_mut();
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        if let Some(Ok(frame)) = &frame
+            && let Some(data) = frame.data_ref()
+        {
+            this.bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
@@ -0,0 +1,1204 @@
+use std::convert::Infallible;
+use std::future::{Future, pending};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use http::{HeaderName, Request, Response};
+use hyper_util::rt::TokioIo;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, watch};
+use tonic::body::Body;
+use tonic::service::Routes;
+use tonic_health::ServingStatus;
+use tonic_health::server::HealthReporter;
+use tower::{ServiceBuilder, ServiceExt};
+use tracing::Instrument;
+
+use super::access_log::AccessLogLayer;
+use super::body;
+use super::catch_panic::CatchPanicLayer;
+use super::compression::Compressed;
+use super::h2c::H2c;
+use super::limit::Limit;
+use super::listener::{Listener, Peer};
+use super::metrics::{self, ServerMetrics};
+use super::proxy::ProxyProtocol;
+use super::rpc_metrics::{RpcMetrics, RpcMetricsLayer};
+use super::web::Web;
+use crate::Error;
+use crate::compression::{Compression, CompressionMetrics};
+use crate::context::{self, RequestContext};
+use crate::deadline::{self, Deadline};
+use crate::tls::ServerTls;
+use tokio_rustls::TlsAcceptor;
+
+const DEFAULT_ADDR: ([u8; 4], u16) = ([0, 0, 0, 0], 8080);
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// gRPC server for tonic `Routes`, speaking h2c with prior knowledge or
+/// through an HTTP/1.1 upgrade.
+///
+/// Every server also serves `grpc.health.v1.Health`, reporting the whole
+/// server as serving until shutdown starts, and gRPC server reflection (v1
+/// and v1alpha) describing the health service and the file descriptor sets
+/// added with [`Server::with_file_descriptor_set`]. On
+/// shutdown the listeners are closed, open connections are sent a GOAWAY and
+/// given `shutdown_timeout` to finish their in-flight calls.
+///
+/// ```no_run
+/// # async fn run(routes: tonic::service::Routes) -> Result<(), f2_utils::Error> {
+/// f2_utils::server::Server::new(routes)
+///     .with_addr(([0, 0, 0, 0], 8080).into())
+///     .with_max_connections(1024)
+///     .serve()
+///     .await
+/// # }
+/// ```
+pub struct Server {
+    routes: Routes,
+    health: HealthReporter,
+    reflection: bool,
+    file_descriptor_sets: Vec<&'static [u8]>,
+    addr: SocketAddr,
+    listener: Option<TcpListener>,
+    unix_socket: Option<String>,
+    unix_permissions: Option<u32>,
+    max_connections: Option<usize>,
+    max_concurrent_streams: Option<u32>,
+    max_in_flight_requests: Option<usize>,
+    max_queued_requests: usize,
+    header_read_timeout: Option<Duration>,
+    keepalive: Option<(Duration, Duration)>,
+    idle_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    shutdown_timeout: Duration,
+    metrics: Option<(Registry, SocketAddr)>,
+    socket_activation: bool,
+    reuseport: Option<usize>,
+    tls: Option<ServerTls>,
+    proxy_protocol: Option<ProxyProtocol>,
+    web: Option<Web>,
+    compression: Option<Compression>,
+    rpc_middleware: bool,
+    forwarded_headers: Vec<HeaderName>,
+}
+
+/// Held by everything serving one accepted connection, including the task
+/// serving it after an h2c upgrade. The connection counts against the limits
+/// until the last holder is gone.
+pub(crate) struct ConnectionGuard {
+    shutdown: watch::Receiver<()>,
+    active: Gauge,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ConnectionGuard {
+    fn new(
+        shutdown: watch::Receiver<()>,
+        active: Gauge,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> Self {
+        active.inc();
+        Self {
+            shutdown,
+            active,
+            _permit: permit,
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.active.dec();
+    }
+}
+
+/// Resolves once the server owning the connection starts shutting down, or
+/// never for connections served outside a [`Server`].
+pub(crate) async fn shutdown_requested(guard: Option<&ConnectionGuard>) {
+    match guard {
+        Some(guard) => {
+            // a dropped sender means the server is gone, which counts too
+            let _ = guard.shutdown.clone().changed().await;
+        }
+        None => pending().await,
+    }
+}
+
+impl Server {
+    pub fn new(routes: Routes) -> Self {
+        let (health, health_service) = tonic_health::server::health_reporter();
+        Self {
+            routes: routes.add_service(health_service),
+            health,
+            reflection: true,
+            file_descriptor_sets: vec![tonic_health::pb::FILE_DESCRIPTOR_SET],
+            addr: DEFAULT_ADDR.into(),
+            listener: None,
+            unix_socket: None,
+            unix_permissions: None,
+            max_connections: None,
+            max_concurrent_streams: None,
+            max_in_flight_requests: None,
+            max_queued_requests: 0,
+            header_read_timeout: None,
+            keepalive: None,
+            idle_timeout: None,
+            request_timeout: None,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            metrics: None,
+            socket_activation: false,
+            reuseport: None,
+            tls: None,
+            proxy_protocol: None,
+            web: None,
+            compression: None,
+            rpc_middleware: false,
+            forwarded_headers: context::forwarded_headers(),
+        }
+    }
+
+    /// Address to listen on, `0.0.0.0:8080` by default.
+    pub fn with_addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    /// Serves on an already bound listener instead of binding the address.
+    pub fn with_listener(mut self, listener: TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Serves on the sockets systemd or listenfd pass down through
+    /// `LISTEN_FDS` when there are any, TCP or Unix, and binds as configured
+    /// otherwise.
+    pub fn with_socket_activation(mut self) -> Self {
+        self.socket_activation = true;
+        self
+    }
+
+    /// Binds `acceptors` listeners to the address with `SO_REUSEPORT`, each
+    /// accepting on its own task, so the kernel spreads connections across
+    /// them and the runtime's worker threads. A restarted server can bind the
+    /// same port while this one drains. Only applies when binding a TCP
+    /// address, not to a listener, a Unix socket or inherited sockets.
+    pub fn with_reuseport(mut self, acceptors: usize) -> Self {
+        self.reuseport = Some(acceptors);
+        self
+    }
+
+    /// Listens on a Unix socket instead of TCP. A path starting with `@`
+    /// names an abstract socket on Linux, which has no file.
+    pub fn with_unix_socket(mut self, path: impl Into<String>) -> Self {
+        self.unix_socket = Some(path.into());
+        self
+    }
+
+    /// Mode of the Unix socket file, e.g. `0o660` to let a group in.
+    pub fn with_unix_permissions(mut self, mode: u32) -> Self {
+        self.unix_permissions = Some(mode);
+        self
+    }
+
+    /// Stops accepting once `max` connections are open.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Streams a client may open on one HTTP/2 connection, advertised in
+    /// its SETTINGS.
+    pub fn with_max_concurrent_streams(mut self, max: u32) -> Self {
+        self.max_concurrent_streams = Some(max);
+        self
+    }
+
+    /// Calls served at once across all connections, at least one. Further
+    /// calls wait in a queue bounded by
+    /// [`with_max_queued_requests`](Self::with_max_queued_requests) and are
+    /// answered with `RESOURCE_EXHAUSTED` once it is full.
+    pub fn with_max_in_flight_requests(mut self, max: usize) -> Self {
+        self.max_in_flight_requests = Some(max.max(1));
+        self
+    }
+
+    /// Calls waiting for a slot before new ones are shed, none by default.
+    pub fn with_max_queued_requests(mut self, max: usize) -> Self {
+        self.max_queued_requests = max;
+        self
+    }
+
+    /// Time HTTP/1.1 clients get to send the headers of their upgrade
+    /// request, 30 seconds unless set.
+    pub fn with_header_read_timeout(mut self, timeout: Duration) -> Self {
+        self.header_read_timeout = Some(timeout);
+        self
+    }
+
+    /// Pings HTTP/2 clients every `interval` and drops connections whose
+    /// ping goes unanswered for `timeout`.
+    pub fn with_keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.keepalive = Some((interval, timeout));
+        self
+    }
+
+    /// Closes connections that had no call for `timeout`.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Answers calls taking longer than `timeout` with `DEADLINE_EXCEEDED`.
+    /// Calls with a shorter `grpc-timeout` are cut off at that instead.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// How long open connections get to finish after shutdown starts.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Adds the server's connection metrics to `registry` and serves it in
+    /// the Prometheus text format on `addr`.
+    pub fn with_metrics(mut self, registry: Registry, addr: SocketAddr) -> Self {
+        self.metrics = Some((registry, addr));
+        self
+    }
+
+    /// Serves over TLS instead of h2c, with `h2` and `http/1.1` offered over
+    /// ALPN.
+    pub fn with_tls(mut self, tls: ServerTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Expects a PROXY protocol header on every connection, from trusted
+    /// proxies only, and hands calls the client address it carries.
+    pub fn with_proxy_protocol(mut self, proxy_protocol: ProxyProtocol) -> Self {
+        self.proxy_protocol = Some(proxy_protocol);
+        self
+    }
+
+    /// Also serves Connect and gRPC-Web calls, for browsers and the Connect
+    /// clients generated for Kotlin and Swift. These may come as plain
+    /// HTTP/1.1 requests, which are then no longer asked to upgrade.
+    pub fn with_web(mut self, web: Web) -> Self {
+        self.web = Some(web);
+        self
+    }
+
+    /// Decompresses requests and compresses responses for clients sending
+    /// `grpc-accept-encoding`, recording compression ratios alongside the
+    /// other metrics.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Logs every call, records [`RpcMetrics`] by service and method under
+    /// the `f2_server` prefix and answers calls whose handler panics with
+    /// `INTERNAL`, see [`AccessLogLayer`], [`RpcMetricsLayer`] and
+    /// [`CatchPanicLayer`].
+    pub fn with_rpc_middleware(mut self) -> Self {
+        self.rpc_middleware = true;
+        self
+    }
+
+    /// Also carries `name` over from calls to the calls made while serving
+    /// them, on top of [`FORWARDED_HEADERS`](context::FORWARDED_HEADERS).
+    pub fn with_forwarded_header(mut self, name: HeaderName) -> Self {
+        self.forwarded_headers.push(name);
+        self
+    }
+
+    /// Turns gRPC server reflection on or off; it is on by default.
+    pub fn with_reflection(mut self, enabled: bool) -> Self {
+        self.reflection = enabled;
+        self
+    }
+
+    /// Describes the services of an encoded `FileDescriptorSet` over
+    /// reflection, on top of the health service. Servers of the f2 protos
+    /// pass [`descriptors::FILE_DESCRIPTOR_SET`](crate::descriptors::FILE_DESCRIPTOR_SET).
+    pub fn with_file_descriptor_set(mut self, file_descriptor_set: &'static [u8]) -> Self {
+        self.file_descriptor_sets.push(file_descriptor_set);
+        self
+    }
+
+    /// Reporter for the health service, e.g. to mark single services as not
+    /// serving.
+    pub fn health_reporter(&self) -> HealthReporter {
+        self.health.clone()
+    }
+
+    /// Serves until `SIGTERM` or `SIGINT`.
+    pub async fn serve(self) -> Result<(), Error> {
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        self.serve_with_shutdown(async move {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = interrupt.recv() => {}
+            }
+        })
+        .await
+    }
+
+    /// Serves until `signal` resolves.
+    pub async fn serve_with_shutdown(self, signal: impl Future<Output = ()>) -> Result<(), Error> {
+        if self.reuseport.is_some() && (self.listener.is_some() || self.unix_socket.is_some()) {
+            tracing::warn!("Ignoring SO_REUSEPORT acceptors, which only apply to a bound address");
+        }
+        let mut listeners = match self.listener {
+            Some(listener) => vec![Listener::Tcp(listener)],
+            None if self.socket_activation => Listener::inherited()?,
+            None => Vec::new(),
+        };
+        if listeners.is_empty() {
+            listeners = match (&self.unix_socket, self.reuseport) {
+                (Some(path), _) => vec![Listener::bind_unix(path, self.unix_permissions)?],
+                (None, Some(acceptors)) => Listener::bind_reuseport(self.addr, acceptors)?,
+                (None, None) => vec![Listener::Tcp(TcpListener::bind(self.addr).await?)],
+            };
+        }
+        for listener in &listeners {
+            tracing::info!("Serving gRPC on {}", listener);
+        }
+
+        let mut compression = self.compression;
+        let mut rpc_metrics = RpcMetrics::default();
+        let metrics = match self.metrics {
+            Some((mut registry, addr)) => {
+                let metrics = ServerMetrics::register(&mut registry);
+                compression = compression.map(|compression| {
+                    let registry = registry.sub_registry_with_prefix("f2_server");
+                    compression.with_metrics(CompressionMetrics::register(registry))
+                });
+                if self.rpc_middleware {
+                    let registry = registry.sub_registry_with_prefix("f2_server");
+                    rpc_metrics = RpcMetrics::register(registry);
+                }
+                let registry = Arc::new(registry);
+                tokio::spawn(async move {
+                    if let Err(e) = metrics::serve(registry, addr).await {
+                        tracing::error!("Metrics server stopped: {}", e);
+                    }
+                });
+                metrics
+            }
+            None => ServerMetrics::default(),
+        };
+
+        let acceptor = self.tls.as_ref().map(ServerTls::acceptor).transpose()?;
+        let proxy = self.proxy_protocol.map(Arc::new);
+        let limit = self
+            .max_connections
+            .map(|max| Arc::new(Semaphore::new(max)));
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        let mut routes = self.routes;
+        if self.reflection {
+            routes = add_reflection(routes, &self.file_descriptor_sets)?;
+        }
+        let middleware = self.rpc_middleware;
+        let svc = ServiceBuilder::new()
+            .option_layer(middleware.then(AccessLogLayer::new))
+            .option_layer(middleware.then(|| RpcMetricsLayer::new(rpc_metrics)))
+            .option_layer(middleware.then(CatchPanicLayer::new))
+            .service(traced(
+                routes.prepare(),
+                self.request_timeout,
+                self.forwarded_headers.into(),
+            ));
+        let svc = Compressed::new(svc, compression);
+        let mut svc = H2c::new(svc);
+        if let Some(max) = self.max_concurrent_streams {
+            svc = svc.with_max_concurrent_streams(max);
+        }
+        if let Some(timeout) = self.header_read_timeout {
+            svc = svc.with_header_read_timeout(timeout);
+        }
+        if let Some((interval, timeout)) = self.keepalive {
+            svc = svc.with_keepalive(interval, timeout);
+        }
+        if let Some(timeout) = self.idle_timeout {
+            svc = svc.with_idle_timeout(timeout);
+        }
+        if let Some(web) = self.web {
+            svc = svc.with_web(web);
+        }
+        if let Some(max) = self.max_in_flight_requests {
+            let limit = Limit::new(max, self.max_queued_requests, metrics.clone());
+            svc = svc.with_limit(Arc::new(limit));
+        }
+        let connections = Connections {
+            svc,
+            tls: acceptor,
+            proxy,
+            limit,
+            metrics,
+            shutdown: shutdown_rx,
+        };
+        let (stop_tx, stop_rx) = watch::channel(());
+        let acceptors: Vec<_> = listeners
+            .into_iter()
+            .map(|listener| tokio::spawn(connections.clone().accept(listener, stop_rx.clone())))
+            .collect();
+        drop(connections);
+        signal.await;
+
+        tracing::info!("Shutting down, draining open connections");
+        self.health
+            .set_service_status("", ServingStatus::NotServing)
+            .await;
+        let _ = stop_tx.send(());
+        for acceptor in acceptors {
+            let _ = acceptor.await;
+        }
+
+        let _ = shutdown_tx.send(());
+        if tokio::time::timeout(self.shutdown_timeout, shutdown_tx.closed())
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                "Closing {} connections still open after the shutdown timeout",
+                shutdown_tx.receiver_count()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// What serving the connections a listener accepts takes, shared by all
+/// acceptors of a server.
+#[derive(Clone)]
+struct Connections<S> {
+    svc: H2c<S>,
+    tls: Option<TlsAcceptor>,
+    proxy: Option<Arc<ProxyProtocol>>,
+    limit: Option<Arc<Semaphore>>,
+    metrics: ServerMetrics,
+    shutdown: watch::Receiver<()>,
+}
+
+impl<S> Connections<S>
+where
+    S: tower::Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>> + 'static,
+{
+    /// Accepts connections off `listener` until `stop` changes, then closes
+    /// the listener.
+    async fn accept(self, listener: Listener, mut stop: watch::Receiver<()>) {
+        loop {
+            let permit = match &self.limit {
+                Some(limit) => tokio::select! {
+                    permit = limit.clone().acquire_owned() => {
+                        Some(permit.expect("connection limit is never closed"))
+                    }
+                    _ = stop.changed() => break,
+                },
+                None => None,
+            };
+            let (mut io, peer) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tracing::warn!("Error accepting connection: {}", e);
+                        continue;
+                    }
+                },
+                _ = stop.changed() => break,
+            };
+
+            if let Some(proxy) = &self.proxy
+                && !proxy.trusts(&peer)
+            {
+                tracing::warn!("Rejecting connection from untrusted proxy {}", peer);
+                continue;
+            }
+
+            self.metrics.connections.inc();
+            let guard = ConnectionGuard::new(
+                self.shutdown.clone(),
+                self.metrics.active_connections.clone(),
+                permit,
+            );
+            let mut svc = self.svc.clone().with_guard(Arc::new(guard));
+            if let Peer::Tcp(addr) = peer {
+                svc = svc.with_peer(addr);
+            }
+            if let Some(addr) = io.local_addr() {
+                svc = svc.with_local_addr(addr);
+            }
+            let tls = self.tls.clone();
+            let proxy = self.proxy.clone();
+            tokio::spawn(
+                async move {
+                    if let Some(proxy) = proxy {
+                        match proxy.read_header(&mut io).await {
+                            Ok(Some(proxied)) => {
+                                svc = svc
+                                    .with_peer(proxied.source)
+                                    .with_local_addr(proxied.destination);
+                            }
+                            Ok(None) => {}
+                            Err(e) => {
+                                tracing::debug!("Closing connection from {}: {}", peer, e);
+                                return;
+                            }
+                        }
+                    }
+                    let served = match tls {
+                        Some(tls) => match tls.accept(io).await {
+                            Ok(io) => svc.serve_connection(TokioIo::new(io)).await,
+                            Err(e) => {
+                                tracing::debug!("TLS handshake with {} failed: {}", peer, e);
+                                return;
+                            }
+                        },
+                        None => svc.serve_connection(TokioIo::new(io)).await,
+                    };
+                    if let Err(e) = served {
+                        tracing::debug!("Connection from {} closed with error: {}", peer, e);
+                    }
+                }
+                .instrument(tracing::debug_span!("connection", %peer)),
+            );
+        }
+    }
+}
+
+/// Adds both versions of the reflection service, describing `sets`.
+fn add_reflection(routes: Routes, sets: &[&'static [u8]]) -> Result<Routes, Error> {
+    let builder = || {
+        sets.iter().fold(
+            tonic_reflection::server::Builder::configure(),
+            |builder, set| builder.register_encoded_file_descriptor_set(set),
+        )
+    };
+    Ok(routes
+        .add_service(builder().build_v1()?)
+        .add_service(builder().build_v1alpha()?))
+}
+
+/// Runs every call in a span and bounds it by the shorter of `timeout` and
+/// its `grpc-timeout`, passing the deadline on in the request extensions and,
+/// with the `forwarded` headers, in the call's [`RequestContext`]. The
+/// response body is polled in that context too.
+fn traced(
+    routes: Routes,
+    timeout: Option<Duration>,
+    forwarded: Arc<[HeaderName]>,
+) -> impl tower::Service<
+    Request<Body>,
+    Response = Response<Body>,
+    Error = Infallible,
+    Future = impl Future<Output = Result<Response<Body>, Infallible>> + Send,
+> + Clone
++ Send
++ 'static {
+    tower::service_fn(move |mut req: Request<Body>| {
+        let span = tracing::info_span!("request", method = %req.method(), path = req.uri().path());
+        let requested = req
+            .headers()
+            .get(deadline::GRPC_TIMEOUT)
+            .and_then(deadline::parse);
+        let deadline = requested
+            .into_iter()
+            .chain(timeout)
+            .min()
+            .map(Deadline::after);
+        if let Some(deadline) = deadline {
+            req.extensions_mut().insert(deadline);
+        }
+        let context = RequestContext::from_request(&req, &forwarded);
+        let call = context.clone().scope(routes.clone().oneshot(req));
+        async move {
+            let res = match deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline.instant(), call).await {
+                    Ok(res) => res?,
+                    Err(_) => tonic::Status::deadline_exceeded("request timed out").into_http(),
+                },
+                None => call.await?,
+            };
+            Ok(res.map(|body| body::scoped(body, context)))
+        }
+        .instrument(span)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::h2c::{H2cChannel, H2cMode};
+    use http_body_util::{BodyExt, StreamBody};
+    use hyper::body::{Bytes, Frame};
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+    use tokio_stream::StreamExt;
+    use tonic::server::NamedService;
+    use tonic::transport::server::TcpConnectInfo;
+    use tonic_health::pb::health_client::HealthClient;
+    use tonic_health::pb::{HealthCheckRequest, health_check_response};
+    use tonic_reflection::pb::v1::server_reflection_client::ServerReflectionClient;
+    use tonic_reflection::pb::v1::server_reflection_request::MessageRequest;
+    use tonic_reflection::pb::v1::server_reflection_response::MessageResponse;
+    use tonic_reflection::pb::v1::{ServerReflectionRequest, ServerReflectionResponse};
+    use tonic_reflection::pb::v1alpha;
+
+    /// Answers every call after a delay.
+    #[derive(Clone)]
+    struct Slow(Duration);
+
+    impl NamedService for Slow {
+        const NAME: &'static str = "f2.test.v1.Slow";
+    }
+
+    impl tower::Service<Request<Body>> for Slow {
+        type Response = Response<Body>;
+        type Error = Infallible;
+        type Future =
+            std::pin::Pin<Box<dyn Future<Output = Result<Response<Body>, Infallible>> + Send>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Request<Body>) -> Self::Future {
+            let delay = self.0;
+            Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                Ok(tonic::Status::ok("").into_http())
+            })
+        }
+    }
+
+    /// Answers with the client address the call was handed.
+    #[derive(Clone)]
+    struct Whoami;
+
+    impl NamedService for Whoami {
+        const NAME: &'static str = "f2.test.v1.Whoami";
+    }
+
+    impl tower::Service<Request<Body>> for Whoami {
+        type Response = Response<Body>;
+        type Error = Infallible;
+        type Future = std::future::Ready<Result<Response<Body>, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request<Body>) -> Self::Future {
+            let info = req.extensions().get::<TcpConnectInfo>().unwrap();
+            let mut res = tonic::Status::ok("").into_http();
+            let remote_addr = info.remote_addr.unwrap().to_string();
+            res.headers_mut()
+                .insert("x-remote-addr", remote_addr.parse().unwrap());
+            std::future::ready(Ok(res))
+        }
+    }
+
+    /// Panics on every call.
+    #[derive(Clone)]
+    struct Panics;
+
+    impl NamedService for Panics {
+        const NAME: &'static str = "f2.test.v1.Panics";
+    }
+
+    impl tower::Service<Request<Body>> for Panics {
+        type Response = Response<Body>;
+        type Error = Infallible;
+        type Future = std::future::Ready<Result<Response<Body>, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Request<Body>) -> Self::Future {
+            panic!("handler bug")
+        }
+    }
+
+    /// Answers with the request ID and deadline of its [`RequestContext`].
+    #[derive(Clone)]
+    struct EchoContext;
+
+    impl NamedService for EchoContext {
+        const NAME: &'static str = "f2.test.v1.EchoContext";
+    }
+
+    impl tower::Service<Request<Body>> for EchoContext {
+        type Response = Response<Body>;
+        type Error = Infallible;
+        type Future =
+            std::pin::Pin<Box<dyn Future<Output = Result<Response<Body>, Infallible>> + Send>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Request<Body>) -> Self::Future {
+            Box::pin(async {
+                let context = RequestContext::current().unwrap();
+                let mut res = tonic::Status::ok("").into_http();
+                res.headers_mut().extend(context.headers().clone());
+                if let Some(deadline) = context.deadline() {
+                    let remaining = deadline::encode(deadline.remaining());
+                    res.headers_mut().insert("x-remaining", remaining);
+                }
+                Ok(res)
+            })
+        }
+    }
+
+    /// Streams the request ID of its [`RequestContext`] once the response
+    /// headers are sent.
+    #[derive(Clone)]
+    struct StreamContext;
+
+    impl NamedService for StreamContext {
+        const NAME: &'static str = "f2.test.v1.StreamContext";
+    }
+
+    impl tower::Service<Request<Body>> for StreamContext {
+        type Response = Response<Body>;
+        type Error = Infallible;
+        type Future = std::future::Ready<Result<Response<Body>, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Request<Body>) -> Self::Future {
+            let frames = tokio_stream::iter([false, true]).map(|trailers| {
+                if trailers {
+                    let mut trailers = http::HeaderMap::new();
+                    trailers.insert("grpc-status", http::HeaderValue::from_static("0"));
+                    return Ok::<_, Infallible>(Frame::trailers(trailers));
+                }
+                let id = RequestContext::current()
+                    .and_then(|context| context.headers().get("x-request-id").cloned());
+                Ok(Frame::data(Bytes::copy_from_slice(
+                    id.as_ref().map_or(&b"none"[..], |id| id.as_bytes()),
+                )))
+            });
+            let res = Response::builder()
+                .header("content-type", "application/grpc")
+                .body(Body::new(StreamBody::new(frames)))
+                .unwrap();
+            std::future::ready(Ok(res))
+        }
+    }
+
+    async fn start(
+        server: Server,
+    ) -> (
+        SocketAddr,
+        oneshot::Sender<()>,
+        JoinHandle<Result<(), Error>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = oneshot::channel();
+        let server = server.with_listener(listener).serve_with_shutdown(async {
+            let _ = stopped.await;
+        });
+        (addr, stop, tokio::spawn(server))
+    }
+
+    /// Calls the slow service and returns its `grpc-status`.
+    async fn call(addr: SocketAddr) -> Result<String, Error> {
+        let channel = H2cChannel::new(Client::builder(TokioExecutor::new()).build_http())
+            .with_mode(H2cMode::PriorKnowledge);
+        let req = Request::builder()
+            .method("POST")
+            .uri(format!("http://{addr}/f2.test.v1.Slow/Call"))
+            .header("content-type", "application/grpc")
+            .body(Body::empty())
+            .unwrap();
+        let res = channel.oneshot(req).await?;
+        Ok(res.headers()["grpc-status"].to_str().unwrap().to_string())
+    }
+
+    /// Asks the v1 reflection service a single question.
+    async fn reflect(
+        addr: SocketAddr,
+        request: MessageRequest,
+    ) -> Result<MessageResponse, tonic::Code> {
+        let channel = tonic::transport::Endpoint::from_shared(format!("http://{addr}"))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let req = ServerReflectionRequest {
+            host: String::new(),
+            message_request: Some(request),
+        };
+        let mut res = ServerReflectionClient::new(channel)
+            .server_reflection_info(tokio_stream::once(req))
+            .await
+            .map_err(|status| status.code())?
+            .into_inner();
+        let res: ServerReflectionResponse = res.message().await.unwrap().unwrap();
+        Ok(res.message_response.unwrap())
+    }
+
+    #[tokio::test]
+    async fn describes_services_over_reflection() {
+        let server = Server::new(Routes::new(Slow(Duration::ZERO)))
+            .with_file_descriptor_set(crate::descriptors::FILE_DESCRIPTOR_SET);
+        let (addr, stop, serving) = start(server).await;
+
+        let Ok(MessageResponse::ListServicesResponse(list)) =
+            reflect(addr, MessageRequest::ListServices(String::new())).await
+        else {
+            panic!("expected a list of services");
+        };
+        let names: Vec<_> = list.service.into_iter().map(|s| s.name).collect();
+        for name in [
+            "grpc.health.v1.Health",
+            "grpc.reflection.v1.ServerReflection",
+            "f2.users.v1.Users",
+        ] {
+            assert!(
+                names.iter().any(|n| n == name),
+                "{name} missing from {names:?}"
+            );
+        }
+
+        let symbol = MessageRequest::FileContainingSymbol("f2.users.v1.Users".into());
+        let Ok(MessageResponse::FileDescriptorResponse(files)) = reflect(addr, symbol).await else {
+            panic!("expected file descriptors");
+        };
+        assert!(!files.file_descriptor_proto.is_empty());
+
+        let channel = tonic::transport::Endpoint::from_shared(format!("http://{addr}"))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let req = v1alpha::ServerReflectionRequest {
+            host: String::new(),
+            message_request: Some(
+                v1alpha::server_reflection_request::MessageRequest::ListServices(String::new()),
+            ),
+        };
+        let mut res = v1alpha::server_reflection_client::ServerReflectionClient::new(channel)
+            .server_reflection_info(tokio_stream::once(req))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(
+            res.message()
+                .await
+                .unwrap()
+                .unwrap()
+                .message_response
+                .is_some()
+        );
+
+        stop.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn describes_only_the_registered_services() {
+        let server = Server::new(Routes::new(Slow(Duration::ZERO)));
+        let (addr, stop, serving) = start(server).await;
+
+        let Ok(MessageResponse::ListServicesResponse(list)) =
+            reflect(addr, MessageRequest::ListServices(String::new())).await
+        else {
+            panic!("expected a list of services");
+        };
+        let names: Vec<_> = list.service.into_iter().map(|s| s.name).collect();
+        assert!(
+            names.iter().any(|n| n == "grpc.health.v1.Health"),
+            "{names:?}"
+        );
+        assert!(!names.iter().any(|n| n.starts_with("f2.")), "{names:?}");
+
+        stop.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn reflection_can_be_turned_off() {
+        let server = Server::new(Routes::new(Slow(Duration::ZERO))).with_reflection(false);
+        let (addr, stop, serving) = start(server).await;
+
+        let code = reflect(addr, MessageRequest::ListServices(String::new()))
+            .await
+            .unwrap_err();
+        assert_eq!(code, tonic::Code::Unimplemented);
+
+        stop.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_health_and_drains_on_shutdown() {
+        let server = Server::new(Routes::new(Slow(Duration::from_millis(200))));
+        let (addr, stop, serving) = start(server).await;
+
+        let channel = tonic::transport::Endpoint::from_shared(format!("http://{addr}"))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut health = HealthClient::new(channel);
+        let res = health.check(HealthCheckRequest::default()).await.unwrap();
+        assert_eq!(
+            res.into_inner().status(),
+            health_check_response::ServingStatus::Serving
+        );
+
+        let in_flight = tokio::spawn(call(addr));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stop.send(()).unwrap();
+
+        assert_eq!(in_flight.await.unwrap().unwrap(), "0");
+        serving.await.unwrap().unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn limits_open_connections() {
+        let server = Server::new(Routes::new(Slow(Duration::ZERO))).with_max_connections(1);
+        let (addr, _stop, _serving) = start(server).await;
+
+        let idle = TcpStream::connect(addr).await.unwrap();
+        let waiting = tokio::spawn(call(addr));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+
+        drop(idle);
+        assert_eq!(waiting.await.unwrap().unwrap(), "0");
+    }
+
+    #[tokio::test]
+    async fn queues_and_sheds_calls_over_the_limit() {
+        let server = Server::new(Routes::new(Slow(Duration::from_millis(200))))
+            .with_max_in_flight_requests(1)
+            .with_max_queued_requests(1);
+        let (addr, _stop, _serving) = start(server).await;
+
+        let calls: Vec<_> = (0..3).map(|_| tokio::spawn(call(addr))).collect();
+        let mut statuses = Vec::new();
+        for call in calls {
+            statuses.push(call.await.unwrap().unwrap());
+        }
+        statuses.sort();
+        let exhausted = (tonic::Code::ResourceExhausted as i32).to_string();
+        assert_eq!(statuses, ["0", "0", exhausted.as_str()]);
+    }
+
+    #[tokio::test]
+    async fn sheds_web_calls_without_reading_their_body() {
+        let server = Server::new(Routes::new(Slow(Duration::from_secs(1))))
+            .with_web(crate::server::web::Web::new())
+            .with_max_in_flight_requests(1);
+        let (addr, _stop, _serving) = start(server).await;
+        let busy = tokio::spawn(call(addr));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // a body that never ends, which would hold the call if it were read
+        let body = StreamBody::new(tokio_stream::pending::<Result<Frame<Bytes>, Infallible>>());
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        let req = Request::post(format!("http://{addr}/f2.test.v1.Slow/Call"))
+            .header("content-type", "application/proto")
+            .body(body)
+            .unwrap();
+        let res = tokio::time::timeout(Duration::from_millis(500), client.request(req))
+            .await
+            .expect("shed call was not answered")
+            .unwrap();
+        assert_eq!(res.status(), http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(busy.await.unwrap().unwrap(), "0");
+    }
+
+    #[tokio::test]
+    async fn serves_one_call_at_a_time_at_limit_zero() {
+        let server = Server::new(Routes::new(Slow(Duration::ZERO))).with_max_in_flight_requests(0);
+        let (addr, _stop, _serving) = start(server).await;
+        assert_eq!(call(addr).await.unwrap(), "0");
+    }
+
+    #[tokio::test]
+    async fn honours_grpc_timeout() {
+        let server = Server::new(Routes::new(Slow(Duration::from_secs(5))))
+            .with_request_timeout(Duration::from_secs(10));
+        let (addr, _stop, _serving) = start(server).await;
+
+        // a bare connection, since gRPC clients enforce the deadline themselves
+        let io = TokioIo::new(TcpStream::connect(addr).await.unwrap());
+        let (mut send, conn) = hyper::client::conn::http2::handshake(TokioExecutor::new(), io)
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+        let req = Request::builder()
+            .method("POST")
+            .uri(format!("http://{addr}/f2.test.v1.Slow/Call"))
+            .header("content-type", "application/grpc")
+            .header("grpc-timeout", "50m")
+            .body(Body::empty())
+            .unwrap();
+        let res = send.send_request(req).await.unwrap();
+        let status = res.headers()["grpc-status"].to_str().unwrap().to_string();
+        assert_eq!(status, (tonic::Code::DeadlineExceeded as i32).to_string());
+    }
+
+    #[tokio::test]
+    async fn closes_idle_and_silent_connections() {
+        let server = Server::new(Routes::default())
+            .with_idle_timeout(Duration::from_millis(100))
+            .with_header_read_timeout(Duration::from_millis(100));
+        let (addr, _stop, _serving) = start(server).await;
+
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+        silent.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+
+        for io in [&mut idle, &mut silent] {
+            let mut buf = Vec::new();
+            let read = tokio::time::timeout(Duration::from_secs(2), io.read_to_end(&mut buf));
+            assert!(read.await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn times_out_slow_calls() {
+        let server = Server::new(Routes::new(Slow(Duration::from_secs(5))))
+            .with_request_timeout(Duration::from_millis(50));
+        let (addr, _stop, _serving) = start(server).await;
+
+        let status = call(addr).await.unwrap();
+        assert_eq!(status, (tonic::Code::DeadlineExceeded as i32).to_string());
+    }
+
+    #[tokio::test]
+    async fn takes_client_addresses_from_trusted_proxies() {
+        let proxy = "127.0.0.0/8".parse().unwrap();
+        let server = Server::new(Routes::new(Whoami)).with_proxy_protocol(proxy);
+        let (addr, _stop, _serving) = start(server).await;
+
+        let mut io = TcpStream::connect(addr).await.unwrap();
+        io.write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 8080\r\n")
+            .await
+            .unwrap();
+        let (mut send, conn) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(io))
+                .await
+                .unwrap();
+        tokio::spawn(conn);
+        let req = Request::builder()
+            .method("POST")
+            .uri(format!("http://{addr}/f2.test.v1.Whoami/Call"))
+            .header("content-type", "application/grpc")
+            .body(Body::empty())
+            .unwrap();
+        let res = send.send_request(req).await.unwrap();
+        assert_eq!(res.headers()["x-remote-addr"], "203.0.113.7:51234");
+    }
+
+    #[tokio::test]
+    async fn rejects_untrusted_proxies() {
+        let proxy = "10.0.0.0/8".parse().unwrap();
+        let server = Server::new(Routes::new(Whoami)).with_proxy_protocol(proxy);
+        let (addr, _stop, _serving) = start(server).await;
+
+        let mut io = TcpStream::connect(addr).await.unwrap();
+        let _ = io
+            .write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 8080\r\n")
+            .await;
+        let mut buf = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(2), io.read_to_end(&mut buf));
+        assert!(read.await.is_ok());
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_panics_with_internal_and_keeps_the_connection() {
+        let server = Server::new(Routes::new(Panics)).with_rpc_middleware();
+        let (addr, _stop, _serving) = start(server).await;
+
+        let io = TokioIo::new(TcpStream::connect(addr).await.unwrap());
+        let (mut send, conn) = hyper::client::conn::http2::handshake(TokioExecutor::new(), io)
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+        for _ in 0..2 {
+            let req = Request::builder()
+                .method("POST")
+                .uri(format!("http://{addr}/f2.test.v1.Panics/Call"))
+                .header("content-type", "application/grpc")
+                .body(Body::empty())
+                .unwrap();
+            let res = send.send_request(req).await.unwrap();
+            let status = res.headers()["grpc-status"].to_str().unwrap().to_string();
+            assert_eq!(status, (tonic::Code::Internal as i32).to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_handlers_in_their_request_context() {
+        let server = Server::new(Routes::new(EchoContext))
+            .with_forwarded_header(HeaderName::from_static("x-tenant"));
+        let (addr, _stop, _serving) = start(server).await;
+
+        let channel = H2cChannel::new(Client::builder(TokioExecutor::new()).build_http())
+            .with_mode(H2cMode::PriorKnowledge);
+        let req = Request::builder()
+            .method("POST")
+            .uri(format!("http://{addr}/f2.test.v1.EchoContext/Call"))
+            .header("content-type", "application/grpc")
+            .header("grpc-timeout", "5S")
+            .header("x-request-id", "req-123")
+            .header("x-tenant", "f2")
+            .header("authorization", "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let res = channel.oneshot(req).await.unwrap();
+        assert_eq!(res.headers()["x-request-id"], "req-123");
+        assert_eq!(res.headers()["x-tenant"], "f2");
+        assert!(res.headers().get("authorization").is_none());
+        let remaining = deadline::parse(&res.headers()["x-remaining"]).unwrap();
+        assert!(remaining <= Duration::from_secs(5) && remaining > Duration::from_secs(4));
+    }
+
+    #[tokio::test]
+    async fn streams_responses_in_their_request_context() {
+        let (addr, _stop, _serving) = start(Server::new(Routes::new(StreamContext))).await;
+
+        let channel = H2cChannel::new(Client::builder(TokioExecutor::new()).build_http())
+            .with_mode(H2cMode::PriorKnowledge);
+        let req = Request::builder()
+            .method("POST")
+            .uri(format!("http://{addr}/f2.test.v1.StreamContext/Call"))
+            .header("content-type", "application/grpc")
+            .header("x-request-id", "req-123")
+            .body(Body::empty())
+            .unwrap();
+        let res = channel.oneshot(req).await.unwrap();
+        let body = res.into_body().collect().await.unwrap();
+        assert_eq!(body.trailers().unwrap()["grpc-status"], "0");
+        assert_eq!(body.to_bytes(), "req-123");
+    }
+}
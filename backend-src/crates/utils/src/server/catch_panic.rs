@@ -0,0 +1,225 @@
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::future::Future;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::pin::Pin;
+use std::sync::Once;
+use std::task::{Context, Poll};
+
+use http::{HeaderMap, Request, Response};
+use hyper::body::{Bytes, Frame, SizeHint};
+use tonic::Status;
+use tonic::body::Body;
+use tower::{Layer, Service};
+
+thread_local! {
+    /// Backtrace of the last panic on this thread, taken by the panic hook.
+    static BACKTRACE: RefCell<Option<Backtrace>> = const { RefCell::new(None) };
+}
+
+static HOOK: Once = Once::new();
+
+/// Chains a panic hook that keeps the backtrace of every panic for
+/// [`CatchPanic`] to log, since the unwound stack is gone once it is caught.
+fn install_hook() {
+    HOOK.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            BACKTRACE.set(Some(Backtrace::force_capture()));
+            previous(info);
+        }));
+    });
+}
+
+/// Logs a caught panic with its message and backtrace.
+fn log_panic(path: &str, payload: Box<dyn Any + Send>) {
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("non-string panic payload");
+    match BACKTRACE.take() {
+        Some(backtrace) => {
+            tracing::error!("Call to {} panicked: {}\n{}", path, message, backtrace)
+        }
+        None => tracing::error!("Call to {} panicked: {}", path, message),
+    }
+}
+
+/// The status a call ends with after a panic. The message stays on the
+/// server.
+fn internal() -> Status {
+    Status::internal("internal error")
+}
+
+/// Turns a panic in a handler, while answering a call or streaming its
+/// response, into an `INTERNAL` status for the caller and an error log with
+/// the backtrace, instead of a connection torn down without a word.
+///
+/// Installs a panic hook chained in front of the existing one the first time
+/// a layer is built.
+#[derive(Clone)]
+pub struct CatchPanicLayer {}
+
+impl CatchPanicLayer {
+    pub fn new() -> Self {
+        install_hook();
+        Self {}
+    }
+}
+
+impl Default for CatchPanicLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Layer<S> for CatchPanicLayer {
+    type Service = CatchPanic<S>;
+
+    fn layer(&self, inner: S) -> CatchPanic<S> {
+        CatchPanic { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct CatchPanic<S> {
+    inner: S,
+}
+
+impl<S> Service<Request<Body>> for CatchPanic<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let path = req.uri().path().to_string();
+        let mut call = match catch_unwind(AssertUnwindSafe(|| self.inner.call(req))) {
+            Ok(call) => Box::pin(call),
+            Err(payload) => {
+                log_panic(&path, payload);
+                return Box::pin(async { Ok(internal().into_http()) });
+            }
+        };
+
+        Box::pin(std::future::poll_fn(move |cx| {
+            match catch_unwind(AssertUnwindSafe(|| call.as_mut().poll(cx))) {
+                Ok(Poll::Ready(Ok(res))) => Poll::Ready(Ok(res.map(|body| {
+                    Body::new(Caught {
+                        inner: body,
+                        path: path.clone(),
+                        done: false,
+                    })
+                }))),
+                Ok(polled) => polled,
+                Err(payload) => {
+                    log_panic(&path, payload);
+                    Poll::Ready(Ok(internal().into_http()))
+                }
+            }
+        }))
+    }
+}
+
+/// Response body ending with `INTERNAL` trailers when a stream panics.
+struct Caught {
+    inner: Body,
+    path: String,
+    done: bool,
+}
+
+impl hyper::body::Body for Caught {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Status>>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+        match catch_unwind(AssertUnwindSafe(|| {
+            Pin::new(&mut this.inner).poll_frame(cx)
+        })) {
+            Ok(polled) => polled,
+            Err(payload) => {
+                log_panic(&this.path, payload);
+                this.done = true;
+                let mut trailers = HeaderMap::new();
+                internal()
+                    .add_header(&mut trailers)
+                    .expect("static status headers are valid");
+                Poll::Ready(Some(Ok(Frame::trailers(trailers))))
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done || self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use http_body_util::{BodyExt, StreamBody};
+    use tokio_stream::StreamExt;
+    use tonic::Code;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::server::body::grpc_code;
+
+    fn request() -> Request<Body> {
+        Request::builder()
+            .uri("/f2.test.v1.Test/Panic")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_panicking_calls_with_internal() {
+        let svc = CatchPanicLayer::new().layer(tower::service_fn(|_: Request<Body>| async {
+            if true {
+                panic!("handler bug");
+            }
+            Ok::<_, Infallible>(Status::ok("").into_http())
+        }));
+        let res = svc.oneshot(request()).await.unwrap();
+        assert_eq!(grpc_code(res.headers()), Some(Code::Internal));
+    }
+
+    #[tokio::test]
+    async fn ends_panicking_streams_with_internal() {
+        let svc = CatchPanicLayer::new().layer(tower::service_fn(|_: Request<Body>| async {
+            let frames = tokio_stream::iter([0, 1]).map(|i| {
+                if i == 1 {
+                    panic!("stream bug");
+                }
+                Ok::<_, Infallible>(Frame::data(Bytes::from_static(b"message")))
+            });
+            Ok::<_, Infallible>(Response::new(Body::new(StreamBody::new(frames))))
+        }));
+        let res = svc.oneshot(request()).await.unwrap();
+        let collected = res.into_body().collect().await.unwrap();
+        let trailers = collected.trailers().cloned().unwrap();
+        assert_eq!(grpc_code(&trailers), Some(Code::Internal));
+        assert_eq!(collected.to_bytes(), "message");
+    }
+}
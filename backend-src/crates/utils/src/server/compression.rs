@@ -0,0 +1,201 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use http::{HeaderValue, Request, Response};
+use tonic::body::Body;
+use tower::Service;
+
+use crate::compression::{ACCEPT_ENCODING, Compression, ENCODING};
+use crate::rewrite::rewrite;
+
+/// Decompresses requests and compresses responses in an encoding the
+/// client accepts, or passes calls through without a [`Compression`].
+#[derive(Clone)]
+pub(crate) struct Compressed<S> {
+    inner: S,
+    compression: Option<Arc<Compression>>,
+}
+
+impl<S> Compressed<S> {
+    pub(crate) fn new(inner: S, compression: Option<Compression>) -> Self {
+        Self {
+            inner,
+            compression: compression.map(Arc::new),
+        }
+    }
+}
+
+impl<S> Service<Request<Body>> for Compressed<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let Some(compression) = self.compression.clone() else {
+            return Box::pin(self.inner.call(req));
+        };
+        let accept_encoding = compression.accept_encoding();
+
+        let (mut parts, body) = req.into_parts();
+        let received = match parts.headers.remove(ENCODING) {
+            Some(name) => match compression.receive(&name) {
+                Ok(encoding) => encoding,
+                Err(status) => {
+                    let mut res = status.into_http();
+                    res.headers_mut().insert(ACCEPT_ENCODING, accept_encoding);
+                    return Box::pin(async move { Ok(res) });
+                }
+            },
+            None => None,
+        };
+        let body = match received {
+            Some(_) => rewrite(body, compression.decompress(received)),
+            None => body,
+        };
+        let sent = compression.send(parts.uri.path(), Some(&parts.headers));
+        let call = self.inner.call(Request::from_parts(parts, body));
+
+        Box::pin(async move {
+            let res = call.await?;
+            let (mut parts, body) = res.into_parts();
+            parts.headers.insert(ACCEPT_ENCODING, accept_encoding);
+            // trailers-only responses have no messages, and services may
+            // have compressed their own
+            let compress =
+                !parts.headers.contains_key("grpc-status") && !parts.headers.contains_key(ENCODING);
+            let body = match sent {
+                Some(encoding) if compress => {
+                    parts
+                        .headers
+                        .insert(ENCODING, HeaderValue::from_static(encoding.name()));
+                    rewrite(body, compression.compress(encoding))
+                }
+                _ => body,
+            };
+            Ok(Response::from_parts(parts, body))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use http_body_util::{BodyExt, Full};
+    use hyper::body::Bytes;
+    use tonic::Code;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::client::methods::Methods;
+    use crate::compression::Encoding;
+    use crate::envelope::{COMPRESSED, Envelopes, envelope};
+
+    /// Echoes the request messages back.
+    fn echo() -> impl Service<
+        Request<Body>,
+        Response = Response<Body>,
+        Error = Infallible,
+        Future = impl Future<Output = Result<Response<Body>, Infallible>> + Send,
+    > + Clone
+    + Send
+    + 'static {
+        tower::service_fn(|req: Request<Body>| async move {
+            let encoding = req.headers().get(ENCODING).cloned();
+            let body = req.into_body().collect().await;
+            let mut res = Response::new(Body::new(
+                Full::new(body.map(|b| b.to_bytes()).unwrap_or_default())
+                    .map_err(|e: Infallible| match e {}),
+            ));
+            if let Some(encoding) = encoding {
+                res.headers_mut().insert("x-seen-encoding", encoding);
+            }
+            Ok(res)
+        })
+    }
+
+    fn request(path: &str, body: Bytes) -> Request<Body> {
+        Request::builder()
+            .method("POST")
+            .uri(path)
+            .header("content-type", "application/grpc")
+            .body(Body::new(
+                Full::new(body).map_err(|e: Infallible| match e {}),
+            ))
+            .unwrap()
+    }
+
+    async fn messages(res: Response<Body>) -> Vec<(u8, Bytes)> {
+        let mut envelopes = Envelopes::default();
+        envelopes.push(&res.into_body().collect().await.unwrap().to_bytes());
+        std::iter::from_fn(|| envelopes.next()).collect()
+    }
+
+    #[tokio::test]
+    async fn compresses_responses_for_clients_accepting_it() {
+        let compression = Compression::new()
+            .with_min_size(16)
+            .with_methods(Methods::new().with_method("f2.test.v1.Test/Raw", false));
+        let svc = Compressed::new(echo(), Some(compression));
+        let message = Bytes::from("image metadata ".repeat(20));
+
+        let mut req = request("/f2.test.v1.Test/Echo", envelope(0, &message));
+        req.headers_mut()
+            .insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        let res = svc.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.headers()[ENCODING], "gzip");
+        assert_eq!(res.headers()[ACCEPT_ENCODING], "zstd,gzip");
+        let (flags, compressed) = messages(res).await.remove(0);
+        assert_eq!(flags, COMPRESSED);
+        assert!(compressed.len() < message.len());
+
+        let mut req = request("/f2.test.v1.Test/Raw", envelope(0, &message));
+        req.headers_mut()
+            .insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        let res = svc.clone().oneshot(req).await.unwrap();
+        assert!(res.headers().get(ENCODING).is_none());
+        assert_eq!(messages(res).await, vec![(0, message.clone())]);
+
+        let req = request("/f2.test.v1.Test/Echo", envelope(0, &message));
+        let res = svc.oneshot(req).await.unwrap();
+        assert!(res.headers().get(ENCODING).is_none());
+    }
+
+    #[tokio::test]
+    async fn decompresses_requests() {
+        let svc = Compressed::new(echo(), Some(Compression::new()));
+        let message = Bytes::from("user ".repeat(100));
+        let compressed = zstd::encode_all(&message[..], 0).unwrap();
+
+        let mut req = request("/f2.test.v1.Test/Echo", envelope(COMPRESSED, &compressed));
+        req.headers_mut()
+            .insert(ENCODING, HeaderValue::from_static("zstd"));
+        let res = svc.oneshot(req).await.unwrap();
+        assert!(res.headers().get("x-seen-encoding").is_none());
+        assert_eq!(messages(res).await, vec![(0, message)]);
+    }
+
+    #[tokio::test]
+    async fn refuses_unsupported_encodings() {
+        let compression = Compression::new().with_encodings([Encoding::Gzip]);
+        let svc = Compressed::new(echo(), Some(compression));
+
+        let mut req = request("/f2.test.v1.Test/Echo", Bytes::new());
+        req.headers_mut()
+            .insert(ENCODING, HeaderValue::from_static("zstd"));
+        let res = svc.oneshot(req).await.unwrap();
+        let code = res.headers()["grpc-status"].to_str().unwrap().to_string();
+        assert_eq!(code, (Code::Unimplemented as i32).to_string());
+        assert_eq!(res.headers()[ACCEPT_ENCODING], "gzip");
+    }
+}
@@ -1,25 +1,206 @@
-use std::pin::Pin;
+use std::net::SocketAddr;
+use std::pin::{Pin, pin};
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
 
 use http::{Request, Response};
 use hyper::body::Incoming;
 use hyper::server::conn::http2;
-use hyper_util::{rt::TokioExecutor, service::TowerToHyperService};
+use hyper_util::{
+    rt::{TokioExecutor, TokioTimer},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
 use tonic::body::Body;
+use tonic::transport::server::TcpConnectInfo;
 use tower::{Service, ServiceExt};
 
-#[derive(Clone)]
+use super::body;
+use super::builder::{ConnectionGuard, shutdown_requested};
+use super::idle::{self, Idle};
+use super::limit::{Admission, Limit};
+use super::web::{Call, Web};
+
+/// Serves gRPC over cleartext HTTP/2. Connections opening with the HTTP/2
+/// preface are served directly, HTTP/1.1 connections have to ask for an
+/// `Upgrade: h2c` first.
+///
+/// With a request limit, `poll_ready` only resolves once the call has a
+/// slot, and calls finding the queue full are answered with
+/// `RESOURCE_EXHAUSTED`.
+///
+/// With [`with_web`](Self::with_web) Connect and gRPC-Web calls are served
+/// too, including plain HTTP/1.1 requests that don't ask for an upgrade.
 pub struct H2c<S> {
     s: S,
+    peer: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    guard: Option<Arc<ConnectionGuard>>,
+    limit: Option<Arc<Limit>>,
+    settings: Settings,
+    idle: Option<Arc<Idle>>,
+    web: Option<Web>,
+    admission: Admission,
+}
+
+/// How connections are run, the same for every connection of a server.
+#[derive(Clone, Copy, Default)]
+struct Settings {
+    max_concurrent_streams: Option<u32>,
+    header_read_timeout: Option<Duration>,
+    keepalive: Option<(Duration, Duration)>,
+    idle_timeout: Option<Duration>,
+}
+
+impl<S: Clone> Clone for H2c<S> {
+    fn clone(&self) -> Self {
+        Self {
+            s: self.s.clone(),
+            peer: self.peer,
+            local_addr: self.local_addr,
+            guard: self.guard.clone(),
+            limit: self.limit.clone(),
+            settings: self.settings,
+            idle: self.idle.clone(),
+            web: self.web.clone(),
+            // a slot belongs to the call it was acquired for
+            admission: Admission::Idle,
+        }
+    }
 }
 
-impl <S> H2c<S> {
+impl<S> H2c<S> {
     pub fn new(s: S) -> Self {
-        Self { s }
+        Self {
+            s,
+            peer: None,
+            local_addr: None,
+            guard: None,
+            limit: None,
+            settings: Settings::default(),
+            idle: None,
+            web: None,
+            admission: Admission::Idle,
+        }
+    }
+
+    /// Address of the client on this connection, used when logging failures
+    /// and handed to calls as [`TcpConnectInfo`], so `Request::remote_addr`
+    /// returns it.
+    pub fn with_peer(mut self, peer: SocketAddr) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+
+    /// Address the client connected to, handed to calls alongside the peer.
+    pub fn with_local_addr(mut self, addr: SocketAddr) -> Self {
+        self.local_addr = Some(addr);
+        self
+    }
+
+    /// Streams a client may open on one HTTP/2 connection.
+    pub fn with_max_concurrent_streams(mut self, max: u32) -> Self {
+        self.settings.max_concurrent_streams = Some(max);
+        self
+    }
+
+    /// Time an HTTP/1.1 client gets to send its request headers, 30 seconds
+    /// unless set.
+    pub fn with_header_read_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.header_read_timeout = Some(timeout);
+        self
+    }
+
+    /// Pings HTTP/2 clients every `interval` and closes the connection when
+    /// a ping goes unanswered for `timeout`.
+    pub fn with_keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.settings.keepalive = Some((interval, timeout));
+        self
+    }
+
+    /// Closes connections that had no call for `timeout`.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Also serves Connect and gRPC-Web calls.
+    pub fn with_web(mut self, web: Web) -> Self {
+        self.web = Some(web);
+        self
+    }
+
+    pub(crate) fn with_guard(mut self, guard: Arc<ConnectionGuard>) -> Self {
+        self.guard = Some(guard);
+        self
+    }
+
+    pub(crate) fn with_limit(mut self, limit: Arc<Limit>) -> Self {
+        self.limit = Some(limit);
+        self
     }
 }
 
 type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
+impl<S> H2c<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError> + 'static,
+{
+    /// Serves one accepted connection, telling HTTP/2 prior knowledge and
+    /// HTTP/1.1 apart by the connection preface.
+    pub async fn serve_connection<I>(mut self, io: I) -> Result<(), BoxError>
+    where
+        I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+    {
+        let settings = self.settings;
+        self.idle = settings
+            .idle_timeout
+            .map(|timeout| Arc::new(Idle::new(timeout)));
+        let guard = self.guard.clone();
+        let idle = self.idle.clone();
+
+        let mut builder = Builder::new(TokioExecutor::new());
+        let mut http1 = builder.http1();
+        http1.timer(TokioTimer::new());
+        if let Some(timeout) = settings.header_read_timeout {
+            http1.header_read_timeout(timeout);
+        }
+        let mut http2 = builder.http2();
+        http2
+            .timer(TokioTimer::new())
+            .max_concurrent_streams(settings.max_concurrent_streams)
+            .keep_alive_interval(settings.keepalive.map(|(interval, _)| interval));
+        if let Some((_, timeout)) = settings.keepalive {
+            http2.keep_alive_timeout(timeout);
+        }
+
+        let mut conn =
+            pin!(builder.serve_connection_with_upgrades(io, TowerToHyperService::new(self)));
+        tokio::select! {
+            res = conn.as_mut() => res,
+            _ = shutdown_requested(guard.as_deref()) => {
+                conn.as_mut().graceful_shutdown();
+                conn.await
+            }
+            _ = idle::expired(idle.as_deref()) => {
+                conn.as_mut().graceful_shutdown();
+                conn.await
+            }
+        }
+    }
+}
+
+fn wants_h2c<B>(req: &Request<B>) -> bool {
+    req.headers()
+        .get_all(hyper::header::UPGRADE)
+        .iter()
+        .any(|v| v.as_bytes().eq_ignore_ascii_case(b"h2c"))
+}
+
 impl<S> Service<Request<Incoming>> for H2c<S>
 where
     S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
@@ -27,31 +208,125 @@ where
     S::Error: Into<BoxError> + 'static,
 {
     type Response = Response<Body>;
-    type Error = hyper::Error;
-    type Future =
-    Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn poll_ready(
-        &mut self,
-        _: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), Self::Error>> {
-        std::task::Poll::Ready(Ok(()))
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let Some(limit) = &self.limit else {
+            return Poll::Ready(Ok(()));
+        };
+        if let Admission::Idle = self.admission {
+            self.admission = limit.admit();
+        }
+        if let Admission::Queued(queued) = &mut self.admission {
+            self.admission = Admission::Admitted(ready!(queued.as_mut().poll(cx)));
+        }
+        Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, req: hyper::Request<Incoming>) -> Self::Future {
+    fn call(&mut self, mut req: hyper::Request<Incoming>) -> Self::Future {
+        if self.peer.is_some() {
+            req.extensions_mut().insert(TcpConnectInfo {
+                local_addr: self.local_addr,
+                remote_addr: self.peer,
+            });
+        }
+        let peer = self
+            .peer
+            .map_or_else(|| "unknown peer".to_string(), |p| p.to_string());
+
+        let admission = std::mem::replace(&mut self.admission, Admission::Idle);
+        if req.version() == http::Version::HTTP_2 || (self.web.is_some() && !wants_h2c(&req)) {
+            let (shed, permit) = match admission {
+                Admission::Shed => (true, None),
+                Admission::Admitted(permit) => (false, Some(permit)),
+                Admission::Idle | Admission::Queued(_) => (false, None),
+            };
+            let active = match shed {
+                true => None,
+                false => self.idle.as_ref().map(Idle::enter),
+            };
+            let svc = self.s.clone();
+            let web = self.web.clone();
+            return Box::pin(async move {
+                let overloaded = tonic::Status::resource_exhausted("server is overloaded");
+                if shed {
+                    tracing::debug!("Shedding call from {}", peer);
+                }
+                // shed calls are answered before their body is read
+                let (req, call) = match &web {
+                    Some(web) => match web
+                        .accept(req.map(Body::new), shed.then_some(&overloaded))
+                        .await
+                    {
+                        Ok(accepted) => accepted,
+                        Err(res) => return Ok(res),
+                    },
+                    None => (req.map(Body::new), Call::grpc()),
+                };
+                if shed {
+                    return Ok(call.respond(overloaded.into_http()).await);
+                }
+                let res = svc.oneshot(req).await.map_err(Into::into)?;
+                let res = call.respond(res).await;
+                Ok(match (permit, active) {
+                    (None, None) => res,
+                    guards => res.map(|res| body::hold(res, guards)),
+                })
+            });
+        }
+
+        if !wants_h2c(&req) {
+            tracing::debug!("Rejecting request without h2c upgrade from {}", peer);
+            let mut res = hyper::Response::new(Body::default());
+            *res.status_mut() = http::StatusCode::UPGRADE_REQUIRED;
+            res.headers_mut().insert(
+                hyper::header::UPGRADE,
+                http::header::HeaderValue::from_static("h2c"),
+            );
+            return Box::pin(async move { Ok(res) });
+        }
+
+        // calls on the upgraded connection go through the same limits
+        let svc = self.clone();
+        let guard = self.guard.clone();
+        let idle = self.idle.clone();
+        let settings = self.settings;
         let mut req = req.map(Body::new);
-        let svc = self
-            .s
-            .clone()
-            .map_request(|req: Request<_>| req.map(Body::new));
         Box::pin(async move {
             tokio::spawn(async move {
-                let upgraded_io = hyper::upgrade::on(&mut req).await.unwrap();
+                let upgraded_io = match hyper::upgrade::on(&mut req).await {
+                    Ok(io) => io,
+                    Err(e) => {
+                        tracing::warn!("h2c upgrade from {} failed: {}", peer, e);
+                        return;
+                    }
+                };
 
-                http2::Builder::new(TokioExecutor::new())
-                    .serve_connection(upgraded_io, TowerToHyperService::new(svc))
-                    .await
-                    .unwrap();
+                let mut builder = http2::Builder::new(TokioExecutor::new());
+                builder
+                    .timer(TokioTimer::new())
+                    .max_concurrent_streams(settings.max_concurrent_streams)
+                    .keep_alive_interval(settings.keepalive.map(|(interval, _)| interval));
+                if let Some((_, timeout)) = settings.keepalive {
+                    builder.keep_alive_timeout(timeout);
+                }
+                let mut conn =
+                    pin!(builder.serve_connection(upgraded_io, TowerToHyperService::new(svc)));
+                let res = tokio::select! {
+                    res = conn.as_mut() => res,
+                    _ = shutdown_requested(guard.as_deref()) => {
+                        conn.as_mut().graceful_shutdown();
+                        conn.await
+                    }
+                    _ = idle::expired(idle.as_deref()) => {
+                        conn.as_mut().graceful_shutdown();
+                        conn.await
+                    }
+                };
+                if let Err(e) = res {
+                    tracing::warn!("h2c connection from {} failed: {}", peer, e);
+                }
             });
 
             let mut res = hyper::Response::new(Body::default());
@@ -65,3 +340,107 @@ where
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::h2c::{H2cChannel, H2cMode};
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioIo;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tonic::service::Routes;
+    use tonic_health::pb::HealthCheckRequest;
+    use tonic_health::pb::health_check_response::ServingStatus;
+    use tonic_health::pb::health_client::HealthClient;
+
+    async fn serve() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_, health) = tonic_health::server::health_reporter();
+        let svc = H2c::new(Routes::new(health).prepare());
+        tokio::spawn(async move {
+            loop {
+                let (io, peer) = listener.accept().await.unwrap();
+                tokio::spawn(
+                    svc.clone()
+                        .with_peer(peer)
+                        .serve_connection(TokioIo::new(io)),
+                );
+            }
+        });
+        addr
+    }
+
+    async fn check<T>(mut client: HealthClient<T>) -> ServingStatus
+    where
+        T: tonic::client::GrpcService<Body>,
+        T::Error: Into<BoxError>,
+        T::ResponseBody: hyper::body::Body<Data = hyper::body::Bytes> + Send + 'static,
+        <T::ResponseBody as hyper::body::Body>::Error: Into<BoxError> + Send,
+    {
+        let res = client.check(HealthCheckRequest::default()).await.unwrap();
+        res.into_inner().status()
+    }
+
+    fn channel(mode: H2cMode) -> H2cChannel {
+        H2cChannel::new(Client::builder(TokioExecutor::new()).build_http()).with_mode(mode)
+    }
+
+    #[tokio::test]
+    async fn serves_tonic_client_with_prior_knowledge() {
+        let addr = serve().await;
+        let channel = tonic::transport::Endpoint::from_shared(format!("http://{addr}"))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        assert_eq!(
+            check(HealthClient::new(channel)).await,
+            ServingStatus::Serving
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_both_client_modes() {
+        let addr = serve().await;
+        let origin: http::Uri = format!("http://{addr}").parse().unwrap();
+        for mode in [H2cMode::Upgrade, H2cMode::PriorKnowledge] {
+            let client = HealthClient::with_origin(channel(mode), origin.clone());
+            assert_eq!(check(client).await, ServingStatus::Serving, "{mode:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn plain_request_needs_upgrade() {
+        let addr = serve().await;
+        let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+        let res = client
+            .get(format!("http://{addr}/").parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), http::StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(res.headers()[hyper::header::UPGRADE], "h2c");
+    }
+
+    #[tokio::test]
+    async fn survives_truncated_handshake() {
+        let addr = serve().await;
+
+        let mut io = TcpStream::connect(addr).await.unwrap();
+        io.write_all(b"GET / HTTP/1.1\r\nhost: f2\r\nconnection: upgrade\r\nupgrade: h2c\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0; 1024];
+        let n = io.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 101"));
+        io.write_all(b"PRI * HTTP/2.0\r\n").await.unwrap();
+        drop(io);
+
+        let client = HealthClient::with_origin(
+            channel(H2cMode::Upgrade),
+            format!("http://{addr}").parse().unwrap(),
+        );
+        assert_eq!(check(client).await, ServingStatus::Serving);
+    }
+}
@@ -0,0 +1,63 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Tracks the calls on one connection to close it once it has had none for
+/// `timeout`.
+pub(crate) struct Idle {
+    timeout: Duration,
+    active: AtomicUsize,
+    since: Mutex<Instant>,
+}
+
+/// Marks a call as running on its connection until dropped.
+pub(crate) struct Active(Arc<Idle>);
+
+impl Idle {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            active: AtomicUsize::new(0),
+            since: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub(crate) fn enter(self: &Arc<Self>) -> Active {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Active(self.clone())
+    }
+
+    /// Resolves once the connection has been idle for the timeout.
+    pub(crate) async fn expired(&self) {
+        loop {
+            let deadline = if self.active.load(Ordering::Relaxed) > 0 {
+                Instant::now() + self.timeout
+            } else {
+                *self.since.lock().unwrap() + self.timeout
+            };
+            tokio::time::sleep_until(deadline).await;
+            if self.active.load(Ordering::Relaxed) == 0
+                && self.since.lock().unwrap().elapsed() >= self.timeout
+            {
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        *self.0.since.lock().unwrap() = Instant::now();
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Resolves once `idle` expires, never without an idle timeout.
+pub(crate) async fn expired(idle: Option<&Idle>) {
+    match idle {
+        Some(idle) => idle.expired().await,
+        None => std::future::pending().await,
+    }
+}
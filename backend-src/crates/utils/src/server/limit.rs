@@ -0,0 +1,95 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Instant;
+
+use prometheus_client::metrics::gauge::Gauge;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::metrics::ServerMetrics;
+
+/// Bounds the calls in flight across a server. Calls over the limit wait for
+/// a slot, up to `max_queued` of them, and the rest are shed.
+pub(crate) struct Limit {
+    in_flight: Arc<Semaphore>,
+    queue: Arc<Semaphore>,
+    metrics: ServerMetrics,
+}
+
+/// Where a call stands after `poll_ready`.
+pub(crate) enum Admission {
+    Idle,
+    Queued(Pin<Box<dyn Future<Output = Permit> + Send>>),
+    Admitted(Permit),
+    Shed,
+}
+
+impl Limit {
+    pub(crate) fn new(max_in_flight: usize, max_queued: usize, metrics: ServerMetrics) -> Self {
+        Self {
+            in_flight: Arc::new(Semaphore::new(max_in_flight)),
+            queue: Arc::new(Semaphore::new(max_queued)),
+            metrics,
+        }
+    }
+
+    /// Admits a call right away if there is room, queues it otherwise.
+    pub(crate) fn admit(self: &Arc<Self>) -> Admission {
+        let started = Instant::now();
+        if let Ok(permit) = self.in_flight.clone().try_acquire_owned() {
+            return Admission::Admitted(self.permit(permit, started));
+        }
+        let Ok(slot) = self.queue.clone().try_acquire_owned() else {
+            self.metrics.shed_requests.inc();
+            return Admission::Shed;
+        };
+
+        let limit = self.clone();
+        Admission::Queued(Box::pin(async move {
+            let _queued = Tracked::new(limit.metrics.queued_requests.clone(), slot);
+            let permit = limit
+                .in_flight
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("request limit is never closed");
+            limit.permit(permit, started)
+        }))
+    }
+
+    fn permit(&self, permit: OwnedSemaphorePermit, started: Instant) -> Permit {
+        self.metrics
+            .queue_wait_seconds
+            .observe(started.elapsed().as_secs_f64());
+        Permit {
+            _slot: Tracked::new(self.metrics.in_flight_requests.clone(), permit),
+        }
+    }
+}
+
+/// Holds a semaphore permit and counts it in a gauge.
+struct Tracked {
+    gauge: Gauge,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Tracked {
+    fn new(gauge: Gauge, permit: OwnedSemaphorePermit) -> Self {
+        gauge.inc();
+        Self {
+            gauge,
+            _permit: permit,
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
+/// A call's slot, given back once its response body is done.
+pub(crate) struct Permit {
+    _slot: Tracked,
+}
@@ -0,0 +1,436 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+/// Where the server accepts connections.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    /// The path is removed again once the listener is dropped, unless the
+    /// socket is abstract.
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+/// First descriptor passed down through `LISTEN_FDS`.
+const LISTEN_FDS_START: RawFd = 3;
+/// Backlog of listeners bound with `SO_REUSEPORT`.
+const BACKLOG: i32 = 1024;
+
+/// Set once the inherited descriptors are owned by listeners, so they
+/// can't be taken twice.
+static INHERITED: AtomicBool = AtomicBool::new(false);
+
+/// Who is on the other end of an accepted connection.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Peer {
+    Tcp(SocketAddr),
+    Unix,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => addr.fmt(f),
+            Peer::Unix => f.write_str("unix socket peer"),
+        }
+    }
+}
+
+/// Socket path for `path`, where a leading `@` names an abstract socket.
+pub(crate) fn unix_socket_path(path: &str) -> PathBuf {
+    match path.strip_prefix('@') {
+        Some(name) => PathBuf::from(format!("\0{name}")),
+        None => PathBuf::from(path),
+    }
+}
+
+/// Binds `socket` in a directory only this process can enter and moves it
+/// into place once it has `mode`, so no one can connect while it still has
+/// the permissions the umask gave it.
+fn bind_private(socket: &Path, mode: u32) -> io::Result<UnixListener> {
+    let parent = socket
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let dir = parent.join(format!(".f2-bind-{}", std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let private = dir.join("sock");
+    let bound = UnixListener::bind(&private).and_then(|listener| {
+        std::fs::set_permissions(&private, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&private, socket)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_dir_all(&dir);
+    bound
+}
+
+/// How many sockets `LISTEN_FDS` passes down to the process `own_pid`,
+/// none when `LISTEN_PID` names another process.
+fn listen_fds(fds: Option<&str>, pid: Option<&str>, own_pid: u32) -> io::Result<RawFd> {
+    let Some(fds) = fds else {
+        return Ok(0);
+    };
+    if let Some(pid) = pid
+        && pid.parse() != Ok(own_pid)
+    {
+        return Ok(0);
+    }
+    fds.parse().ok().filter(|fds| *fds >= 0).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid LISTEN_FDS {fds:?}"),
+        )
+    })
+}
+
+impl Listener {
+    /// Binds a Unix socket, replacing a stale socket file left behind by an
+    /// earlier run, and applies `mode` to the socket file. A socket another
+    /// server still accepts on is left alone and fails the bind.
+    pub(crate) fn bind_unix(path: &str, mode: Option<u32>) -> io::Result<Self> {
+        let socket = unix_socket_path(path);
+        if path.starts_with('@') {
+            return Ok(Listener::Unix(UnixListener::bind(socket)?, None));
+        }
+
+        if let Ok(metadata) = std::fs::symlink_metadata(&socket)
+            && metadata.file_type().is_socket()
+        {
+            match std::os::unix::net::UnixStream::connect(&socket) {
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} is in use by another server", socket.display()),
+                    ));
+                }
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    std::fs::remove_file(&socket)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let listener = match mode {
+            Some(mode) => bind_private(&socket, mode)?,
+            None => UnixListener::bind(&socket)?,
+        };
+        Ok(Listener::Unix(listener, Some(socket)))
+    }
+
+    /// Listeners for the sockets systemd or listenfd passed down through
+    /// `LISTEN_FDS`, none when there are none for this process or they were
+    /// taken before.
+    pub(crate) fn inherited() -> io::Result<Vec<Self>> {
+        let fds = std::env::var("LISTEN_FDS").ok();
+        let pid = std::env::var("LISTEN_PID").ok();
+        let fds = listen_fds(fds.as_deref(), pid.as_deref(), std::process::id())?;
+        if fds == 0 || INHERITED.swap(true, Ordering::SeqCst) {
+            return Ok(Vec::new());
+        }
+        (LISTEN_FDS_START..LISTEN_FDS_START + fds)
+            .map(|fd| {
+                // SAFETY: the descriptors are passed down for this process to
+                // own, and INHERITED makes sure only one listener owns each
+                let socket = unsafe { Socket::from_raw_fd(fd) };
+                Self::from_socket(socket)
+            })
+            .collect()
+    }
+
+    /// Listener for an inherited listening socket, TCP or Unix.
+    fn from_socket(socket: Socket) -> io::Result<Self> {
+        socket.set_cloexec(true)?;
+        socket.set_nonblocking(true)?;
+        if socket.local_addr()?.domain() == Domain::UNIX {
+            // the socket file belongs to whoever bound it
+            Ok(Listener::Unix(UnixListener::from_std(socket.into())?, None))
+        } else {
+            Ok(Listener::Tcp(TcpListener::from_std(socket.into())?))
+        }
+    }
+
+    /// Binds `count` TCP listeners to `addr` with `SO_REUSEPORT`, so the
+    /// kernel spreads connections across them. With port 0 they all share
+    /// the port the first one got.
+    pub(crate) fn bind_reuseport(mut addr: SocketAddr, count: usize) -> io::Result<Vec<Self>> {
+        let mut listeners = Vec::with_capacity(count);
+        for _ in 0..count.max(1) {
+            let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+            socket.set_reuse_address(true)?;
+            socket.set_reuse_port(true)?;
+            socket.set_nonblocking(true)?;
+            socket.bind(&addr.into())?;
+            socket.listen(BACKLOG)?;
+            let listener = TcpListener::from_std(socket.into())?;
+            addr = listener.local_addr()?;
+            listeners.push(Listener::Tcp(listener));
+        }
+        Ok(listeners)
+    }
+
+    pub(crate) async fn accept(&self) -> io::Result<(Stream, Peer)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (io, peer) = listener.accept().await?;
+                let _ = io.set_nodelay(true);
+                Ok((Stream::Tcp(io), Peer::Tcp(peer)))
+            }
+            Listener::Unix(listener, _) => {
+                let (io, _) = listener.accept().await?;
+                Ok((Stream::Unix(io), Peer::Unix))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => addr.fmt(f),
+                Err(_) => f.write_str("TCP listener"),
+            },
+            Listener::Unix(_, Some(path)) => write!(f, "unix:{}", path.display()),
+            Listener::Unix(_, None) => f.write_str("abstract unix socket"),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, Some(path)) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// An accepted TCP or Unix connection.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Address the client connected to, for TCP connections.
+    pub(crate) fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(io) => io.local_addr().ok(),
+            Stream::Unix(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(io) => Pin::new(io).poll_read(cx, buf),
+            Stream::Unix(io) => Pin::new(io).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(io) => Pin::new(io).poll_write(cx, buf),
+            Stream::Unix(io) => Pin::new(io).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(io) => Pin::new(io).poll_flush(cx),
+            Stream::Unix(io) => Pin::new(io).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(io) => Pin::new(io).poll_shutdown(cx),
+            Stream::Unix(io) => Pin::new(io).poll_shutdown(cx),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(io) => Pin::new(io).poll_write_vectored(cx, bufs),
+            Stream::Unix(io) => Pin::new(io).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Stream::Tcp(io) => io.is_write_vectored(),
+            Stream::Unix(io) => io.is_write_vectored(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::h2c::H2cChannel;
+    use crate::client::parse_origin;
+    use crate::server::Server;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+    use tonic::service::Routes;
+    use tonic_health::pb::HealthCheckRequest;
+    use tonic_health::pb::health_client::HealthClient;
+
+    /// Checks health over `addr`, retrying while the server is still binding.
+    async fn check(addr: &str) {
+        let channel = H2cChannel::new(Client::builder(TokioExecutor::new()).build_http());
+        let mut client = HealthClient::with_origin(channel, parse_origin(addr).unwrap());
+        for _ in 0..50 {
+            if client.check(HealthCheckRequest::default()).await.is_ok() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{addr} never became healthy");
+    }
+
+    #[tokio::test]
+    async fn serves_on_unix_socket() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("auth.sock");
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = Server::new(Routes::default())
+            .with_unix_socket(path.to_str().unwrap())
+            .with_unix_permissions(0o660);
+        let serving = tokio::spawn(server.serve_with_shutdown(async {
+            let _ = stopped.await;
+        }));
+
+        check(&format!("unix:{}", path.display())).await;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        stop.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn replaces_only_stale_sockets() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("auth.sock");
+        let path = path.to_str().unwrap();
+
+        let mut listener = Listener::bind_unix(path, Some(0o600)).unwrap();
+        let err = Listener::bind_unix(path, None).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        // closed like after a crash, leaving the file behind
+        if let Listener::Unix(_, socket) = &mut listener {
+            socket.take();
+        }
+        drop(listener);
+        let _rebound = Listener::bind_unix(path, Some(0o600)).unwrap();
+        // only the socket, without the directory it was bound in
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn serves_on_abstract_socket() {
+        let name = format!("@f2-utils-test-{}", std::process::id());
+        let server = Server::new(Routes::default()).with_unix_socket(name.clone());
+        tokio::spawn(server.serve_with_shutdown(std::future::pending()));
+
+        check(&format!("unix:{name}")).await;
+    }
+
+    #[test]
+    fn counts_sockets_passed_to_this_process() {
+        assert_eq!(listen_fds(Some("2"), Some("42"), 42).unwrap(), 2);
+        assert_eq!(listen_fds(Some("1"), None, 42).unwrap(), 1);
+        assert_eq!(listen_fds(None, Some("42"), 42).unwrap(), 0);
+    }
+
+    #[test]
+    fn skips_sockets_passed_to_another_process() {
+        assert_eq!(listen_fds(Some("2"), Some("41"), 42).unwrap(), 0);
+        assert_eq!(listen_fds(Some("2"), Some("not-a-pid"), 42).unwrap(), 0);
+    }
+
+    #[test]
+    fn rejects_invalid_listen_fds() {
+        for fds in ["", "two", "-1"] {
+            let err = listen_fds(Some(fds), Some("42"), 42).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{fds:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_on_inherited_sockets() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("auth.sock");
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        let (_, health) = tonic_health::server::health_reporter();
+        for socket in [Socket::from(tcp), Socket::from(unix)] {
+            let listener = Listener::from_socket(socket).unwrap();
+            let routes = Routes::new(health.clone()).prepare();
+            tokio::spawn(async move {
+                loop {
+                    let (io, _) = listener.accept().await.unwrap();
+                    let svc = crate::server::h2c::H2c::new(routes.clone());
+                    tokio::spawn(svc.serve_connection(hyper_util::rt::TokioIo::new(io)));
+                }
+            });
+        }
+
+        check(&format!("http://{addr}")).await;
+        check(&format!("unix:{}", path.display())).await;
+        // the socket file belongs to whoever bound it
+        assert!(path.exists());
+    }
+
+    #[tokio::test]
+    async fn serves_on_reuseport_acceptors() {
+        let listeners = Listener::bind_reuseport(([127, 0, 0, 1], 0).into(), 2).unwrap();
+        let addrs: Vec<_> = listeners
+            .iter()
+            .map(|listener| match listener {
+                Listener::Tcp(listener) => listener.local_addr().unwrap(),
+                Listener::Unix(..) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(addrs[0], addrs[1]);
+        drop(listeners);
+
+        let server = Server::new(Routes::default())
+            .with_addr(addrs[0])
+            .with_reuseport(4);
+        tokio::spawn(server.serve_with_shutdown(std::future::pending()));
+        for _ in 0..8 {
+            check(&format!("http://{}", addrs[0])).await;
+        }
+    }
+}
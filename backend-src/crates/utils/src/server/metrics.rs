@@ -0,0 +1,116 @@
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response};
+use hyper_util::rt::TokioIo;
+use prometheus_client::encoding::text::encode;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{Histogram, exponential_buckets};
+use prometheus_client::registry::Registry;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+#[derive(Clone)]
+pub(crate) struct ServerMetrics {
+    pub connections: Counter,
+    pub active_connections: Gauge,
+    pub in_flight_requests: Gauge,
+    pub queued_requests: Gauge,
+    pub queue_wait_seconds: Histogram,
+    pub shed_requests: Counter,
+}
+
+impl Default for ServerMetrics {
+    fn default() -> Self {
+        Self {
+            connections: Counter::default(),
+            active_connections: Gauge::default(),
+            in_flight_requests: Gauge::default(),
+            queued_requests: Gauge::default(),
+            // 100µs up to about 6.5s
+            queue_wait_seconds: Histogram::new(exponential_buckets(0.0001, 4.0, 9)),
+            shed_requests: Counter::default(),
+        }
+    }
+}
+
+impl ServerMetrics {
+    pub(crate) fn register(registry: &mut Registry) -> Self {
+        let metrics = Self::default();
+        let registry = registry.sub_registry_with_prefix("f2_server");
+        registry.register(
+            "connections",
+            "Connections accepted",
+            metrics.connections.clone(),
+        );
+        registry.register(
+            "active_connections",
+            "Connections currently open, including upgraded h2c connections",
+            metrics.active_connections.clone(),
+        );
+        registry.register(
+            "in_flight_requests",
+            "Calls holding one of the server's request slots",
+            metrics.in_flight_requests.clone(),
+        );
+        registry.register(
+            "queued_requests",
+            "Calls waiting for a request slot",
+            metrics.queued_requests.clone(),
+        );
+        registry.register(
+            "queue_wait_seconds",
+            "Time calls waited for a request slot",
+            metrics.queue_wait_seconds.clone(),
+        );
+        registry.register(
+            "shed_requests",
+            "Calls answered with RESOURCE_EXHAUSTED because the queue was full",
+            metrics.shed_requests.clone(),
+        );
+        metrics
+    }
+}
+
+/// Serves the registry in the Prometheus text format on every path.
+pub async fn serve(registry: Arc<Registry>, addr: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+
+    loop {
+        let (io, _) = listener.accept().await?;
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            let svc = service_fn(move |_: Request<Incoming>| {
+                let registry = registry.clone();
+                async move {
+                    let mut body = String::new();
+                    let res = match encode(&mut body, &registry) {
+                        Ok(()) => Response::builder()
+                            .header(
+                                hyper::header::CONTENT_TYPE,
+                                "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                            )
+                            .body(Full::new(Bytes::from(body))),
+                        Err(e) => {
+                            tracing::error!("Failed to encode metrics: {}", e);
+                            Response::builder()
+                                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+                                .body(Full::default())
+                        }
+                    };
+                    Ok::<_, Infallible>(res.expect("static response parts are valid"))
+                }
+            });
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(io), svc)
+                .await
+            {
+                tracing::debug!("Metrics connection closed with error: {}", e);
+            }
+        });
+    }
+}
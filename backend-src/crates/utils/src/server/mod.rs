@@ -1 +1,15 @@
-pub mod h2c;
\ No newline at end of file
+pub mod access_log;
+mod body;
+mod builder;
+pub mod catch_panic;
+mod compression;
+pub mod h2c;
+mod idle;
+mod limit;
+pub(crate) mod listener;
+pub mod metrics;
+pub mod proxy;
+pub mod rpc_metrics;
+pub mod web;
+
+pub use builder::Server;
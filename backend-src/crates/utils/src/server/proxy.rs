@@ -0,0 +1,332 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use ipnet::IpNet;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::listener::Peer;
+use crate::Error;
+
+const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Signature opening a v2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest v1 header, including the `\r\n`.
+const V1_MAX_LEN: usize = 107;
+
+/// Reads a PROXY protocol v1 or v2 header off every connection before TLS
+/// and HTTP, so calls see the client address a TCP load balancer passed on
+/// instead of the load balancer's.
+///
+/// Only peers in `trusted` may send a header, connections from anywhere else
+/// are closed right away. Connections on a Unix socket are trusted. Headers
+/// the proxy sends for itself, v1 `UNKNOWN` and v2 `LOCAL` such as for its
+/// health checks, keep the socket's addresses.
+///
+/// ```
+/// # fn proxy() -> Result<(), f2_utils::Error> {
+/// use f2_utils::server::proxy::ProxyProtocol;
+///
+/// let proxy: ProxyProtocol = "10.0.0.0/8,192.168.1.7".parse()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ProxyProtocol {
+    trusted: Vec<IpNet>,
+    header_timeout: Duration,
+}
+
+/// Addresses of a proxied connection as the proxy saw them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Proxied {
+    pub(crate) source: SocketAddr,
+    pub(crate) destination: SocketAddr,
+}
+
+impl ProxyProtocol {
+    pub fn new(trusted: impl IntoIterator<Item = IpNet>) -> Self {
+        Self {
+            trusted: trusted.into_iter().collect(),
+            header_timeout: DEFAULT_HEADER_TIMEOUT,
+        }
+    }
+
+    /// Time a proxy gets to send the header, 5 seconds unless set.
+    pub fn with_header_timeout(mut self, timeout: Duration) -> Self {
+        self.header_timeout = timeout;
+        self
+    }
+
+    pub(crate) fn trusts(&self, peer: &Peer) -> bool {
+        match peer {
+            Peer::Tcp(addr) => {
+                let ip = addr.ip().to_canonical();
+                self.trusted.iter().any(|net| net.contains(&ip))
+            }
+            Peer::Unix => true,
+        }
+    }
+
+    /// Reads the header, leaving `io` at the first byte after it.
+    pub(crate) async fn read_header<R: AsyncRead + Unpin>(
+        &self,
+        io: &mut R,
+    ) -> io::Result<Option<Proxied>> {
+        match tokio::time::timeout(self.header_timeout, read_header(io)).await {
+            Ok(read) => read,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no PROXY protocol header in time",
+            )),
+        }
+    }
+}
+
+/// Parses a comma-separated list of trusted networks, where a bare address
+/// stands for itself.
+impl FromStr for ProxyProtocol {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let trusted = s
+            .split(',')
+            .map(str::trim)
+            .filter(|net| !net.is_empty())
+            .map(|net| {
+                net.parse::<IpNet>()
+                    .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| Error::InvalidNetwork(net.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::new(trusted))
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("PROXY protocol: {message}"),
+    )
+}
+
+/// Reads exactly the header, so whatever follows stays on `io`.
+async fn read_header<R: AsyncRead + Unpin>(io: &mut R) -> io::Result<Option<Proxied>> {
+    // the shortest v1 header, `PROXY UNKNOWN\r\n`, is longer than this
+    let mut start = [0; 12];
+    io.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        let mut fixed = [0; 4];
+        io.read_exact(&mut fixed).await?;
+        let mut addresses = vec![0; u16::from_be_bytes([fixed[2], fixed[3]]) as usize];
+        io.read_exact(&mut addresses).await?;
+        return parse_v2(fixed[0], fixed[1], &addresses);
+    }
+
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("connection did not start with a header"));
+    }
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid("v1 header too long"));
+        }
+        line.push(io.read_u8().await?);
+    }
+    parse_v1(&line[..line.len() - 2])
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<Proxied>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let ipv4 = match fields.get(1) {
+        Some(&"TCP4") => true,
+        Some(&"TCP6") => false,
+        Some(&"UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("unknown v1 protocol")),
+    };
+    let [_, _, source, destination, source_port, destination_port] = fields[..] else {
+        return Err(invalid("v1 header needs two addresses and ports"));
+    };
+    let addr = |ip: &str, port: &str| {
+        let ip = ip
+            .parse::<IpAddr>()
+            .map_err(|_| invalid("invalid v1 address"))?;
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| invalid("invalid v1 port"))?;
+        if ip.is_ipv4() != ipv4 {
+            return Err(invalid("v1 address does not match the protocol"));
+        }
+        Ok(SocketAddr::new(ip, port))
+    };
+    Ok(Some(Proxied {
+        source: addr(source, source_port)?,
+        destination: addr(destination, destination_port)?,
+    }))
+}
+
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<Proxied>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        _ => return Err(invalid("unknown v2 command")),
+    }
+
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    match family {
+        // TCP over IPv4
+        0x11 if addresses.len() >= 12 => {
+            let ip = |at: usize| {
+                let octets: [u8; 4] = addresses[at..at + 4].try_into().unwrap();
+                IpAddr::V4(Ipv4Addr::from(octets))
+            };
+            Ok(Some(Proxied {
+                source: SocketAddr::new(ip(0), port(8)),
+                destination: SocketAddr::new(ip(4), port(10)),
+            }))
+        }
+        // TCP over IPv6
+        0x21 if addresses.len() >= 36 => {
+            let ip = |at: usize| {
+                let octets: [u8; 16] = addresses[at..at + 16].try_into().unwrap();
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            Ok(Some(Proxied {
+                source: SocketAddr::new(ip(0), port(32)),
+                destination: SocketAddr::new(ip(16), port(34)),
+            }))
+        }
+        0x11 | 0x21 => Err(invalid("v2 addresses cut short")),
+        // unspecified, UDP or Unix sockets carry no client address to use
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    async fn read(mut bytes: &[u8]) -> (io::Result<Option<Proxied>>, Vec<u8>) {
+        let proxied = read_header(&mut bytes).await;
+        let mut rest = Vec::new();
+        bytes.read_to_end(&mut rest).await.unwrap();
+        (proxied, rest)
+    }
+
+    fn proxied(source: &str, destination: &str) -> Option<Proxied> {
+        Some(Proxied {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        })
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers() {
+        let (header, rest) = read(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 8080\r\nPRI *").await;
+        assert_eq!(
+            header.unwrap(),
+            proxied("203.0.113.7:51234", "10.0.0.1:8080")
+        );
+        assert_eq!(rest, b"PRI *");
+
+        let (header, _) = read(b"PROXY TCP6 2001:db8::7 2001:db8::1 443 8080\r\n").await;
+        assert_eq!(
+            header.unwrap(),
+            proxied("[2001:db8::7]:443", "[2001:db8::1]:8080")
+        );
+
+        let (header, rest) = read(b"PROXY UNKNOWN\r\nPRI *").await;
+        assert_eq!(header.unwrap(), None);
+        assert_eq!(rest, b"PRI *");
+
+        for bad in [
+            &b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"[..],
+            b"PROXY TCP4 2001:db8::7 10.0.0.1 1 2\r\n",
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 51234\r\n",
+            b"PROXY UDP4 203.0.113.7 10.0.0.1 1 2\r\n",
+            &[b"PROXY TCP4 ".as_slice(), &[b'1'; 100]].concat(),
+        ] {
+            assert!(
+                read(bad).await.0.is_err(),
+                "{}",
+                String::from_utf8_lossy(bad)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11, 0, 12 + 4]);
+        header.extend([203, 0, 113, 7, 10, 0, 0, 1]);
+        header.extend(51234u16.to_be_bytes());
+        header.extend(8080u16.to_be_bytes());
+        // a TLV the parser skips
+        header.extend([0x04, 0, 1, 0]);
+        header.extend(b"PRI *");
+        let (proxied_v4, rest) = read(&header).await;
+        assert_eq!(
+            proxied_v4.unwrap(),
+            proxied("203.0.113.7:51234", "10.0.0.1:8080")
+        );
+        assert_eq!(rest, b"PRI *");
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x21, 0, 36]);
+        header.extend("2001:db8::7".parse::<Ipv6Addr>().unwrap().octets());
+        header.extend("2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        header.extend([1, 187, 31, 144]);
+        let (proxied_v6, _) = read(&header).await;
+        assert_eq!(
+            proxied_v6.unwrap(),
+            proxied("[2001:db8::7]:443", "[2001:db8::1]:8080")
+        );
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(read(&local).await.0.unwrap(), None);
+
+        let mut short = V2_SIGNATURE.to_vec();
+        short.extend([0x21, 0x11, 0, 4, 203, 0, 113, 7]);
+        assert!(read(&short).await.0.is_err());
+    }
+
+    #[test]
+    fn trusts_listed_networks() {
+        let proxy: ProxyProtocol = "10.0.0.0/8, 2001:db8::/32,192.168.1.7".parse().unwrap();
+        for trusted in [
+            "10.1.2.3:1",
+            "[::ffff:10.1.2.3]:1",
+            "[2001:db8::7]:1",
+            "192.168.1.7:1",
+        ] {
+            assert!(
+                proxy.trusts(&Peer::Tcp(trusted.parse().unwrap())),
+                "{trusted}"
+            );
+        }
+        for untrusted in ["11.1.2.3:1", "192.168.1.8:1", "[2001:db9::7]:1"] {
+            assert!(
+                !proxy.trusts(&Peer::Tcp(untrusted.parse().unwrap())),
+                "{untrusted}"
+            );
+        }
+        assert!(proxy.trusts(&Peer::Unix));
+
+        assert!(matches!(
+            "10.0.0.0/33".parse::<ProxyProtocol>(),
+            Err(Error::InvalidNetwork(net)) if net == "10.0.0.0/33"
+        ));
+    }
+}
@@ -0,0 +1,200 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use http::{Request, Response};
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::histogram::{Histogram, exponential_buckets};
+use prometheus_client::registry::Registry;
+use tonic::Code;
+use tonic::body::Body;
+use tower::{Layer, Service};
+
+use super::body::{self, grpc_code};
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct MethodLabels {
+    service: String,
+    method: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CallLabels {
+    service: String,
+    method: String,
+    code: String,
+}
+
+/// Rate, errors and duration of calls by service and method.
+///
+/// Calls ending `UNIMPLEMENTED` are counted under service and method
+/// `unknown`, so clients calling made-up paths can't add label values.
+#[derive(Clone)]
+pub struct RpcMetrics {
+    calls: Family<CallLabels, Counter>,
+    duration: Family<MethodLabels, Histogram, fn() -> Histogram>,
+}
+
+impl Default for RpcMetrics {
+    fn default() -> Self {
+        Self {
+            calls: Family::default(),
+            // 500µs up to about 16s
+            duration: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.0005, 2.0, 16))
+            }),
+        }
+    }
+}
+
+impl RpcMetrics {
+    /// Registers the metrics in `registry`, e.g. a sub-registry with the
+    /// `f2_server` prefix as the server uses.
+    pub fn register(registry: &mut Registry) -> Self {
+        let metrics = Self::default();
+        registry.register(
+            "rpc_calls",
+            "Calls finished, by service, method and gRPC code",
+            metrics.calls.clone(),
+        );
+        registry.register(
+            "rpc_duration_seconds",
+            "Time from receiving a call until its response was sent",
+            metrics.duration.clone(),
+        );
+        metrics
+    }
+
+    fn record(&self, path: &str, code: Code, started: Instant) {
+        let (service, method) = match code {
+            Code::Unimplemented => ("unknown", "unknown"),
+            _ => split_path(path),
+        };
+        let labels = MethodLabels {
+            service: service.to_string(),
+            method: method.to_string(),
+        };
+        self.duration
+            .get_or_create(&labels)
+            .observe(started.elapsed().as_secs_f64());
+        self.calls
+            .get_or_create(&CallLabels {
+                service: labels.service,
+                method: labels.method,
+                code: format!("{code:?}"),
+            })
+            .inc();
+    }
+}
+
+/// Service and method of a `/package.Service/Method` path.
+fn split_path(path: &str) -> (&str, &str) {
+    path.trim_start_matches('/')
+        .split_once('/')
+        .unwrap_or(("unknown", "unknown"))
+}
+
+/// Records [`RpcMetrics`] for every call once its response has been sent.
+#[derive(Clone)]
+pub struct RpcMetricsLayer {
+    metrics: RpcMetrics,
+}
+
+impl RpcMetricsLayer {
+    pub fn new(metrics: RpcMetrics) -> Self {
+        Self { metrics }
+    }
+}
+
+impl<S> Layer<S> for RpcMetricsLayer {
+    type Service = Measured<S>;
+
+    fn layer(&self, inner: S) -> Measured<S> {
+        Measured {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Measured<S> {
+    inner: S,
+    metrics: RpcMetrics,
+}
+
+impl<S> Service<Request<Body>> for Measured<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let started = Instant::now();
+        let path = req.uri().path().to_string();
+        let metrics = self.metrics.clone();
+        let call = self.inner.call(req);
+
+        Box::pin(async move {
+            let res = call.await?;
+            let code = grpc_code(res.headers());
+            Ok(res.map(|body| {
+                body::observe(body, code, move |code, _| {
+                    metrics.record(&path, code, started)
+                })
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use http_body_util::BodyExt;
+    use prometheus_client::encoding::text::encode;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn counts_calls_by_method_and_code() {
+        let mut registry = Registry::default();
+        let metrics = RpcMetrics::register(&mut registry);
+        let svc = RpcMetricsLayer::new(metrics).layer(tower::service_fn(
+            |req: Request<Body>| async move {
+                let status = match req.uri().path() {
+                    "/f2.users.v1.Users/GetUser" => tonic::Status::not_found("no such user"),
+                    _ => tonic::Status::unimplemented(""),
+                };
+                Ok::<_, Infallible>(status.into_http())
+            },
+        ));
+
+        for path in ["/f2.users.v1.Users/GetUser", "/f2.users.v1.Users/Made/Up"] {
+            let req = Request::builder().uri(path).body(Body::empty()).unwrap();
+            let res = svc.clone().oneshot(req).await.unwrap();
+            res.into_body().collect().await.unwrap();
+        }
+
+        let mut text = String::new();
+        encode(&mut text, &registry).unwrap();
+        for expected in [
+            "rpc_calls_total{service=\"f2.users.v1.Users\",method=\"GetUser\",code=\"NotFound\"} 1",
+            "rpc_calls_total{service=\"unknown\",method=\"unknown\",code=\"Unimplemented\"} 1",
+            "rpc_duration_seconds_count{service=\"f2.users.v1.Users\",method=\"GetUser\"} 1",
+        ] {
+            assert!(text.contains(expected), "{text}");
+        }
+    }
+}
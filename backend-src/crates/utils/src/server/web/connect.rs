@@ -0,0 +1,507 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD_NO_PAD, URL_SAFE_NO_PAD};
+use http::request::Parts;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, Request, Response, header};
+use http_body_util::Full;
+use hyper::body::{Bytes, Frame};
+use serde_json::{Map, Value, json};
+use tonic::body::Body;
+use tonic::{Code, Status};
+
+use super::json::{Codec, Json};
+use super::{
+    copy_metadata, details, grpc_headers, http_status, query_decode, unary, unsupported_media_type,
+};
+use crate::envelope::{COMPRESSED, END_STREAM, Envelopes, envelope};
+use crate::rewrite::{Rewrite, rewrite};
+use crate::{compression, deadline};
+
+const PROTOCOL_VERSION: &str = "connect-protocol-version";
+const TIMEOUT_MS: &str = "connect-timeout-ms";
+const CONTENT_ENCODING: &str = "connect-content-encoding";
+const ACCEPT_ENCODING: &str = "connect-accept-encoding";
+
+/// A Connect call, unary or streaming.
+pub(crate) struct Call {
+    codec: Codec,
+    streaming: bool,
+}
+
+/// Accepts a unary `POST`, whose body is the bare request message.
+#[allow(clippy::result_large_err)]
+pub(super) async fn accept_unary(
+    req: Request<Body>,
+    codec: &str,
+    json: Option<&Json>,
+    refusal: Option<&Status>,
+) -> Result<(Request<Body>, super::Protocol), Response<Body>> {
+    let codec = Codec::new(codec, req.uri().path(), json).ok_or_else(unsupported_media_type)?;
+    let call = Call {
+        codec,
+        streaming: false,
+    };
+    if let Some(encoding) = req.headers().get(header::CONTENT_ENCODING)
+        && encoding != "identity"
+    {
+        let status = Status::unimplemented(format!("unsupported content-encoding {encoding:?}"));
+        return Err(call.error(&HeaderMap::new(), &status));
+    }
+
+    let (parts, body) = req.into_parts();
+    let message = match super::read_body(body, refusal).await {
+        Ok(message) => message,
+        Err(status) => return Err(call.error(&HeaderMap::new(), &status)),
+    };
+    call.unary(parts, message)
+}
+
+/// Accepts a unary `GET`, which carries the request message in the query
+/// so the response can be cached.
+#[allow(clippy::result_large_err)]
+pub(super) fn accept_get(
+    req: Request<Body>,
+    json: Option<&Json>,
+) -> Result<(Request<Body>, super::Protocol), Response<Body>> {
+    let mut message = Vec::new();
+    let mut encoding = None;
+    let mut base64 = false;
+    let mut compression = None;
+    for pair in req.uri().query().unwrap_or_default().split('&') {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match key {
+            "message" => message = query_decode(value),
+            "encoding" => encoding = Some(value),
+            "base64" => base64 = value == "1",
+            "compression" => compression = Some(value),
+            _ => {}
+        }
+    }
+    let codec = encoding
+        .and_then(|encoding| Codec::new(encoding, req.uri().path(), json))
+        .ok_or_else(unsupported_media_type)?;
+    let call = Call {
+        codec,
+        streaming: false,
+    };
+    if let Some(compression) = compression.filter(|c| *c != "identity") {
+        let status = Status::unimplemented(format!("unsupported compression {compression:?}"));
+        return Err(call.error(&HeaderMap::new(), &status));
+    }
+    if base64 {
+        // padding is optional, and percent-encoded if present
+        let trimmed = message.strip_suffix(b"==").or(message.strip_suffix(b"="));
+        message = match URL_SAFE_NO_PAD.decode(trimmed.unwrap_or(&message)) {
+            Ok(message) => message,
+            Err(e) => {
+                let status = Status::invalid_argument(format!("invalid base64 message: {e}"));
+                return Err(call.error(&HeaderMap::new(), &status));
+            }
+        };
+    }
+
+    let (mut parts, _) = req.into_parts();
+    parts.method = Method::POST;
+    parts.uri = match parts.uri.path().parse() {
+        Ok(uri) => uri,
+        Err(_) => return Err(call.error(&HeaderMap::new(), &Status::invalid_argument("bad path"))),
+    };
+    call.unary(parts, message.into())
+}
+
+/// Accepts a streaming call, whose body holds enveloped messages like
+/// native gRPC.
+#[allow(clippy::result_large_err)]
+pub(super) fn accept_stream(
+    req: Request<Body>,
+    codec: &str,
+    json: Option<&Json>,
+) -> Result<(Request<Body>, super::Protocol), Response<Body>> {
+    let codec = Codec::new(codec, req.uri().path(), json).ok_or_else(unsupported_media_type)?;
+    let call = Call {
+        codec,
+        streaming: true,
+    };
+    if let Some(encoding) = req.headers().get(CONTENT_ENCODING)
+        && encoding != "identity"
+    {
+        let status = Status::unimplemented(format!("unsupported content-encoding {encoding:?}"));
+        return Err(call.error(&HeaderMap::new(), &status));
+    }
+
+    let (mut parts, body) = req.into_parts();
+    if let Err(status) = grpc_request(&mut parts.headers) {
+        return Err(call.error(&HeaderMap::new(), &status));
+    }
+    let body = match &call.codec {
+        Codec::Proto => body,
+        Codec::Json(_) => rewrite(
+            body,
+            Requests {
+                codec: call.codec.clone(),
+                envelopes: Envelopes::default(),
+            },
+        ),
+    };
+    Ok((
+        Request::from_parts(parts, body),
+        super::Protocol::Connect(call),
+    ))
+}
+
+/// Turns Connect request headers into gRPC ones.
+#[allow(clippy::result_large_err)]
+fn grpc_request(headers: &mut HeaderMap) -> Result<(), Status> {
+    if let Some(timeout) = headers.remove(TIMEOUT_MS) {
+        let ms = timeout
+            .to_str()
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .ok_or_else(|| Status::invalid_argument(format!("invalid {TIMEOUT_MS} {timeout:?}")))?;
+        headers.insert(
+            deadline::GRPC_TIMEOUT,
+            deadline::encode(Duration::from_millis(ms)),
+        );
+    }
+    // responses are read back as uncompressed gRPC messages
+    for name in [
+        PROTOCOL_VERSION,
+        CONTENT_ENCODING,
+        ACCEPT_ENCODING,
+        compression::ACCEPT_ENCODING,
+    ] {
+        headers.remove(name);
+    }
+    headers.remove(header::CONTENT_ENCODING);
+    headers.remove(header::ACCEPT_ENCODING);
+    grpc_headers(headers);
+    Ok(())
+}
+
+impl Call {
+    #[allow(clippy::result_large_err)]
+    fn unary(
+        self,
+        mut parts: Parts,
+        message: Bytes,
+    ) -> Result<(Request<Body>, super::Protocol), Response<Body>> {
+        let message = self
+            .codec
+            .request(message)
+            .and_then(|message| grpc_request(&mut parts.headers).map(|()| message));
+        match message {
+            Ok(message) => {
+                let body = Body::new(Full::new(envelope(0, &message)));
+                Ok((
+                    Request::from_parts(parts, body),
+                    super::Protocol::Connect(self),
+                ))
+            }
+            Err(status) => Err(self.error(&HeaderMap::new(), &status)),
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub(super) async fn respond(self, res: Response<Body>) -> Response<Body> {
+        if self.streaming {
+            // a trailers-only response, usually an error
+            return match Status::from_header_map(res.headers()) {
+                Some(status) => self.error(res.headers(), &status),
+                None => self.stream(res),
+            };
+        }
+
+        let unary = unary(res).await;
+        let mut metadata = unary.headers;
+        for (name, value) in &unary.trailers {
+            if let Ok(name) = HeaderName::try_from(format!("trailer-{name}")) {
+                metadata.append(name, value.clone());
+            }
+        }
+        match unary
+            .message
+            .and_then(|message| self.codec.response(message))
+        {
+            Ok(message) => {
+                let mut res = Response::new(Body::new(Full::new(message)));
+                copy_metadata(&metadata, res.headers_mut());
+                res.headers_mut()
+                    .insert(header::CONTENT_TYPE, self.content_type());
+                res
+            }
+            Err(status) => self.error(&metadata, &status),
+        }
+    }
+
+    fn stream(self, res: Response<Body>) -> Response<Body> {
+        let (mut parts, body) = res.into_parts();
+        let mut headers = HeaderMap::new();
+        copy_metadata(&parts.headers, &mut headers);
+        headers.insert(header::CONTENT_TYPE, self.content_type());
+        parts.headers = headers;
+        let body = rewrite(
+            body,
+            Responses {
+                codec: self.codec,
+                envelopes: Envelopes::default(),
+                ended: false,
+            },
+        );
+        Response::from_parts(parts, body)
+    }
+
+    /// Answers with `status`: unary calls with an HTTP error status and the
+    /// error as JSON, streaming calls with a lone end-of-stream message.
+    fn error(&self, metadata: &HeaderMap, status: &Status) -> Response<Body> {
+        let mut headers = HeaderMap::new();
+        copy_metadata(metadata, &mut headers);
+        let res = match self.streaming {
+            true => {
+                headers.insert(header::CONTENT_TYPE, self.content_type());
+                let end = end_of_stream(status, &HeaderMap::new());
+                Response::new(Body::new(Full::new(end)))
+            }
+            false => {
+                headers.insert(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("application/json"),
+                );
+                let body = error_json(status).to_string();
+                let mut res = Response::new(Body::new(Full::new(Bytes::from(body))));
+                *res.status_mut() = http_status(status.code());
+                res
+            }
+        };
+        let (mut parts, body) = res.into_parts();
+        parts.headers = headers;
+        Response::from_parts(parts, body)
+    }
+
+    fn content_type(&self) -> HeaderValue {
+        let content_type = match (self.streaming, self.codec.name()) {
+            (true, "json") => "application/connect+json",
+            (true, _) => "application/connect+proto",
+            (false, "json") => "application/json",
+            (false, _) => "application/proto",
+        };
+        HeaderValue::from_static(content_type)
+    }
+}
+
+/// Transcodes the messages of a streaming JSON request to protobuf.
+struct Requests {
+    codec: Codec,
+    envelopes: Envelopes,
+}
+
+impl Rewrite for Requests {
+    fn data(&mut self, data: Bytes, out: &mut VecDeque<Frame<Bytes>>) -> Result<(), Status> {
+        self.envelopes.push(&data);
+        loop {
+            self.envelopes.check_len()?;
+            let Some((flags, message)) = self.envelopes.next() else {
+                break;
+            };
+            if flags & COMPRESSED != 0 {
+                return Err(Status::unimplemented(
+                    "compressed messages are not supported",
+                ));
+            }
+            let message = self.codec.request(message)?;
+            out.push_back(Frame::data(envelope(0, &message)));
+        }
+        Ok(())
+    }
+
+    fn end(&mut self, _: Option<HeaderMap>, _: &mut VecDeque<Frame<Bytes>>) -> Result<(), Status> {
+        match self.envelopes.is_empty() {
+            true => Ok(()),
+            false => Err(Status::invalid_argument("request message was cut off")),
+        }
+    }
+}
+
+/// Transcodes the messages of a streaming response and turns its trailers
+/// into the end-of-stream message.
+struct Responses {
+    codec: Codec,
+    envelopes: Envelopes,
+    ended: bool,
+}
+
+impl Responses {
+    fn end_with(
+        &mut self,
+        status: &Status,
+        metadata: &HeaderMap,
+        out: &mut VecDeque<Frame<Bytes>>,
+    ) {
+        if !self.ended {
+            self.ended = true;
+            out.push_back(Frame::data(end_of_stream(status, metadata)));
+        }
+    }
+}
+
+impl Rewrite for Responses {
+    fn data(&mut self, data: Bytes, out: &mut VecDeque<Frame<Bytes>>) -> Result<(), Status> {
+        if self.ended {
+            return Ok(());
+        }
+        if let Codec::Proto = self.codec {
+            // protobuf messages are enveloped the same as in gRPC
+            out.push_back(Frame::data(data));
+            return Ok(());
+        }
+        self.envelopes.push(&data);
+        while let Some((flags, message)) = self.envelopes.next() {
+            let message = match flags & COMPRESSED {
+                0 => self.codec.response(message),
+                _ => Err(Status::internal("can't transcode a compressed message")),
+            };
+            match message {
+                Ok(message) => out.push_back(Frame::data(envelope(0, &message))),
+                Err(status) => {
+                    self.end_with(&status, &HeaderMap::new(), out);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn end(
+        &mut self,
+        trailers: Option<HeaderMap>,
+        out: &mut VecDeque<Frame<Bytes>>,
+    ) -> Result<(), Status> {
+        let trailers = trailers.unwrap_or_default();
+        let status = Status::from_header_map(&trailers)
+            .unwrap_or_else(|| Status::unknown("response ended without a status"));
+        self.end_with(&status, &trailers, out);
+        Ok(())
+    }
+
+    fn error(&mut self, e: Status, out: &mut VecDeque<Frame<Bytes>>) -> Result<(), Status> {
+        self.end_with(&e, &HeaderMap::new(), out);
+        Ok(())
+    }
+}
+
+/// The message ending a Connect stream, with the error if the call failed
+/// and the trailers as metadata.
+fn end_of_stream(status: &Status, trailers: &HeaderMap) -> Bytes {
+    let mut metadata = Map::new();
+    for name in trailers.keys() {
+        if name.as_str().starts_with("grpc-") {
+            continue;
+        }
+        let values = trailers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .map(Value::from)
+            .collect();
+        metadata.insert(name.to_string(), Value::Array(values));
+    }
+    let mut end = json!({ "metadata": metadata });
+    if status.code() != Code::Ok {
+        end["error"] = error_json(status);
+    }
+    envelope(END_STREAM, end.to_string().as_bytes())
+}
+
+fn error_json(status: &Status) -> Value {
+    let mut error = json!({ "code": code_name(status.code()) });
+    if !status.message().is_empty() {
+        error["message"] = status.message().into();
+    }
+    let details = details(status);
+    if !details.is_empty() {
+        let details = details
+            .iter()
+            .map(|any| {
+                json!({
+                    "type": any.type_url.rsplit('/').next().unwrap_or_default(),
+                    "value": STANDARD_NO_PAD.encode(&any.value),
+                })
+            })
+            .collect();
+        error["details"] = Value::Array(details);
+    }
+    error
+}
+
+fn code_name(code: Code) -> &'static str {
+    match code {
+        Code::Ok => "ok",
+        Code::Cancelled => "canceled",
+        Code::Unknown => "unknown",
+        Code::InvalidArgument => "invalid_argument",
+        Code::DeadlineExceeded => "deadline_exceeded",
+        Code::NotFound => "not_found",
+        Code::AlreadyExists => "already_exists",
+        Code::PermissionDenied => "permission_denied",
+        Code::ResourceExhausted => "resource_exhausted",
+        Code::FailedPrecondition => "failed_precondition",
+        Code::Aborted => "aborted",
+        Code::OutOfRange => "out_of_range",
+        Code::Unimplemented => "unimplemented",
+        Code::Internal => "internal",
+        Code::Unavailable => "unavailable",
+        Code::DataLoss => "data_loss",
+        Code::Unauthenticated => "unauthenticated",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Any, RpcStatus};
+    use super::*;
+    use http::StatusCode;
+    use prost::Message;
+
+    #[test]
+    fn describes_errors_as_json() {
+        let details = RpcStatus {
+            code: Code::NotFound as i32,
+            message: "no such user".to_string(),
+            details: vec![Any {
+                type_url: "type.googleapis.com/f2.errors.v1.Error".to_string(),
+                value: vec![1, 2, 3],
+            }],
+        };
+        let status = Status::with_details(
+            Code::NotFound,
+            "no such user",
+            details.encode_to_vec().into(),
+        );
+        assert_eq!(
+            error_json(&status),
+            json!({
+                "code": "not_found",
+                "message": "no such user",
+                "details": [{ "type": "f2.errors.v1.Error", "value": "AQID" }],
+            })
+        );
+        assert_eq!(http_status(status.code()), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn refuses_oversized_length_prefixes() {
+        let mut header = vec![0];
+        header.extend_from_slice(&u32::MAX.to_be_bytes());
+        header.extend_from_slice(b"{\"only\": \"the start");
+
+        let mut requests = Requests {
+            codec: Codec::Proto,
+            envelopes: Envelopes::default(),
+        };
+        let status = requests
+            .data(header.into(), &mut VecDeque::new())
+            .unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+    }
+}
@@ -0,0 +1,77 @@
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, header};
+use tonic::body::Body;
+
+/// Methods calls come in with: `POST`, and `GET` for Connect and REST.
+const ALLOWED_METHODS: &str = "GET, POST";
+
+/// Response headers scripts may read besides the safelisted ones: the status
+/// of gRPC-Web calls and the request ID.
+const EXPOSED_HEADERS: &str =
+    "grpc-status, grpc-message, grpc-status-details-bin, grpc-encoding, x-request-id";
+
+/// How long browsers may cache a preflight answer, in seconds.
+const MAX_AGE: &str = "7200";
+
+/// Origins allowed to call from a browser, where `*` allows any.
+pub(super) struct Cors {
+    origins: Vec<String>,
+}
+
+impl Cors {
+    pub(super) fn new(origins: Vec<String>) -> Self {
+        Self { origins }
+    }
+
+    /// The `Origin` of a request, if it is allowed.
+    pub(super) fn allowed(&self, headers: &HeaderMap) -> Option<HeaderValue> {
+        let origin = headers.get(header::ORIGIN)?;
+        let name = origin.to_str().ok()?;
+        self.origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(name))
+            .then(|| origin.clone())
+    }
+}
+
+/// Whether a request is a CORS preflight, asking whether the call may be
+/// made at all.
+pub(super) fn is_preflight<B>(req: &Request<B>) -> bool {
+    req.method() == Method::OPTIONS
+        && req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+/// Answers a preflight, allowing the call when it comes from an allowed
+/// `origin`. Without one the browser refuses to make it.
+pub(super) fn preflight(origin: Option<HeaderValue>, headers: &HeaderMap) -> Response<Body> {
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = StatusCode::NO_CONTENT;
+    let Some(origin) = origin else {
+        return res;
+    };
+    let allowed = res.headers_mut();
+    allow(origin, allowed);
+    allowed.insert(
+        header::ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_static(ALLOWED_METHODS),
+    );
+    if let Some(requested) = headers.get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+        allowed.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+    }
+    allowed.insert(
+        header::ACCESS_CONTROL_MAX_AGE,
+        HeaderValue::from_static(MAX_AGE),
+    );
+    res
+}
+
+/// Lets scripts from `origin` read a response.
+pub(super) fn allow(origin: HeaderValue, headers: &mut HeaderMap) {
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.insert(
+        header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static(EXPOSED_HEADERS),
+    );
+    headers.append(header::VARY, HeaderValue::from_static("origin"));
+}
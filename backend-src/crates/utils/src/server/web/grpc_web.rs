@@ -0,0 +1,173 @@
+use std::collections::VecDeque;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use http::{HeaderMap, HeaderValue, Request, Response, header};
+use hyper::body::{Bytes, Frame};
+use tonic::Status;
+use tonic::body::Body;
+
+use super::grpc_headers;
+use crate::envelope::{TRAILERS, envelope};
+use crate::rewrite::{Rewrite, rewrite};
+
+/// Whether a content type is gRPC-Web, and if so whether it is the base64
+/// text variant.
+pub(super) fn content_type(mime: &str) -> Option<bool> {
+    let rest = mime.strip_prefix("application/grpc-web")?;
+    let (text, rest) = match rest.strip_prefix("-text") {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+    matches!(rest, "" | "+proto").then_some(text)
+}
+
+pub(super) fn accept(req: Request<Body>, text: bool) -> (Request<Body>, super::Protocol) {
+    let (mut parts, body) = req.into_parts();
+    grpc_headers(&mut parts.headers);
+    let body = match text {
+        true => rewrite(body, Base64Decode::default()),
+        false => body,
+    };
+    (
+        Request::from_parts(parts, body),
+        super::Protocol::GrpcWeb(Call { text }),
+    )
+}
+
+pub(crate) struct Call {
+    text: bool,
+}
+
+impl Call {
+    /// Moves the trailers into the body, where HTTP/1.1 and browsers can
+    /// see them.
+    pub(super) fn respond(self, res: Response<Body>) -> Response<Body> {
+        let (mut parts, body) = res.into_parts();
+        let content_type = match self.text {
+            true => "application/grpc-web-text+proto",
+            false => "application/grpc-web+proto",
+        };
+        parts
+            .headers
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        parts.headers.remove(header::CONTENT_LENGTH);
+        let body = rewrite(body, Trailers { text: self.text });
+        Response::from_parts(parts, body)
+    }
+}
+
+/// Decodes a base64 request body, which clients may send as separately
+/// padded chunks.
+#[derive(Default)]
+pub(super) struct Base64Decode {
+    buf: Vec<u8>,
+}
+
+impl Rewrite for Base64Decode {
+    fn data(&mut self, data: Bytes, out: &mut VecDeque<Frame<Bytes>>) -> Result<(), Status> {
+        self.buf.extend_from_slice(&data);
+        let whole = self.buf.len() / 4 * 4;
+        let mut decoded = Vec::with_capacity(whole / 4 * 3);
+        let mut start = 0;
+        for end in (4..=whole).step_by(4) {
+            if end == whole || self.buf[end - 1] == b'=' {
+                STANDARD
+                    .decode_vec(&self.buf[start..end], &mut decoded)
+                    .map_err(|e| Status::invalid_argument(format!("invalid base64 body: {e}")))?;
+                start = end;
+            }
+        }
+        self.buf.drain(..whole);
+        if !decoded.is_empty() {
+            out.push_back(Frame::data(decoded.into()));
+        }
+        Ok(())
+    }
+
+    fn end(
+        &mut self,
+        trailers: Option<HeaderMap>,
+        out: &mut VecDeque<Frame<Bytes>>,
+    ) -> Result<(), Status> {
+        if !self.buf.is_empty() {
+            return Err(Status::invalid_argument("base64 body was cut off"));
+        }
+        out.extend(trailers.map(Frame::trailers));
+        Ok(())
+    }
+}
+
+/// Sends the trailers as a last message flagged as such, and base64
+/// encodes the body for text clients.
+struct Trailers {
+    text: bool,
+}
+
+impl Trailers {
+    fn encode(&self, data: Bytes) -> Bytes {
+        match self.text {
+            true => STANDARD.encode(data).into(),
+            false => data,
+        }
+    }
+}
+
+impl Rewrite for Trailers {
+    fn data(&mut self, data: Bytes, out: &mut VecDeque<Frame<Bytes>>) -> Result<(), Status> {
+        out.push_back(Frame::data(self.encode(data)));
+        Ok(())
+    }
+
+    fn end(
+        &mut self,
+        trailers: Option<HeaderMap>,
+        out: &mut VecDeque<Frame<Bytes>>,
+    ) -> Result<(), Status> {
+        // trailers-only responses already carry the status in the headers
+        let Some(trailers) = trailers else {
+            return Ok(());
+        };
+        let mut block = Vec::new();
+        for (name, value) in &trailers {
+            block.extend_from_slice(name.as_str().as_bytes());
+            block.extend_from_slice(b": ");
+            block.extend_from_slice(value.as_bytes());
+            block.extend_from_slice(b"\r\n");
+        }
+        out.push_back(Frame::data(self.encode(envelope(TRAILERS, &block))));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognises_content_types() {
+        assert_eq!(content_type("application/grpc-web"), Some(false));
+        assert_eq!(content_type("application/grpc-web+proto"), Some(false));
+        assert_eq!(content_type("application/grpc-web-text"), Some(true));
+        assert_eq!(content_type("application/grpc-web-text+proto"), Some(true));
+        assert_eq!(content_type("application/grpc-web+json"), None);
+        assert_eq!(content_type("application/grpc"), None);
+    }
+
+    #[test]
+    fn decodes_padded_chunks() {
+        let mut decode = Base64Decode::default();
+        let mut out = VecDeque::new();
+        decode
+            .data(Bytes::from_static(b"QQ==QkM"), &mut out)
+            .unwrap();
+        decode.data(Bytes::from_static(b"="), &mut out).unwrap();
+        decode.end(None, &mut out).unwrap();
+
+        let decoded: Vec<u8> = out
+            .into_iter()
+            .flat_map(|frame| frame.into_data().unwrap())
+            .collect();
+        assert_eq!(decoded, b"ABC");
+    }
+}
@@ -0,0 +1,104 @@
+use hyper::body::Bytes;
+use prost::Message;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor, MethodDescriptor};
+use tonic::Status;
+
+use crate::Error;
+
+/// Services to transcode JSON messages for.
+pub(super) struct Json {
+    pool: DescriptorPool,
+}
+
+impl Json {
+    pub(super) fn decode(file_descriptor_set: &[u8]) -> Result<Self, Error> {
+        let pool = DescriptorPool::decode(file_descriptor_set)?;
+        Ok(Self { pool })
+    }
+
+    pub(super) fn pool(&self) -> &DescriptorPool {
+        &self.pool
+    }
+
+    /// The method behind a `/package.Service/Method` path.
+    pub(super) fn method(&self, path: &str) -> Option<MethodDescriptor> {
+        let (service, method) = path.strip_prefix('/')?.split_once('/')?;
+        self.pool
+            .get_service_by_name(service)?
+            .methods()
+            .find(|m| m.name() == method)
+    }
+}
+
+/// How the messages of a call are encoded.
+#[derive(Clone)]
+pub(super) enum Codec {
+    Proto,
+    Json(MethodDescriptor),
+}
+
+impl Codec {
+    /// Picks the codec named by a content type suffix or `encoding` query
+    /// parameter, `None` when the server can't speak it for `path`.
+    pub(super) fn new(name: &str, path: &str, json: Option<&Json>) -> Option<Self> {
+        match name {
+            "proto" => Some(Codec::Proto),
+            "json" => json?.method(path).map(Codec::Json),
+            _ => None,
+        }
+    }
+
+    pub(super) fn name(&self) -> &'static str {
+        match self {
+            Codec::Proto => "proto",
+            Codec::Json(_) => "json",
+        }
+    }
+
+    /// Turns a request message into protobuf.
+    #[allow(clippy::result_large_err)]
+    pub(super) fn request(&self, message: Bytes) -> Result<Bytes, Status> {
+        match self {
+            Codec::Proto => Ok(message),
+            Codec::Json(method) => to_proto(method.input(), &message),
+        }
+    }
+
+    /// Turns a protobuf response message into this encoding.
+    #[allow(clippy::result_large_err)]
+    pub(super) fn response(&self, message: Bytes) -> Result<Bytes, Status> {
+        match self {
+            Codec::Proto => Ok(message),
+            Codec::Json(method) => to_json(method.output(), &message),
+        }
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn to_proto(desc: MessageDescriptor, json: &[u8]) -> Result<Bytes, Status> {
+    let mut deserializer = serde_json::Deserializer::from_slice(json);
+    let message = DynamicMessage::deserialize(desc, &mut deserializer)
+        .and_then(|message| deserializer.end().map(|()| message))
+        .map_err(|e| Status::invalid_argument(format!("invalid JSON message: {e}")))?;
+    Ok(message.encode_to_vec().into())
+}
+
+/// Encodes a JSON value as a protobuf message.
+#[allow(clippy::result_large_err)]
+pub(super) fn from_value(
+    desc: MessageDescriptor,
+    json: serde_json::Value,
+) -> Result<Bytes, Status> {
+    let message = DynamicMessage::deserialize(desc, json)
+        .map_err(|e| Status::invalid_argument(format!("invalid JSON message: {e}")))?;
+    Ok(message.encode_to_vec().into())
+}
+
+#[allow(clippy::result_large_err)]
+pub(super) fn to_json(desc: MessageDescriptor, proto: &[u8]) -> Result<Bytes, Status> {
+    let message = DynamicMessage::decode(desc, proto)
+        .map_err(|e| Status::internal(format!("invalid response message: {e}")))?;
+    serde_json::to_vec(&message)
+        .map(Bytes::from)
+        .map_err(|e| Status::internal(format!("failed to encode response as JSON: {e}")))
+}
@@ -0,0 +1,855 @@
+//! Connect and gRPC-Web, translated to native gRPC in front of the routes so
+//! browser, Kotlin and Swift clients reach the same services without a
+//! proxy.
+//!
+//! Calls are told apart by their content type:
+//!
+//! - `application/grpc*` is native gRPC and passes through untouched.
+//! - `application/grpc-web*` is gRPC-Web, binary or base64 text, with the
+//!   trailers sent as a last message in the body.
+//! - `application/proto` and `application/json` are Connect unary calls, as
+//!   are `GET` requests carrying the message in the query.
+//! - `application/connect+proto` and `application/connect+json` are Connect
+//!   streaming calls, ending with an end-of-stream message in the body.
+//!
+//! JSON needs the descriptors of the services, see [`Web::with_descriptors`].
+//! Methods annotated with `google.api.http` in them are served as REST
+//! routes as well, with errors as `f2.errors.v1.Error` JSON.
+//!
+//! Browsers on other origins need to be let in with [`Web::with_cors`].
+
+use std::sync::Arc;
+
+use http::{HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode, header};
+use http_body_util::{BodyExt, Limited};
+use hyper::body::Bytes;
+use prost::Message;
+use tonic::body::Body;
+use tonic::{Code, Status};
+
+use crate::Error;
+use crate::envelope::{Envelopes, MAX_MESSAGE_SIZE};
+
+mod connect;
+mod cors;
+mod grpc_web;
+mod json;
+mod rest;
+
+use cors::Cors;
+use json::Json;
+use rest::Rest;
+
+/// Which protocols besides native gRPC a server speaks, and the descriptors
+/// to transcode JSON messages with.
+///
+/// ```no_run
+/// # fn web(descriptors: &[u8]) -> Result<f2_utils::server::web::Web, f2_utils::Error> {
+/// f2_utils::server::web::Web::new().with_descriptors(descriptors)
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct Web {
+    json: Option<Arc<Json>>,
+    rest: Option<Arc<Rest>>,
+    cors: Option<Arc<Cors>>,
+}
+
+impl Web {
+    /// Connect and gRPC-Web with binary protobuf messages.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Transcodes Connect JSON messages with the services described in an
+    /// encoded `google.protobuf.FileDescriptorSet`. Without descriptors JSON
+    /// calls are answered with `415 Unsupported Media Type`.
+    ///
+    /// Unary methods with `google.api.http` rules get REST routes, mapping
+    /// path variables, query parameters and the JSON body onto the request
+    /// message. Rules that don't fit their method fail here.
+    pub fn with_descriptors(mut self, file_descriptor_set: &[u8]) -> Result<Self, Error> {
+        let json = Json::decode(file_descriptor_set)?;
+        self.rest = Rest::new(json.pool())?.map(Arc::new);
+        self.json = Some(Arc::new(json));
+        Ok(self)
+    }
+
+    /// Lets browser apps served from `origins`, e.g.
+    /// `https://app.f2.example`, call across origins, answering their
+    /// preflights and letting them read the responses. `*` allows any
+    /// origin. Preflights from other origins are answered without allowing
+    /// anything, so the browser never makes the call.
+    pub fn with_cors(mut self, origins: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        let origins = origins.into_iter().map(Into::into).collect();
+        self.cors = Some(Arc::new(Cors::new(origins)));
+        self
+    }
+
+    /// Turns a request into a native gRPC call, or answers it straight away
+    /// when it can't be. With a `refusal`, calls whose body has to be read
+    /// up front are answered with it instead, so refusing them costs no
+    /// buffering; the others are translated as usual for the caller to
+    /// answer.
+    #[allow(clippy::result_large_err)]
+    pub(crate) async fn accept(
+        &self,
+        req: Request<Body>,
+        refusal: Option<&Status>,
+    ) -> Result<(Request<Body>, Call), Response<Body>> {
+        let origin = self
+            .cors
+            .as_ref()
+            .and_then(|cors| cors.allowed(req.headers()));
+        if cors::is_preflight(&req) {
+            return Err(cors::preflight(origin, req.headers()));
+        }
+        match self.translate(req, refusal).await {
+            Ok((req, protocol)) => Ok((req, Call { protocol, origin })),
+            Err(mut res) => {
+                if let Some(origin) = origin {
+                    cors::allow(origin, res.headers_mut());
+                }
+                Err(res)
+            }
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    async fn translate(
+        &self,
+        req: Request<Body>,
+        refusal: Option<&Status>,
+    ) -> Result<(Request<Body>, Protocol), Response<Body>> {
+        let content_type = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        if let Some(text) = grpc_web::content_type(&mime) {
+            return Ok(grpc_web::accept(req, text));
+        }
+        if mime.starts_with("application/grpc") {
+            return Ok((req, Protocol::Grpc));
+        }
+        if let Some(rest) = &self.rest
+            && let Some(matched) = rest.route(req.method(), req.uri().path())
+        {
+            return rest::accept(req, matched, refusal).await;
+        }
+        if req.method() == http::Method::GET {
+            return connect::accept_get(req, self.json.as_deref());
+        }
+        if let Some(codec) = mime.strip_prefix("application/connect+") {
+            return connect::accept_stream(req, codec, self.json.as_deref());
+        }
+        if let Some(codec) = mime.strip_prefix("application/") {
+            return connect::accept_unary(req, codec, self.json.as_deref(), refusal).await;
+        }
+        Err(unsupported_media_type())
+    }
+}
+
+/// How the response of an accepted call has to be translated back, and the
+/// origin allowed to read it.
+pub(crate) struct Call {
+    protocol: Protocol,
+    origin: Option<HeaderValue>,
+}
+
+enum Protocol {
+    Grpc,
+    GrpcWeb(grpc_web::Call),
+    Connect(connect::Call),
+    Rest(rest::Call),
+}
+
+impl Call {
+    /// A native gRPC call, whose response passes through untouched.
+    pub(crate) fn grpc() -> Self {
+        Self {
+            protocol: Protocol::Grpc,
+            origin: None,
+        }
+    }
+
+    pub(crate) async fn respond(self, res: Response<Body>) -> Response<Body> {
+        let mut res = match self.protocol {
+            Protocol::Grpc => res,
+            Protocol::GrpcWeb(call) => call.respond(res),
+            Protocol::Connect(call) => call.respond(res).await,
+            Protocol::Rest(call) => call.respond(res).await,
+        };
+        if let Some(origin) = self.origin {
+            cors::allow(origin, res.headers_mut());
+        }
+        res
+    }
+}
+
+fn unsupported_media_type() -> Response<Body> {
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+    res.headers_mut().insert(
+        HeaderName::from_static("accept-post"),
+        HeaderValue::from_static(
+            "application/grpc, application/grpc-web, application/grpc-web-text, \
+             application/proto, application/json, application/connect+proto, \
+             application/connect+json",
+        ),
+    );
+    res
+}
+
+/// Turns the headers of a request in another protocol into those of a
+/// native gRPC call.
+fn grpc_headers(headers: &mut HeaderMap) {
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/grpc"),
+    );
+    headers.insert(header::TE, HeaderValue::from_static("trailers"));
+    headers.remove(header::CONTENT_LENGTH);
+}
+
+/// A unary gRPC response taken apart: the headers and trailers meant for the
+/// client, and the one message or the status the call failed with.
+struct Unary {
+    headers: HeaderMap,
+    trailers: HeaderMap,
+    message: Result<Bytes, Status>,
+}
+
+async fn unary(res: Response<Body>) -> Unary {
+    let (parts, body) = res.into_parts();
+    // a trailers-only response, usually an error
+    if let Some(status) = Status::from_header_map(&parts.headers) {
+        let status = match status.code() {
+            Code::Ok => Status::internal("response had no message"),
+            _ => status,
+        };
+        return Unary {
+            headers: parts.headers,
+            trailers: HeaderMap::new(),
+            message: Err(status),
+        };
+    }
+
+    let body = match body.collect().await {
+        Ok(body) => body,
+        Err(status) => {
+            return Unary {
+                headers: parts.headers,
+                trailers: HeaderMap::new(),
+                message: Err(status),
+            };
+        }
+    };
+    let trailers = body.trailers().cloned().unwrap_or_default();
+    let status = Status::from_header_map(&trailers)
+        .unwrap_or_else(|| Status::internal("response ended without a status"));
+    let message = match status.code() {
+        Code::Ok => {
+            let mut envelopes = Envelopes::default();
+            envelopes.push(&body.to_bytes());
+            match (envelopes.next(), envelopes.is_empty()) {
+                (Some((0, message)), true) => Ok(message),
+                _ => Err(Status::internal(
+                    "expected one uncompressed response message",
+                )),
+            }
+        }
+        _ => Err(status),
+    };
+    Unary {
+        headers: parts.headers,
+        trailers,
+        message,
+    }
+}
+
+/// Reads the body of a unary request, failing with `RESOURCE_EXHAUSTED` once
+/// it grows past [`MAX_MESSAGE_SIZE`] instead of buffering whatever the
+/// client sends. With a `refusal` it fails with that without reading.
+#[allow(clippy::result_large_err)]
+async fn read_body(body: Body, refusal: Option<&Status>) -> Result<Bytes, Status> {
+    if let Some(status) = refusal {
+        return Err(status.clone());
+    }
+    match Limited::new(body, MAX_MESSAGE_SIZE).collect().await {
+        Ok(body) => Ok(body.to_bytes()),
+        Err(e) => match e.downcast::<Status>() {
+            Ok(status) => Err(*status),
+            Err(_) => Err(Status::resource_exhausted(format!(
+                "request body is larger than {MAX_MESSAGE_SIZE} bytes"
+            ))),
+        },
+    }
+}
+
+/// `google.rpc.Status`, which gRPC sends in `grpc-status-details-bin`.
+#[derive(Clone, PartialEq, Message)]
+struct RpcStatus {
+    #[prost(int32, tag = "1")]
+    code: i32,
+    #[prost(string, tag = "2")]
+    message: String,
+    #[prost(message, repeated, tag = "3")]
+    details: Vec<Any>,
+}
+
+/// `google.protobuf.Any`.
+#[derive(Clone, PartialEq, Message)]
+struct Any {
+    #[prost(string, tag = "1")]
+    type_url: String,
+    #[prost(bytes = "vec", tag = "2")]
+    value: Vec<u8>,
+}
+
+/// The details a status carries in `grpc-status-details-bin`.
+fn details(status: &Status) -> Vec<Any> {
+    RpcStatus::decode(status.details())
+        .map(|status| status.details)
+        .unwrap_or_default()
+}
+
+/// Copies the headers meant for the client, leaving out those describing
+/// the gRPC body.
+fn copy_metadata(from: &HeaderMap, to: &mut HeaderMap) {
+    for (name, value) in from {
+        let reserved = name == header::CONTENT_TYPE
+            || name == header::CONTENT_LENGTH
+            || name == header::TE
+            || name.as_str().starts_with("grpc-");
+        if !reserved {
+            to.append(name, value.clone());
+        }
+    }
+}
+
+/// The HTTP status a unary call failing with `code` is answered with.
+fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::Cancelled => StatusCode::from_u16(499).expect("499 is a valid status"),
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+            StatusCode::BAD_REQUEST
+        }
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::Unknown | Code::Internal | Code::DataLoss => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Decodes a percent-encoded path segment.
+fn percent_decode(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+/// Decodes a query parameter, where `+` stands for a space.
+fn query_decode(value: &str) -> Vec<u8> {
+    percent_decode(&value.replace('+', " "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::{END_STREAM, TRAILERS, envelope};
+    use crate::rewrite::Rewrite;
+    use crate::server::h2c::H2c;
+    use base64::Engine;
+    use base64::engine::general_purpose::{STANDARD, URL_SAFE};
+    use http_body_util::{BodyExt, Full};
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use prost::Message;
+    use prost_reflect::prost_types::{DescriptorProto, FileDescriptorSet};
+    use rest::{HttpRule, MethodOptions, Pattern};
+    use std::collections::VecDeque;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+    use tonic::service::Routes;
+    use tonic_health::pb::{HealthCheckRequest, HealthCheckResponse, health_check_response};
+
+    // enough of descriptor.proto to annotate the health service with rules
+
+    #[derive(Clone, PartialEq, Message)]
+    struct FileSet {
+        #[prost(message, repeated, tag = "1")]
+        file: Vec<File>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct File {
+        #[prost(string, tag = "1")]
+        name: String,
+        #[prost(string, tag = "2")]
+        package: String,
+        #[prost(message, repeated, tag = "4")]
+        message_type: Vec<DescriptorProto>,
+        #[prost(message, repeated, tag = "6")]
+        service: Vec<Service>,
+        #[prost(string, tag = "12")]
+        syntax: String,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Service {
+        #[prost(string, tag = "1")]
+        name: String,
+        #[prost(message, repeated, tag = "2")]
+        method: Vec<Method>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Method {
+        #[prost(string, tag = "1")]
+        name: String,
+        #[prost(string, tag = "2")]
+        input_type: String,
+        #[prost(string, tag = "3")]
+        output_type: String,
+        #[prost(message, optional, tag = "4")]
+        options: Option<MethodOptions>,
+    }
+
+    fn rule(pattern: Pattern, body: &str, response_body: &str) -> HttpRule {
+        HttpRule {
+            pattern: Some(pattern),
+            body: body.to_string(),
+            response_body: response_body.to_string(),
+            additional_bindings: Vec::new(),
+        }
+    }
+
+    /// The health service's descriptors with `rule` on `Check`.
+    fn annotated(rule: HttpRule) -> Vec<u8> {
+        let health = FileDescriptorSet::decode(tonic_health::pb::FILE_DESCRIPTOR_SET).unwrap();
+        let file = &health.file[0];
+        let check = Method {
+            name: "Check".to_string(),
+            input_type: ".grpc.health.v1.HealthCheckRequest".to_string(),
+            output_type: ".grpc.health.v1.HealthCheckResponse".to_string(),
+            options: Some(MethodOptions { http: Some(rule) }),
+        };
+        FileSet {
+            file: vec![File {
+                name: file.name().to_string(),
+                package: file.package().to_string(),
+                message_type: file.message_type.clone(),
+                service: vec![Service {
+                    name: "Health".to_string(),
+                    method: vec![check],
+                }],
+                syntax: "proto3".to_string(),
+            }],
+        }
+        .encode_to_vec()
+    }
+
+    async fn serve(web: Web) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (reporter, health) = tonic_health::server::health_reporter();
+        reporter
+            .set_service_status("f2.test.v1.Test", tonic_health::ServingStatus::Serving)
+            .await;
+        let svc = H2c::new(Routes::new(health).prepare()).with_web(web);
+        tokio::spawn(async move {
+            loop {
+                let (io, _) = listener.accept().await.unwrap();
+                tokio::spawn(svc.clone().serve_connection(TokioIo::new(io)));
+            }
+        });
+        addr
+    }
+
+    fn with_json() -> Web {
+        Web::new()
+            .with_descriptors(tonic_health::pb::FILE_DESCRIPTOR_SET)
+            .unwrap()
+    }
+
+    async fn send(req: http::request::Builder, body: impl Into<Bytes>) -> Response<Bytes> {
+        let client = Client::builder(TokioExecutor::new()).build_http::<Full<Bytes>>();
+        let res = client
+            .request(req.body(Full::new(body.into())).unwrap())
+            .await
+            .unwrap();
+        let (parts, body) = res.into_parts();
+        Response::from_parts(parts, body.collect().await.unwrap().to_bytes())
+    }
+
+    fn post(addr: SocketAddr, method: &str, content_type: &str) -> http::request::Builder {
+        Request::post(format!("http://{addr}/grpc.health.v1.Health/{method}"))
+            .header(header::CONTENT_TYPE, content_type)
+            .header("connect-protocol-version", "1")
+    }
+
+    #[test]
+    fn decodes_query_values() {
+        assert_eq!(query_decode("%7B%22a%22%3A1%7D"), b"{\"a\":1}");
+        assert_eq!(query_decode("a+b%2"), b"a b%2");
+        assert_eq!(percent_decode("a+b"), b"a+b");
+    }
+
+    #[tokio::test]
+    async fn serves_connect_unary_json() {
+        let addr = serve(with_json()).await;
+
+        let res = send(post(addr, "Check", "application/json"), r#"{"service":""}"#).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(res.body(), r#"{"status":"SERVING"}"#);
+
+        let res = send(
+            post(addr, "Check", "application/json"),
+            r#"{"service":"nope"}"#,
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let error: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(error["code"], "not_found");
+
+        let res = send(post(addr, "Check", "application/json"), "{").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn answers_preflights_from_allowed_origins() {
+        let addr = serve(with_json().with_cors(["https://app.f2.example"])).await;
+
+        let preflight = |origin| {
+            Request::options(format!("http://{addr}/grpc.health.v1.Health/Check"))
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .header(
+                    header::ACCESS_CONTROL_REQUEST_HEADERS,
+                    "content-type, connect-protocol-version",
+                )
+        };
+        let res = send(preflight("https://app.f2.example"), Bytes::new()).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let headers = res.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.f2.example"
+        );
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type, connect-protocol-version"
+        );
+
+        let res = send(preflight("https://evil.example"), Bytes::new()).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(
+            !res.headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        );
+
+        let call = post(addr, "Check", "application/json")
+            .header(header::ORIGIN, "https://app.f2.example");
+        let res = send(call, "{}").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.f2.example"
+        );
+        assert!(
+            res.headers()
+                .contains_key(header::ACCESS_CONTROL_EXPOSE_HEADERS)
+        );
+    }
+
+    #[tokio::test]
+    async fn allows_no_origin_without_cors() {
+        let addr = serve(with_json()).await;
+
+        let call = post(addr, "Check", "application/json")
+            .header(header::ORIGIN, "https://app.f2.example");
+        let res = send(call, "{}").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(
+            !res.headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_connect_bodies() {
+        let addr = serve(with_json()).await;
+
+        let body = vec![b' '; MAX_MESSAGE_SIZE + 1];
+        let res = send(post(addr, "Check", "application/json"), body).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let error: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(error["code"], "resource_exhausted");
+    }
+
+    #[tokio::test]
+    async fn serves_connect_get() {
+        let addr = serve(with_json()).await;
+
+        let uri = format!(
+            "http://{addr}/grpc.health.v1.Health/Check?connect=v1&encoding=json&message=%7B%7D"
+        );
+        let res = send(Request::get(uri), Bytes::new()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), r#"{"status":"SERVING"}"#);
+
+        let message = URL_SAFE.encode(HealthCheckRequest::default().encode_to_vec());
+        let uri = format!(
+            "http://{addr}/grpc.health.v1.Health/Check?encoding=proto&base64=1&message={message}"
+        );
+        let res = send(Request::get(uri), Bytes::new()).await;
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/proto");
+        let res = HealthCheckResponse::decode(res.into_body()).unwrap();
+        assert_eq!(res.status(), health_check_response::ServingStatus::Serving);
+    }
+
+    #[tokio::test]
+    async fn serves_connect_proto_without_descriptors() {
+        let addr = serve(Web::new()).await;
+
+        let request = HealthCheckRequest::default().encode_to_vec();
+        let res = send(post(addr, "Check", "application/proto"), request).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = HealthCheckResponse::decode(res.into_body()).unwrap();
+        assert_eq!(res.status(), health_check_response::ServingStatus::Serving);
+
+        let res = send(post(addr, "Check", "application/json"), "{}").await;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let res = send(post(addr, "Nope", "application/proto"), Bytes::new()).await;
+        assert_eq!(res.status(), StatusCode::NOT_IMPLEMENTED);
+    }
+
+    #[tokio::test]
+    async fn serves_connect_streams() {
+        let addr = serve(with_json()).await;
+
+        let client = Client::builder(TokioExecutor::new()).build_http::<Full<Bytes>>();
+        let req = post(addr, "Watch", "application/connect+json")
+            .body(Full::new(envelope(0, b"{}")))
+            .unwrap();
+        let res = client.request(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()[header::CONTENT_TYPE],
+            "application/connect+json"
+        );
+
+        let mut body = res.into_body();
+        let mut envelopes = Envelopes::default();
+        while let Some(frame) = body.frame().await {
+            envelopes.push(&frame.unwrap().into_data().unwrap());
+            if let Some(message) = envelopes.next() {
+                assert_eq!(message, (0, Bytes::from_static(br#"{"status":"SERVING"}"#)));
+                return;
+            }
+        }
+        panic!("stream ended without a message");
+    }
+
+    #[tokio::test]
+    async fn ends_failed_connect_streams_in_the_body() {
+        let addr = serve(with_json()).await;
+
+        let res = send(
+            post(addr, "Nope", "application/connect+proto"),
+            Bytes::new(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut envelopes = Envelopes::default();
+        envelopes.push(res.body());
+        let mut last = None;
+        while let Some(message) = envelopes.next() {
+            last = Some(message);
+        }
+        let (flags, end) = last.unwrap();
+        assert_eq!(flags, END_STREAM);
+        let end: serde_json::Value = serde_json::from_slice(&end).unwrap();
+        assert_eq!(end["error"]["code"], "unimplemented");
+    }
+
+    #[tokio::test]
+    async fn serves_grpc_web_text() {
+        let addr = serve(Web::new()).await;
+
+        let request = envelope(0, &HealthCheckRequest::default().encode_to_vec());
+        let res = send(
+            post(addr, "Check", "application/grpc-web-text"),
+            STANDARD.encode(request),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()[header::CONTENT_TYPE],
+            "application/grpc-web-text+proto"
+        );
+
+        // every chunk is encoded on its own
+        let mut decoded = VecDeque::new();
+        let mut decode = grpc_web::Base64Decode::default();
+        decode.data(res.into_body(), &mut decoded).unwrap();
+        decode.end(None, &mut decoded).unwrap();
+        let mut envelopes = Envelopes::default();
+        for frame in decoded {
+            envelopes.push(&frame.into_data().unwrap());
+        }
+        let (flags, message) = envelopes.next().unwrap();
+        assert_eq!(flags, 0);
+        let message = HealthCheckResponse::decode(message).unwrap();
+        assert_eq!(
+            message.status(),
+            health_check_response::ServingStatus::Serving
+        );
+        let (flags, trailers) = envelopes.next().unwrap();
+        assert_eq!(flags, TRAILERS);
+        assert!(trailers.starts_with(b"grpc-status: 0\r\n"));
+    }
+
+    #[tokio::test]
+    async fn transcodes_rest_calls() {
+        let mut check = rule(Pattern::Get("/v1/health/{service}".to_string()), "", "");
+        check.additional_bindings = vec![
+            rule(Pattern::Get("/v1/health".to_string()), "", ""),
+            rule(Pattern::Post("/v1/health:check".to_string()), "*", ""),
+            rule(
+                Pattern::Get("/v1/health/{service}/status".to_string()),
+                "",
+                "status",
+            ),
+        ];
+        let web = Web::new().with_descriptors(&annotated(check)).unwrap();
+        let addr = serve(web).await;
+        let uri = |path: &str| format!("http://{addr}{path}");
+
+        let serving = r#"{"status":"SERVING"}"#;
+        let res = send(
+            Request::get(uri("/v1/health/f2.test.v1.Test")),
+            Bytes::new(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(res.body(), serving);
+
+        let res = send(
+            Request::get(uri("/v1/health?service=f2.test.v1.Test&_=1")),
+            Bytes::new(),
+        )
+        .await;
+        assert_eq!(res.body(), serving);
+
+        let body = r#"{"service":"f2.test.v1.Test"}"#;
+        let res = send(Request::post(uri("/v1/health:check")), body).await;
+        assert_eq!(res.body(), serving);
+
+        let res = send(
+            Request::get(uri("/v1/health/f2.test.v1.Test/status")),
+            Bytes::new(),
+        )
+        .await;
+        assert_eq!(res.body(), r#""SERVING""#);
+    }
+
+    #[tokio::test]
+    async fn answers_rest_errors_as_f2_errors() {
+        let check = rule(Pattern::Post("/v1/health/{service}".to_string()), "*", "");
+        let web = Web::new().with_descriptors(&annotated(check)).unwrap();
+        let addr = serve(web).await;
+        let uri = |path: &str| format!("http://{addr}{path}");
+
+        let res = send(Request::post(uri("/v1/health/nope")), Bytes::new()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+        let error: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(
+            error,
+            serde_json::json!({
+                "code": "ERROR_CODE_NOT_FOUND",
+                "message": "service not registered",
+            })
+        );
+
+        let res = send(Request::post(uri("/v1/health/nope")), "[]").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let error: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(error["code"], "ERROR_CODE_INVALID_ARGUMENT");
+
+        let body = vec![b' '; MAX_MESSAGE_SIZE + 1];
+        let res = send(Request::post(uri("/v1/health/nope")), body).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn rejects_rules_that_do_not_fit() {
+        for rule in [
+            rule(Pattern::Get("/v1/health/{nope}".to_string()), "", ""),
+            rule(Pattern::Post("/v1/health".to_string()), "nope", ""),
+            rule(Pattern::Get("/v1/health".to_string()), "", "nope"),
+            rule(Pattern::Get("v1/health".to_string()), "", ""),
+        ] {
+            assert!(matches!(
+                Web::new().with_descriptors(&annotated(rule)),
+                Err(Error::HttpRule(..))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn still_serves_native_grpc() {
+        let addr = serve(with_json()).await;
+
+        let channel = tonic::transport::Endpoint::from_shared(format!("http://{addr}"))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = tonic_health::pb::health_client::HealthClient::new(channel);
+        let res = client.check(HealthCheckRequest::default()).await.unwrap();
+        assert_eq!(
+            res.into_inner().status(),
+            health_check_response::ServingStatus::Serving
+        );
+
+        let res = send(post(addr, "Check", "text/plain"), Bytes::new()).await;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
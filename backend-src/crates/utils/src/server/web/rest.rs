@@ -0,0 +1,666 @@
+use std::iter;
+
+use f2_proto::f2::errors::v1::{Error as ErrorDetail, ErrorCode};
+use http::{HeaderMap, HeaderValue, Method, Request, Response, header};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use prost::Message;
+use prost_reflect::{
+    DescriptorPool, DynamicMessage, FieldDescriptor, Kind, MessageDescriptor, MethodDescriptor,
+    SerializeOptions,
+};
+use serde_json::{Map, Value, json};
+use tonic::body::Body;
+use tonic::{Code, Status};
+
+use super::{
+    Unary, copy_metadata, details, grpc_headers, http_status, json, percent_decode, query_decode,
+    unary,
+};
+use crate::Error;
+use crate::compression;
+use crate::envelope::envelope;
+
+/// `google.protobuf.MethodOptions`, read only for its `google.api.http`
+/// extension.
+#[derive(Clone, PartialEq, Message)]
+pub(super) struct MethodOptions {
+    #[prost(message, optional, tag = "72295728")]
+    pub(super) http: Option<HttpRule>,
+}
+
+/// `google.api.HttpRule`.
+#[derive(Clone, PartialEq, Message)]
+pub(super) struct HttpRule {
+    #[prost(oneof = "Pattern", tags = "2, 3, 4, 5, 6, 8")]
+    pub(super) pattern: Option<Pattern>,
+    #[prost(string, tag = "7")]
+    pub(super) body: String,
+    #[prost(string, tag = "12")]
+    pub(super) response_body: String,
+    #[prost(message, repeated, tag = "11")]
+    pub(super) additional_bindings: Vec<HttpRule>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub(super) enum Pattern {
+    #[prost(string, tag = "2")]
+    Get(String),
+    #[prost(string, tag = "3")]
+    Put(String),
+    #[prost(string, tag = "4")]
+    Post(String),
+    #[prost(string, tag = "5")]
+    Delete(String),
+    #[prost(string, tag = "6")]
+    Patch(String),
+    #[prost(message, tag = "8")]
+    Custom(CustomPattern),
+}
+
+/// `google.api.CustomHttpPattern`.
+#[derive(Clone, PartialEq, Message)]
+pub(super) struct CustomPattern {
+    #[prost(string, tag = "1")]
+    pub(super) kind: String,
+    #[prost(string, tag = "2")]
+    pub(super) path: String,
+}
+
+/// Type of the `f2.errors.v1.Error` REST callers get instead of a gRPC
+/// status.
+const ERROR_TYPE: &str = "f2.errors.v1.Error";
+
+/// REST routes for the unary methods annotated with `google.api.http`.
+pub(super) struct Rest {
+    routes: Vec<Route>,
+}
+
+struct Route {
+    method: Method,
+    template: Template,
+    /// `*` for the whole request message, or the field the body fills.
+    body: Option<String>,
+    response_body: Option<FieldDescriptor>,
+    rpc: MethodDescriptor,
+}
+
+/// A route matched by a request, with the values of its path variables.
+pub(super) struct Matched<'a> {
+    route: &'a Route,
+    variables: Vec<(&'a str, String)>,
+}
+
+impl Rest {
+    /// Routes for every annotated method, `None` if there are none.
+    pub(super) fn new(pool: &DescriptorPool) -> Result<Option<Self>, Error> {
+        let mut routes = Vec::new();
+        for rpc in pool
+            .services()
+            .flat_map(|service| service.methods().collect::<Vec<_>>())
+        {
+            let options = MethodOptions::decode(rpc.options().encode_to_vec().as_slice())
+                .map_err(|e| Error::HttpRule(rpc.full_name().to_string(), e.to_string()))?;
+            let Some(rule) = options.http else {
+                continue;
+            };
+            if rpc.is_client_streaming() || rpc.is_server_streaming() {
+                tracing::warn!("Not transcoding streaming method {}", rpc.full_name());
+                continue;
+            }
+            for rule in iter::once(&rule).chain(&rule.additional_bindings) {
+                let route = Route::new(rule, &rpc)
+                    .map_err(|reason| Error::HttpRule(rpc.full_name().to_string(), reason))?;
+                routes.push(route);
+            }
+        }
+        Ok((!routes.is_empty()).then_some(Self { routes }))
+    }
+
+    /// The first route matching a request.
+    pub(super) fn route(&self, method: &Method, path: &str) -> Option<Matched<'_>> {
+        self.routes
+            .iter()
+            .filter(|route| route.method == method)
+            .find_map(|route| {
+                let variables = route.template.matches(path)?;
+                Some(Matched { route, variables })
+            })
+    }
+}
+
+impl Route {
+    fn new(rule: &HttpRule, rpc: &MethodDescriptor) -> Result<Self, String> {
+        let (method, path) = match &rule.pattern {
+            Some(Pattern::Get(path)) => (Method::GET, path),
+            Some(Pattern::Put(path)) => (Method::PUT, path),
+            Some(Pattern::Post(path)) => (Method::POST, path),
+            Some(Pattern::Delete(path)) => (Method::DELETE, path),
+            Some(Pattern::Patch(path)) => (Method::PATCH, path),
+            Some(Pattern::Custom(custom)) => {
+                let method = Method::from_bytes(custom.kind.as_bytes())
+                    .map_err(|_| format!("invalid HTTP method {:?}", custom.kind))?;
+                (method, &custom.path)
+            }
+            None => return Err("rule has no pattern".to_string()),
+        };
+        let template = Template::parse(path)?;
+        let input = rpc.input();
+        for variable in &template.variables {
+            field(&input, &variable.field)
+                .ok_or_else(|| format!("{path} binds unknown field {:?}", variable.field))?;
+        }
+        let body = match rule.body.as_str() {
+            "" => None,
+            "*" => Some(rule.body.clone()),
+            body => {
+                field(&input, body).ok_or_else(|| format!("body names unknown field {body:?}"))?;
+                Some(rule.body.clone())
+            }
+        };
+        let response_body = match rule.response_body.as_str() {
+            "" => None,
+            name => Some(
+                rpc.output()
+                    .get_field_by_name(name)
+                    .ok_or_else(|| format!("response_body names unknown field {name:?}"))?,
+            ),
+        };
+        Ok(Self {
+            method,
+            template,
+            body,
+            response_body,
+            rpc: rpc.clone(),
+        })
+    }
+}
+
+/// Turns a REST request into a call of the method it is routed to.
+#[allow(clippy::result_large_err)]
+pub(super) async fn accept(
+    req: Request<Body>,
+    matched: Matched<'_>,
+    refusal: Option<&Status>,
+) -> Result<(Request<Body>, super::Protocol), Response<Body>> {
+    let route = matched.route;
+    let call = Call {
+        rpc: route.rpc.clone(),
+        response_body: route.response_body.clone(),
+    };
+    if let Some(encoding) = req.headers().get(header::CONTENT_ENCODING)
+        && encoding != "identity"
+    {
+        let status = Status::unimplemented(format!("unsupported content-encoding {encoding:?}"));
+        return Err(call.error(&HeaderMap::new(), &status));
+    }
+
+    let (mut parts, body) = req.into_parts();
+    let body = match super::read_body(body, refusal).await {
+        Ok(body) => body,
+        Err(status) => return Err(call.error(&HeaderMap::new(), &status)),
+    };
+    let message = request(route, matched.variables, parts.uri.query(), &body)
+        .and_then(|message| json::from_value(route.rpc.input(), message));
+    let message = match message {
+        Ok(message) => message,
+        Err(status) => return Err(call.error(&HeaderMap::new(), &status)),
+    };
+
+    let path = format!(
+        "/{}/{}",
+        route.rpc.parent_service().full_name(),
+        route.rpc.name()
+    );
+    parts.uri = match path.parse() {
+        Ok(uri) => uri,
+        Err(_) => return Err(call.error(&HeaderMap::new(), &Status::internal("bad method path"))),
+    };
+    parts.method = Method::POST;
+    parts.headers.remove(header::ACCEPT_ENCODING);
+    parts.headers.remove(compression::ACCEPT_ENCODING);
+    grpc_headers(&mut parts.headers);
+    let body = Body::new(Full::new(envelope(0, &message)));
+    Ok((
+        Request::from_parts(parts, body),
+        super::Protocol::Rest(call),
+    ))
+}
+
+/// Builds the request message as JSON from the body, the path variables
+/// and, unless the body fills the whole message, the query.
+#[allow(clippy::result_large_err)]
+fn request(
+    route: &Route,
+    variables: Vec<(&str, String)>,
+    query: Option<&str>,
+    body: &[u8],
+) -> Result<Value, Status> {
+    let input = route.rpc.input();
+    let parsed = || {
+        serde_json::from_slice::<Value>(body)
+            .map_err(|e| Status::invalid_argument(format!("invalid JSON body: {e}")))
+    };
+    let mut message = Map::new();
+    match route.body.as_deref() {
+        None => {}
+        Some(_) if body.is_empty() => {}
+        Some("*") => match parsed()? {
+            Value::Object(body) => message = body,
+            _ => return Err(Status::invalid_argument("JSON body must be an object")),
+        },
+        Some(field) => set(&mut message, &input, field, parsed()?)?,
+    }
+
+    for (field, value) in &variables {
+        set(&mut message, &input, field, Value::String(value.clone()))?;
+    }
+
+    if route.body.as_deref() == Some("*") {
+        return Ok(Value::Object(message));
+    }
+    let bound = |name: &str| {
+        variables.iter().any(|(field, _)| *field == name)
+            || route.body.as_deref() == Some(name.split('.').next().unwrap_or_default())
+    };
+    for pair in query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|p| !p.is_empty())
+    {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let name = String::from_utf8_lossy(&query_decode(name)).into_owned();
+        // parameters naming no field, like cache busters, are left alone
+        if bound(&name) || field(&input, &name).is_none() {
+            continue;
+        }
+        let value = String::from_utf8_lossy(&query_decode(value)).into_owned();
+        set(&mut message, &input, &name, Value::String(value))?;
+    }
+    Ok(Value::Object(message))
+}
+
+/// The field a dotted path of proto or JSON names leads to.
+fn field(desc: &MessageDescriptor, path: &str) -> Option<FieldDescriptor> {
+    let (name, rest) = match path.split_once('.') {
+        Some((name, rest)) => (name, Some(rest)),
+        None => (path, None),
+    };
+    let field = desc
+        .get_field_by_name(name)
+        .or_else(|| desc.get_field_by_json_name(name))?;
+    match (rest, field.kind()) {
+        (None, _) => Some(field),
+        (Some(rest), Kind::Message(desc)) => self::field(&desc, rest),
+        (Some(_), _) => None,
+    }
+}
+
+/// Sets the field at `path` in a JSON message, replacing whatever the body
+/// had for it and appending to repeated fields. Path and query values come
+/// as strings, which the JSON mapping accepts for every scalar but `bool`.
+#[allow(clippy::result_large_err)]
+fn set(
+    message: &mut Map<String, Value>,
+    desc: &MessageDescriptor,
+    path: &str,
+    value: Value,
+) -> Result<(), Status> {
+    let (name, rest) = match path.split_once('.') {
+        Some((name, rest)) => (name, Some(rest)),
+        None => (path, None),
+    };
+    let field = desc
+        .get_field_by_name(name)
+        .or_else(|| desc.get_field_by_json_name(name))
+        .ok_or_else(|| Status::invalid_argument(format!("unknown field {path:?}")))?;
+    let existing = message
+        .remove(field.json_name())
+        .or_else(|| message.remove(field.name()));
+
+    let value = match (rest, field.kind()) {
+        (Some(rest), Kind::Message(desc)) => {
+            let mut nested = match existing {
+                Some(Value::Object(nested)) => nested,
+                _ => Map::new(),
+            };
+            set(&mut nested, &desc, rest, value)?;
+            Value::Object(nested)
+        }
+        (Some(_), _) => {
+            return Err(Status::invalid_argument(format!("{name:?} has no fields")));
+        }
+        (None, kind) => {
+            let value = match (kind, value) {
+                (Kind::Bool, Value::String(v)) if v == "true" => Value::Bool(true),
+                (Kind::Bool, Value::String(v)) if v == "false" => Value::Bool(false),
+                (_, value) => value,
+            };
+            match (field.is_list(), existing) {
+                (true, Some(Value::Array(mut values))) => {
+                    values.push(value);
+                    Value::Array(values)
+                }
+                (true, _) if !value.is_array() => Value::Array(vec![value]),
+                _ => value,
+            }
+        }
+    };
+    message.insert(field.name().to_string(), value);
+    Ok(())
+}
+
+/// A REST call, answered with its response message as JSON.
+pub(crate) struct Call {
+    rpc: MethodDescriptor,
+    response_body: Option<FieldDescriptor>,
+}
+
+impl Call {
+    #[allow(clippy::result_large_err)]
+    pub(super) async fn respond(self, res: Response<Body>) -> Response<Body> {
+        let Unary {
+            headers, message, ..
+        } = unary(res).await;
+        match message.and_then(|message| self.json(message)) {
+            Ok(body) => {
+                let mut res = Response::new(Body::new(Full::new(body)));
+                copy_metadata(&headers, res.headers_mut());
+                res.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("application/json"),
+                );
+                res
+            }
+            Err(status) => self.error(&headers, &status),
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn json(&self, message: Bytes) -> Result<Bytes, Status> {
+        let Some(field) = &self.response_body else {
+            return json::to_json(self.rpc.output(), &message);
+        };
+        let message = DynamicMessage::decode(self.rpc.output(), message)
+            .map_err(|e| Status::internal(format!("invalid response message: {e}")))?;
+        let options = SerializeOptions::new().skip_default_fields(false);
+        let mut body = message
+            .serialize_with_options(serde_json::value::Serializer, &options)
+            .map_err(|e| Status::internal(format!("failed to encode response as JSON: {e}")))?;
+        let body = body
+            .get_mut(field.json_name())
+            .map(Value::take)
+            .unwrap_or_default();
+        Ok(body.to_string().into())
+    }
+
+    /// Answers with the HTTP status for `status` and the error as an
+    /// `f2.errors.v1.Error`.
+    fn error(&self, metadata: &HeaderMap, status: &Status) -> Response<Body> {
+        let mut res = Response::new(Body::new(Full::new(Bytes::from(
+            error_json(status).to_string(),
+        ))));
+        *res.status_mut() = http_status(status.code());
+        copy_metadata(metadata, res.headers_mut());
+        res.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        res
+    }
+}
+
+/// The `f2.errors.v1.Error` for a status, the one the service attached if
+/// it did.
+fn error_json(status: &Status) -> Value {
+    let attached = details(status)
+        .into_iter()
+        .find(|any| any.type_url.rsplit('/').next() == Some(ERROR_TYPE))
+        .and_then(|any| ErrorDetail::decode(any.value.as_slice()).ok());
+    let (code, message) = match attached {
+        Some(error) => {
+            let code = match ErrorCode::try_from(error.code) {
+                Ok(code) => Value::from(code.as_str_name()),
+                Err(_) => Value::from(error.code),
+            };
+            (code, error.message)
+        }
+        None => (
+            Value::from(error_code(status.code())),
+            status.message().to_string(),
+        ),
+    };
+    json!({ "code": code, "message": message })
+}
+
+fn error_code(code: Code) -> &'static str {
+    let code = match code {
+        Code::NotFound => ErrorCode::NotFound,
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+            ErrorCode::InvalidArgument
+        }
+        Code::PermissionDenied | Code::Unauthenticated => ErrorCode::PermissionDenied,
+        Code::Internal | Code::Unknown | Code::DataLoss => ErrorCode::Internal,
+        _ => ErrorCode::Unspecified,
+    };
+    code.as_str_name()
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    /// `*`, one segment.
+    Any,
+    /// `**`, all remaining segments.
+    Rest,
+}
+
+impl Segment {
+    fn parse(segment: &str) -> Result<Self, String> {
+        match segment {
+            "*" => Ok(Segment::Any),
+            "**" => Ok(Segment::Rest),
+            "" => Err("empty path segment".to_string()),
+            s if s.contains(['{', '}', '=']) => Err(format!("invalid path segment {s:?}")),
+            s => Ok(Segment::Literal(s.to_string())),
+        }
+    }
+}
+
+/// A variable binding the segments `start..end` of its template.
+#[derive(Debug, PartialEq)]
+struct Variable {
+    field: String,
+    start: usize,
+    end: usize,
+}
+
+/// A `google.api.http` path template, like `/v1/{name=users/*}:undelete`.
+#[derive(Debug, PartialEq)]
+struct Template {
+    segments: Vec<Segment>,
+    variables: Vec<Variable>,
+    verb: Option<String>,
+}
+
+impl Template {
+    fn parse(template: &str) -> Result<Self, String> {
+        let path = template
+            .strip_prefix('/')
+            .ok_or_else(|| format!("{template} does not start with /"))?;
+        // the verb follows the last segment, outside of any variable
+        let (mut path, verb) = match path.rfind(':') {
+            Some(i) if !path[i..].contains(['/', '}']) => {
+                (&path[..i], Some(path[i + 1..].to_string()))
+            }
+            _ => (path, None),
+        };
+
+        let mut segments = Vec::new();
+        let mut variables = Vec::new();
+        loop {
+            if let Some(variable) = path.strip_prefix('{') {
+                let end = variable
+                    .find('}')
+                    .ok_or_else(|| format!("{template} has an unclosed variable"))?;
+                let (field, pattern) = variable[..end]
+                    .split_once('=')
+                    .unwrap_or((&variable[..end], "*"));
+                let start = segments.len();
+                for segment in pattern.split('/') {
+                    segments.push(Segment::parse(segment)?);
+                }
+                variables.push(Variable {
+                    field: field.to_string(),
+                    start,
+                    end: segments.len(),
+                });
+                path = &variable[end + 1..];
+            } else {
+                let end = path.find('/').unwrap_or(path.len());
+                segments.push(Segment::parse(&path[..end])?);
+                path = &path[end..];
+            }
+            match path.strip_prefix('/') {
+                Some(rest) => path = rest,
+                None if path.is_empty() => break,
+                None => return Err(format!("{template} has a malformed segment")),
+            }
+        }
+        if segments.iter().rev().skip(1).any(|s| *s == Segment::Rest) {
+            return Err(format!("{template} has ** before its last segment"));
+        }
+        Ok(Self {
+            segments,
+            variables,
+            verb,
+        })
+    }
+
+    /// The values of the variables if `path` matches.
+    fn matches(&self, path: &str) -> Option<Vec<(&str, String)>> {
+        let mut path = path.strip_prefix('/')?;
+        if let Some(verb) = &self.verb {
+            path = path.strip_suffix(verb.as_str())?.strip_suffix(':')?;
+        }
+        let parts: Vec<String> = path
+            .split('/')
+            .map(|part| String::from_utf8_lossy(&percent_decode(part)).into_owned())
+            .collect();
+
+        // the parts of the path each segment of the template took
+        let mut taken = Vec::with_capacity(self.segments.len());
+        let mut next = 0;
+        for segment in &self.segments {
+            let start = next;
+            match segment {
+                Segment::Literal(literal) if parts.get(next) == Some(literal) => next += 1,
+                Segment::Any if parts.get(next).is_some_and(|p| !p.is_empty()) => next += 1,
+                Segment::Rest => next = parts.len(),
+                _ => return None,
+            }
+            taken.push(start..next);
+        }
+        if next != parts.len() {
+            return None;
+        }
+
+        let variables = self
+            .variables
+            .iter()
+            .map(|variable| {
+                let parts = &parts[taken[variable.start].start..taken[variable.end - 1].end];
+                (variable.field.as_str(), parts.join("/"))
+            })
+            .collect();
+        Some(variables)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_templates() {
+        let template = Template::parse("/v1/{name=users/*/posts/**}:undelete").unwrap();
+        assert_eq!(
+            template,
+            Template {
+                segments: vec![
+                    Segment::Literal("v1".to_string()),
+                    Segment::Literal("users".to_string()),
+                    Segment::Any,
+                    Segment::Literal("posts".to_string()),
+                    Segment::Rest,
+                ],
+                variables: vec![Variable {
+                    field: "name".to_string(),
+                    start: 1,
+                    end: 5,
+                }],
+                verb: Some("undelete".to_string()),
+            }
+        );
+
+        for invalid in [
+            "v1/users",
+            "/v1/{id",
+            "/v1/**/users",
+            "/v1//users",
+            "/v1/{id}x",
+        ] {
+            assert!(Template::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn matches_paths() {
+        let template = Template::parse("/v1/users/{user.id}/posts/{post=**}").unwrap();
+        assert_eq!(
+            template.matches("/v1/users/a%20b/posts/2025/06"),
+            Some(vec![
+                ("user.id", "a b".to_string()),
+                ("post", "2025/06".to_string())
+            ])
+        );
+        assert_eq!(template.matches("/v1/users//posts/1"), None);
+        assert_eq!(template.matches("/v1/groups/1/posts/1"), None);
+
+        let template = Template::parse("/v1/users/{id}:ban").unwrap();
+        assert_eq!(
+            template.matches("/v1/users/1:ban"),
+            Some(vec![("id", "1".to_string())])
+        );
+        assert_eq!(template.matches("/v1/users/1"), None);
+        assert_eq!(template.matches("/v1/users/1/x"), None);
+    }
+
+    #[test]
+    fn describes_errors_as_f2_errors() {
+        let status = Status::permission_denied("not yours");
+        assert_eq!(
+            error_json(&status),
+            json!({ "code": "ERROR_CODE_PERMISSION_DENIED", "message": "not yours" })
+        );
+
+        let attached = ErrorDetail {
+            code: ErrorCode::NotFound.into(),
+            message: "no user with that handle".to_string(),
+        };
+        let details = super::super::RpcStatus {
+            code: Code::NotFound as i32,
+            message: "not found".to_string(),
+            details: vec![super::super::Any {
+                type_url: format!("type.googleapis.com/{ERROR_TYPE}"),
+                value: attached.encode_to_vec(),
+            }],
+        };
+        let status =
+            Status::with_details(Code::NotFound, "not found", details.encode_to_vec().into());
+        assert_eq!(
+            error_json(&status),
+            json!({ "code": "ERROR_CODE_NOT_FOUND", "message": "no user with that handle" })
+        );
+    }
+}
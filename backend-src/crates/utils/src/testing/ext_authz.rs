@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+
+use envoy_types::pb::envoy::service::auth::v3::{
+    AttributeContext, CheckRequest, attribute_context,
+};
+
+/// Builds the `CheckRequest` Envoy's ext_authz filter sends for an HTTP
+/// request, leaving everything not set empty.
+///
+/// ```
+/// # use f2_utils::testing::CheckRequestBuilder;
+/// let req = CheckRequestBuilder::new()
+///     .method("POST")
+///     .path("/rest/v1/images")
+///     .header("authorization", "Bearer token")
+///     .build();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CheckRequestBuilder {
+    http: attribute_context::HttpRequest,
+    context_extensions: HashMap<String, String>,
+}
+
+impl CheckRequestBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The request ID Envoy takes from or generates for `x-request-id`.
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.http.id = id.into();
+        self
+    }
+
+    pub fn method(mut self, method: impl Into<String>) -> Self {
+        self.http.method = method.into();
+        self
+    }
+
+    /// The path with its query string, as Envoy sends it.
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.http.path = path.into();
+        self
+    }
+
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.http.host = host.into();
+        self
+    }
+
+    /// Adds a request header, replacing an earlier one of the same name.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.http.headers.insert(name.into(), value.into());
+        self
+    }
+
+    /// Sets the request body, as Envoy sends it when buffering is on.
+    pub fn body(mut self, body: impl Into<String>) -> Self {
+        self.http.body = body.into();
+        self.http.size = self.http.body.len() as i64;
+        self
+    }
+
+    /// Adds a context extension set on the route's ext_authz filter config.
+    pub fn context_extension(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.context_extensions.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn build(self) -> CheckRequest {
+        CheckRequest {
+            attributes: Some(AttributeContext {
+                request: Some(attribute_context::Request {
+                    time: None,
+                    http: Some(self.http),
+                }),
+                context_extensions: self.context_extensions,
+                ..Default::default()
+            }),
+        }
+    }
+}
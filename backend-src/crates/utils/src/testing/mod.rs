@@ -0,0 +1,174 @@
+//! Harness for end-to-end tests of tonic services, behind the `testing`
+//! feature.
+//!
+//! [`TestServer`] serves `Routes` through the same h2c transport as
+//! [`Server`] and hands out a client channel connected to it, so tests go
+//! through HTTP/2 framing and routing instead of calling service methods
+//! directly.
+//!
+//! ```no_run
+//! # async fn run(routes: tonic::service::Routes) {
+//! let server = f2_utils::testing::TestServer::duplex(routes).await;
+//! let channel = server.channel();
+//! # }
+//! ```
+
+mod ext_authz;
+
+use std::convert::Infallible;
+use std::future::pending;
+use std::net::SocketAddr;
+
+use http::Uri;
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tonic::service::Routes;
+use tonic::transport::{Channel, Endpoint};
+
+pub use ext_authz::CheckRequestBuilder;
+
+use crate::server::Server;
+use crate::server::h2c::H2c;
+
+/// Bytes buffered in each direction of an in-memory connection.
+const DUPLEX_BUFFER: usize = 64 * 1024;
+
+/// A server started for a test, stopped when dropped.
+pub struct TestServer {
+    channel: Channel,
+    addr: Option<SocketAddr>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    /// Serves `routes` over in-memory streams, one per connection the
+    /// channel opens, without touching the network.
+    pub async fn duplex(routes: Routes) -> Self {
+        let svc = routes.prepare();
+        let connector = tower::service_fn(move |_: Uri| {
+            let (client, server) = tokio::io::duplex(DUPLEX_BUFFER);
+            let conn = H2c::new(svc.clone()).serve_connection(TokioIo::new(server));
+            tokio::spawn(async move {
+                if let Err(e) = conn.await {
+                    tracing::warn!("In-memory connection failed: {}", e);
+                }
+            });
+            async move { Ok::<_, Infallible>(TokioIo::new(client)) }
+        });
+        let channel = Endpoint::from_static("http://duplex")
+            .connect_with_connector(connector)
+            .await
+            .expect("in-memory connections don't fail");
+        Self {
+            channel,
+            addr: None,
+            task: None,
+        }
+    }
+
+    /// Serves `routes` with a [`Server`] on an ephemeral port on the
+    /// loopback interface, health and reflection included.
+    ///
+    /// # Panics
+    ///
+    /// Panics when no port can be bound or connected to.
+    pub async fn ephemeral(routes: Routes) -> Self {
+        Self::serve(Server::new(routes)).await
+    }
+
+    /// Serves a configured [`Server`] on an ephemeral port on the loopback
+    /// interface, replacing any address or listener it was given.
+    ///
+    /// # Panics
+    ///
+    /// Panics when no port can be bound or connected to.
+    pub async fn serve(server: Server) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind an ephemeral port");
+        let addr = listener
+            .local_addr()
+            .expect("bound listener has an address");
+        let serving = server
+            .with_listener(listener)
+            .serve_with_shutdown(pending());
+        let task = tokio::spawn(async move {
+            if let Err(e) = serving.await {
+                tracing::warn!("Test server stopped: {}", e);
+            }
+        });
+        let channel = Endpoint::from_shared(format!("http://{addr}"))
+            .expect("socket address is a valid URI")
+            .connect()
+            .await
+            .expect("failed to connect to the test server");
+        Self {
+            channel,
+            addr: Some(addr),
+            task: Some(task),
+        }
+    }
+
+    /// A client channel connected to the server, e.g. for a generated
+    /// client's `new`.
+    pub fn channel(&self) -> Channel {
+        self.channel.clone()
+    }
+
+    /// The port the server listens on, `None` for in-memory servers.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic_health::pb::health_client::HealthClient;
+    use tonic_health::pb::{HealthCheckRequest, health_check_response};
+
+    use super::*;
+
+    async fn check(channel: Channel, service: &str) -> health_check_response::ServingStatus {
+        let req = HealthCheckRequest {
+            service: service.into(),
+        };
+        let res = HealthClient::new(channel).check(req).await.unwrap();
+        res.into_inner().status()
+    }
+
+    #[tokio::test]
+    async fn serves_routes_in_memory() {
+        let (reporter, health) = tonic_health::server::health_reporter();
+        reporter
+            .set_service_status("f2.test.v1.Test", tonic_health::ServingStatus::Serving)
+            .await;
+        let server = TestServer::duplex(Routes::new(health)).await;
+
+        assert_eq!(server.addr(), None);
+        assert_eq!(
+            check(server.channel(), "f2.test.v1.Test").await,
+            health_check_response::ServingStatus::Serving
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_on_an_ephemeral_port() {
+        // the server brings its own health service
+        let server = TestServer::ephemeral(Routes::default()).await;
+
+        assert!(server.addr().unwrap().ip().is_loopback());
+        assert_eq!(
+            check(server.channel(), "").await,
+            health_check_response::ServingStatus::Serving
+        );
+    }
+}
@@ -0,0 +1,376 @@
+//! rustls configuration for the server and the client.
+//!
+//! Certificates and keys are PEM files. The server re-reads its certificate
+//! and key while running, so files rotated in place by cert-manager are picked
+//! up without a restart. The client reads its files for every new connection.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+
+use rustls::RootCertStore;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::Error;
+
+pub(crate) const ALPN_H2: &[u8] = b"h2";
+const ALPN_HTTP1: &[u8] = b"http/1.1";
+
+const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Server certificate, key and, for mutual TLS, the CA client certificates
+/// must be signed by. Both `h2` and `http/1.1` are offered over ALPN.
+#[derive(Clone, Debug)]
+pub struct ServerTls {
+    cert: PathBuf,
+    key: PathBuf,
+    client_ca: Option<PathBuf>,
+    reload_interval: Duration,
+}
+
+/// CA to verify servers against and, for mutual TLS, the client's own
+/// certificate and key. Only `h2` is offered over ALPN.
+#[derive(Clone, Debug)]
+pub struct ClientTls {
+    ca: PathBuf,
+    identity: Option<(PathBuf, PathBuf)>,
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect())
+        .map_err(|e| Error::Pem(path.to_path_buf(), e))
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>, Error> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| Error::Pem(path.to_path_buf(), e))
+}
+
+fn read_roots(path: &Path) -> Result<RootCertStore, Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+fn certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey, Error> {
+    Ok(CertifiedKey::from_der(
+        read_certs(cert)?,
+        read_key(key)?,
+        &provider(),
+    )?)
+}
+
+impl ServerTls {
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        Self {
+            cert: cert.into(),
+            key: key.into(),
+            client_ca: None,
+            reload_interval: DEFAULT_RELOAD_INTERVAL,
+        }
+    }
+
+    /// Requires clients to present a certificate signed by this CA.
+    pub fn with_client_ca(mut self, ca: impl Into<PathBuf>) -> Self {
+        self.client_ca = Some(ca.into());
+        self
+    }
+
+    /// How often the certificate and key files are checked for changes,
+    /// every 30 seconds by default.
+    pub fn with_reload_interval(mut self, interval: Duration) -> Self {
+        self.reload_interval = interval;
+        self
+    }
+
+    /// Loads the certificate and starts watching the files. The watch ends
+    /// once the acceptor and every connection using it are gone.
+    pub(crate) fn acceptor(&self) -> Result<TlsAcceptor, Error> {
+        let resolver = Arc::new(ReloadingCert::load(&self.cert, &self.key)?);
+        tokio::spawn(watch(
+            Arc::downgrade(&resolver),
+            self.cert.clone(),
+            self.key.clone(),
+            self.reload_interval,
+        ));
+
+        let builder = rustls::ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.client_ca {
+            Some(ca) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(
+                    Arc::new(read_roots(ca)?),
+                    provider(),
+                )
+                .build()
+                .map_err(|e| Error::Tls(rustls::Error::General(e.to_string())))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_cert_resolver(resolver);
+        config.alpn_protocols = vec![ALPN_H2.to_vec(), ALPN_HTTP1.to_vec()];
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// Serves the most recently loaded certificate.
+struct ReloadingCert {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadingCert {
+    fn load(cert: &Path, key: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            current: RwLock::new(Arc::new(certified_key(cert, key)?)),
+        })
+    }
+}
+
+impl fmt::Debug for ReloadingCert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReloadingCert").finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Reloads the certificate whenever the contents of either file change. A
+/// half-written pair fails to load and is retried on the next tick.
+async fn watch(resolver: Weak<ReloadingCert>, cert: PathBuf, key: PathBuf, interval: Duration) {
+    let read = || Some((std::fs::read(&cert).ok()?, std::fs::read(&key).ok()?));
+    let mut loaded = read();
+    let mut ticks = tokio::time::interval(interval);
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+        let Some(resolver) = resolver.upgrade() else {
+            return;
+        };
+        let files = read();
+        if files.is_none() || files == loaded {
+            continue;
+        }
+        match certified_key(&cert, &key) {
+            Ok(certified) => {
+                *resolver.current.write().unwrap() = Arc::new(certified);
+                loaded = files;
+                tracing::info!("Reloaded TLS certificate from {}", cert.display());
+            }
+            Err(e) => tracing::warn!("Failed to reload TLS certificate: {}", e),
+        }
+    }
+}
+
+impl ClientTls {
+    /// Verifies servers against the CA certificates in `ca`.
+    pub fn new(ca: impl Into<PathBuf>) -> Self {
+        Self {
+            ca: ca.into(),
+            identity: None,
+        }
+    }
+
+    /// Presents this certificate and key to servers asking for one.
+    pub fn with_identity(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.identity = Some((cert.into(), key.into()));
+        self
+    }
+
+    pub(crate) fn connector(&self) -> Result<TlsConnector, Error> {
+        let builder = rustls::ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(read_roots(&self.ca)?);
+        let mut config = match &self.identity {
+            Some((cert, key)) => {
+                builder.with_client_auth_cert(read_certs(cert)?, read_key(key)?)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = vec![ALPN_H2.to_vec()];
+        Ok(TlsConnector::from(Arc::new(config)))
+    }
+}
+
+pub(crate) fn server_name(host: &str) -> Result<ServerName<'static>, Error> {
+    ServerName::try_from(host.trim_matches(['[', ']']).to_string())
+        .map_err(|e| Error::Tls(rustls::Error::General(e.to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::balance::{BalancedChannel, Static};
+    use crate::client::h2c::H2cChannel;
+    use crate::server::Server;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+    use std::net::SocketAddr;
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tonic::service::Routes;
+    use tonic_health::pb::HealthCheckRequest;
+    use tonic_health::pb::health_client::HealthClient;
+
+    /// A throwaway CA issuing `localhost` certificates into a temp directory.
+    struct Pki {
+        dir: TempDir,
+        ca: CertifiedIssuer<'static, KeyPair>,
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+            let dir = TempDir::new().unwrap();
+            std::fs::write(dir.path().join("ca.pem"), ca.pem()).unwrap();
+            Self { dir, ca }
+        }
+
+        fn ca(&self) -> PathBuf {
+            self.dir.path().join("ca.pem")
+        }
+
+        /// Writes `<name>.pem` and `<name>.key`, returning their paths and the
+        /// certificate.
+        fn issue(&self, name: &str) -> (PathBuf, PathBuf, CertificateDer<'static>) {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec!["localhost".to_string()])
+                .unwrap()
+                .signed_by(&key, &self.ca)
+                .unwrap();
+            let cert_path = self.dir.path().join(format!("{name}.pem"));
+            let key_path = self.dir.path().join(format!("{name}.key"));
+            std::fs::write(&cert_path, cert.pem()).unwrap();
+            std::fs::write(&key_path, key.serialize_pem()).unwrap();
+            (cert_path, key_path, cert.der().clone())
+        }
+    }
+
+    async fn serve(tls: ServerTls) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new(Routes::default())
+            .with_listener(listener)
+            .with_tls(tls);
+        tokio::spawn(server.serve_with_shutdown(std::future::pending()));
+        addr
+    }
+
+    async fn check(addr: SocketAddr, tls: ClientTls) -> bool {
+        let channel =
+            H2cChannel::new(Client::builder(TokioExecutor::new()).build_http()).with_tls(tls);
+        let origin = format!("https://localhost:{}", addr.port())
+            .parse()
+            .unwrap();
+        let mut client = HealthClient::with_origin(channel, origin);
+        client.check(HealthCheckRequest::default()).await.is_ok()
+    }
+
+    async fn handshake(
+        addr: SocketAddr,
+        tls: &ClientTls,
+        alpn: &[u8],
+    ) -> tokio_rustls::client::TlsStream<TcpStream> {
+        let mut config = rustls::ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(read_roots(&tls.ca).unwrap())
+            .with_no_client_auth();
+        config.alpn_protocols = vec![alpn.to_vec()];
+        let io = TcpStream::connect(addr).await.unwrap();
+        TlsConnector::from(Arc::new(config))
+            .connect(server_name("localhost").unwrap(), io)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_h2_over_tls() {
+        let pki = Pki::new();
+        let (cert, key, _) = pki.issue("server");
+        let addr = serve(ServerTls::new(cert, key)).await;
+
+        assert!(check(addr, ClientTls::new(pki.ca())).await);
+    }
+
+    #[tokio::test]
+    async fn balances_over_tls_verifying_the_target_name() {
+        let pki = Pki::new();
+        let (cert, key, _) = pki.issue("server");
+        let addr = serve(ServerTls::new(cert, key)).await;
+
+        let channel = H2cChannel::new(Client::builder(TokioExecutor::new()).build_http())
+            .with_tls(ClientTls::new(pki.ca()));
+        let channel = BalancedChannel::new(channel, Static::new([addr]));
+        let mut client = HealthClient::with_origin(channel, "https://localhost".parse().unwrap());
+        assert!(client.check(HealthCheckRequest::default()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn negotiates_http1_over_alpn() {
+        let pki = Pki::new();
+        let (cert, key, _) = pki.issue("server");
+        let addr = serve(ServerTls::new(cert, key)).await;
+
+        let mut io = handshake(addr, &ClientTls::new(pki.ca()), ALPN_HTTP1).await;
+        assert_eq!(io.get_ref().1.alpn_protocol(), Some(ALPN_HTTP1));
+
+        io.write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0; 1024];
+        let n = io.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 426"));
+    }
+
+    #[tokio::test]
+    async fn requires_client_certificate() {
+        let pki = Pki::new();
+        let (cert, key, _) = pki.issue("server");
+        let addr = serve(ServerTls::new(cert, key).with_client_ca(pki.ca())).await;
+
+        assert!(!check(addr, ClientTls::new(pki.ca())).await);
+
+        let (cert, key, _) = pki.issue("client");
+        assert!(check(addr, ClientTls::new(pki.ca()).with_identity(cert, key)).await);
+    }
+
+    #[tokio::test]
+    async fn reloads_rotated_certificate() {
+        let pki = Pki::new();
+        let (cert, key, first) = pki.issue("server");
+        let tls = ServerTls::new(cert, key).with_reload_interval(Duration::from_millis(20));
+        let addr = serve(tls).await;
+        let client = ClientTls::new(pki.ca());
+
+        let io = handshake(addr, &client, ALPN_H2).await;
+        assert_eq!(io.get_ref().1.peer_certificates().unwrap()[0], first);
+
+        let (_, _, second) = pki.issue("server");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let io = handshake(addr, &client, ALPN_H2).await;
+        assert_eq!(io.get_ref().1.peer_certificates().unwrap()[0], second);
+    }
+}